@@ -0,0 +1,194 @@
+//! Base archives for differential archives (`--base`)
+//!
+//! A differential archive stores only data its base archives lack. Blocks or
+//! chunks already in a base are written as BaseReference extents naming the
+//! base by its identity and the data by its BLAKE3 hash, and are restored
+//! from the base on extraction.
+
+use crate::format::*;
+use crate::stream::StreamCompression;
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+/// Hex form of an archive identity, as shown to users
+pub fn archive_id_hex(archive_id: &[u8; 32]) -> String {
+    blake3::Hash::from(*archive_id).to_hex().to_string()
+}
+
+/// A base archive opened for lookups and reads
+pub struct BaseArchive {
+    pub archive_id: [u8; 32],
+    /// File name, recorded in differential archives as a hint
+    pub name: String,
+    pub block_size: u32,
+    file: File,
+    extents: HashMap<u64, ExtentEntry>, // Data extents by extent id
+}
+
+impl BaseArchive {
+    /// Open an archive and load its extent table from the footer
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path).with_context(|| format!("Failed to open base archive {:?}", path))?;
+        let mut magic = [0u8; 6];
+        let n = file.read(&mut magic)?;
+        let compression = StreamCompression::detect(&magic[..n]);
+        if compression != StreamCompression::None {
+            bail!("Base archive {:?} is {}-compressed; decompress it first", path, compression.name());
+        }
+        file.seek(SeekFrom::Start(0))?;
+        let header = ArchiveHeader::read(&mut file)?;
+        if header.encryption.is_some() {
+            bail!("Base archive {:?} is encrypted; differential archives need a plain base", path);
+        }
+        let footer = ArchiveFooter::locate(&mut file)?
+            .with_context(|| format!("Base archive {:?} has no footer index", path))?;
+
+        Ok(Self {
+            archive_id: footer.archive_id(),
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            block_size: header.block_size,
+            file,
+            extents: footer.extents.iter().map(|entry| (entry.extent_id, *entry)).collect(),
+        })
+    }
+
+    /// Data extents of the archive, in no particular order
+    pub fn extents(&self) -> impl Iterator<Item = &ExtentEntry> {
+        self.extents.values()
+    }
+
+    /// Write an extent's file data to `dest` at `dest_offset`, reflinking
+    /// from the base archive when the ranges are block-aligned and otherwise
+    /// copying (after checking the checksum); returns whether it reflinked
+    fn restore_extent(&self, entry: &ExtentEntry, dest: &File, dest_offset: u64, reflink_supported: bool) -> Result<bool> {
+        let block_size = self.block_size as u64;
+        let length = entry.covered_length;
+        let aligned = [entry.data_offset, dest_offset, length]
+            .iter()
+            .all(|value| value.is_multiple_of(block_size));
+        if reflink_supported && aligned && entry.compression.is_none() {
+            let cloned = crate::reflink::try_reflink_range(&self.file, entry.data_offset, dest, dest_offset, length)
+                .unwrap_or(false);
+            if cloned {
+                return Ok(true);
+            }
+        }
+
+        let data = self.read_extent(entry)?;
+        dest.write_all_at(&data, dest_offset)?;
+        Ok(false)
+    }
+
+    /// Read an extent's file data, checking its checksum
+    fn read_extent(&self, entry: &ExtentEntry) -> Result<Vec<u8>> {
+        let length = entry.covered_length;
+        let mut data = vec![0u8; entry.length_in_blocks as usize * self.block_size as usize];
+        self.file.read_exact_at(&mut data, entry.data_offset)?;
+        let calculated_checksum = crc32fast::hash(&data);
+        if calculated_checksum != entry.checksum {
+            bail!(
+                "Checksum mismatch for extent {} in base archive {}: expected {}, got {}",
+                entry.extent_id,
+                self.name,
+                entry.checksum,
+                calculated_checksum
+            );
+        }
+        if let Some(compression) = &entry.compression {
+            data = crate::compression::decompress(compression, &data, length)?;
+        }
+        data.truncate(length as usize);
+        Ok(data)
+    }
+}
+
+/// Base archives available to an extraction, by identity
+#[derive(Default)]
+pub struct BaseArchives {
+    archives: HashMap<[u8; 32], BaseArchive>,
+}
+
+impl BaseArchives {
+    pub fn add(&mut self, base: BaseArchive) {
+        self.archives.insert(base.archive_id, base);
+    }
+
+    /// Restore a BaseReference extent into `dest`, returning how many bytes
+    /// were reflinked rather than copied
+    pub fn restore(
+        &self,
+        extent_header: &ExtentHeader,
+        block_size: u32,
+        dest: &File,
+        dest_offset: u64,
+        reflink_supported: bool,
+    ) -> Result<u64> {
+        let (base, entries) = self.resolve(extent_header, block_size)?;
+        let mut offset = dest_offset;
+        let mut reflinked = 0;
+        for entry in entries {
+            if base.restore_extent(entry, dest, offset, reflink_supported)? {
+                reflinked += entry.covered_length;
+            }
+            offset += entry.covered_length;
+        }
+        Ok(reflinked)
+    }
+
+    /// Read the file data a base reference extent stands for
+    pub fn read(&self, extent_header: &ExtentHeader, block_size: u32) -> Result<Vec<u8>> {
+        let (base, entries) = self.resolve(extent_header, block_size)?;
+        let mut data = Vec::new();
+        for entry in entries {
+            data.extend_from_slice(&base.read_extent(entry)?);
+        }
+        Ok(data)
+    }
+
+    /// Find the base archive and extents a base reference extent names
+    fn resolve(&self, extent_header: &ExtentHeader, block_size: u32) -> Result<(&BaseArchive, Vec<&ExtentEntry>)> {
+        let Some(link) = &extent_header.base else {
+            bail!("Extent {} is not a base reference", extent_header.extent_id);
+        };
+        let Some(base) = self.archives.get(&link.archive_id) else {
+            bail!(
+                "Archive needs base archive {} (pass it with --base)",
+                archive_id_hex(&link.archive_id)
+            );
+        };
+
+        // The first extent must be the one the reference was made from
+        let first = base.extents.get(&extent_header.extent_id);
+        if first.is_none_or(|entry| entry.content_hash != link.content_hash || entry.checksum != extent_header.checksum) {
+            bail!(
+                "Base archive {} does not hold extent {} as referenced",
+                base.name,
+                extent_header.extent_id
+            );
+        }
+
+        let mut entries = Vec::with_capacity(link.extent_count as usize);
+        for extent_id in extent_header.extent_id..extent_header.extent_id + link.extent_count as u64 {
+            let entry = base.extents.get(&extent_id).ok_or_else(|| {
+                anyhow::anyhow!("Base archive {} has no extent {}", base.name, extent_id)
+            })?;
+            entries.push(entry);
+        }
+        let length: u64 = entries.iter().map(|entry| entry.covered_length).sum();
+        if length != extent_header.covered_length(block_size) {
+            bail!(
+                "Base reference to extent {} of {} has the wrong length",
+                extent_header.extent_id,
+                base.name
+            );
+        }
+        Ok((base, entries))
+    }
+}
@@ -0,0 +1,172 @@
+//! Content-defined chunking (FastCDC)
+//!
+//! Chunk boundaries are chosen by a rolling gear hash over the data itself,
+//! so inserting or removing bytes only changes the chunks around the edit
+//! and the rest of a file still deduplicates against earlier copies.
+
+use anyhow::{bail, Result};
+
+/// Default average chunk size
+pub const DEFAULT_AVERAGE_CHUNK_SIZE: u32 = 64 * 1024;
+
+/// Smallest and largest average chunk sizes accepted
+const MIN_AVERAGE_CHUNK_SIZE: u32 = 256;
+const MAX_AVERAGE_CHUNK_SIZE: u32 = 64 * 1024 * 1024;
+
+/// Gear table: fixed pseudo-random values, one per byte value. Changing it
+/// moves every chunk boundary, so it must never change.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64 from a fixed seed
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x7265_6674_6172_4344; // "reftarCD"
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Chunk size limits derived from an average size
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkSizes {
+    pub min: usize,
+    pub average: usize,
+    pub max: usize,
+    mask_small: u64, // Harder to match, used before the average size
+    mask_large: u64, // Easier to match, used after it
+}
+
+impl ChunkSizes {
+    /// Chunks between a quarter and four times `average` bytes
+    pub fn new(average: u32) -> Result<Self> {
+        if !(MIN_AVERAGE_CHUNK_SIZE..=MAX_AVERAGE_CHUNK_SIZE).contains(&average) {
+            bail!(
+                "Average chunk size must be between {} and {} bytes",
+                MIN_AVERAGE_CHUNK_SIZE,
+                MAX_AVERAGE_CHUNK_SIZE
+            );
+        }
+
+        // Normalized chunking (level 2) keeps chunk sizes close to the average
+        let bits = average.ilog2();
+        Ok(Self {
+            min: average as usize / 4,
+            average: average as usize,
+            max: average as usize * 4,
+            mask_small: top_bits(bits + 2),
+            mask_large: top_bits(bits - 2),
+        })
+    }
+
+    /// Length of the chunk starting at `data[0]`
+    ///
+    /// `data` should hold at least `max` bytes unless it runs to the end of
+    /// the file; the result only depends on the first `max` bytes.
+    pub fn next_chunk_length(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min {
+            return data.len();
+        }
+        let end = data.len().min(self.max);
+        let normal = end.min(self.average);
+
+        // Bytes before `min` are never a boundary, so are not hashed
+        let mut hash = 0u64;
+        let mut i = self.min;
+        while i < normal {
+            hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+            if hash & self.mask_small == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        while i < end {
+            hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+            if hash & self.mask_large == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        end
+    }
+}
+
+/// A mask of the `count` most significant bits; the gear hash shifts left,
+/// so its high bits depend on the most bytes
+const fn top_bits(count: u32) -> u64 {
+    !(u64::MAX >> count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk_lengths(sizes: &ChunkSizes, data: &[u8]) -> Vec<usize> {
+        let mut lengths = Vec::new();
+        let mut start = 0;
+        while start < data.len() {
+            let length = sizes.next_chunk_length(&data[start..]);
+            lengths.push(length);
+            start += length;
+        }
+        lengths
+    }
+
+    fn pseudo_random(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunk_sizes_within_limits() {
+        let sizes = ChunkSizes::new(4096).unwrap();
+        let data = pseudo_random(1 << 20, 1);
+        let lengths = chunk_lengths(&sizes, &data);
+
+        assert_eq!(lengths.iter().sum::<usize>(), data.len());
+        for length in &lengths[..lengths.len() - 1] {
+            assert!((sizes.min..=sizes.max).contains(length));
+        }
+        // Roughly the requested average
+        let average = data.len() / lengths.len();
+        assert!(average > 2048 && average < 8192, "average {}", average);
+
+        assert!(ChunkSizes::new(100).is_err());
+    }
+
+    #[test]
+    fn test_boundaries_survive_insertion() {
+        let sizes = ChunkSizes::new(4096).unwrap();
+        let original = pseudo_random(256 * 1024, 2);
+        let mut edited = vec![b'!'];
+        edited.extend_from_slice(&original);
+
+        let boundaries = |data: &[u8], shift: usize| {
+            let mut offset = 0;
+            chunk_lengths(&sizes, data)
+                .into_iter()
+                .map(|length| {
+                    offset += length;
+                    offset - shift
+                })
+                .collect::<std::collections::HashSet<_>>()
+        };
+        let before = boundaries(&original, 0);
+        let after = boundaries(&edited, 1);
+
+        // All but the first few boundaries line up again after the edit
+        let shared = before.intersection(&after).count();
+        assert!(shared + 3 >= before.len(), "{} of {} shared", shared, before.len());
+    }
+}
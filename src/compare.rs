@@ -0,0 +1,384 @@
+//! Comparing an archive against the filesystem (`reftar diff`)
+//!
+//! Like `tar -d`: every member is looked up under a directory and its type,
+//! size, permissions, owner, modification time, symlink target and contents
+//! compared. Contents are rebuilt from the extents in memory and compared
+//! piece by piece with the file on disk, so nothing is extracted.
+
+use crate::extract::{ArchiveExtractor, FileChunk};
+use crate::format::{FileHeader, FileType};
+use anyhow::{bail, Result};
+use std::fmt;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Seek};
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::path::Path;
+
+/// What about a member can be compared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Attribute {
+    Type,
+    Size,
+    Mode,
+    Owner,
+    Mtime,
+    LinkTarget,
+    Contents,
+}
+
+/// How a member differs from the path on disk
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// Nothing at the path
+    Missing,
+    Type { archive: FileType, disk: Option<FileType> },
+    Size { archive: u64, disk: u64 },
+    Mode { archive: u32, disk: u32 },
+    Owner { archive: (u64, u64), disk: (u64, u64) },
+    Mtime { archive: u64, disk: u64 },
+    LinkTarget { archive: String, disk: String },
+    /// A hard link member is not the same file as its target on disk
+    NotLinked { target: String },
+    /// Contents differ, first at this file offset
+    Contents { offset: u64 },
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Difference::Missing => write!(f, "Missing from disk"),
+            Difference::Type { archive, disk } => match disk {
+                Some(disk) => write!(f, "File type differs (archive {:?}, disk {:?})", archive, disk),
+                None => write!(f, "File type differs (archive {:?}, disk unsupported)", archive),
+            },
+            Difference::Size { archive, disk } => write!(f, "Size differs (archive {}, disk {})", archive, disk),
+            Difference::Mode { archive, disk } => write!(f, "Mode differs (archive {:04o}, disk {:04o})", archive, disk),
+            Difference::Owner { archive, disk } => write!(
+                f,
+                "Owner differs (archive {}:{}, disk {}:{})",
+                archive.0, archive.1, disk.0, disk.1
+            ),
+            Difference::Mtime { archive, disk } => write!(f, "Mod time differs (archive {}, disk {})", archive, disk),
+            Difference::LinkTarget { archive, disk } => {
+                write!(f, "Symlink target differs (archive {:?}, disk {:?})", archive, disk)
+            }
+            Difference::NotLinked { target } => write!(f, "Not linked to {}", target),
+            Difference::Contents { offset } => write!(f, "Contents differ from byte {}", offset),
+        }
+    }
+}
+
+/// Totals of `compare_archive`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompareSummary {
+    pub members: usize,
+    /// Members with at least one difference
+    pub differing: usize,
+}
+
+/// Compare every member of the archive with the same path under `root`,
+/// passing each difference found to `report` with the member path.
+/// Attributes in `ignore` are not compared.
+pub fn compare_archive<R, F>(
+    extractor: &mut ArchiveExtractor<R>,
+    root: &Path,
+    ignore: &[Attribute],
+    mut report: F,
+) -> Result<CompareSummary>
+where
+    R: Read + Seek,
+    F: FnMut(&str, &Difference),
+{
+    let compare = |attribute| !ignore.contains(&attribute);
+    let mut summary = CompareSummary::default();
+
+    loop {
+        // The file on disk, opened on the first piece of a regular file whose
+        // size matches, and where its contents first differ
+        let mut disk_file: Option<Option<File>> = None;
+        let mut differs_at = None;
+        let header = extractor.read_member_contents(|header, chunk| {
+            if !compare(Attribute::Contents) || differs_at.is_some() {
+                return Ok(());
+            }
+            let file = disk_file.get_or_insert_with(|| open_same_size(&root.join(header.member_path()), header));
+            if let Some(file) = file {
+                differs_at = first_difference(file, &chunk)?;
+            }
+            Ok(())
+        })?;
+        let Some(header) = header else {
+            break;
+        };
+
+        let path = header.member_path();
+        let mut differences = member_differences(&header, root, compare)?;
+        if let Some(offset) = differs_at {
+            differences.push(Difference::Contents { offset });
+        }
+        summary.members += 1;
+        if !differences.is_empty() {
+            summary.differing += 1;
+        }
+        for difference in &differences {
+            report(&path, difference);
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Open the regular file at `path` if it has the member's size, so its
+/// contents are worth comparing
+fn open_same_size(path: &Path, header: &FileHeader) -> Option<File> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    if !metadata.is_file() || metadata.len() != header.file_size as u64 {
+        return None;
+    }
+    File::open(path).ok()
+}
+
+/// Offset of the first byte of `chunk` that differs from the file
+fn first_difference(file: &File, chunk: &FileChunk) -> Result<Option<u64>> {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut done = 0u64;
+    while done < chunk.length {
+        let n = (chunk.length - done).min(buf.len() as u64) as usize;
+        file.read_exact_at(&mut buf[..n], chunk.offset + done)?;
+        let mismatch = match chunk.data {
+            Some(data) => buf[..n].iter().zip(&data[done as usize..]).position(|(disk, archive)| disk != archive),
+            None => buf[..n].iter().position(|&byte| byte != 0),
+        };
+        if let Some(position) = mismatch {
+            return Ok(Some(chunk.offset + done + position as u64));
+        }
+        done += n as u64;
+    }
+    Ok(None)
+}
+
+/// Metadata differences between a member and its path under `root`
+fn member_differences(
+    header: &FileHeader,
+    root: &Path,
+    compare: impl Fn(Attribute) -> bool,
+) -> Result<Vec<Difference>> {
+    let path = root.join(header.member_path());
+    let metadata = match fs::symlink_metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![Difference::Missing]),
+        Err(e) => bail!("Cannot stat {}: {}", path.display(), e),
+    };
+
+    let mut differences = Vec::new();
+    let disk_type = file_type(&metadata);
+    let expected_type = match header.file_type {
+        FileType::HardLink => FileType::Regular,
+        file_type => file_type,
+    };
+    if disk_type != Some(expected_type) {
+        // Nothing else compares across types
+        if compare(Attribute::Type) {
+            differences.push(Difference::Type {
+                archive: header.file_type,
+                disk: disk_type,
+            });
+        }
+        return Ok(differences);
+    }
+
+    // A hard link has the metadata of its target
+    if header.file_type == FileType::HardLink {
+        let target = fs::symlink_metadata(root.join(&header.link_name));
+        let linked = target.is_ok_and(|target| (target.dev(), target.ino()) == (metadata.dev(), metadata.ino()));
+        if compare(Attribute::LinkTarget) && !linked {
+            differences.push(Difference::NotLinked {
+                target: header.link_name.clone(),
+            });
+        }
+        return Ok(differences);
+    }
+
+    let size = header.file_size as u64;
+    if compare(Attribute::Size) && header.file_type == FileType::Regular && size != metadata.len() {
+        differences.push(Difference::Size {
+            archive: size,
+            disk: metadata.len(),
+        });
+    }
+    // Symlink permissions mean nothing on Linux
+    let disk_mode = metadata.mode() & 0o7777;
+    if let Some(mode) = header.mode.filter(|_| compare(Attribute::Mode) && header.file_type != FileType::SymbolicLink) {
+        if mode & 0o7777 != disk_mode {
+            differences.push(Difference::Mode {
+                archive: mode & 0o7777,
+                disk: disk_mode,
+            });
+        }
+    }
+    let disk_owner = (metadata.uid() as u64, metadata.gid() as u64);
+    if compare(Attribute::Owner) && (header.uid, header.gid) != disk_owner {
+        differences.push(Difference::Owner {
+            archive: (header.uid, header.gid),
+            disk: disk_owner,
+        });
+    }
+    let disk_mtime = metadata.mtime().max(0) as u64;
+    if compare(Attribute::Mtime) && header.modify_time != disk_mtime {
+        differences.push(Difference::Mtime {
+            archive: header.modify_time,
+            disk: disk_mtime,
+        });
+    }
+    if compare(Attribute::LinkTarget) && header.file_type == FileType::SymbolicLink {
+        let target = fs::read_link(&path)?.to_string_lossy().to_string();
+        if target != header.link_name {
+            differences.push(Difference::LinkTarget {
+                archive: header.link_name.clone(),
+                disk: target,
+            });
+        }
+    }
+    Ok(differences)
+}
+
+/// Member type of what is on disk, None for sockets
+fn file_type(metadata: &Metadata) -> Option<FileType> {
+    let file_type = metadata.file_type();
+    if file_type.is_file() {
+        Some(FileType::Regular)
+    } else if file_type.is_dir() {
+        Some(FileType::Directory)
+    } else if file_type.is_symlink() {
+        Some(FileType::SymbolicLink)
+    } else if file_type.is_char_device() {
+        Some(FileType::CharDevice)
+    } else if file_type.is_block_device() {
+        Some(FileType::BlockDevice)
+    } else if file_type.is_fifo() {
+        Some(FileType::FIFO)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::ArchiveCreator;
+    use crate::test_util::{archive_of, extractor_for, hard_link_header, test_data};
+    use std::io::Cursor;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_compare_reports_differences() {
+        let dir = TempDir::new().unwrap();
+        let contents = test_data(20000);
+        for name in ["same", "changed", "resized", "gone"] {
+            fs::write(dir.path().join(name), &contents).unwrap();
+            fs::set_permissions(dir.path().join(name), fs::Permissions::from_mode(0o644)).unwrap();
+        }
+        std::os::unix::fs::symlink("same", dir.path().join("link")).unwrap();
+        let archive = archive_of(dir.path(), &["same", "changed", "resized", "gone", "link"]);
+
+        let mut changed = contents.clone();
+        changed[12345] ^= 1;
+        let changed_file = File::options().write(true).open(dir.path().join("changed")).unwrap();
+        changed_file.write_all_at(&changed[12345..12346], 12345).unwrap();
+        fs::write(dir.path().join("resized"), &contents[..100]).unwrap();
+        fs::remove_file(dir.path().join("gone")).unwrap();
+        fs::remove_file(dir.path().join("link")).unwrap();
+        std::os::unix::fs::symlink("elsewhere", dir.path().join("link")).unwrap();
+        fs::set_permissions(dir.path().join("same"), fs::Permissions::from_mode(0o600)).unwrap();
+
+        let compare = |ignore: &[Attribute]| {
+            let mut extractor = extractor_for(&archive, dir.path());
+            let mut found = Vec::new();
+            let summary = compare_archive(&mut extractor, dir.path(), ignore, |path, difference| {
+                found.push((path.to_string(), difference.clone()))
+            })
+            .unwrap();
+            (summary, found)
+        };
+
+        let (summary, found) = compare(&[Attribute::Mtime]);
+        assert_eq!(summary, CompareSummary { members: 5, differing: 5 });
+        assert_eq!(
+            found,
+            [
+                ("same".to_string(), Difference::Mode { archive: 0o644, disk: 0o600 }),
+                ("changed".to_string(), Difference::Contents { offset: 12345 }),
+                ("resized".to_string(), Difference::Size { archive: 20000, disk: 100 }),
+                ("gone".to_string(), Difference::Missing),
+                (
+                    "link".to_string(),
+                    Difference::LinkTarget {
+                        archive: "same".to_string(),
+                        disk: "elsewhere".to_string()
+                    }
+                ),
+            ]
+        );
+
+        let ignore = [Attribute::Mtime, Attribute::Mode, Attribute::Contents, Attribute::LinkTarget];
+        let (summary, _) = compare(&ignore);
+        assert_eq!(summary.differing, 2);
+    }
+
+    #[test]
+    fn test_compare_types_hard_links_and_times() {
+        let dir = TempDir::new().unwrap();
+        let tree = dir.path().join("t");
+        fs::create_dir_all(tree.join("sub")).unwrap();
+        fs::write(tree.join("file"), b"contents").unwrap();
+        fs::hard_link(tree.join("file"), tree.join("hard")).unwrap();
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None).unwrap();
+        for name in ["t", "t/file", "t/sub"] {
+            creator.add_file(&dir.path().join(name), Path::new(name)).unwrap();
+        }
+        let link = hard_link_header("t/hard", "t/file");
+        creator.add_stream_member(link, &mut io::empty()).unwrap();
+        let archive = creator.finish().unwrap().into_inner();
+
+        let compare = |ignore: &[Attribute]| {
+            let mut extractor = extractor_for(&archive, dir.path());
+            let mut found = Vec::new();
+            compare_archive(&mut extractor, dir.path(), ignore, |path, difference| {
+                found.push((path.to_string(), difference.clone()))
+            })
+            .unwrap();
+            found.sort_by(|a, b| a.0.cmp(&b.0));
+            found
+        };
+        assert_eq!(compare(&[]), []);
+
+        // A copy in place of the hard link, a file in place of the
+        // directory, and an older modification time
+        let mtime = fs::metadata(tree.join("file")).unwrap().mtime() as u64;
+        fs::remove_file(tree.join("hard")).unwrap();
+        fs::write(tree.join("hard"), b"contents").unwrap();
+        fs::remove_dir(tree.join("sub")).unwrap();
+        fs::write(tree.join("sub"), b"").unwrap();
+        let old = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1000);
+        File::options().write(true).open(tree.join("file")).unwrap().set_modified(old).unwrap();
+
+        // The directory's own time changed along with its entries
+        let found: Vec<(String, Difference)> = compare(&[]).into_iter().filter(|(path, _)| path != "t").collect();
+        assert_eq!(
+            found,
+            [
+                ("t/file".to_string(), Difference::Mtime { archive: mtime, disk: 1000 }),
+                ("t/hard".to_string(), Difference::NotLinked { target: "t/file".to_string() }),
+                (
+                    "t/sub".to_string(),
+                    Difference::Type {
+                        archive: FileType::Directory,
+                        disk: Some(FileType::Regular)
+                    }
+                ),
+            ]
+        );
+        assert_eq!(compare(&[Attribute::Mtime, Attribute::LinkTarget, Attribute::Type]), []);
+    }
+}
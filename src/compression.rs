@@ -0,0 +1,139 @@
+//! Per-extent compression (`--compress`)
+//!
+//! Data extents are compressed one at a time and kept compressed only when
+//! that saves at least one block, since stored data is padded to whole
+//! blocks. Extents left uncompressed stay block-aligned and reflinkable, and
+//! deduplication keys on the uncompressed data, so compression doesn't
+//! change what is shared.
+
+use crate::format::{CompressionAlgorithm, ExtentCompression};
+use anyhow::{bail, Context, Result};
+
+/// Default zstd level, for `--compress=zstd` and `--compress=auto`
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// Bytes compressed by `auto` to judge whether an extent is compressible
+const AUTO_SAMPLE_SIZE: usize = 16 * 1024;
+
+/// Which extents are compressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompressionPolicy {
+    /// Store every extent as is
+    #[default]
+    None,
+    /// Compress every extent with zstd at `level`
+    Zstd { level: i32 },
+    /// Compress with zstd at the default level, skipping extents whose
+    /// start does not compress
+    Auto,
+}
+
+impl CompressionPolicy {
+    /// Parse `none`, `auto`, `zstd` or `zstd:LEVEL`
+    pub fn parse(value: &str) -> Result<Self> {
+        let (name, level) = match value.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (value, None),
+        };
+        match (name, level) {
+            ("none", None) => Ok(Self::None),
+            ("auto", None) => Ok(Self::Auto),
+            ("zstd", None) => Ok(Self::Zstd { level: DEFAULT_ZSTD_LEVEL }),
+            ("zstd", Some(level)) => {
+                let level: i32 = level
+                    .parse()
+                    .with_context(|| format!("Invalid zstd level {:?}", level))?;
+                if !zstd::compression_level_range().contains(&level) {
+                    bail!("zstd level {} is out of range", level);
+                }
+                Ok(Self::Zstd { level })
+            }
+            _ => bail!("Unknown compression {:?}, expected none, auto, zstd or zstd:LEVEL", value),
+        }
+    }
+
+    /// Compress an extent's data, returning the compressed bytes if they
+    /// take fewer blocks than `data`
+    pub fn compress(self, data: &[u8], block_size: u32) -> Result<Option<Vec<u8>>> {
+        let level = match self {
+            Self::None => return Ok(None),
+            Self::Zstd { level } => level,
+            Self::Auto => {
+                let sample = &data[..data.len().min(AUTO_SAMPLE_SIZE)];
+                let compressed = zstd::bulk::compress(sample, 1)?;
+                if compressed.len() > sample.len() / 8 * 7 {
+                    return Ok(None);
+                }
+                DEFAULT_ZSTD_LEVEL
+            }
+        };
+
+        let block_size = block_size as usize;
+        let compressed = zstd::bulk::compress(data, level)?;
+        if compressed.len().div_ceil(block_size) < data.len().div_ceil(block_size) {
+            Ok(Some(compressed))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// Decompress a Compressed extent's stored data to its `length` bytes
+pub fn decompress(compression: &ExtentCompression, stored: &[u8], length: u64) -> Result<Vec<u8>> {
+    let compressed = stored
+        .get(..compression.stored_length as usize)
+        .context("Compressed extent is shorter than its stored length")?;
+    let data = match compression.algorithm {
+        CompressionAlgorithm::Zstd => zstd::bulk::decompress(compressed, length as usize)?,
+    };
+    if data.len() as u64 != length {
+        bail!("Compressed extent holds {} bytes, expected {}", data.len(), length);
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_policy_parse() {
+        assert_eq!(CompressionPolicy::parse("none").unwrap(), CompressionPolicy::None);
+        assert_eq!(CompressionPolicy::parse("auto").unwrap(), CompressionPolicy::Auto);
+        assert_eq!(
+            CompressionPolicy::parse("zstd").unwrap(),
+            CompressionPolicy::Zstd { level: DEFAULT_ZSTD_LEVEL }
+        );
+        assert_eq!(CompressionPolicy::parse("zstd:19").unwrap(), CompressionPolicy::Zstd { level: 19 });
+        assert!(CompressionPolicy::parse("zstd:99").is_err());
+        assert!(CompressionPolicy::parse("gzip").is_err());
+    }
+
+    #[test]
+    fn test_compress_only_when_blocks_are_saved() {
+        let text: Vec<u8> = b"reftar compresses extents one at a time. ".repeat(400);
+        let compressed = CompressionPolicy::Auto.compress(&text, 4096).unwrap().unwrap();
+        let compression = ExtentCompression {
+            algorithm: CompressionAlgorithm::Zstd,
+            stored_length: compressed.len() as u64,
+        };
+        let mut stored = compressed.clone();
+        stored.resize(4096, 0);
+        assert_eq!(decompress(&compression, &stored, text.len() as u64).unwrap(), text);
+
+        // A single block cannot shrink below one block
+        assert!(CompressionPolicy::Zstd { level: 3 }.compress(&text[..4096], 4096).unwrap().is_none());
+
+        // Incompressible data is skipped by auto without compressing it all
+        let mut state = 0x9E3779B97F4A7C15u64;
+        let noise: Vec<u8> = (0..65536)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        assert!(CompressionPolicy::Auto.compress(&noise, 4096).unwrap().is_none());
+    }
+}
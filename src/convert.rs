@@ -0,0 +1,923 @@
+//! Converting between tar and reftar archives (`reftar convert`)
+//!
+//! Tar members are streamed one by one into an `ArchiveCreator`, so the
+//! result gets block-aligned extents and block-level deduplication as if it
+//! had been made from files on disk. ustar, GNU and pax archives are read,
+//! with long names, pax times, owner names and extended attributes, hard
+//! links, and sparse members in the old GNU format and the pax 0.0, 0.1 and
+//! 1.0 formats.
+//!
+//! The other way, each member is rebuilt in memory from its Data, Reference
+//! and Sparse extents and written as a pax tar member that `tar x` can
+//! read: what doesn't fit a ustar header goes in pax records, and files
+//! with holes become GNU 1.0 sparse members.
+
+use crate::create::ArchiveCreator;
+use crate::extract::{ArchiveExtractor, FileChunk};
+use crate::format::{FileHeader, FileType, TimeNanos};
+use crate::transform::normalize_member_path;
+use anyhow::{bail, Context, Result};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// Totals of `import_tar`
+#[derive(Debug, Clone, Default)]
+pub struct TarImport {
+    pub members: usize,
+    /// Tar entries that have no reftar equivalent, such as GNU volume labels
+    pub skipped: Vec<String>,
+}
+
+/// Add every member of the tar stream `input` to `creator`, calling `added`
+/// with each member path
+pub fn import_tar<R: Read, W: Write>(
+    input: R,
+    creator: &mut ArchiveCreator<W>,
+    mut added: impl FnMut(&str),
+) -> Result<TarImport> {
+    let mut import = TarImport::default();
+    let mut archive = tar::Archive::new(input);
+
+    for entry in archive.entries().context("Failed to read tar archive")? {
+        let mut entry = entry.context("Failed to read tar member header")?;
+        let pax = PaxFields::read(&mut entry)?;
+        let entry_type = entry.header().entry_type();
+        let path = match &pax.sparse_name {
+            Some(name) => PathBuf::from(name),
+            None => entry.path()?.into_owned(),
+        };
+        let member_path = normalize_member_path(&path);
+
+        let file_type = if entry_type.is_file() || entry_type.is_contiguous() || entry_type.is_gnu_sparse() {
+            FileType::Regular
+        } else if entry_type.is_hard_link() {
+            FileType::HardLink
+        } else if entry_type.is_symlink() {
+            FileType::SymbolicLink
+        } else if entry_type.is_character_special() {
+            FileType::CharDevice
+        } else if entry_type.is_block_special() {
+            FileType::BlockDevice
+        } else if entry_type.is_dir() {
+            FileType::Directory
+        } else if entry_type.is_fifo() {
+            FileType::FIFO
+        } else {
+            if !entry_type.is_pax_global_extensions() {
+                import.skipped.push(format!(
+                    "{}: tar entry type '{}' not supported",
+                    path.display(),
+                    entry_type.as_byte().escape_ascii()
+                ));
+            }
+            continue;
+        };
+        // The archive root, as in `tar cf - .`
+        if member_path.as_os_str().is_empty() {
+            continue;
+        }
+
+        let link_name = match file_type {
+            FileType::SymbolicLink => entry
+                .link_name_bytes()
+                .map(|link| String::from_utf8_lossy(&link).to_string())
+                .unwrap_or_default(),
+            FileType::HardLink => match entry.link_name()? {
+                Some(target) => normalize_member_path(&target).to_string_lossy().to_string(),
+                None => bail!("Hard link {} has no target", path.display()),
+            },
+            _ => String::new(),
+        };
+
+        // Sparse members list the regions of the file they store
+        let stored = entry.size();
+        let (file_size, regions) = match file_type {
+            FileType::Regular => match pax.sparse_format() {
+                Some(format) => {
+                    let real_size = pax.sparse_size.context("Sparse member has no real size")?;
+                    let regions = match format {
+                        SparseFormat::Map(regions) => regions,
+                        SparseFormat::InData => read_sparse_map(&mut entry)?,
+                    };
+                    (real_size, regions)
+                }
+                None => (stored, vec![(0, stored)]),
+            },
+            FileType::SymbolicLink => (link_name.len() as u64, Vec::new()),
+            _ => (0, Vec::new()),
+        };
+
+        let header = member_header(&entry, &pax, &member_path, file_type, file_size, link_name)?;
+        let mut contents = MemberData::new(&mut entry, regions, file_size)
+            .with_context(|| format!("Sparse map of {} is invalid", path.display()))?;
+        creator
+            .add_stream_member(header, &mut contents)
+            .with_context(|| format!("Failed to add {}", path.display()))?;
+        import.members += 1;
+        added(&member_path.to_string_lossy());
+    }
+
+    Ok(import)
+}
+
+/// Build the reftar header of a tar member
+fn member_header<R: Read>(
+    entry: &tar::Entry<R>,
+    pax: &PaxFields,
+    member_path: &Path,
+    file_type: FileType,
+    file_size: u64,
+    link_name: String,
+) -> Result<FileHeader> {
+    let header = entry.header();
+    let (device_major, device_minor) = match file_type {
+        FileType::CharDevice | FileType::BlockDevice => (
+            header.device_major()?.unwrap_or(0) as u64,
+            header.device_minor()?.unwrap_or(0) as u64,
+        ),
+        _ => (0, 0),
+    };
+    let name = |pax: &Option<String>, bytes: Option<&[u8]>| match pax {
+        Some(name) => name.clone(),
+        None => bytes.map(|bytes| String::from_utf8_lossy(bytes).to_string()).unwrap_or_default(),
+    };
+    let (modify_time, modify_nanos) = match pax.mtime {
+        Some(mtime) => mtime,
+        None => (header.mtime()?, 0),
+    };
+    let (access_time, access_nanos) = pax.atime.unwrap_or((modify_time, modify_nanos));
+    let (creation_time, creation_nanos) = pax.ctime.unwrap_or((0, 0));
+
+    Ok(FileHeader {
+        file_size: file_size as u128,
+        file_type,
+        uid: header.uid()?,
+        gid: header.gid()?,
+        device_major,
+        device_minor,
+        access_time,
+        modify_time,
+        creation_time,
+        username: name(&pax.uname, header.username_bytes()),
+        groupname: name(&pax.gname, header.groupname_bytes()),
+        file_path: member_path.parent().unwrap_or(Path::new("")).to_string_lossy().to_string(),
+        file_name: member_path.file_name().unwrap_or(member_path.as_os_str()).to_string_lossy().to_string(),
+        link_name,
+        extended_permissions: Vec::new(),
+        source_filesystem_type: String::new(),
+        source_filesystem_id: 0,
+        mode: Some(header.mode()? & 0o7777),
+        directory_listing: None,
+        xattrs: pax.xattrs.clone(),
+        time_nanos: TimeNanos {
+            access: access_nanos,
+            modify: modify_nanos,
+            creation: creation_nanos,
+        },
+        data_seal: None,
+        inline_data: Vec::new(),
+    })
+}
+
+/// Pax records of a member that the tar crate does not apply itself
+#[derive(Debug, Default)]
+struct PaxFields {
+    uname: Option<String>,
+    gname: Option<String>,
+    atime: Option<(u64, u32)>, // Seconds and nanoseconds
+    mtime: Option<(u64, u32)>,
+    ctime: Option<(u64, u32)>,
+    xattrs: Vec<(String, Vec<u8>)>,
+    sparse_major: Option<u64>,
+    sparse_name: Option<String>,
+    sparse_size: Option<u64>,
+    sparse_map: Vec<u64>, // Offsets and lengths from GNU.sparse.map, or GNU.sparse.offset/numbytes
+}
+
+/// Where a pax sparse member's map is
+enum SparseFormat {
+    /// In the pax records (formats 0.0 and 0.1)
+    Map(Vec<(u64, u64)>),
+    /// At the start of the member data (format 1.0)
+    InData,
+}
+
+impl PaxFields {
+    fn read<R: Read>(entry: &mut tar::Entry<R>) -> Result<Self> {
+        let mut fields = Self::default();
+        let Some(extensions) = entry.pax_extensions()? else {
+            return Ok(fields);
+        };
+        for extension in extensions {
+            let extension = extension?;
+            let Ok(key) = extension.key() else {
+                continue;
+            };
+            let value = || String::from_utf8_lossy(extension.value_bytes()).to_string();
+            let number = || {
+                value()
+                    .parse::<u64>()
+                    .with_context(|| format!("Invalid pax record {}={}", key, value()))
+            };
+            match key {
+                "uname" => fields.uname = Some(value()),
+                "gname" => fields.gname = Some(value()),
+                "atime" => fields.atime = Some(pax_time(&value())?),
+                "mtime" => fields.mtime = Some(pax_time(&value())?),
+                "ctime" => fields.ctime = Some(pax_time(&value())?),
+                "GNU.sparse.major" => fields.sparse_major = Some(number()?),
+                "GNU.sparse.name" => fields.sparse_name = Some(value()),
+                "GNU.sparse.realsize" | "GNU.sparse.size" => fields.sparse_size = Some(number()?),
+                "GNU.sparse.offset" | "GNU.sparse.numbytes" => fields.sparse_map.push(number()?),
+                "GNU.sparse.map" => {
+                    for part in value().split(',').filter(|part| !part.is_empty()) {
+                        let number = part.parse().with_context(|| format!("Invalid GNU.sparse.map entry {:?}", part))?;
+                        fields.sparse_map.push(number);
+                    }
+                }
+                _ => {
+                    if let Some(name) = key.strip_prefix("SCHILY.xattr.") {
+                        fields.xattrs.push((name.to_string(), extension.value_bytes().to_vec()));
+                    }
+                }
+            }
+        }
+        Ok(fields)
+    }
+
+    /// The sparse format of the member, or None if it is not sparse
+    fn sparse_format(&self) -> Option<SparseFormat> {
+        if self.sparse_major == Some(1) {
+            return Some(SparseFormat::InData);
+        }
+        if self.sparse_size.is_none() && self.sparse_map.is_empty() {
+            return None;
+        }
+        let regions = self.sparse_map.chunks(2).map(|pair| (pair[0], *pair.get(1).unwrap_or(&0))).collect();
+        Some(SparseFormat::Map(regions))
+    }
+}
+
+/// Seconds and nanoseconds of a pax time such as `1700000000.123456789`;
+/// digits past the nanoseconds are dropped, and times before the epoch
+/// become 0
+fn pax_time(value: &str) -> Result<(u64, u32)> {
+    let invalid = || anyhow::anyhow!("Invalid pax time {:?}", value);
+    let (seconds, fraction) = value.split_once('.').unwrap_or((value, ""));
+    let seconds: i64 = seconds.parse().map_err(|_| invalid())?;
+    if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let digits = &fraction[..fraction.len().min(9)];
+    let nanos = format!("{:0<9}", digits).parse::<u32>().map_err(|_| invalid())?;
+    match seconds < 0 || value.starts_with('-') {
+        true => Ok((0, 0)),
+        false => Ok((seconds as u64, nanos)),
+    }
+}
+
+/// Read the sparse map at the start of a pax 1.0 sparse member: decimal
+/// numbers on lines, the region count then each region's offset and length,
+/// padded to a 512-byte boundary
+fn read_sparse_map(reader: &mut impl Read) -> Result<Vec<(u64, u64)>> {
+    let mut numbers: Vec<u64> = Vec::new();
+    let mut digits = String::new();
+    let mut block = [0u8; 512];
+    loop {
+        reader.read_exact(&mut block).context("Sparse map ends early")?;
+        for &byte in &block {
+            if byte != b'\n' {
+                digits.push(byte as char);
+                continue;
+            }
+            numbers.push(digits.parse().with_context(|| format!("Invalid sparse map entry {:?}", digits))?);
+            digits.clear();
+            let count = numbers[0];
+            if count.checked_mul(2).and_then(|n| n.checked_add(1)) == Some(numbers.len() as u64) {
+                return Ok(numbers[1..].chunks(2).map(|pair| (pair[0], pair[1])).collect());
+            }
+        }
+    }
+}
+
+/// Contents of a member: the regions stored in the tar data, with zeros
+/// between and after them up to the file size. Tar data that ends early is
+/// an error.
+struct MemberData<'a> {
+    inner: &'a mut dyn Read,
+    regions: std::vec::IntoIter<(u64, u64)>,
+    region: Option<(u64, u64)>,
+    size: u64,
+    position: u64,
+}
+
+impl<'a> MemberData<'a> {
+    fn new(inner: &'a mut dyn Read, regions: Vec<(u64, u64)>, size: u64) -> Result<Self> {
+        let mut end = 0;
+        for &(offset, length) in &regions {
+            if offset < end {
+                bail!("Regions overlap or are out of order");
+            }
+            end = offset.checked_add(length).filter(|&end| end <= size).context("Region ends after the file")?;
+        }
+        let mut regions = regions.into_iter();
+        Ok(Self {
+            inner,
+            region: regions.next(),
+            regions,
+            size,
+            position: 0,
+        })
+    }
+}
+
+impl Read for MemberData<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Skip past regions already read
+        while let Some((offset, length)) = self.region {
+            if self.position < offset + length {
+                break;
+            }
+            self.region = self.regions.next();
+        }
+        let wanted = buf.len() as u64;
+        let n = match self.region {
+            Some((offset, _)) if self.position < offset => {
+                let n = wanted.min(offset - self.position) as usize;
+                buf[..n].fill(0);
+                n
+            }
+            Some((offset, length)) => {
+                let n = wanted.min(offset + length - self.position) as usize;
+                match self.inner.read(&mut buf[..n])? {
+                    0 if n > 0 => {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Tar member data ends early"));
+                    }
+                    n => n,
+                }
+            }
+            None => {
+                let n = wanted.min(self.size - self.position) as usize;
+                buf[..n].fill(0);
+                n
+            }
+        };
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+/// Largest uid or gid a ustar header holds (7 octal digits)
+const USTAR_ID_MAX: u64 = 0o7777777;
+/// Largest size or time a ustar header holds (11 octal digits)
+const USTAR_NUMBER_MAX: u64 = 0o77777777777;
+
+/// Write every member of the archive to `output` as a pax tar stream,
+/// calling `exported` with each member path, and return the output
+///
+/// Member paths go through the extractor's path rewrite, as when
+/// extracting. With `random_access`, where each member's data is in the
+/// file is read ahead from its extent headers, and the data then goes
+/// straight to the output; an archive read front to back has each member's
+/// data held in memory until all of it has been read.
+pub fn export_tar<R: Read + Seek, W: Write>(
+    extractor: &mut ArchiveExtractor<R>,
+    output: W,
+    random_access: bool,
+    mut exported: impl FnMut(&str),
+) -> Result<W> {
+    let mut builder = tar::Builder::new(output);
+
+    loop {
+        let member = match random_access {
+            true => extractor.peek_member_layout()?.map(|(header, regions)| (header, regions, None)),
+            false => {
+                let mut contents = MemberContents::default();
+                let header = extractor.read_member_contents(|_, chunk| {
+                    contents.add(&chunk);
+                    Ok(())
+                })?;
+                header.map(|header| (header, contents.regions, Some(contents.data)))
+            }
+        };
+        let Some((header, regions, data)) = member else {
+            break;
+        };
+        // A member read ahead is still to be read, even if it is skipped
+        let skip = |extractor: &mut ArchiveExtractor<R>| match data.is_none() {
+            true => extractor.read_member_contents(|_, _| Ok(())).map(|_| ()),
+            false => Ok(()),
+        };
+        let Some(path) = extractor.rewritten_path(&header.member_path()) else {
+            skip(extractor)?;
+            continue;
+        };
+        let path = path.to_string_lossy().to_string();
+        let link_name = match header.file_type {
+            FileType::HardLink => match extractor.rewritten_path(&header.link_name) {
+                Some(target) => target.to_string_lossy().to_string(),
+                None => {
+                    eprintln!("Skipping hard link {}: target {} is not exported", path, header.link_name);
+                    skip(extractor)?;
+                    continue;
+                }
+            },
+            _ => header.link_name.clone(),
+        };
+
+        let context = || format!("Failed to write {} to the tar archive", path);
+        let mut member = start_tar_member(&mut builder, &header, &path, &link_name, regions).with_context(context)?;
+        match data {
+            Some(data) => member.write(&data).with_context(context)?,
+            None => {
+                extractor.read_member_contents(|_, chunk| match chunk.data {
+                    Some(data) => member.write(data).with_context(context),
+                    None => Ok(()),
+                })?;
+            }
+        }
+        member.finish().with_context(context)?;
+        exported(&path);
+    }
+
+    Ok(builder.into_inner()?)
+}
+
+/// A regular file's contents as the data regions a tar member stores
+#[derive(Debug, Default)]
+struct MemberContents {
+    regions: Vec<(u64, u64)>, // Offsets and lengths in the file, holes left out
+    data: Vec<u8>,
+}
+
+impl MemberContents {
+    fn add(&mut self, chunk: &FileChunk) {
+        // Holes have no data
+        let Some(data) = chunk.data else {
+            return;
+        };
+        match self.regions.last_mut() {
+            Some((offset, length)) if *offset + *length == chunk.offset => *length += chunk.length,
+            _ => self.regions.push((chunk.offset, chunk.length)),
+        }
+        self.data.extend_from_slice(data);
+    }
+}
+
+/// The map starting the data of a GNU 1.0 sparse member: the number of
+/// regions, then each region's offset and length, padded to a tar block.
+/// A file that ends in a hole ends with an empty region, so that its size
+/// is kept.
+fn sparse_map(mut regions: Vec<(u64, u64)>, file_size: u64) -> Vec<u8> {
+    if regions.last().is_none_or(|(offset, length)| offset + length < file_size) {
+        regions.push((file_size, 0));
+    }
+    let mut map = format!("{}\n", regions.len());
+    for (offset, length) in &regions {
+        map.push_str(&format!("{}\n{}\n", offset, length));
+    }
+    let mut map = map.into_bytes();
+    map.resize(map.len().div_ceil(512) * 512, 0);
+    map
+}
+
+/// A tar member whose pax records and header are written, and whose data
+/// is written next
+struct TarMember<'a, W: Write> {
+    output: &'a mut W,
+    size: u64, // Bytes of data the header gives
+    written: u64,
+}
+
+impl<W: Write> TarMember<'_, W> {
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.written += data.len() as u64;
+        if self.written > self.size {
+            bail!("Member has more data than its header gives, {} bytes", self.size);
+        }
+        Ok(self.output.write_all(data)?)
+    }
+
+    /// Pad the data to a whole tar block
+    fn finish(self) -> Result<()> {
+        if self.written != self.size {
+            bail!("Member has {} bytes of data, its header gives {}", self.written, self.size);
+        }
+        let padding = (512 - self.size % 512) % 512;
+        self.output.write_all(&[0u8; 512][..padding as usize])?;
+        Ok(())
+    }
+}
+
+/// Write the header of one member, preceded by its pax records if it needs
+/// any, for its data in `regions` (offsets and lengths, holes left out) to
+/// be written after it
+fn start_tar_member<'a, W: Write>(
+    builder: &'a mut tar::Builder<W>,
+    header: &FileHeader,
+    path: &str,
+    link_name: &str,
+    regions: Vec<(u64, u64)>,
+) -> Result<TarMember<'a, W>> {
+    let mut pax: Vec<(String, Vec<u8>)> = Vec::new();
+    let mut record = |key: &str, value: &[u8]| pax.push((key.to_string(), value.to_vec()));
+    let mut tar_header = tar::Header::new_ustar();
+
+    let entry_type = match header.file_type {
+        FileType::Regular => tar::EntryType::Regular,
+        FileType::HardLink => tar::EntryType::Link,
+        FileType::SymbolicLink => tar::EntryType::Symlink,
+        FileType::CharDevice => tar::EntryType::Char,
+        FileType::BlockDevice => tar::EntryType::Block,
+        FileType::Directory => tar::EntryType::Directory,
+        FileType::FIFO => tar::EntryType::Fifo,
+    };
+    tar_header.set_entry_type(entry_type);
+    let default_mode = match header.file_type {
+        FileType::Directory => 0o755,
+        _ => 0o644,
+    };
+    tar_header.set_mode(header.mode.unwrap_or(default_mode) & 0o7777);
+
+    tar_header.set_uid(header.uid);
+    if header.uid > USTAR_ID_MAX {
+        record("uid", header.uid.to_string().as_bytes());
+    }
+    tar_header.set_gid(header.gid);
+    if header.gid > USTAR_ID_MAX {
+        record("gid", header.gid.to_string().as_bytes());
+    }
+    if tar_header.set_username(&header.username).is_err() {
+        record("uname", header.username.as_bytes());
+    }
+    if tar_header.set_groupname(&header.groupname).is_err() {
+        record("gname", header.groupname.as_bytes());
+    }
+
+    // The ustar field holds whole seconds; a pax record keeps the fraction
+    let nanos = header.time_nanos;
+    tar_header.set_mtime(header.modify_time);
+    if header.modify_time > USTAR_NUMBER_MAX || nanos.modify != 0 {
+        record("mtime", pax_time_value(header.modify_time, nanos.modify).as_bytes());
+    }
+    if header.access_time != 0 {
+        record("atime", pax_time_value(header.access_time, nanos.access).as_bytes());
+    }
+    if header.creation_time != 0 {
+        record("ctime", pax_time_value(header.creation_time, nanos.creation).as_bytes());
+    }
+
+    if matches!(header.file_type, FileType::CharDevice | FileType::BlockDevice) {
+        let major = u32::try_from(header.device_major).context("Device major number too large for tar")?;
+        let minor = u32::try_from(header.device_minor).context("Device minor number too large for tar")?;
+        tar_header.set_device_major(major)?;
+        tar_header.set_device_minor(minor)?;
+    }
+    if matches!(header.file_type, FileType::HardLink | FileType::SymbolicLink)
+        && tar_header.set_link_name(link_name).is_err()
+    {
+        record("linkpath", link_name.as_bytes());
+        set_truncated(&mut tar_header.as_ustar_mut().expect("ustar header").linkname, link_name);
+    }
+
+    let file_size = header.file_size as u64;
+    let data_size: u64 = regions.iter().map(|(_, length)| length).sum();
+    let sparse = header.file_type == FileType::Regular && data_size != file_size;
+    let (name, map) = if sparse {
+        // The real path is in GNU.sparse.name; GNU tar names the member
+        // DIR/GNUSparseFile.PID/NAME so that older tars don't clobber it
+        record("GNU.sparse.major", b"1");
+        record("GNU.sparse.minor", b"0");
+        record("GNU.sparse.name", path.as_bytes());
+        record("GNU.sparse.realsize", file_size.to_string().as_bytes());
+        let name = match path.rsplit_once('/') {
+            Some((dir, name)) => format!("{}/GNUSparseFile.0/{}", dir, name),
+            None => format!("GNUSparseFile.0/{}", path),
+        };
+        (name, sparse_map(regions, file_size))
+    } else {
+        (path.to_string(), Vec::new())
+    };
+    if tar_header.set_path(&name).is_err() {
+        if !sparse {
+            record("path", name.as_bytes());
+        }
+        let ustar = tar_header.as_ustar_mut().expect("ustar header");
+        ustar.prefix.fill(0);
+        set_truncated(&mut ustar.name, &name);
+    }
+
+    let size = map.len() as u64 + data_size;
+    tar_header.set_size(size);
+    if size > USTAR_NUMBER_MAX {
+        record("size", size.to_string().as_bytes());
+    }
+    for (name, value) in &header.xattrs {
+        record(&format!("SCHILY.xattr.{}", name), value);
+    }
+    tar_header.set_cksum();
+
+    if !pax.is_empty() {
+        builder.append_pax_extensions(pax.iter().map(|(key, value)| (key.as_str(), value.as_slice())))?;
+    }
+    let output = builder.get_mut();
+    output.write_all(tar_header.as_bytes())?;
+    let mut member = TarMember {
+        output,
+        size,
+        written: 0,
+    };
+    member.write(&map)?;
+    Ok(member)
+}
+
+/// Fill a header field with as much of `value` as fits, for tars that
+/// don't read the pax record holding all of it
+fn set_truncated(field: &mut [u8], value: &str) {
+    let n = value.len().min(field.len());
+    field.fill(0);
+    field[..n].copy_from_slice(&value.as_bytes()[..n]);
+}
+
+/// A time as a pax record value: whole seconds, with nine digits of
+/// fraction when there is one
+fn pax_time_value(seconds: u64, nanos: u32) -> String {
+    match nanos {
+        0 => seconds.to_string(),
+        _ => format!("{}.{:09}", seconds, nanos),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::ArchiveExtractor;
+    use crate::test_util::{archive_from_tar, archive_of, extractor_for, tar_header, test_data};
+    use std::fs;
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    /// A tar with a directory, a long name, a hard link, and a pax 1.0
+    /// sparse member with an xattr: 5000 bytes at 8192 of a 20000 byte file
+    fn sample_tar(contents: &[u8], long_name: &str) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar_header(tar::Header::new_gnu(), tar::EntryType::Directory, 0o750, 0);
+        builder.append_data(&mut header, "./dir/", io::empty()).unwrap();
+        for name in [long_name, "dir/copy"] {
+            let mut header = tar_header(tar::Header::new_gnu(), tar::EntryType::Regular, 0o640, contents.len());
+            header.set_mtime(1_700_000_000);
+            builder.append_data(&mut header, name, contents).unwrap();
+        }
+        let mut header = tar_header(tar::Header::new_gnu(), tar::EntryType::Link, 0o640, 0);
+        builder.append_link(&mut header, "dir/link", "dir/copy").unwrap();
+
+        let mut sparse = b"1\n8192\n5000\n".to_vec();
+        sparse.resize(512, 0);
+        sparse.extend_from_slice(&contents[..5000]);
+        builder
+            .append_pax_extensions([
+                ("GNU.sparse.major", b"1".as_slice()),
+                ("GNU.sparse.minor", b"0"),
+                ("GNU.sparse.name", b"dir/sparse"),
+                ("GNU.sparse.realsize", b"20000"),
+                ("SCHILY.xattr.user.origin", b"tar"),
+            ])
+            .unwrap();
+        let mut header = tar_header(tar::Header::new_ustar(), tar::EntryType::Regular, 0o600, sparse.len());
+        builder.append_data(&mut header, "dir/GNUSparseFile.0/sparse", sparse.as_slice()).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn test_import_tar_members() {
+        let contents = test_data(20000);
+        let long_name = format!("dir/{}", "x".repeat(150));
+        let tar = sample_tar(&contents, &long_name);
+
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None).unwrap();
+        let mut added = Vec::new();
+        let import = import_tar(tar.as_slice(), &mut creator, |path| added.push(path.to_string())).unwrap();
+        let archive = creator.finish().unwrap().into_inner();
+        assert_eq!(import.members, 5);
+        assert_eq!(added, ["dir", long_name.as_str(), "dir/copy", "dir/link", "dir/sparse"]);
+
+        let out = TempDir::new().unwrap();
+        let mut extractor = extractor_for(&archive, out.path());
+        extractor.extract_all().unwrap();
+        assert_eq!(fs::read(out.path().join(&long_name)).unwrap(), contents);
+        let mut expected = vec![0u8; 20000];
+        expected[8192..13192].copy_from_slice(&contents[..5000]);
+        assert_eq!(fs::read(out.path().join("dir/sparse")).unwrap(), expected);
+        let copy = fs::metadata(out.path().join("dir/copy")).unwrap();
+        assert_eq!(std::os::unix::fs::MetadataExt::nlink(&copy), 2);
+
+        // The second copy is stored as references to the first
+        let mut extractor = extractor_for(&archive, out.path());
+        let mut headers = Vec::new();
+        let mut stored = 0;
+        while let Some(header) = extractor
+            .read_member_contents(|_, chunk| {
+                if matches!(chunk.source, crate::extract::ChunkSource::Data { .. }) {
+                    stored += chunk.length;
+                }
+                Ok(())
+            })
+            .unwrap()
+        {
+            headers.push(header);
+        }
+        assert_eq!(stored, 20000 + 4096);
+        assert_eq!(headers[0].mode, Some(0o750));
+        assert_eq!(headers[1].modify_time, 1_700_000_000);
+        assert_eq!(headers[4].xattrs, [("user.origin".to_string(), b"tar".to_vec())]);
+    }
+    #[test]
+    fn test_import_gnu_sparse_pax_records_and_links() {
+        let contents = test_data(20000);
+        let mut builder = tar::Builder::new(Vec::new());
+
+        // Old GNU sparse member: 5000 bytes at 8192, then a hole to 20000
+        let mut header = tar_header(tar::Header::new_gnu(), tar::EntryType::GNUSparse, 0o644, 5000);
+        header.set_path("old/sparse").unwrap();
+        let gnu = header.as_gnu_mut().unwrap();
+        gnu.sparse[0].set_offset(8192);
+        gnu.sparse[0].set_length(5000);
+        gnu.sparse[1].set_offset(20000);
+        gnu.sparse[1].set_length(0);
+        gnu.set_real_size(20000);
+        header.set_cksum();
+        builder.append(&header, &contents[..5000]).unwrap();
+
+        // pax 0.1 sparse member, its map in the records
+        builder
+            .append_pax_extensions([
+                ("GNU.sparse.size", b"20000".as_slice()),
+                ("GNU.sparse.map", b"0,100,12288,4096"),
+                ("GNU.sparse.name", b"pax01/sparse"),
+            ])
+            .unwrap();
+        let mut header = tar_header(tar::Header::new_ustar(), tar::EntryType::Regular, 0o644, 4196);
+        builder.append_data(&mut header, "pax01/GNUSparseFile.0/sparse", &contents[..4196]).unwrap();
+
+        // Times, a long owner name and xattrs in pax records, then a hard
+        // link to the file
+        let owner = "o".repeat(40);
+        builder
+            .append_pax_extensions([
+                ("mtime", b"1700000000.75".as_slice()),
+                ("atime", b"1600000000"),
+                ("uname", owner.as_bytes()),
+                ("SCHILY.xattr.user.a", b"1"),
+                ("SCHILY.xattr.security.b", b"\0bin"),
+            ])
+            .unwrap();
+        let mut header = tar_header(tar::Header::new_ustar(), tar::EntryType::Regular, 0o644, 6000);
+        builder.append_data(&mut header, "pax/file", &contents[..6000]).unwrap();
+        let mut header = tar_header(tar::Header::new_gnu(), tar::EntryType::Link, 0o644, 0);
+        builder.append_link(&mut header, "pax/link", "pax/file").unwrap();
+
+        // A volume label has no reftar equivalent
+        let mut header = tar_header(tar::Header::new_gnu(), tar::EntryType::new(b'V'), 0o644, 0);
+        builder.append_data(&mut header, "label", io::empty()).unwrap();
+        let tar = builder.into_inner().unwrap();
+
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None).unwrap();
+        let import = import_tar(tar.as_slice(), &mut creator, |_| {}).unwrap();
+        assert_eq!(import.members, 4);
+        assert_eq!(import.skipped, ["label: tar entry type 'V' not supported"]);
+        let archive = creator.finish().unwrap().into_inner();
+
+        let out = TempDir::new().unwrap();
+        let mut extractor = extractor_for(&archive, out.path());
+        extractor.extract_all().unwrap();
+        let mut expected = vec![0u8; 20000];
+        expected[8192..13192].copy_from_slice(&contents[..5000]);
+        assert_eq!(fs::read(out.path().join("old/sparse")).unwrap(), expected);
+        let mut expected = vec![0u8; 20000];
+        expected[..100].copy_from_slice(&contents[..100]);
+        expected[12288..16384].copy_from_slice(&contents[100..4196]);
+        assert_eq!(fs::read(out.path().join("pax01/sparse")).unwrap(), expected);
+        let file = fs::metadata(out.path().join("pax/file")).unwrap();
+        assert_eq!(std::os::unix::fs::MetadataExt::nlink(&file), 2);
+
+        let mut extractor = extractor_for(&archive, out.path());
+        let mut headers = Vec::new();
+        while let Some(header) = extractor.read_member_contents(|_, _| Ok(())).unwrap() {
+            headers.push(header);
+        }
+        assert_eq!((headers[2].modify_time, headers[2].access_time), (1_700_000_000, 1_600_000_000));
+        assert_eq!((headers[2].time_nanos.modify, headers[2].time_nanos.access), (750_000_000, 0));
+        assert_eq!(headers[2].username, owner);
+        assert_eq!(
+            headers[2].xattrs,
+            [
+                ("user.a".to_string(), b"1".to_vec()),
+                ("security.b".to_string(), b"\0bin".to_vec())
+            ]
+        );
+        assert_eq!((headers[3].file_type, headers[3].link_name.as_str()), (FileType::HardLink, "pax/file"));
+    }
+
+    #[test]
+    fn test_import_rejects_bad_sparse_map() {
+        let mut builder = tar::Builder::new(Vec::new());
+        builder
+            .append_pax_extensions([("GNU.sparse.size", b"1000".as_slice()), ("GNU.sparse.map", b"512,1024")])
+            .unwrap();
+        let mut header = tar_header(tar::Header::new_ustar(), tar::EntryType::Regular, 0o644, 1024);
+        builder.append_data(&mut header, "bad", [1u8; 1024].as_slice()).unwrap();
+        let tar = builder.into_inner().unwrap();
+
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None).unwrap();
+        let error = import_tar(tar.as_slice(), &mut creator, |_| {}).unwrap_err();
+        assert_eq!(format!("{:#}", error), "Sparse map of bad is invalid: Region ends after the file");
+    }
+
+    #[test]
+    fn test_export_tar_roundtrip() {
+        let contents = test_data(20000);
+        let long_name = format!("dir/{}", "x".repeat(150));
+        let archive = archive_from_tar(&sample_tar(&contents, &long_name));
+
+        let out = TempDir::new().unwrap();
+        let mut extractor = extractor_for(&archive, out.path());
+        let mut exported = Vec::new();
+        let tar = export_tar(&mut extractor, Vec::new(), true, |path| exported.push(path.to_string())).unwrap();
+        assert_eq!(exported, ["dir", long_name.as_str(), "dir/copy", "dir/link", "dir/sparse"]);
+
+        // Read front to back, without reading ahead, the export is the same
+        let stream = crate::stream::ForwardReader::new(Cursor::new(archive));
+        let mut extractor = ArchiveExtractor::new(stream, out.path().to_path_buf()).unwrap();
+        assert_eq!(export_tar(&mut extractor, Vec::new(), false, |_| {}).unwrap(), tar);
+
+        // The hole is left out of the sparse member's data
+        let mut entries = tar::Archive::new(tar.as_slice());
+        let mut entry = entries.entries().unwrap().nth(4).unwrap().unwrap();
+        assert_eq!(entry.path().unwrap(), Path::new("dir/GNUSparseFile.0/sparse"));
+        let records: Vec<(String, Vec<u8>)> = entry
+            .pax_extensions()
+            .unwrap()
+            .unwrap()
+            .map(|record| record.unwrap())
+            .map(|record| (record.key().unwrap().to_string(), record.value_bytes().to_vec()))
+            .collect();
+        assert!(records.contains(&("GNU.sparse.realsize".to_string(), b"20000".to_vec())));
+        assert!(records.contains(&("SCHILY.xattr.user.origin".to_string(), b"tar".to_vec())));
+        assert_eq!(entry.size(), 512 + 8192);
+
+        // Importing the export again gives the same members
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None).unwrap();
+        let import = import_tar(tar.as_slice(), &mut creator, |_| {}).unwrap();
+        assert_eq!(import.members, 5);
+        let archive = creator.finish().unwrap().into_inner();
+        let mut extractor = extractor_for(&archive, out.path());
+        extractor.extract_all().unwrap();
+        assert_eq!(fs::read(out.path().join(&long_name)).unwrap(), contents);
+        let mut expected = vec![0u8; 20000];
+        expected[8192..13192].copy_from_slice(&contents[..5000]);
+        assert_eq!(fs::read(out.path().join("dir/sparse")).unwrap(), expected);
+        let copy = fs::metadata(out.path().join("dir/copy")).unwrap();
+        assert_eq!(std::os::unix::fs::MetadataExt::nlink(&copy), 2);
+    }
+
+    #[test]
+    fn test_pax_time() {
+        assert_eq!(pax_time("1700000000").unwrap(), (1_700_000_000, 0));
+        assert_eq!(pax_time("1700000000.75").unwrap(), (1_700_000_000, 750_000_000));
+        assert_eq!(pax_time("1700000000.0000000019").unwrap(), (1_700_000_000, 1));
+        assert_eq!(pax_time("-0.5").unwrap(), (0, 0));
+        assert!(pax_time("1700000000.7x").is_err());
+        assert!(pax_time("").is_err());
+    }
+
+    #[test]
+    fn test_export_keeps_subsecond_times() {
+        let source = TempDir::new().unwrap();
+        let path = source.path().join("file");
+        fs::write(&path, b"data").unwrap();
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::new(1_700_000_000, 250_000_000);
+        fs::File::options().write(true).open(&path).unwrap().set_modified(mtime).unwrap();
+        let archive = archive_of(source.path(), &["file"]);
+
+        // The pax record keeps the fraction the ustar field can't hold
+        let out = TempDir::new().unwrap();
+        let mut extractor = extractor_for(&archive, out.path());
+        let tar = export_tar(&mut extractor, Vec::new(), true, |_| {}).unwrap();
+        let mut entries = tar::Archive::new(tar.as_slice());
+        let mut entry = entries.entries().unwrap().next().unwrap().unwrap();
+        assert_eq!(entry.header().mtime().unwrap(), 1_700_000_000);
+        let mtime_record = entry
+            .pax_extensions()
+            .unwrap()
+            .unwrap()
+            .map(|record| record.unwrap())
+            .find(|record| record.key().unwrap() == "mtime")
+            .map(|record| record.value().unwrap().to_string());
+        assert_eq!(mtime_record.as_deref(), Some("1700000000.250000000"));
+
+        // Extraction restores the fraction too
+        let mut extractor = extractor_for(&archive, out.path());
+        extractor.extract_all().unwrap();
+        assert_eq!(fs::metadata(out.path().join("file")).unwrap().modified().unwrap(), mtime);
+    }
+}
@@ -1,13 +1,25 @@
 //! Archive creation functionality
 
+use crate::base::BaseArchive;
+use crate::chunking::ChunkSizes;
+use crate::compression::CompressionPolicy;
+use crate::encryption::{ArchiveCipher, ArchiveKey, KeySource};
+use crate::filter::{IgnoreRules, PathFilter, CACHEDIR_TAG_NAME};
 use crate::format::*;
-use crate::reflink;
-use anyhow::{Context, Result};
-use std::collections::HashMap;
+use crate::incremental::{Snapshot, SnapshotEntry};
+use crate::progress::{NoProgress, ProgressObserver};
+use crate::signature::{self, ManifestBuilder};
+use crate::transform::{normalize_member_path, PathRewrite};
+use crate::reflink::FilesystemCapabilities;
+use anyhow::{bail, Context, Result};
+use ed25519_dalek::SigningKey;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::{self, File};
-use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
-use std::os::unix::fs::MetadataExt;
+use std::io::{BufWriter, Read, Write};
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
 use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
 
 /// Extent tracking for deduplication and references
 #[derive(Debug, Clone)]
@@ -23,103 +35,822 @@ pub struct ExtentInfo {
     pub checksum: u32,
 }
 
+/// Blocks handed to a reader thread at a time
+const PIPELINE_BATCH_BLOCKS: u32 = 64;
+
+/// Batches per job that may be read ahead of the writer
+const PIPELINE_WINDOW: usize = 4;
+
+/// A run of consecutive blocks in a source file
+#[derive(Debug, Clone, Copy)]
+struct BlockRun {
+    first_block: u32,
+    blocks: u32,
+}
+
+impl BlockRun {
+    /// Split the run into pipeline batches
+    fn batches(self) -> impl Iterator<Item = BlockRun> {
+        let end = self.first_block + self.blocks;
+        (self.first_block..end)
+            .step_by(PIPELINE_BATCH_BLOCKS as usize)
+            .map(move |first_block| BlockRun {
+                first_block,
+                blocks: PIPELINE_BATCH_BLOCKS.min(end - first_block),
+            })
+    }
+}
+
+/// Part of a source file: either a hole or data to be read
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    hole: bool,
+    run: BlockRun,
+}
+
+/// A block read and hashed ahead of the writer
+struct HashedBlock {
+    data: Vec<u8>, // Padded to the block size
+    checksum: u32,
+    key: [u8; 32], // Deduplication key (BLAKE3 of the padded block, keyed when encrypting)
+    length: u64,   // Bytes of file data in the block
+    zero: bool,    // Every byte is zero
+}
+
+/// Grow a pending hole by a run that directly follows it
+fn extend_hole(hole: &mut Option<BlockRun>, run: BlockRun) {
+    match hole {
+        Some(pending) => pending.blocks += run.blocks,
+        None => *hole = Some(run),
+    }
+}
+
+/// How file data is split into extents
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Chunking {
+    /// One extent per block; shared blocks can be reflinked on extraction
+    #[default]
+    FixedBlocks,
+    /// FastCDC chunks averaging `average_size` bytes; finds shifted
+    /// duplicates, but chunks are not block-aligned
+    ContentDefined { average_size: u32 },
+}
+
+/// Bytes read from a file at a time when chunking by content
+const CHUNK_READ_SIZE: usize = 8 * 1024 * 1024;
+
+/// Order in which directory entries are archived
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortOrder {
+    /// Whatever order the filesystem returns
+    #[default]
+    None,
+    /// By file name, byte-wise
+    Name,
+}
+
+/// How member modification times are recorded
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MtimePolicy {
+    /// Record the file's own mtime
+    #[default]
+    Keep,
+    /// Record this time for every member
+    Set(u64),
+    /// Record this time for members modified after it
+    Clamp(u64),
+}
+
+impl MtimePolicy {
+    /// The modification time to record, in seconds and nanoseconds, for a
+    /// file modified at `mtime` and `nanos`
+    fn apply(self, mtime: u64, nanos: u32) -> (u64, u32) {
+        match self {
+            MtimePolicy::Keep => (mtime, nanos),
+            MtimePolicy::Set(time) => (time, 0),
+            MtimePolicy::Clamp(time) if mtime < time => (mtime, nanos),
+            MtimePolicy::Clamp(time) => (time, 0),
+        }
+    }
+}
+
+/// Which host-specific metadata is recorded in member headers
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct MetadataOptions {
+    pub mtime: MtimePolicy,
+    pub zero_atime: bool,
+    pub zero_ctime: bool,
+    pub zero_filesystem_id: bool,
+    pub zero_filesystem_type: bool,
+    pub numeric_owner: bool,         // Leave out user and group names
+    pub owner: Option<OwnerOverride>, // Recorded instead of each file's owner
+    pub group: Option<OwnerOverride>, // Recorded instead of each file's group
+}
+
+/// A user or group recorded for every member, as with `--owner`/`--group`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OwnerOverride {
+    pub name: String, // Empty to record the id alone
+    pub id: u64,
+}
+
+/// Callback deciding whether a walked entry is archived, given its archive
+/// path and metadata
+pub type EntryFilter = Box<dyn FnMut(&Path, &fs::Metadata) -> bool>;
+
+/// State carried while walking one input directory
+struct Walk {
+    root_dev: u64,
+    ignore_rules: Vec<IgnoreRules>, // From the walked directories, outermost first
+    ancestors: Vec<(u64, u64)>,     // (dev, ino) of the directories being walked
+}
+
+/// Which symbolic links are archived as their targets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dereference {
+    /// Archive every symlink as a link
+    #[default]
+    Never,
+    /// Follow inputs given to `add_input`, `add_file` and `add_directory`,
+    /// but not links found while walking
+    Arguments,
+    /// Follow every symlink
+    Always,
+}
+
+/// Consecutive base archive extents waiting to be written as one
+/// BaseReference extent
+struct BaseRun {
+    base: usize, // Index into the creator's bases
+    first: ExtentEntry,
+    extent_count: u32,
+    file_offset: u64,
+    length: u64,
+}
+
+/// Writer wrapper that tracks the current archive offset and reports
+/// what is written
+struct CountingWriter<W: Write> {
+    inner: W,
+    position: u64,
+    progress: Arc<dyn ProgressObserver>,
+    manifest: Option<ManifestBuilder>, // Hashes what is written when signing
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.position += written as u64;
+        self.progress.bytes_written(written as u64);
+        if let Some(manifest) = &mut self.manifest {
+            manifest.write_all(&buf[..written])?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 /// Archive creator
-pub struct ArchiveCreator<W: Write + Seek> {
-    writer: BufWriter<W>,
+pub struct ArchiveCreator<W: Write> {
+    writer: CountingWriter<BufWriter<W>>,
     block_size: u32,
-    extent_map: HashMap<u32, ExtentInfo>, // Maps checksum to extent info
+    extent_map: HashMap<[u8; 32], ExtentInfo>, // Maps dedup key to extent info
     next_extent_id: u64,
+    fs_capabilities: HashMap<u64, FilesystemCapabilities>, // Maps device ID to capabilities
+    index: Vec<IndexEntry>,                                // Footer index of members written
+    extent_table: Vec<ExtentEntry>,                        // Footer table of Data extents written
+    base_extents: HashMap<[u8; 32], (usize, ExtentEntry)>, // Extents in base archives, by content hash
+    bases: Vec<(BaseEntry, bool)>,                         // Base archives, and whether referenced
+    base_run: Option<BaseRun>,                             // Base references not yet written
+    previous_snapshot: Option<Snapshot>,                   // Set for incremental archives
+    snapshot: Snapshot,                                    // Members seen in this run
+    jobs: usize,                                           // Reader/hasher threads
+    path_filter: PathFilter,
+    entry_filter: Option<EntryFilter>,
+    sort_order: SortOrder,
+    metadata_options: MetadataOptions,
+    dereference: Dereference,
+    path_rewrite: PathRewrite,
+    warnings: Vec<String>,
+    chunking: Chunking,
+    compression: CompressionPolicy,
+    encryption: Option<(ArchiveKey, EncryptionHeader)>, // Set when encrypting
+    cipher: ArchiveCipher, // Seals records once the archive header is written
+    signing_key: Option<SigningKey>,                    // Set when signing
+    header_written: bool,
     #[allow(dead_code)]
     source_file: Option<File>, // Keep track of source file for reflinks
 }
 
-impl<W: Write + Seek> ArchiveCreator<W> {
+impl<W: Write> ArchiveCreator<W> {
     /// Create a new archive creator
     pub fn new(writer: W, block_size: Option<u32>) -> Result<Self> {
         let block_size = block_size.unwrap_or(DEFAULT_BLOCK_SIZE);
 
-        let mut creator = Self {
-            writer: BufWriter::new(writer),
+        let creator = Self {
+            writer: CountingWriter {
+                inner: BufWriter::new(writer),
+                position: 0,
+                progress: Arc::new(NoProgress),
+                manifest: None,
+            },
             block_size,
             extent_map: HashMap::new(),
             next_extent_id: 0,
+            fs_capabilities: HashMap::new(),
+            index: Vec::new(),
+            extent_table: Vec::new(),
+            base_extents: HashMap::new(),
+            bases: Vec::new(),
+            base_run: None,
+            previous_snapshot: None,
+            snapshot: Snapshot::new(),
+            jobs: 1,
+            path_filter: PathFilter::new(),
+            entry_filter: None,
+            sort_order: SortOrder::None,
+            metadata_options: MetadataOptions::default(),
+            dereference: Dereference::Never,
+            path_rewrite: PathRewrite::new(),
+            warnings: Vec::new(),
+            chunking: Chunking::FixedBlocks,
+            compression: CompressionPolicy::None,
+            encryption: None,
+            cipher: ArchiveCipher::default(),
+            signing_key: None,
+            header_written: false,
             source_file: None,
         };
 
-        // Write archive header
-        let header = ArchiveHeader::new(block_size);
-        header.write(&mut creator.writer)?;
-
         Ok(creator)
     }
 
+    /// Split file data into content-defined chunks instead of fixed blocks
+    ///
+    /// Must be set before anything is added.
+    pub fn with_chunking(mut self, chunking: Chunking) -> Result<Self> {
+        if let Chunking::ContentDefined { average_size } = chunking {
+            ChunkSizes::new(average_size)?;
+        }
+        self.chunking = chunking;
+        Ok(self)
+    }
+
+    /// Compress Data extents according to `policy`
+    ///
+    /// An extent is only stored compressed if that saves at least a block,
+    /// so fixed-block chunking, with one block per extent, never compresses;
+    /// use content-defined chunks. Must be set before anything is added.
+    pub fn with_compression(mut self, policy: CompressionPolicy) -> Self {
+        self.compression = policy;
+        self
+    }
+
+    /// Sign the archive with an Ed25519 key
+    ///
+    /// The footer gets a manifest hashing every member and a signature over
+    /// it. Must be set before anything is added.
+    pub fn with_signing_key(mut self, key: SigningKey) -> Self {
+        self.writer.manifest = Some(ManifestBuilder::new());
+        self.signing_key = Some(key);
+        self
+    }
+
+    /// Encrypt the archive, with a key slot for each of `keys`
+    ///
+    /// With `clear_metadata`, member and extent headers and the footer are
+    /// authenticated but not encrypted, so the archive can be listed without
+    /// a key. Encrypted archives can't reference base archives. Must be set
+    /// before anything is added.
+    pub fn with_encryption(mut self, keys: &[KeySource], clear_metadata: bool) -> Result<Self> {
+        if !self.bases.is_empty() {
+            bail!("Encrypted archives can't reference base archives");
+        }
+        let key = ArchiveKey::generate()?;
+        let header = EncryptionHeader {
+            clear_metadata,
+            key_slots: key.key_slots(keys)?,
+        };
+        self.encryption = Some((key, header));
+        Ok(self)
+    }
+
+    /// Store data already in a base archive as references into it
+    ///
+    /// The base must have a footer and the same block size. With several
+    /// bases, the first one holding a block is referenced.
+    pub fn with_base(mut self, path: &Path) -> Result<Self> {
+        if self.encryption.is_some() {
+            bail!("Encrypted archives can't reference base archives");
+        }
+        let base = BaseArchive::open(path)?;
+        if base.block_size != self.block_size {
+            bail!(
+                "Base archive {:?} has {} byte blocks, not {}",
+                path,
+                base.block_size,
+                self.block_size
+            );
+        }
+
+        let index = self.bases.len();
+        for entry in base.extents() {
+            self.base_extents.entry(entry.content_hash).or_insert((index, *entry));
+        }
+        self.bases.push((
+            BaseEntry {
+                archive_id: base.archive_id,
+                name: base.name,
+            },
+            false,
+        ));
+        Ok(self)
+    }
+
+    /// Create an incremental archive: only members that are new or changed
+    /// since `previous` was taken are stored
+    ///
+    /// Directories are always stored, with a listing of their entries, so
+    /// that extraction can remove what was deleted or renamed since.
+    /// `take_snapshot` returns the state to compare the next run against.
+    pub fn with_listed_incremental(mut self, previous: Snapshot) -> Self {
+        self.previous_snapshot = Some(previous);
+        self
+    }
+
+    /// The snapshot of the members seen so far, for the next incremental run
+    pub fn take_snapshot(&mut self) -> Snapshot {
+        std::mem::take(&mut self.snapshot)
+    }
+
+    /// Record a member in the snapshot, and report whether it is unchanged
+    /// since the previous one
+    fn unchanged_since_snapshot(&mut self, member_path: &Path, metadata: &fs::Metadata) -> bool {
+        let Some(previous) = &self.previous_snapshot else {
+            return false;
+        };
+        let entry = SnapshotEntry::from_metadata(metadata);
+        let unchanged = previous.get(member_path) == Some(&entry);
+        self.snapshot.insert(member_path.to_path_buf(), entry);
+        unchanged
+    }
+
+    /// Write the archive header before the first member (or the footer)
+    fn write_archive_header(&mut self) -> Result<()> {
+        if self.header_written {
+            return Ok(());
+        }
+
+        let mut header = ArchiveHeader::new(self.block_size);
+        header.flags |= ARCHIVE_FLAG_HEADER_CHECKSUMS;
+        if let Chunking::ContentDefined { average_size } = self.chunking {
+            header.flags |= ARCHIVE_FLAG_CONTENT_CHUNKS;
+            header.chunk_size = average_size;
+        }
+        if self.compression != CompressionPolicy::None {
+            header.flags |= ARCHIVE_FLAG_COMPRESSED_EXTENTS;
+        }
+        if let Some((_, encryption)) = &self.encryption {
+            header.flags |= ARCHIVE_FLAG_ENCRYPTED;
+            header.encryption = Some(encryption.clone());
+        }
+        header.write(&mut self.writer)?;
+        self.cipher = ArchiveCipher::new(&header)?;
+        if let Some((key, _)) = &self.encryption {
+            self.cipher = self.cipher.clone().with_key(key.clone());
+        }
+        self.header_written = true;
+        Ok(())
+    }
+
+    /// Read and hash file data on `jobs` threads
+    ///
+    /// The archive is byte-identical whatever the number of jobs.
+    pub fn with_jobs(mut self, jobs: usize) -> Self {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    /// Set the order in which directory entries are archived
+    pub fn with_sort_order(mut self, order: SortOrder) -> Self {
+        self.sort_order = order;
+        self
+    }
+
+    /// Control the timestamps and host-specific fields in member headers
+    pub fn with_metadata_options(mut self, options: MetadataOptions) -> Self {
+        self.metadata_options = options;
+        self
+    }
+
+    /// Report progress to an observer
+    pub fn with_progress(mut self, observer: Arc<dyn ProgressObserver>) -> Self {
+        self.writer.progress = observer;
+        self
+    }
+
+    /// Filter entries found while walking directories by pattern
+    pub fn with_path_filter(mut self, filter: PathFilter) -> Self {
+        self.path_filter = filter;
+        self
+    }
+
+    /// Filter entries found while walking directories with a callback
+    ///
+    /// The callback runs after the path filter and returns `false` to skip
+    /// an entry; a skipped directory is not walked.
+    #[allow(dead_code)]
+    pub fn with_filter<F>(mut self, filter: F) -> Self
+    where
+        F: FnMut(&Path, &fs::Metadata) -> bool + 'static,
+    {
+        self.entry_filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Choose which symbolic links are followed
+    pub fn with_dereference(mut self, dereference: Dereference) -> Self {
+        self.dereference = dereference;
+        self
+    }
+
+    /// Problems that did not stop archiving, such as symlink loops
+    #[allow(dead_code)]
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Rewrite member paths with `--transform`/`--strip-components` rules
+    pub fn with_path_rewrite(mut self, rewrite: PathRewrite) -> Self {
+        self.path_rewrite = rewrite;
+        self
+    }
+
+    /// Add a command-line input the way tar does: `input` is read relative
+    /// to `base_dir` and stored under its own relative path, without any
+    /// leading `/`. A directory is walked.
+    pub fn add_input(&mut self, base_dir: &Path, input: &Path) -> Result<()> {
+        let source_path = base_dir.join(input);
+        let archive_path = normalize_member_path(input);
+        let metadata = self.input_metadata(&source_path)?;
+
+        if metadata.is_dir() {
+            self.add_directory_as(&source_path, &archive_path, &metadata)
+        } else {
+            self.add_entry(&source_path, &archive_path, &metadata)
+        }
+    }
+
     /// Add a file to the archive
+    #[allow(dead_code)]
     pub fn add_file(&mut self, source_path: &Path, archive_path: &Path) -> Result<()> {
-        let metadata = fs::metadata(source_path)
-            .with_context(|| format!("Failed to read metadata for {:?}", source_path))?;
+        let metadata = self.input_metadata(source_path)?;
+        self.add_entry(source_path, archive_path, &metadata)
+    }
+
+    /// Metadata of a command-line input, following a symlink if asked to
+    fn input_metadata(&self, source_path: &Path) -> Result<fs::Metadata> {
+        let metadata = if self.dereference == Dereference::Never {
+            fs::symlink_metadata(source_path)
+        } else {
+            fs::metadata(source_path)
+        };
+        metadata.with_context(|| format!("Failed to read metadata for {:?}", source_path))
+    }
+
+    /// Add a single non-directory member
+    fn add_entry(&mut self, source_path: &Path, archive_path: &Path, metadata: &fs::Metadata) -> Result<()> {
+        if metadata.file_type().is_socket() {
+            self.warn(format!("{}: socket ignored", source_path.display()));
+            return Ok(());
+        }
+
+        let Some(member_path) = self.path_rewrite.apply(archive_path) else {
+            return Ok(());
+        };
+        if member_path.as_os_str().is_empty() {
+            bail!("No member name for {:?}", source_path);
+        }
+        if self.unchanged_since_snapshot(&member_path, metadata) && !metadata.is_dir() {
+            return Ok(());
+        }
+
+        let progress = self.writer.progress.clone();
+        progress.file_started(&member_path, metadata.len());
 
         // Build file header
-        let file_header = self.build_file_header(source_path, archive_path, &metadata)?;
+        let file_header = self.build_file_header(source_path, &member_path, metadata)?;
 
         // Write file header
-        file_header.write(&mut self.writer, self.block_size)?;
+        self.write_file_header(&file_header)?;
+        progress.bytes_read(file_header.inline_data.len() as u64);
 
         // Handle file data based on size and type
         if file_header.file_type == FileType::Regular && file_header.inline_data.is_empty() {
             // File is large enough to have extents
-            self.write_file_extents(source_path, file_header.file_size)?;
+            match self.chunking {
+                Chunking::FixedBlocks => self.write_file_extents(source_path, file_header.file_size)?,
+                Chunking::ContentDefined { average_size } => {
+                    let sizes = ChunkSizes::new(average_size)?;
+                    let mut file = File::open(source_path)?;
+                    self.write_file_chunks(source_path, &mut file, file_header.file_size as u64, &sizes)?
+                }
+            }
+            self.flush_base_run()?;
+        }
+        progress.file_finished(&member_path);
+
+        Ok(())
+    }
+
+    /// Add a member that does not come from the filesystem, such as a tar
+    /// member: `header` describes it, and a regular file's `file_size` bytes
+    /// are read from `contents` front to back. Small files are stored
+    /// inline, zero blocks as holes, and data is deduplicated as for files
+    /// on disk. The path rewrite applies to the member path and to a hard
+    /// link's target.
+    pub fn add_stream_member(&mut self, mut header: FileHeader, contents: &mut dyn Read) -> Result<()> {
+        let archive_path = PathBuf::from(header.member_path());
+        let Some(member_path) = self.path_rewrite.apply(&archive_path) else {
+            return Ok(());
+        };
+        if member_path.as_os_str().is_empty() {
+            bail!("No member name for {:?}", archive_path);
+        }
+        header.file_path = member_path.parent().unwrap_or(Path::new("")).to_string_lossy().to_string();
+        header.file_name = member_path.file_name().unwrap_or(member_path.as_os_str()).to_string_lossy().to_string();
+        if header.file_type == FileType::HardLink {
+            if let Some(target) = self.path_rewrite.apply(Path::new(&header.link_name)) {
+                header.link_name = target.to_string_lossy().to_string();
+            }
         }
 
+        let file_size = header.file_size as u64;
+        let regular = header.file_type == FileType::Regular;
+        header.inline_data.clear();
+        if regular && file_size > 0 && file_size < self.block_size as u64 {
+            header.inline_data = vec![0u8; file_size as usize];
+            contents.read_exact(&mut header.inline_data)?;
+        }
+
+        let progress = self.writer.progress.clone();
+        progress.file_started(&member_path, file_size);
+        self.write_file_header(&header)?;
+        progress.bytes_read(header.inline_data.len() as u64);
+
+        if regular && file_size > 0 && header.inline_data.is_empty() {
+            match self.chunking {
+                Chunking::FixedBlocks => self.write_stream_extents(&member_path, contents, file_size)?,
+                Chunking::ContentDefined { average_size } => {
+                    let sizes = ChunkSizes::new(average_size)?;
+                    self.write_file_chunks(&member_path, contents, file_size, &sizes)?
+                }
+            }
+            self.flush_base_run()?;
+        }
+        progress.file_finished(&member_path);
+
         Ok(())
     }
 
     /// Add a directory recursively to the archive
+    #[allow(dead_code)]
     pub fn add_directory(&mut self, source_path: &Path, archive_base: &Path) -> Result<()> {
-        let metadata = fs::metadata(source_path)?;
+        let metadata = self.input_metadata(source_path)?;
 
-        // Add the directory itself
         let archive_path = archive_base.join(
             source_path
                 .file_name()
                 .unwrap_or(source_path.as_os_str()),
         );
-        let dir_header = self.build_file_header(source_path, &archive_path, &metadata)?;
-        dir_header.write(&mut self.writer, self.block_size)?;
 
-        // Recursively add contents
-        if metadata.is_dir() {
-            for entry in fs::read_dir(source_path)? {
-                let entry = entry?;
-                let entry_path = entry.path();
-                let rel_path = archive_path.join(entry.file_name());
+        self.add_directory_as(source_path, &archive_path, &metadata)
+    }
 
-                if entry_path.is_dir() {
-                    self.add_directory(&entry_path, &archive_path)?;
-                } else {
-                    self.add_file(&entry_path, &rel_path)?;
+    /// Walk a directory tree stored under `archive_path`
+    fn add_directory_as(&mut self, source_path: &Path, archive_path: &Path, metadata: &fs::Metadata) -> Result<()> {
+        let mut walk = Walk {
+            root_dev: metadata.dev(),
+            ignore_rules: Vec::new(),
+            ancestors: Vec::new(),
+        };
+
+        self.walk_directory(source_path, archive_path, metadata, &mut walk)
+    }
+
+    /// Add a directory and whichever of its contents pass the filters
+    fn walk_directory(
+        &mut self,
+        source_path: &Path,
+        archive_path: &Path,
+        metadata: &fs::Metadata,
+        walk: &mut Walk,
+    ) -> Result<()> {
+        let descend = metadata.is_dir()
+            && !(self.path_filter.stays_on_file_system() && metadata.dev() != walk.root_dev);
+
+        // Cache directories keep only their tag file
+        let cache_directory = descend && self.path_filter.is_cache_directory(source_path);
+        let ignore_rules = if descend && self.path_filter.reads_ignore_files() {
+            IgnoreRules::load(source_path, archive_path)?
+        } else {
+            None
+        };
+        let pushed_rules = ignore_rules.is_some();
+        walk.ignore_rules.extend(ignore_rules);
+
+        let mut entries = if descend {
+            fs::read_dir(source_path)?.collect::<std::io::Result<Vec<_>>>()?
+        } else {
+            Vec::new()
+        };
+        if self.sort_order == SortOrder::Name {
+            entries.sort_by_key(|entry| entry.file_name());
+        }
+
+        // Choose the contents first, so an incremental archive can list them
+        let mut selected = Vec::new();
+        for entry in entries {
+            if cache_directory && entry.file_name() != CACHEDIR_TAG_NAME {
+                continue;
+            }
+
+            let entry_path = entry.path();
+            let rel_path = archive_path.join(entry.file_name());
+            let link_metadata = fs::symlink_metadata(&entry_path)
+                .with_context(|| format!("Failed to read metadata for {:?}", entry_path))?;
+            let entry_metadata = if link_metadata.is_symlink() && self.dereference == Dereference::Always {
+                match fs::metadata(&entry_path) {
+                    Ok(target_metadata) => target_metadata,
+                    Err(e) => {
+                        self.warn(format!("{}: cannot follow link ({}), archived as a link", entry_path.display(), e));
+                        link_metadata.clone()
+                    }
                 }
+            } else {
+                link_metadata.clone()
+            };
+            if !self.is_selected(&rel_path, &entry_metadata, walk) {
+                continue;
             }
+            if !entry_metadata.is_dir()
+                && self.path_filter.stays_on_file_system()
+                && entry_metadata.dev() != walk.root_dev
+            {
+                continue;
+            }
+            selected.push((entry_path, rel_path, link_metadata, entry_metadata));
+        }
+
+        // Add the directory itself, unless it is the unnamed root of an
+        // input like "." or its name is rewritten away
+        let member_path = match archive_path.as_os_str().is_empty() {
+            true => None,
+            false => self.path_rewrite.apply(archive_path),
+        };
+        if let Some(member_path) = member_path {
+            self.writer.progress.file_started(&member_path, 0);
+            let mut dir_header = self.build_file_header(source_path, &member_path, metadata)?;
+            if self.previous_snapshot.is_some() {
+                self.unchanged_since_snapshot(&member_path, metadata);
+                let listing = selected
+                    .iter()
+                    .filter_map(|(_, rel_path, _, _)| self.path_rewrite.apply(rel_path))
+                    .filter_map(|path| Some(path.file_name()?.to_string_lossy().into_owned()))
+                    .collect();
+                dir_header.directory_listing = Some(listing);
+            }
+            self.write_file_header(&dir_header)?;
+            self.writer.progress.file_finished(&member_path);
+        }
+
+        walk.ancestors.push((metadata.dev(), metadata.ino()));
+
+        // Recursively add contents
+        for (entry_path, rel_path, link_metadata, entry_metadata) in selected {
+            if entry_metadata.is_dir() {
+                // Following links can lead back to a directory being walked
+                if walk.ancestors.contains(&(entry_metadata.dev(), entry_metadata.ino())) {
+                    self.warn(format!("{}: file system loop detected", entry_path.display()));
+                    if link_metadata.is_symlink() {
+                        self.add_entry(&entry_path, &rel_path, &link_metadata)?;
+                    }
+                    continue;
+                }
+                self.walk_directory(&entry_path, &rel_path, &entry_metadata, walk)?;
+            } else {
+                self.add_entry(&entry_path, &rel_path, &entry_metadata)?;
+            }
+        }
+
+        walk.ancestors.pop();
+        if pushed_rules {
+            walk.ignore_rules.pop();
         }
 
         Ok(())
     }
 
+    /// Report a problem that does not stop archiving
+    fn warn(&mut self, message: String) {
+        eprintln!("Warning: {}", message);
+        self.warnings.push(message);
+    }
+
+    /// Apply the path filter, ignore files and filter callback to an entry
+    fn is_selected(&mut self, archive_path: &Path, metadata: &fs::Metadata, walk: &Walk) -> bool {
+        let is_dir = metadata.is_dir();
+        if self.path_filter.is_excluded(archive_path, is_dir) {
+            return false;
+        }
+
+        // The innermost ignore file with a matching rule decides
+        let ignored = walk
+            .ignore_rules
+            .iter()
+            .rev()
+            .find_map(|rules| rules.decision(archive_path, is_dir));
+        if ignored == Some(true) {
+            return false;
+        }
+
+        match self.entry_filter.as_mut() {
+            Some(filter) => filter(archive_path, metadata),
+            None => true,
+        }
+    }
+
+    /// Write a member header and record it in the footer index
+    fn write_file_header(&mut self, header: &FileHeader) -> Result<()> {
+        self.write_archive_header()?;
+        let offset = self.writer.position;
+        if let Some(manifest) = &mut self.writer.manifest {
+            manifest.start_member(offset);
+        }
+        self.index.push(IndexEntry {
+            header_offset: offset,
+            file_type: header.file_type,
+            path: header.member_path(),
+        });
+
+        self.cipher.write_file_header(&mut self.writer, header, offset, self.block_size)
+    }
+
+    /// Write an extent header, sealed in encrypted archives
+    fn write_extent_header(&mut self, header: &ExtentHeader) -> Result<()> {
+        let offset = self.writer.position;
+        self.cipher.write_extent_header(&mut self.writer, header, offset, self.block_size)
+    }
+
+    /// Look up (and cache per device) the capabilities of a source filesystem
+    fn capabilities_for(&mut self, file: &File) -> Option<&FilesystemCapabilities> {
+        let dev = file.metadata().ok()?.dev();
+        if let Entry::Vacant(entry) = self.fs_capabilities.entry(dev) {
+            entry.insert(FilesystemCapabilities::inspect(file).ok()?);
+        }
+        self.fs_capabilities.get(&dev)
+    }
+
     /// Build a file header from filesystem metadata
     fn build_file_header(
-        &self,
+        &mut self,
         source_path: &Path,
         archive_path: &Path,
         metadata: &fs::Metadata,
     ) -> Result<FileHeader> {
-        let file_type = if metadata.is_dir() {
+        let kind = metadata.file_type();
+        let file_type = if kind.is_dir() {
             FileType::Directory
-        } else if metadata.is_symlink() {
+        } else if kind.is_symlink() {
             FileType::SymbolicLink
+        } else if kind.is_char_device() {
+            FileType::CharDevice
+        } else if kind.is_block_device() {
+            FileType::BlockDevice
+        } else if kind.is_fifo() {
+            FileType::FIFO
         } else {
             FileType::Regular
         };
+        let (device_major, device_minor) = match file_type {
+            FileType::CharDevice | FileType::BlockDevice => {
+                let rdev = metadata.rdev();
+                (
+                    nix::sys::stat::major(rdev) as u64,
+                    nix::sys::stat::minor(rdev) as u64,
+                )
+            }
+            _ => (0, 0),
+        };
 
-        let file_size = metadata.len() as u128;
+        // Only regular files and symlinks have a meaningful size
+        let file_size = match file_type {
+            FileType::Regular | FileType::SymbolicLink => metadata.len() as u128,
+            _ => 0,
+        };
 
         // Read inline data for small files
         let inline_data = if file_type == FileType::Regular
@@ -141,35 +872,66 @@ impl<W: Write + Seek> ArchiveCreator<W> {
             String::new()
         };
 
-        // Get username and groupname
-        let username = get_username(metadata.uid()).unwrap_or_else(|| metadata.uid().to_string());
-        let groupname =
-            get_groupname(metadata.gid()).unwrap_or_else(|| metadata.gid().to_string());
+        let options = self.metadata_options.clone();
+
+        // Get the owner and group, and their names
+        let (uid, username) = match &options.owner {
+            Some(owner) => (owner.id, owner.name.clone()),
+            None if options.numeric_owner => (metadata.uid() as u64, String::new()),
+            None => (
+                metadata.uid() as u64,
+                get_username(metadata.uid()).unwrap_or_else(|| metadata.uid().to_string()),
+            ),
+        };
+        let (gid, groupname) = match &options.group {
+            Some(group) => (group.id, group.name.clone()),
+            None if options.numeric_owner => (metadata.gid() as u64, String::new()),
+            None => (
+                metadata.gid() as u64,
+                get_groupname(metadata.gid()).unwrap_or_else(|| metadata.gid().to_string()),
+            ),
+        };
+        let (username, groupname) = match options.numeric_owner {
+            true => (String::new(), String::new()),
+            false => (username, groupname),
+        };
 
         // Get filesystem info if we can open the file
-        let (source_filesystem_type, source_filesystem_id) = if file_type == FileType::Regular {
-            if let Ok(file) = File::open(source_path) {
-                (
-                    reflink::get_filesystem_type(&file).unwrap_or_default(),
-                    reflink::get_filesystem_id(&file).unwrap_or(0),
-                )
-            } else {
-                (String::new(), 0)
+        let (mut source_filesystem_type, mut source_filesystem_id) = if file_type == FileType::Regular {
+            match File::open(source_path)
+                .ok()
+                .and_then(|file| self.capabilities_for(&file).cloned())
+            {
+                Some(caps) => (caps.filesystem_type, caps.filesystem_id),
+                None => (String::new(), 0),
             }
         } else {
             (String::new(), 0)
         };
+        if options.zero_filesystem_type {
+            source_filesystem_type.clear();
+        }
+        if options.zero_filesystem_id {
+            source_filesystem_id = 0;
+        }
+
+        let (modify_time, modify_nanos) = options.mtime.apply(metadata.mtime() as u64, metadata.mtime_nsec() as u32);
+        let time_nanos = TimeNanos {
+            access: if options.zero_atime { 0 } else { metadata.atime_nsec() as u32 },
+            modify: modify_nanos,
+            creation: if options.zero_ctime { 0 } else { metadata.ctime_nsec() as u32 },
+        };
 
         Ok(FileHeader {
             file_size,
             file_type,
-            uid: metadata.uid() as u64,
-            gid: metadata.gid() as u64,
-            device_major: 0,
-            device_minor: 0,
-            access_time: metadata.atime() as u64,
-            modify_time: metadata.mtime() as u64,
-            creation_time: metadata.ctime() as u64,
+            uid,
+            gid,
+            device_major,
+            device_minor,
+            access_time: if options.zero_atime { 0 } else { metadata.atime() as u64 },
+            modify_time,
+            creation_time: if options.zero_ctime { 0 } else { metadata.ctime() as u64 },
             username,
             groupname,
             file_path: archive_path
@@ -186,81 +948,518 @@ impl<W: Write + Seek> ArchiveCreator<W> {
             extended_permissions: Vec::new(), // TODO: implement xattr support
             source_filesystem_type,
             source_filesystem_id,
+            mode: Some(metadata.mode() & 0o7777),
+            directory_listing: None,
+            xattrs: Vec::new(),
+            time_nanos,
+            data_seal: None,
             inline_data,
         })
     }
 
     /// Write file extents (for files larger than block size)
+    ///
+    /// With more than one job, reader threads fetch and hash batches of
+    /// blocks ahead of the writer while this thread emits extents strictly
+    /// in file order, so the archive is identical to a single-threaded run.
     fn write_file_extents(&mut self, source_path: &Path, file_size: u128) -> Result<()> {
-        let mut file = File::open(source_path)?;
-        let num_blocks = ((file_size + self.block_size as u128 - 1) / self.block_size as u128) as u32;
-
-        for block_idx in 0..num_blocks {
-            let block_offset = block_idx as u64 * self.block_size as u64;
-            let block_len = if block_idx == num_blocks - 1 {
-                // Last block might be partial
-                (file_size - block_offset as u128) as usize
-            } else {
-                self.block_size as usize
-            };
+        let file = File::open(source_path)?;
+        let file_size = file_size as u64;
+        let block_size = self.block_size;
+
+        // Only look for holes where the source filesystem can have them
+        let detect_holes = self
+            .capabilities_for(&file)
+            .map(|caps| caps.sparse)
+            .unwrap_or(false);
+        let segments = self.plan_segments(&file, file_size, detect_holes)?;
+
+        let archive_key = self.encryption.as_ref().map(|(key, _)| key.clone());
+        let archive_key = archive_key.as_ref();
+        if self.jobs <= 1 {
+            return self.emit_segments(source_path, &segments, |batch| {
+                read_batch(&file, block_size, file_size, batch, archive_key)
+            });
+        }
+
+        let batches: Vec<BlockRun> = segments
+            .iter()
+            .filter(|segment| !segment.hole)
+            .flat_map(|segment| segment.run.batches())
+            .collect();
+        let jobs = self.jobs;
+
+        let (job_tx, job_rx) = mpsc::channel::<(usize, BlockRun)>();
+        let (result_tx, result_rx) = mpsc::channel::<(usize, Result<Vec<HashedBlock>>)>();
+        let (credit_tx, credit_rx) = mpsc::channel::<()>();
+        let job_rx = Mutex::new(job_rx);
+
+        std::thread::scope(|scope| {
+            // Feeder: keeps at most PIPELINE_WINDOW batches in flight
+            let batches = &batches;
+            scope.spawn(move || {
+                for (index, batch) in batches.iter().enumerate() {
+                    if index >= PIPELINE_WINDOW * jobs && credit_rx.recv().is_err() {
+                        break;
+                    }
+                    if job_tx.send((index, *batch)).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            // Readers/hashers
+            for _ in 0..jobs {
+                let job_rx = &job_rx;
+                let result_tx = result_tx.clone();
+                let file = &file;
+                scope.spawn(move || loop {
+                    let job = job_rx.lock().unwrap().recv();
+                    let Ok((index, batch)) = job else {
+                        break;
+                    };
+                    let blocks = read_batch(file, block_size, file_size, batch, archive_key);
+                    if result_tx.send((index, blocks)).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(result_tx);
+
+            // Ordered writer
+            let mut pending = BTreeMap::new();
+            let mut next_index = 0;
+            let result = self.emit_segments(source_path, &segments, |_batch| loop {
+                if let Some(blocks) = pending.remove(&next_index) {
+                    next_index += 1;
+                    let _ = credit_tx.send(());
+                    return blocks;
+                }
+                let (index, blocks) = result_rx
+                    .recv()
+                    .map_err(|_| anyhow::anyhow!("Reader threads stopped unexpectedly"))?;
+                pending.insert(index, blocks);
+            });
+
+            // Unblock the feeder and readers if we stopped early
+            drop(credit_tx);
+            drop(result_rx);
+            result
+        })
+    }
 
-            // Read block data
-            let mut block_data = vec![0u8; self.block_size as usize];
-            file.seek(SeekFrom::Start(block_offset))?;
-            let bytes_read = file.read(&mut block_data[..block_len])?;
+    /// Write fixed-block extents for file data read front to back
+    ///
+    /// The extents are the same as for the file on disk, except that holes
+    /// are only found as zero blocks.
+    fn write_stream_extents(&mut self, source_path: &Path, reader: &mut dyn Read, file_size: u64) -> Result<()> {
+        let block_size = self.block_size as u64;
+        let archive_key = self.encryption.as_ref().map(|(key, _)| key.clone());
+        let segments = [Segment {
+            hole: false,
+            run: BlockRun {
+                first_block: 0,
+                blocks: u32::try_from(file_size.div_ceil(block_size)).context("File too large for the block size")?,
+            },
+        }];
 
-            // Calculate checksum on the full block (including padding)
-            // This must match what we write and what extraction will verify
-            let checksum = crc32fast::hash(&block_data);
+        self.emit_segments(source_path, &segments, |batch| {
+            (batch.first_block..batch.first_block + batch.blocks)
+                .map(|block_idx| {
+                    let block_offset = block_idx as u64 * block_size;
+                    let block_len = (file_size - block_offset).min(block_size) as usize;
+                    let mut data = vec![0u8; block_size as usize];
+                    reader.read_exact(&mut data[..block_len])?;
+                    Ok(hash_block(data, block_len as u64, archive_key.as_ref()))
+                })
+                .collect()
+        })
+    }
+
+    /// Split a file into runs of hole blocks and data blocks
+    fn plan_segments(&self, file: &File, file_size: u64, detect_holes: bool) -> Result<Vec<Segment>> {
+        let block_size = self.block_size as u64;
+        let num_blocks = file_size.div_ceil(block_size) as u32;
+        let mut segments = Vec::new();
+
+        let mut block_idx = 0;
+        while block_idx < num_blocks {
+            let remaining = num_blocks - block_idx;
+            let block_offset = block_idx as u64 * block_size;
 
-            // Check if this block is a duplicate (could be referenced)
-            let (extent_id, extent_type) = if let Some(existing) = self.extent_map.get(&checksum) {
-                // Found duplicate - create reference extent pointing to existing extent
-                (existing.extent_id, ExtentType::Reference)
+            let (hole, blocks) = if !detect_holes {
+                (false, remaining)
             } else {
-                // New data - create data extent with new ID
-                let new_id = self.next_extent_id;
-                self.next_extent_id += 1;
-                (new_id, ExtentType::Data)
+                let hole_blocks = self.hole_blocks_at(file, block_offset, file_size)?;
+                if hole_blocks > 0 {
+                    (true, hole_blocks.min(remaining))
+                } else {
+                    // Data runs up to the next hole; a partly filled block is data
+                    let hole_start = next_hole_offset(file, block_offset, file_size)?;
+                    let data_blocks = hole_start.saturating_sub(block_offset).div_ceil(block_size);
+                    (false, (data_blocks as u32).clamp(1, remaining))
+                }
             };
 
-            let extent_header = ExtentHeader {
+            segments.push(Segment {
+                hole,
+                run: BlockRun {
+                    first_block: block_idx,
+                    blocks,
+                },
+            });
+            block_idx += blocks;
+        }
+
+        Ok(segments)
+    }
+
+    /// Write the extents for a planned file, pulling hashed data blocks from
+    /// `next_batch` one batch at a time in file order
+    fn emit_segments<F>(&mut self, source_path: &Path, segments: &[Segment], mut next_batch: F) -> Result<()>
+    where
+        F: FnMut(BlockRun) -> Result<Vec<HashedBlock>>,
+    {
+        // Holes and all-zero blocks are merged into one Sparse extent, so the
+        // output does not depend on whether the filesystem reports holes
+        let mut hole: Option<BlockRun> = None;
+
+        for segment in segments {
+            if segment.hole {
+                extend_hole(&mut hole, segment.run);
+                continue;
+            }
+
+            for batch in segment.run.batches() {
+                let blocks = next_batch(batch)?;
+                for (i, block) in blocks.into_iter().enumerate() {
+                    let block_idx = batch.first_block + i as u32;
+                    if block.zero {
+                        extend_hole(
+                            &mut hole,
+                            BlockRun {
+                                first_block: block_idx,
+                                blocks: 1,
+                            },
+                        );
+                        continue;
+                    }
+
+                    if let Some(run) = hole.take() {
+                        self.write_hole(run)?;
+                    }
+                    self.write_chunk(source_path, block_idx as u64 * self.block_size as u64, block, false)?;
+                }
+            }
+        }
+
+        if let Some(run) = hole {
+            self.write_hole(run)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a Sparse extent covering a run of blocks
+    fn write_hole(&mut self, run: BlockRun) -> Result<()> {
+        let block_size = self.block_size as u64;
+        self.write_sparse(run.first_block as u64 * block_size, run.blocks as u64 * block_size)
+    }
+
+    /// Write a Sparse extent covering `length` bytes at `offset`
+    fn write_sparse(&mut self, offset: u64, length: u64) -> Result<()> {
+        self.flush_base_run()?;
+        self.writer.progress.bytes_read(length);
+        let block_size = self.block_size as u64;
+        let extent_header = ExtentHeader {
+            extent_id: 0,
+            length_in_blocks: length.div_ceil(block_size) as u32,
+            extent_type: ExtentType::Sparse,
+            source_extent_start: offset,
+            checksum: 0,
+            byte_length: (!length.is_multiple_of(block_size)).then_some(length),
+            base: None,
+            compression: None,
+            seal: None,
+        };
+        self.write_extent_header(&extent_header)
+    }
+
+    /// Write file data as content-defined chunks
+    ///
+    /// Boundaries are found sequentially; with more than one job the chunks
+    /// of each read are hashed in parallel. Either way the output is the same.
+    fn write_file_chunks(
+        &mut self,
+        source_path: &Path,
+        reader: &mut dyn Read,
+        file_size: u64,
+        sizes: &ChunkSizes,
+    ) -> Result<()> {
+        let mut reader = reader.take(file_size);
+        let mut buffer = Vec::with_capacity(CHUNK_READ_SIZE.max(sizes.max * 2));
+        let mut buffer_offset = 0u64; // File offset of buffer[0]
+        let mut hole: Option<(u64, u64)> = None; // Pending zero run (offset, length)
+        let mut eof = false;
+        let archive_key = self.encryption.as_ref().map(|(key, _)| key.clone());
+        let archive_key = archive_key.as_ref();
+
+        loop {
+            // Refill, keeping enough bytes to find the next boundary
+            while !eof && buffer.len() < buffer.capacity() {
+                let filled = buffer.len();
+                buffer.resize(buffer.capacity(), 0);
+                let n = reader.read(&mut buffer[filled..])?;
+                buffer.truncate(filled + n);
+                eof = n == 0;
+            }
+            if buffer.is_empty() {
+                break;
+            }
+
+            let mut cuts = Vec::new();
+            let mut start = 0;
+            while start < buffer.len() && (eof || buffer.len() - start >= sizes.max) {
+                let length = sizes.next_chunk_length(&buffer[start..]);
+                cuts.push((start, length));
+                start += length;
+            }
+
+            for ((chunk_start, length), chunk) in cuts.iter().zip(hash_chunks(&buffer, &cuts, self.block_size, self.jobs, archive_key)) {
+                let offset = buffer_offset + *chunk_start as u64;
+                if chunk.zero {
+                    match hole.as_mut() {
+                        Some((_, hole_length)) => *hole_length += *length as u64,
+                        None => hole = Some((offset, *length as u64)),
+                    }
+                    continue;
+                }
+                if let Some((hole_offset, hole_length)) = hole.take() {
+                    self.write_sparse(hole_offset, hole_length)?;
+                }
+                self.write_chunk(source_path, offset, chunk, true)?;
+            }
+
+            buffer.drain(..start);
+            buffer_offset += start as u64;
+        }
+
+        // A file that shrank while being read is padded with zeros
+        if buffer_offset < file_size {
+            match hole.as_mut() {
+                Some((_, hole_length)) => *hole_length += file_size - buffer_offset,
+                None => hole = Some((buffer_offset, file_size - buffer_offset)),
+            }
+        }
+        if let Some((hole_offset, hole_length)) = hole {
+            self.write_sparse(hole_offset, hole_length)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write a block or chunk as a Data extent, or as a Reference if
+    /// identical data was stored earlier
+    ///
+    /// With `exact`, extents that do not fill their last block record their
+    /// length in bytes.
+    fn write_chunk(&mut self, source_path: &Path, block_offset: u64, block: HashedBlock, exact: bool) -> Result<()> {
+        let block_size = self.block_size as u64;
+        let covered = if exact { block.length } else { block.data.len() as u64 };
+        self.writer.progress.bytes_read(block.length);
+
+        // Data stored in a base archive, and not yet in this one, is
+        // referenced there; consecutive base extents share one header
+        if !self.extent_map.contains_key(&block.key) {
+            let base_hit = self
+                .base_extents
+                .get(&block.key)
+                .filter(|(_, entry)| entry.covered_length == covered)
+                .copied();
+            if let Some((base, entry)) = base_hit {
+                self.writer.progress.dedup_hit(block.length);
+                return self.add_to_base_run(base, entry, block_offset);
+            }
+        }
+        self.flush_base_run()?;
+
+        // Check if this block is a duplicate (could be referenced)
+        let (extent_id, extent_type, checksum) = if let Some(existing) = self.extent_map.get(&block.key) {
+            // Found duplicate - create reference extent pointing to existing extent
+            self.writer.progress.dedup_hit(block.length);
+            (existing.extent_id, ExtentType::Reference, existing.checksum)
+        } else {
+            // New data - create data extent with new ID
+            let new_id = self.next_extent_id;
+            self.next_extent_id += 1;
+            (new_id, ExtentType::Data, block.checksum)
+        };
+
+        let mut extent_header = ExtentHeader {
+            extent_id,
+            length_in_blocks: (block.data.len() as u64 / block_size) as u32,
+            extent_type,
+            source_extent_start: block_offset,
+            checksum,
+            byte_length: (!covered.is_multiple_of(block_size)).then_some(covered),
+            base: None,
+            compression: None,
+            seal: None,
+        };
+
+        // New data is stored compressed if that saves blocks
+        let compressed = match extent_type {
+            ExtentType::Data => self.compression.compress(&block.data[..covered as usize], self.block_size)?,
+            _ => None,
+        };
+        let stored = match compressed {
+            Some(mut compressed) => {
+                extent_header.extent_type = ExtentType::Compressed;
+                extent_header.byte_length = Some(covered);
+                extent_header.compression = Some(ExtentCompression {
+                    algorithm: CompressionAlgorithm::Zstd,
+                    stored_length: compressed.len() as u64,
+                });
+                compressed.resize(compressed.len().next_multiple_of(block_size as usize), 0);
+                extent_header.length_in_blocks = (compressed.len() as u64 / block_size) as u32;
+                extent_header.checksum = crc32fast::hash(&compressed);
+                compressed
+            }
+            None => block.data,
+        };
+
+        // Encrypted archives store data sealed, checksummed as stored
+        let mut stored = stored;
+        if extent_type == ExtentType::Data {
+            if let Some(seal) = self.cipher.seal_extent_data(&mut stored, extent_id)? {
+                extent_header.seal = Some(seal);
+                extent_header.checksum = crc32fast::hash(&stored);
+            }
+        }
+
+        // Write extent header
+        self.write_extent_header(&extent_header)?;
+
+        // Write data if not a reference
+        if extent_type == ExtentType::Data {
+            // Write the full blocks (padded to block_size)
+            self.extent_table.push(ExtentEntry {
+                content_hash: block.key,
                 extent_id,
-                length_in_blocks: 1,
-                extent_type,
-                source_extent_start: block_offset,
-                checksum,
-            };
+                data_offset: self.writer.position,
+                length_in_blocks: extent_header.length_in_blocks,
+                covered_length: covered,
+                checksum: extent_header.checksum,
+                compression: extent_header.compression,
+            });
+            self.writer.write_all(&stored)?;
 
-            // Write extent header
-            extent_header.write(&mut self.writer, self.block_size)?;
-
-            // Write data if not a reference
-            if extent_type == ExtentType::Data {
-                // Write the full block (padded to block_size)
-                self.writer.write_all(&block_data)?;
-
-                // Track this extent for future references
-                self.extent_map.insert(
-                    checksum,
-                    ExtentInfo {
-                        extent_id,
-                        file_path: source_path.to_path_buf(),
-                        offset: block_offset,
-                        length: bytes_read as u64,
-                        checksum,
+            // Track this extent for future references
+            self.extent_map.insert(
+                block.key,
+                ExtentInfo {
+                    extent_id,
+                    file_path: source_path.to_path_buf(),
+                    offset: block_offset,
+                    length: block.length,
+                    // References to sealed data carry the checksum of what
+                    // is stored, not that of the plain data
+                    checksum: match extent_header.seal {
+                        Some(_) => extent_header.checksum,
+                        None => block.checksum,
                     },
-                );
+                },
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Extend the pending run of base references, or start a new one
+    fn add_to_base_run(&mut self, base: usize, entry: ExtentEntry, file_offset: u64) -> Result<()> {
+        let block_size = self.block_size as u64;
+        if let Some(run) = self.base_run.as_mut() {
+            let follows = run.base == base
+                && run.first.extent_id + run.extent_count as u64 == entry.extent_id
+                && run.file_offset + run.length == file_offset;
+            let fits = run.extent_count < u32::MAX
+                && (run.length + entry.covered_length).div_ceil(block_size) <= u32::MAX as u64;
+            if follows && fits {
+                run.extent_count += 1;
+                run.length += entry.covered_length;
+                return Ok(());
             }
         }
 
+        self.flush_base_run()?;
+        self.bases[base].1 = true;
+        self.base_run = Some(BaseRun {
+            base,
+            first: entry,
+            extent_count: 1,
+            file_offset,
+            length: entry.covered_length,
+        });
         Ok(())
     }
 
-    /// Flush and finish writing the archive
+    /// Write the pending run of base references as one extent
+    fn flush_base_run(&mut self) -> Result<()> {
+        let Some(run) = self.base_run.take() else {
+            return Ok(());
+        };
+        let block_size = self.block_size as u64;
+        let extent_header = ExtentHeader {
+            extent_id: run.first.extent_id,
+            length_in_blocks: run.length.div_ceil(block_size) as u32,
+            extent_type: ExtentType::BaseReference,
+            source_extent_start: run.file_offset,
+            checksum: run.first.checksum,
+            byte_length: (!run.length.is_multiple_of(block_size)).then_some(run.length),
+            base: Some(BaseLink {
+                archive_id: self.bases[run.base].0.archive_id,
+                content_hash: run.first.content_hash,
+                extent_count: run.extent_count,
+            }),
+            compression: None,
+            seal: None,
+        };
+        self.write_extent_header(&extent_header)
+    }
+
+    /// Count the whole blocks starting at `offset` that lie in a hole
+    fn hole_blocks_at(&self, file: &File, offset: u64, file_size: u64) -> Result<u32> {
+        let data_start = match next_data_offset(file, offset)? {
+            Some(data) => data,
+            None => file_size.next_multiple_of(self.block_size as u64),
+        };
+        Ok((data_start.saturating_sub(offset) / self.block_size as u64) as u32)
+    }
+
+    /// Write the footer index, flush and finish writing the archive
     pub fn finish(mut self) -> Result<W> {
+        self.write_archive_header()?;
+        self.flush_base_run()?;
+        let mut footer = ArchiveFooter {
+            members: std::mem::take(&mut self.index),
+            extents: std::mem::take(&mut self.extent_table),
+            bases: self
+                .bases
+                .iter()
+                .filter(|(_, used)| *used)
+                .map(|(base, _)| base.clone())
+                .collect(),
+            ..Default::default()
+        };
+        if let (Some(key), Some(manifest)) = (&self.signing_key, self.writer.manifest.take()) {
+            signature::sign_footer(&mut footer, manifest.finish(), key)?;
+        }
+        let footer_offset = self.writer.position;
+        self.cipher
+            .write_footer(&mut self.writer, &footer, footer_offset, self.block_size)?;
+
         self.writer.flush()?;
-        match self.writer.into_inner() {
+        match self.writer.inner.into_inner() {
             Ok(writer) => Ok(writer),
             Err(e) => {
                 // If we can't unwrap the writer, return the underlying IO error
@@ -270,6 +1469,152 @@ impl<W: Write + Seek> ArchiveCreator<W> {
     }
 }
 
+/// Deduplication key of file data: its BLAKE3 hash, keyed by the archive
+/// key when encrypting so that equal data hashes differently per archive
+fn dedup_key(data: &[u8], archive_key: Option<&ArchiveKey>) -> [u8; 32] {
+    match archive_key {
+        Some(key) => key.dedup_hash(data),
+        None => *blake3::hash(data).as_bytes(),
+    }
+}
+
+/// Read and hash a run of blocks, padding the last block of the file
+fn read_batch(
+    file: &File,
+    block_size: u32,
+    file_size: u64,
+    batch: BlockRun,
+    archive_key: Option<&ArchiveKey>,
+) -> Result<Vec<HashedBlock>> {
+    let mut blocks = Vec::with_capacity(batch.blocks as usize);
+
+    for block_idx in batch.first_block..batch.first_block + batch.blocks {
+        let block_offset = block_idx as u64 * block_size as u64;
+        // Last block might be partial
+        let block_len = (file_size - block_offset).min(block_size as u64) as usize;
+
+        let mut data = vec![0u8; block_size as usize];
+        let mut filled = 0;
+        while filled < block_len {
+            match file.read_at(&mut data[filled..block_len], block_offset + filled as u64)? {
+                0 => break, // File shrank while we were reading it
+                n => filled += n,
+            }
+        }
+
+        blocks.push(hash_block(data, filled as u64, archive_key));
+    }
+
+    Ok(blocks)
+}
+
+/// Hash a block padded to the block size, holding `length` bytes of file data
+fn hash_block(data: Vec<u8>, length: u64, archive_key: Option<&ArchiveKey>) -> HashedBlock {
+    // Zero blocks are stored as holes and need no hashing
+    let zero = data.iter().all(|&byte| byte == 0);
+
+    // Calculate checksum on the full block (including padding)
+    // This must match what we write and what extraction will verify
+    let (checksum, key) = if zero {
+        (0, [0u8; 32])
+    } else {
+        (crc32fast::hash(&data), dedup_key(&data, archive_key))
+    };
+
+    HashedBlock {
+        data,
+        checksum,
+        key,
+        length,
+        zero,
+    }
+}
+
+/// Hash chunks of `buffer`, padding each to whole blocks, on up to `jobs`
+/// threads; results are in chunk order
+fn hash_chunks(
+    buffer: &[u8],
+    cuts: &[(usize, usize)],
+    block_size: u32,
+    jobs: usize,
+    archive_key: Option<&ArchiveKey>,
+) -> Vec<HashedBlock> {
+    let hash_one = |&(start, length): &(usize, usize)| {
+        let chunk = &buffer[start..start + length];
+        let zero = chunk.iter().all(|&byte| byte == 0);
+        let mut data = chunk.to_vec();
+        data.resize(length.next_multiple_of(block_size as usize), 0);
+
+        let (checksum, key) = if zero {
+            (0, [0u8; 32])
+        } else {
+            (crc32fast::hash(&data), dedup_key(chunk, archive_key))
+        };
+        HashedBlock {
+            data,
+            checksum,
+            key,
+            length: length as u64,
+            zero,
+        }
+    };
+
+    if jobs <= 1 || cuts.len() < 2 {
+        return cuts.iter().map(hash_one).collect();
+    }
+
+    let per_job = cuts.len().div_ceil(jobs);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = cuts
+            .chunks(per_job)
+            .map(|part| scope.spawn(move || part.iter().map(hash_one).collect::<Vec<_>>()))
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("hashing thread panicked"))
+            .collect()
+    })
+}
+
+/// Find the next hole at or after `offset` (the end of file counts as one)
+#[cfg(target_os = "linux")]
+fn next_hole_offset(file: &File, offset: u64, file_size: u64) -> Result<u64> {
+    use nix::unistd::{lseek, Whence};
+    use std::os::unix::io::AsRawFd;
+
+    match lseek(file.as_raw_fd(), offset as i64, Whence::SeekHole) {
+        Ok(hole) => Ok(hole as u64),
+        Err(nix::errno::Errno::ENXIO) | Err(nix::errno::Errno::EINVAL) => Ok(file_size),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn next_hole_offset(_file: &File, _offset: u64, file_size: u64) -> Result<u64> {
+    Ok(file_size)
+}
+
+/// Find the next offset at or after `offset` that holds data
+/// Returns None if the rest of the file is a hole
+#[cfg(target_os = "linux")]
+fn next_data_offset(file: &File, offset: u64) -> Result<Option<u64>> {
+    use nix::unistd::{lseek, Whence};
+    use std::os::unix::io::AsRawFd;
+
+    match lseek(file.as_raw_fd(), offset as i64, Whence::SeekData) {
+        Ok(data) => Ok(Some(data as u64)),
+        Err(nix::errno::Errno::ENXIO) => Ok(None),
+        // Filesystems without SEEK_DATA treat the whole file as data
+        Err(nix::errno::Errno::EINVAL) => Ok(Some(offset)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn next_data_offset(_file: &File, offset: u64) -> Result<Option<u64>> {
+    Ok(Some(offset))
+}
+
 // Helper functions for getting user/group names
 fn get_username(uid: u32) -> Option<String> {
     // Use nix crate to get username
@@ -288,8 +1633,8 @@ fn get_groupname(gid: u32) -> Option<String> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::Cursor;
-    use tempfile::NamedTempFile;
+    use std::io::{Cursor, Seek, SeekFrom};
+    use tempfile::{tempdir, NamedTempFile};
 
     #[test]
     fn test_create_empty_archive() {
@@ -311,4 +1656,242 @@ mod tests {
             .unwrap();
         creator.finish().unwrap();
     }
+
+    #[test]
+    fn test_sparse_file_holes_not_stored() {
+        let temp_file = NamedTempFile::new().unwrap();
+        let file = temp_file.as_file();
+        file.set_len(64 * 4096).unwrap();
+        let mut handle = file.try_clone().unwrap();
+        handle.seek(SeekFrom::Start(32 * 4096)).unwrap();
+        handle.write_all(&[0xAB; 4096]).unwrap();
+        handle.flush().unwrap();
+
+        let archive_len = {
+            let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None).unwrap();
+            creator
+                .add_file(temp_file.path(), Path::new("sparse.bin"))
+                .unwrap();
+            creator.finish().unwrap().into_inner().len()
+        };
+
+        // Archive header, file header, a hole, one data extent, its data,
+        // another hole and the footer
+        assert!(archive_len <= 7 * 4096);
+    }
+
+    #[test]
+    fn test_parallel_create_is_deterministic() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("src");
+        fs::create_dir(&source).unwrap();
+        for (name, blocks) in [("a.bin", 200u32), ("b.bin", 3), ("c.bin", 130)] {
+            // Repeat some blocks so that references appear across batches
+            let data: Vec<u8> = (0..blocks * 4096 + 123)
+                .map(|i| ((i / 4096) % 50) as u8 ^ (i % 251) as u8)
+                .collect();
+            fs::write(source.join(name), &data).unwrap();
+        }
+
+        // Reading the tree updates access times, so they are left out: this
+        // checks the order `-j` writes in, not the clock
+        let metadata = MetadataOptions {
+            zero_atime: true,
+            ..Default::default()
+        };
+        let build = |jobs| {
+            let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None)
+                .unwrap()
+                .with_jobs(jobs)
+                .with_metadata_options(metadata.clone());
+            creator.add_directory(&source, Path::new("")).unwrap();
+            creator.finish().unwrap().into_inner()
+        };
+
+        let sequential = build(1);
+        assert_eq!(sequential, build(4));
+    }
+
+    #[test]
+    fn test_directory_filters() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("proj");
+        for sub in ["src", ".git", "cache", "logs"] {
+            fs::create_dir_all(source.join(sub)).unwrap();
+        }
+        fs::write(source.join("src/main.rs"), b"fn main() {}").unwrap();
+        fs::write(source.join("src/notes.tmp"), b"scratch").unwrap();
+        fs::write(source.join(".git/HEAD"), b"ref").unwrap();
+        fs::write(
+            source.join("cache/CACHEDIR.TAG"),
+            b"Signature: 8a477f597d28d172789f06886806bc55\n",
+        )
+        .unwrap();
+        fs::write(source.join("cache/blob"), b"cached").unwrap();
+        fs::write(source.join("logs/today.log"), b"log").unwrap();
+        fs::write(source.join("logs/keep.txt"), b"keep").unwrap();
+        fs::write(source.join(".reftarignore"), b"*.tmp\n").unwrap();
+
+        let filter = PathFilter::new()
+            .exclude("*.log")
+            .unwrap()
+            .exclude_caches(true)
+            .exclude_vcs(true);
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None)
+            .unwrap()
+            .with_path_filter(filter)
+            .with_filter(|path, _| !path.ends_with("keep.txt"));
+        creator.add_directory(&source, Path::new("")).unwrap();
+
+        let mut paths: Vec<_> = creator.index.iter().map(|e| e.path.clone()).collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                "proj",
+                "proj/.reftarignore",
+                "proj/cache",
+                "proj/cache/CACHEDIR.TAG",
+                "proj/logs",
+                "proj/src",
+                "proj/src/main.rs",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reproducible_archive() {
+        let build_tree = |names: &[&str]| {
+            let dir = tempdir().unwrap();
+            let tree = dir.path().join("tree");
+            fs::create_dir(&tree).unwrap();
+            for name in names {
+                fs::write(tree.join(name), name.repeat(3000)).unwrap();
+            }
+            dir
+        };
+        // Same contents, created in a different order at different times
+        let first = build_tree(&["a", "b", "c", "d"]);
+        let second = build_tree(&["d", "c", "b", "a"]);
+
+        let archive = |dir: &Path| {
+            let options = MetadataOptions {
+                mtime: MtimePolicy::Set(1_700_000_000),
+                zero_atime: true,
+                zero_ctime: true,
+                zero_filesystem_id: true,
+                zero_filesystem_type: true,
+                numeric_owner: true,
+                ..Default::default()
+            };
+            let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None)
+                .unwrap()
+                .with_sort_order(SortOrder::Name)
+                .with_metadata_options(options);
+            creator.add_directory(&dir.join("tree"), Path::new("")).unwrap();
+            creator.finish().unwrap().into_inner()
+        };
+
+        assert_eq!(archive(first.path()), archive(second.path()));
+    }
+
+    #[test]
+    fn test_owner_and_group_overrides() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, b"data").unwrap();
+        let metadata = fs::symlink_metadata(&path).unwrap();
+
+        let header = |options: MetadataOptions| {
+            let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None)
+                .unwrap()
+                .with_metadata_options(options);
+            creator.build_file_header(&path, Path::new("file"), &metadata).unwrap()
+        };
+        let owner = Some(OwnerOverride {
+            name: "builder".to_string(),
+            id: 1234,
+        });
+        let group = Some(OwnerOverride {
+            name: String::new(),
+            id: 0,
+        });
+
+        let overridden = header(MetadataOptions {
+            owner: owner.clone(),
+            group: group.clone(),
+            ..Default::default()
+        });
+        assert_eq!((overridden.uid, overridden.gid), (1234, 0));
+        assert_eq!((overridden.username.as_str(), overridden.groupname.as_str()), ("builder", ""));
+
+        // --numeric-owner still leaves the names out
+        let numeric = header(MetadataOptions {
+            owner,
+            group,
+            numeric_owner: true,
+            ..Default::default()
+        });
+        assert_eq!((numeric.uid, numeric.username.as_str()), (1234, ""));
+    }
+
+    #[test]
+    fn test_mtime_policy() {
+        assert_eq!(MtimePolicy::Keep.apply(500, 7), (500, 7));
+        assert_eq!(MtimePolicy::Set(100).apply(500, 7), (100, 0));
+        assert_eq!(MtimePolicy::Clamp(100).apply(500, 7), (100, 0));
+        assert_eq!(MtimePolicy::Clamp(100).apply(100, 7), (100, 0));
+        assert_eq!(MtimePolicy::Clamp(100).apply(50, 7), (50, 7));
+    }
+
+    #[test]
+    fn test_symlink_loop_is_a_warning() {
+        let dir = tempdir().unwrap();
+        let source = dir.path().join("tree");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("sub/file"), b"data").unwrap();
+        std::os::unix::fs::symlink("..", source.join("sub/up")).unwrap();
+
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None)
+            .unwrap()
+            .with_dereference(Dereference::Always);
+        creator.add_directory(&source, Path::new("")).unwrap();
+        assert_eq!(creator.warnings().len(), 1);
+        let up = creator.index.iter().find(|e| e.path == "tree/sub/up").unwrap();
+        assert_eq!(up.file_type, FileType::SymbolicLink);
+
+        // Without dereferencing the link is simply stored
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None).unwrap();
+        creator.add_directory(&source, Path::new("")).unwrap();
+        assert!(creator.warnings().is_empty());
+        assert_eq!(creator.index.len(), 4);
+    }
+
+    #[test]
+    fn test_inputs_keep_relative_paths() {
+        let dir = tempdir().unwrap();
+        for sub in ["a/b", "x/b"] {
+            fs::create_dir_all(dir.path().join(sub)).unwrap();
+            fs::write(dir.path().join(sub).join("c.txt"), sub).unwrap();
+        }
+
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None).unwrap();
+        creator.add_input(dir.path(), Path::new("a/b/c.txt")).unwrap();
+        creator.add_input(dir.path(), Path::new("./x/b")).unwrap();
+        let absolute = dir.path().join("a/b");
+        creator.add_input(Path::new("."), &absolute).unwrap();
+
+        let paths: Vec<_> = creator.index.iter().map(|e| e.path.clone()).collect();
+        let absolute = absolute.strip_prefix("/").unwrap().to_string_lossy().into_owned();
+        assert_eq!(
+            paths,
+            [
+                "a/b/c.txt".to_string(),
+                "x/b".to_string(),
+                "x/b/c.txt".to_string(),
+                absolute.clone(),
+                format!("{}/c.txt", absolute),
+            ]
+        );
+    }
 }
@@ -0,0 +1,821 @@
+//! Archive encryption (`create --encrypt`)
+//!
+//! An encrypted archive has a random master key, stored in the archive
+//! header once per key slot, wrapped by a key derived from a passphrase
+//! (Argon2id) or from the contents of a key file. Every record after the
+//! archive header - file headers, extent headers and the footer - is sealed
+//! with XChaCha20-Poly1305 in an envelope bound to its archive offset and to
+//! the archive header. File data is encrypted in place, so extents stay
+//! block-aligned; its nonce and tag are kept in the sealed header that
+//! describes it. With clear metadata the records are authenticated but not
+//! encrypted, so the archive can be listed without a key.
+//!
+//! Deduplication hashes are BLAKE3 keyed by the master key, so equal data
+//! does not hash the same way in different archives.
+
+use crate::format::{
+    read_member_header, ArchiveFooter, ArchiveHeader, DataSeal, FileHeader, ExtentHeader,
+    KeySlot, KeySlotKind, ARCHIVE_FLAG_HEADER_CHECKSUMS, FILE_HEADER_MAGIC, FOOTER_MAGIC, SEALED_RECORD_MAGIC,
+};
+use anyhow::{Context, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{Key, Tag, XChaCha20Poly1305, XNonce};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// Argon2id cost of new passphrase key slots: 64 MiB, 3 passes, 1 lane
+const PASSPHRASE_MEMORY_KIB: u32 = 64 * 1024;
+const PASSPHRASE_ITERATIONS: u32 = 3;
+const PASSPHRASE_PARALLELISM: u32 = 1;
+
+/// Highest Argon2id cost accepted from an archive header, so that a
+/// crafted header can't exhaust memory or time
+const MAX_PASSPHRASE_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+const MAX_PASSPHRASE_ITERATIONS: u32 = 64;
+
+/// Key files shorter than this are refused
+const MIN_KEY_FILE_LEN: usize = 32;
+
+/// BLAKE3 key derivation contexts
+const CONTEXT_RECORD_KEY: &str = "reftar 2026-10-18 archive encryption key";
+const CONTEXT_DEDUP_KEY: &str = "reftar 2026-10-18 deduplication hash key";
+const CONTEXT_KEY_FILE: &str = "reftar 2026-10-18 key file";
+
+/// Sealed record envelope: magic, mode, 3 reserved bytes, body length and
+/// nonce, then the body and the tag
+const SEALED_RECORD_PREFIX: usize = 4 + 1 + 3 + 4 + 24;
+const TAG_SIZE: usize = 16;
+
+/// Sealed record modes
+const RECORD_ENCRYPTED: u8 = 1;
+const RECORD_AUTHENTICATED: u8 = 2;
+
+/// What a sealed item is; the first byte of its associated data
+const PURPOSE_RECORD: u8 = 1;
+const PURPOSE_EXTENT_DATA: u8 = 2;
+const PURPOSE_INLINE_DATA: u8 = 3;
+
+const LOCKED_MESSAGE: &str = "Archive is encrypted; a passphrase or key file is needed";
+
+/// A passphrase or key file, used to create or open key slots
+pub enum KeySource {
+    Passphrase(Zeroizing<String>),
+    KeyFile(Zeroizing<Vec<u8>>),
+}
+
+impl KeySource {
+    pub fn passphrase(passphrase: String) -> Result<Self> {
+        if passphrase.is_empty() {
+            anyhow::bail!("The passphrase is empty");
+        }
+        Ok(KeySource::Passphrase(Zeroizing::new(passphrase)))
+    }
+
+    /// The passphrase on the first line of a file
+    pub fn passphrase_file(path: &Path) -> Result<Self> {
+        let contents = Zeroizing::new(
+            std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read passphrase file {:?}", path))?,
+        );
+        let line = contents.lines().next().unwrap_or("");
+        Self::passphrase(line.to_string()).with_context(|| format!("Passphrase file {:?}", path))
+    }
+
+    pub fn key_file(path: &Path) -> Result<Self> {
+        let contents = Zeroizing::new(
+            std::fs::read(path).with_context(|| format!("Failed to read key file {:?}", path))?,
+        );
+        if contents.len() < MIN_KEY_FILE_LEN {
+            anyhow::bail!(
+                "Key file {:?} is too short; use at least {} random bytes",
+                path,
+                MIN_KEY_FILE_LEN
+            );
+        }
+        Ok(KeySource::KeyFile(contents))
+    }
+
+    /// Kind of a new key slot for this source
+    fn new_slot_kind(&self) -> Result<KeySlotKind> {
+        Ok(match self {
+            KeySource::Passphrase(_) => KeySlotKind::Passphrase {
+                salt: random_bytes()?,
+                memory_kib: PASSPHRASE_MEMORY_KIB,
+                iterations: PASSPHRASE_ITERATIONS,
+                parallelism: PASSPHRASE_PARALLELISM,
+            },
+            KeySource::KeyFile(_) => KeySlotKind::KeyFile,
+        })
+    }
+
+    /// Derive the key wrapping the master key in a slot of this kind, or
+    /// None if the slot is for another kind of source
+    fn slot_key(&self, kind: &KeySlotKind) -> Result<Option<Zeroizing<[u8; 32]>>> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        match (self, kind) {
+            (
+                KeySource::Passphrase(passphrase),
+                KeySlotKind::Passphrase {
+                    salt,
+                    memory_kib,
+                    iterations,
+                    parallelism,
+                },
+            ) => {
+                if *memory_kib > MAX_PASSPHRASE_MEMORY_KIB || *iterations > MAX_PASSPHRASE_ITERATIONS {
+                    anyhow::bail!("Key slot asks for an excessive Argon2id cost");
+                }
+                let params = Params::new(*memory_kib, *iterations, *parallelism, Some(32))
+                    .map_err(|e| anyhow::anyhow!("Invalid Argon2id parameters: {}", e))?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(passphrase.as_bytes(), salt, &mut key[..])
+                    .map_err(|e| anyhow::anyhow!("Argon2id failed: {}", e))?;
+            }
+            (KeySource::KeyFile(contents), KeySlotKind::KeyFile) => {
+                *key = blake3::derive_key(CONTEXT_KEY_FILE, contents);
+            }
+            _ => return Ok(None),
+        }
+        Ok(Some(key))
+    }
+}
+
+/// The master key of an encrypted archive, with the keys derived from it
+#[derive(Clone)]
+pub struct ArchiveKey {
+    master: Zeroizing<[u8; 32]>,
+    cipher: XChaCha20Poly1305,
+    dedup_key: Zeroizing<[u8; 32]>,
+}
+
+impl ArchiveKey {
+    /// A random key for a new archive
+    pub fn generate() -> Result<Self> {
+        Ok(Self::from_master(Zeroizing::new(random_bytes()?)))
+    }
+
+    fn from_master(master: Zeroizing<[u8; 32]>) -> Self {
+        let record_key = Zeroizing::new(blake3::derive_key(CONTEXT_RECORD_KEY, &master[..]));
+        Self {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&record_key[..])),
+            dedup_key: Zeroizing::new(blake3::derive_key(CONTEXT_DEDUP_KEY, &master[..])),
+            master,
+        }
+    }
+
+    /// Wrap the key in one key slot per source
+    pub fn key_slots(&self, sources: &[KeySource]) -> Result<Vec<KeySlot>> {
+        if sources.is_empty() {
+            anyhow::bail!("An encrypted archive needs a passphrase or key file");
+        }
+        sources
+            .iter()
+            .map(|source| {
+                let mut slot = KeySlot {
+                    kind: source.new_slot_kind()?,
+                    nonce: random_bytes()?,
+                    wrapped_key: [0; 48],
+                };
+                let slot_key = source
+                    .slot_key(&slot.kind)?
+                    .expect("a new slot matches its source");
+                let mut wrapped = self.master.clone();
+                let tag = XChaCha20Poly1305::new(Key::from_slice(&slot_key[..]))
+                    .encrypt_in_place_detached(XNonce::from_slice(&slot.nonce), &slot.parameters(), &mut wrapped[..])
+                    .map_err(|_| anyhow::anyhow!("Failed to wrap the archive key"))?;
+                slot.wrapped_key[..32].copy_from_slice(&wrapped[..]);
+                slot.wrapped_key[32..].copy_from_slice(&tag);
+                Ok(slot)
+            })
+            .collect()
+    }
+
+    /// Unwrap the key from the first slot that one of the sources opens
+    pub fn unlock(slots: &[KeySlot], sources: &[KeySource]) -> Result<Self> {
+        for source in sources {
+            for slot in slots {
+                let Some(slot_key) = source.slot_key(&slot.kind)? else {
+                    continue;
+                };
+                let mut master = Zeroizing::new([0u8; 32]);
+                master.copy_from_slice(&slot.wrapped_key[..32]);
+                let opened = XChaCha20Poly1305::new(Key::from_slice(&slot_key[..])).decrypt_in_place_detached(
+                    XNonce::from_slice(&slot.nonce),
+                    &slot.parameters(),
+                    &mut master[..],
+                    Tag::from_slice(&slot.wrapped_key[32..]),
+                );
+                if opened.is_ok() {
+                    return Ok(Self::from_master(master));
+                }
+            }
+        }
+        anyhow::bail!("No key slot of the archive opens with the given passphrase or key file")
+    }
+
+    /// Keyed hash of file data, for deduplication
+    pub fn dedup_hash(&self, data: &[u8]) -> [u8; 32] {
+        *blake3::keyed_hash(&self.dedup_key, data).as_bytes()
+    }
+}
+
+/// Encryption state of an encrypted archive
+#[derive(Clone)]
+struct Sealing {
+    clear_metadata: bool,
+    key_slots: Vec<KeySlot>,
+    /// BLAKE3 hash of the archive header block, bound into every seal
+    header_digest: [u8; 32],
+    block_size: u32,
+    /// None until unlocked
+    key: Option<ArchiveKey>,
+}
+
+/// Reads and writes the records and data of an archive, sealing them when
+/// the archive is encrypted; plain reads and writes otherwise
+#[derive(Clone, Default)]
+pub struct ArchiveCipher {
+    sealing: Option<Sealing>,
+    /// Headers must carry checksum records (`ARCHIVE_FLAG_HEADER_CHECKSUMS`)
+    header_checksums: bool,
+}
+
+impl ArchiveCipher {
+    /// Cipher of an archive with this header. Encrypted archives start
+    /// locked; see `unlock` and `with_key`.
+    pub fn new(header: &ArchiveHeader) -> Result<Self> {
+        let sealing = match &header.encryption {
+            Some(encryption) => {
+                let mut block = Vec::new();
+                header.write(&mut block)?;
+                Some(Sealing {
+                    clear_metadata: encryption.clear_metadata,
+                    key_slots: encryption.key_slots.clone(),
+                    header_digest: *blake3::hash(&block).as_bytes(),
+                    block_size: header.block_size,
+                    key: None,
+                })
+            }
+            None => None,
+        };
+        Ok(Self {
+            sealing,
+            header_checksums: header.flags & ARCHIVE_FLAG_HEADER_CHECKSUMS != 0,
+        })
+    }
+
+    /// Use the key of a new archive
+    pub fn with_key(mut self, key: ArchiveKey) -> Self {
+        if let Some(sealing) = &mut self.sealing {
+            sealing.key = Some(key);
+        }
+        self
+    }
+
+    /// Unlock an encrypted archive; does nothing for other archives
+    pub fn unlock(&mut self, sources: &[KeySource]) -> Result<()> {
+        if let Some(sealing) = &mut self.sealing {
+            if sealing.key.is_none() {
+                sealing.key = Some(ArchiveKey::unlock(&sealing.key_slots, sources)?);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the archive is encrypted and no key was given yet
+    pub fn is_locked(&self) -> bool {
+        self.sealing.as_ref().is_some_and(|sealing| sealing.key.is_none())
+    }
+
+    /// Whether member headers and the footer can be read as things stand
+    pub fn can_read_metadata(&self) -> bool {
+        self.sealing
+            .as_ref()
+            .is_none_or(|sealing| sealing.clear_metadata || sealing.key.is_some())
+    }
+
+    /// Fail unless file data can be read
+    pub fn require_key(&self) -> Result<()> {
+        if self.is_locked() {
+            anyhow::bail!(LOCKED_MESSAGE);
+        }
+        Ok(())
+    }
+
+    /// Write a file header at archive offset `offset`
+    pub fn write_file_header<W: Write>(
+        &self,
+        writer: &mut W,
+        header: &FileHeader,
+        offset: u64,
+        block_size: u32,
+    ) -> Result<()> {
+        let sealing = match &self.sealing {
+            Some(sealing) => sealing,
+            None => return header.write(writer, block_size),
+        };
+        let mut header = header.clone();
+        if !header.inline_data.is_empty() {
+            let seal = self.seal_data(sealing, &mut header.inline_data, PURPOSE_INLINE_DATA, offset)?;
+            header.data_seal = Some(seal);
+        }
+        let mut body = Vec::new();
+        header.write(&mut body, block_size)?;
+        self.write_record(sealing, writer, body, offset, block_size)
+    }
+
+    /// Write an extent header at archive offset `offset`
+    pub fn write_extent_header<W: Write>(
+        &self,
+        writer: &mut W,
+        header: &ExtentHeader,
+        offset: u64,
+        block_size: u32,
+    ) -> Result<()> {
+        let sealing = match &self.sealing {
+            Some(sealing) => sealing,
+            None => return header.write(writer, block_size),
+        };
+        let mut body = Vec::new();
+        header.write(&mut body, block_size)?;
+        self.write_record(sealing, writer, body, offset, block_size)
+    }
+
+    /// Write the footer and its trailer at archive offset `offset`
+    pub fn write_footer<W: Write>(
+        &self,
+        writer: &mut W,
+        footer: &ArchiveFooter,
+        offset: u64,
+        block_size: u32,
+    ) -> Result<()> {
+        match &self.sealing {
+            Some(sealing) => {
+                let record = self.seal_record(sealing, footer.encode()?, offset)?;
+                ArchiveFooter::write_with_trailer(writer, &record, block_size, offset)
+            }
+            None => footer.write(writer, block_size, offset),
+        }
+    }
+
+    /// Encrypt the stored data of an extent in place. Returns None for
+    /// archives that aren't encrypted.
+    pub fn seal_extent_data(&self, data: &mut [u8], extent_id: u64) -> Result<Option<DataSeal>> {
+        match &self.sealing {
+            Some(sealing) => self
+                .seal_data(sealing, data, PURPOSE_EXTENT_DATA, extent_id)
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Decrypt the stored data of an extent in place, checking it wasn't
+    /// altered
+    pub fn open_extent_data(&self, seal: Option<&DataSeal>, data: &mut [u8], extent_id: u64) -> Result<()> {
+        let sealing = match &self.sealing {
+            Some(sealing) => sealing,
+            None => return Ok(()),
+        };
+        let seal = seal.ok_or_else(|| anyhow::anyhow!("Extent {} of an encrypted archive is not sealed", extent_id))?;
+        self.open_data(sealing, seal, data, PURPOSE_EXTENT_DATA, extent_id)
+            .with_context(|| format!("Extent {}", extent_id))
+    }
+
+    /// Whether a block starts like a member header or the footer, when
+    /// scanning a damaged archive for the next member
+    pub fn may_start_member(&self, block: &[u8]) -> bool {
+        match self.sealing {
+            Some(_) => block.starts_with(SEALED_RECORD_MAGIC),
+            None => block.starts_with(FILE_HEADER_MAGIC) || block.starts_with(FOOTER_MAGIC),
+        }
+    }
+
+    /// Read the member header at archive offset `offset`
+    /// Returns None at the archive footer or at a clean end of input
+    pub fn read_member_header<R: Read>(
+        &self,
+        reader: &mut R,
+        offset: u64,
+        block_size: u32,
+    ) -> Result<Option<FileHeader>> {
+        let sealing = match &self.sealing {
+            Some(sealing) => sealing,
+            None => return read_member_header(reader, offset, block_size, self.header_checksums),
+        };
+        let (body, record_len) = match self.read_record(sealing, reader, offset)? {
+            Some(record) => record,
+            None => return Ok(None),
+        };
+        if body.starts_with(FOOTER_MAGIC) {
+            ArchiveFooter::read(&mut body.as_slice()).context("Archive footer is damaged")?;
+            skip_padding(reader, record_len, footer_padded_len(record_len, block_size), offset)?;
+            ArchiveFooter::read_trailer(reader, offset)?;
+            return Ok(None);
+        }
+        skip_padding(reader, record_len, record_len.next_multiple_of(block_size as usize), offset)?;
+        self.open_file_header(sealing, &body, offset, block_size).map(Some)
+    }
+
+    /// Read the file header at archive offset `offset`
+    pub fn read_file_header<R: Read>(&self, reader: &mut R, offset: u64, block_size: u32) -> Result<FileHeader> {
+        let sealing = match &self.sealing {
+            Some(sealing) => sealing,
+            None => return FileHeader::read(reader, block_size, self.header_checksums),
+        };
+        let (body, record_len) = self
+            .read_record(sealing, reader, offset)?
+            .ok_or_else(|| anyhow::anyhow!("Truncated file header"))?;
+        skip_padding(reader, record_len, record_len.next_multiple_of(block_size as usize), offset)?;
+        self.open_file_header(sealing, &body, offset, block_size)
+    }
+
+    /// Read the extent header at archive offset `offset`
+    pub fn read_extent_header<R: Read>(&self, reader: &mut R, offset: u64, block_size: u32) -> Result<ExtentHeader> {
+        let sealing = match &self.sealing {
+            Some(sealing) => sealing,
+            None => return ExtentHeader::read(reader, block_size, self.header_checksums),
+        };
+        let (body, record_len) = self
+            .read_record(sealing, reader, offset)?
+            .ok_or_else(|| anyhow::anyhow!("Truncated extent header"))?;
+        skip_padding(reader, record_len, record_len.next_multiple_of(block_size as usize), offset)?;
+        ExtentHeader::read(&mut body.as_slice().chain(io::repeat(0)), block_size, self.header_checksums)
+    }
+
+    /// Find and read the footer of a seekable archive, see
+    /// `ArchiveFooter::locate`
+    pub fn locate_footer<R: Read + Seek>(&self, reader: &mut R) -> Result<Option<ArchiveFooter>> {
+        let offset = match ArchiveFooter::locate_offset(reader)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        reader.seek(SeekFrom::Start(offset))?;
+        self.read_footer(reader, offset).map(Some)
+    }
+
+    /// Read the footer at archive offset `offset`
+    pub fn read_footer<R: Read>(&self, reader: &mut R, offset: u64) -> Result<ArchiveFooter> {
+        let sealing = match &self.sealing {
+            Some(sealing) => sealing,
+            None => return ArchiveFooter::read(reader),
+        };
+        let (body, record_len) = self
+            .read_record(sealing, reader, offset)?
+            .ok_or_else(|| anyhow::anyhow!("Archive trailer points at an invalid footer"))?;
+        skip_padding(reader, record_len, footer_padded_len(record_len, sealing.block_size), offset)?;
+        ArchiveFooter::read(&mut body.as_slice())
+    }
+
+    /// Parse a file header from a record body and decrypt its inline data
+    fn open_file_header(&self, sealing: &Sealing, body: &[u8], offset: u64, block_size: u32) -> Result<FileHeader> {
+        // Record bodies leave out the header's trailing zeros
+        let mut header = FileHeader::read(&mut body.chain(io::repeat(0)), block_size, self.header_checksums)?;
+        if !header.inline_data.is_empty() && sealing.key.is_some() {
+            let seal = header
+                .data_seal
+                .ok_or_else(|| anyhow::anyhow!("Inline data of {} is not sealed", header.member_path()))?;
+            self.open_data(sealing, &seal, &mut header.inline_data, PURPOSE_INLINE_DATA, offset)
+                .with_context(|| format!("Inline data of {}", header.member_path()))?;
+        }
+        Ok(header)
+    }
+
+    /// Associated data binding a sealed item to its archive and position
+    fn associated_data(sealing: &Sealing, purpose: u8, position: u64) -> Vec<u8> {
+        let mut aad = Vec::with_capacity(1 + 8 + 32);
+        aad.push(purpose);
+        aad.extend_from_slice(&position.to_le_bytes());
+        aad.extend_from_slice(&sealing.header_digest);
+        aad
+    }
+
+    fn key<'a>(&self, sealing: &'a Sealing) -> Result<&'a ArchiveKey> {
+        sealing.key.as_ref().ok_or_else(|| anyhow::anyhow!(LOCKED_MESSAGE))
+    }
+
+    fn seal_data(&self, sealing: &Sealing, data: &mut [u8], purpose: u8, position: u64) -> Result<DataSeal> {
+        let nonce: [u8; 24] = random_bytes()?;
+        let tag = self
+            .key(sealing)?
+            .cipher
+            .encrypt_in_place_detached(
+                XNonce::from_slice(&nonce),
+                &Self::associated_data(sealing, purpose, position),
+                data,
+            )
+            .map_err(|_| anyhow::anyhow!("Failed to encrypt file data"))?;
+        Ok(DataSeal { nonce, tag: tag.into() })
+    }
+
+    fn open_data(&self, sealing: &Sealing, seal: &DataSeal, data: &mut [u8], purpose: u8, position: u64) -> Result<()> {
+        self.key(sealing)?
+            .cipher
+            .decrypt_in_place_detached(
+                XNonce::from_slice(&seal.nonce),
+                &Self::associated_data(sealing, purpose, position),
+                data,
+                Tag::from_slice(&seal.tag),
+            )
+            .map_err(|_| anyhow::anyhow!("Data failed authentication (wrong key or altered archive)"))
+    }
+
+    /// Build the sealed envelope of a record body, not padded
+    fn seal_record(&self, sealing: &Sealing, mut body: Vec<u8>, offset: u64) -> Result<Vec<u8>> {
+        let cipher = &self.key(sealing)?.cipher;
+        let nonce: [u8; 24] = random_bytes()?;
+        let mut aad = Self::associated_data(sealing, PURPOSE_RECORD, offset);
+        let (mode, tag) = if sealing.clear_metadata {
+            aad.extend_from_slice(&body);
+            (RECORD_AUTHENTICATED, cipher.encrypt_in_place_detached(XNonce::from_slice(&nonce), &aad, &mut []))
+        } else {
+            (RECORD_ENCRYPTED, cipher.encrypt_in_place_detached(XNonce::from_slice(&nonce), &aad, &mut body))
+        };
+        let tag = tag.map_err(|_| anyhow::anyhow!("Failed to seal archive record"))?;
+
+        let mut record = Vec::with_capacity(SEALED_RECORD_PREFIX + body.len() + TAG_SIZE);
+        record.extend_from_slice(SEALED_RECORD_MAGIC);
+        record.push(mode);
+        record.extend_from_slice(&[0; 3]);
+        record.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        record.extend_from_slice(&nonce);
+        record.extend_from_slice(&body);
+        record.extend_from_slice(&tag);
+        Ok(record)
+    }
+
+    /// Seal a serialized header and write it padded to a block
+    fn write_record<W: Write>(
+        &self,
+        sealing: &Sealing,
+        writer: &mut W,
+        mut body: Vec<u8>,
+        offset: u64,
+        block_size: u32,
+    ) -> Result<()> {
+        // Trailing zeros (padding and zero-filled fields) read back as zeros
+        let len = body.iter().rposition(|&b| b != 0).map_or(0, |last| last + 1);
+        body.truncate(len);
+        let mut record = self.seal_record(sealing, body, offset)?;
+        record.resize(record.len().next_multiple_of(block_size as usize), 0);
+        writer.write_all(&record)?;
+        Ok(())
+    }
+
+    /// Read and open the sealed record at archive offset `offset`, leaving
+    /// the reader just after its tag. Returns the body and the record
+    /// length, or None at a clean end of input.
+    fn read_record<R: Read>(&self, sealing: &Sealing, reader: &mut R, offset: u64) -> Result<Option<(Vec<u8>, usize)>> {
+        let mut prefix = [0u8; SEALED_RECORD_PREFIX];
+        let mut filled = 0;
+        while filled < prefix.len() {
+            match reader.read(&mut prefix[filled..])? {
+                0 if filled == 0 => return Ok(None),
+                0 => return Err(truncated_record(offset)),
+                n => filled += n,
+            }
+        }
+        if &prefix[..4] != SEALED_RECORD_MAGIC {
+            anyhow::bail!("Expected a sealed record at offset {}", offset);
+        }
+        let mode = prefix[4];
+        let expected = if sealing.clear_metadata { RECORD_AUTHENTICATED } else { RECORD_ENCRYPTED };
+        if mode != expected {
+            anyhow::bail!("Sealed record at offset {} has the wrong protection mode", offset);
+        }
+        let body_len = u32::from_le_bytes(prefix[8..12].try_into().unwrap()) as usize;
+        let nonce = XNonce::clone_from_slice(&prefix[12..]);
+
+        let mut body = Vec::new();
+        reader.take(body_len as u64).read_to_end(&mut body)?;
+        let mut tag = [0u8; TAG_SIZE];
+        if body.len() < body_len || reader.read_exact(&mut tag).is_err() {
+            return Err(truncated_record(offset));
+        }
+
+        let key = match &sealing.key {
+            Some(key) => key,
+            // Clear metadata can be read without the key, unverified
+            None if mode == RECORD_AUTHENTICATED => return Ok(Some((body, SEALED_RECORD_PREFIX + body_len + TAG_SIZE))),
+            None => anyhow::bail!(LOCKED_MESSAGE),
+        };
+        let mut aad = Self::associated_data(sealing, PURPOSE_RECORD, offset);
+        let opened = if mode == RECORD_AUTHENTICATED {
+            aad.extend_from_slice(&body);
+            key.cipher.decrypt_in_place_detached(&nonce, &aad, &mut [], Tag::from_slice(&tag))
+        } else {
+            key.cipher.decrypt_in_place_detached(&nonce, &aad, &mut body, Tag::from_slice(&tag))
+        };
+        if opened.is_err() {
+            anyhow::bail!(
+                "Record at offset {} failed authentication (wrong key or altered archive)",
+                offset
+            );
+        }
+        Ok(Some((body, SEALED_RECORD_PREFIX + body_len + TAG_SIZE)))
+    }
+}
+
+/// Length of a sealed record before its block padding, or None if `record`
+/// is not one
+pub fn sealed_record_length(record: &[u8]) -> Option<usize> {
+    if record.get(..4)? != SEALED_RECORD_MAGIC {
+        return None;
+    }
+    let body_len = u32::from_le_bytes(record.get(8..12)?.try_into().unwrap()) as usize;
+    Some(SEALED_RECORD_PREFIX + body_len + TAG_SIZE)
+}
+
+/// Read the padding after the sealed record of `record_len` bytes at
+/// archive offset `offset`, up to `padded_len` bytes from its start. The
+/// AEAD doesn't cover it, so it must be zero: anything else means the
+/// archive was altered.
+fn skip_padding<R: Read>(reader: &mut R, record_len: usize, padded_len: usize, offset: u64) -> Result<()> {
+    let mut padding = vec![0u8; padded_len - record_len];
+    reader.read_exact(&mut padding)?;
+    if let Some(position) = padding.iter().position(|&byte| byte != 0) {
+        anyhow::bail!(
+            "Nonzero padding at offset {} after the sealed header at {}",
+            offset + (record_len + position) as u64,
+            offset
+        );
+    }
+    Ok(())
+}
+
+/// Error for a sealed record the input ends inside, as an `UnexpectedEof`
+/// so that readers can tell truncation from damage
+fn truncated_record(offset: u64) -> anyhow::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, format!("Truncated sealed record at offset {}", offset)).into()
+}
+
+/// Length of a footer record of `record_len` bytes with its padding: the
+/// trailer after it ends on a block boundary
+fn footer_padded_len(record_len: usize, block_size: u32) -> usize {
+    let trailer_len = ArchiveFooter::TRAILER_SIZE;
+    (record_len + trailer_len).next_multiple_of(block_size as usize) - trailer_len
+}
+
+fn random_bytes<const N: usize>() -> Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    getrandom::getrandom(&mut bytes).map_err(|e| anyhow::anyhow!("No system randomness: {}", e))?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::ArchiveCreator;
+    use crate::extract::ArchiveExtractor;
+    use std::fs::File;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    fn key_file(byte: u8) -> KeySource {
+        KeySource::KeyFile(Zeroizing::new(vec![byte; MIN_KEY_FILE_LEN]))
+    }
+
+    /// Archive two files, one with repeated blocks, returning the archive
+    fn encrypted_archive(temp_dir: &TempDir, clear_metadata: bool) -> Vec<u8> {
+        let source = temp_dir.path().join("source");
+        std::fs::create_dir(&source).unwrap();
+        std::fs::write(source.join("small.txt"), b"secret inline text").unwrap();
+        std::fs::write(source.join("large.bin"), b"secret block data".repeat(2000)).unwrap();
+
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None)
+            .unwrap()
+            .with_encryption(&[key_file(1)], clear_metadata)
+            .unwrap();
+        creator.add_input(temp_dir.path(), Path::new("source")).unwrap();
+        creator.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_key_slots() {
+        let key = ArchiveKey::generate().unwrap();
+        let passphrase = KeySource::passphrase("correct horse".to_string()).unwrap();
+        let slots = key.key_slots(&[passphrase, key_file(1)]).unwrap();
+        assert!(matches!(slots[0].kind, KeySlotKind::Passphrase { .. }));
+
+        let data = b"some file data";
+        let unlocked = ArchiveKey::unlock(&slots, &[key_file(1)]).unwrap();
+        assert_eq!(unlocked.dedup_hash(data), key.dedup_hash(data));
+        let passphrase = KeySource::passphrase("correct horse".to_string()).unwrap();
+        assert!(ArchiveKey::unlock(&slots, &[passphrase]).is_ok());
+
+        assert!(ArchiveKey::unlock(&slots, &[key_file(2)]).is_err());
+        let wrong = KeySource::passphrase("wrong horse".to_string()).unwrap();
+        assert!(ArchiveKey::unlock(&slots, &[wrong]).is_err());
+
+        // Dedup hashes don't match across archives
+        let other = ArchiveKey::generate().unwrap();
+        assert_ne!(other.dedup_hash(data), key.dedup_hash(data));
+    }
+
+    #[test]
+    fn test_encrypted_archive_roundtrip() {
+        let temp_dir = TempDir::new().unwrap();
+        let archive = encrypted_archive(&temp_dir, false);
+        assert!(!archive.windows(6).any(|w| w == b"secret" || w == b"large."));
+
+        let output = temp_dir.path().join("output");
+        let mut extractor = ArchiveExtractor::new(Cursor::new(archive.clone()), output.clone()).unwrap();
+        assert!(extractor.list_files().is_err());
+        let extractor = ArchiveExtractor::new(Cursor::new(archive.clone()), output.clone())
+            .unwrap()
+            .with_keys(&[key_file(2)]);
+        assert!(extractor.is_err());
+
+        let archive_path = temp_dir.path().join("archive.rtar");
+        std::fs::write(&archive_path, &archive).unwrap();
+        let mut extractor = ArchiveExtractor::new(File::open(&archive_path).unwrap(), output.clone())
+            .unwrap()
+            .with_keys(&[key_file(1)])
+            .unwrap();
+        extractor.extract_all_parallel(2).unwrap();
+        assert_eq!(std::fs::read(output.join("source/small.txt")).unwrap(), b"secret inline text");
+        assert_eq!(
+            std::fs::read(output.join("source/large.bin")).unwrap(),
+            b"secret block data".repeat(2000)
+        );
+
+        // Altering a stored byte is detected
+        let header = ArchiveHeader::read(&mut archive.as_slice()).unwrap();
+        let mut cipher = ArchiveCipher::new(&header).unwrap();
+        cipher.unlock(&[key_file(1)]).unwrap();
+        let footer = cipher.locate_footer(&mut Cursor::new(&archive)).unwrap().unwrap();
+        let mut altered = archive;
+        altered[footer.extents[0].data_offset as usize] ^= 1;
+        let mut extractor = ArchiveExtractor::new(Cursor::new(altered), temp_dir.path().join("altered"))
+            .unwrap()
+            .with_keys(&[key_file(1)])
+            .unwrap();
+        assert!(extractor.extract_all().is_err());
+    }
+
+    #[test]
+    fn test_tampered_padding_fails_extraction() {
+        let temp_dir = TempDir::new().unwrap();
+        let archive = encrypted_archive(&temp_dir, false);
+
+        // Header padding isn't covered by the seals, so it is checked on its
+        // own; altering any block, padding or not, must stop extraction
+        for block in 0..archive.len() / 4096 {
+            let mut tampered = archive.clone();
+            tampered[block * 4096 + 4000] ^= 0x55;
+            let output = temp_dir.path().join(format!("output{}", block));
+            let extracted = ArchiveExtractor::new(Cursor::new(tampered), output)
+                .and_then(|extractor| extractor.with_keys(&[key_file(1)]))
+                .and_then(|mut extractor| extractor.extract_all());
+            assert!(extracted.is_err(), "tampering with block {} went unnoticed", block);
+        }
+    }
+
+    #[test]
+    fn test_truncated_encrypted_archive_fails() {
+        let temp_dir = TempDir::new().unwrap();
+        let archive = encrypted_archive(&temp_dir, true);
+        let trailer = &archive[archive.len() - 16..archive.len() - 8];
+        let footer_offset = u64::from_le_bytes(trailer.try_into().unwrap()) as usize;
+
+        // Cut where a record would start, in the footer record and in the
+        // trailer
+        for cut in [footer_offset, footer_offset + 20, archive.len() - 8] {
+            let truncated = archive[..cut].to_vec();
+            let mut extractor = ArchiveExtractor::new(Cursor::new(truncated.clone()), PathBuf::new()).unwrap();
+            assert!(extractor.list_files().is_err(), "cut at {} went unnoticed", cut);
+            let mut extractor = ArchiveExtractor::new(Cursor::new(truncated), PathBuf::new())
+                .unwrap()
+                .with_keys(&[key_file(1)])
+                .unwrap();
+            assert!(!extractor.verify().unwrap().is_ok(), "cut at {} went unnoticed", cut);
+            let extracted = ArchiveExtractor::new(Cursor::new(archive[..cut].to_vec()), temp_dir.path().join("out"))
+                .and_then(|extractor| extractor.with_keys(&[key_file(1)]))
+                .and_then(|mut extractor| extractor.extract_all());
+            assert!(extracted.is_err(), "cut at {} went unnoticed", cut);
+        }
+    }
+
+    #[test]
+    fn test_clear_metadata_lists_without_key() {
+        let temp_dir = TempDir::new().unwrap();
+        let archive = encrypted_archive(&temp_dir, true);
+        assert!(!archive.windows(6).any(|w| w == b"secret"));
+
+        let output = temp_dir.path().join("output");
+        let mut extractor = ArchiveExtractor::new(Cursor::new(archive.clone()), output.clone()).unwrap();
+        let files = extractor.list_files().unwrap();
+        assert!(files.iter().any(|f| f.ends_with("large.bin")));
+        let mut extractor = ArchiveExtractor::new(Cursor::new(archive.clone()), output.clone()).unwrap();
+        assert!(extractor.extract_all().is_err());
+
+        let mut extractor = ArchiveExtractor::new(Cursor::new(archive), output.clone())
+            .unwrap()
+            .with_keys(&[key_file(1)])
+            .unwrap();
+        extractor.extract_all().unwrap();
+        assert_eq!(std::fs::read(output.join("source/small.txt")).unwrap(), b"secret inline text");
+    }
+}
@@ -1,12 +1,23 @@
 //! Archive extraction functionality
 
+use crate::base::{BaseArchive, BaseArchives};
+use crate::compression::decompress;
+use crate::encryption::{sealed_record_length, ArchiveCipher, KeySource};
 use crate::format::*;
-use anyhow::Result;
+use crate::progress::{NoProgress, ProgressObserver};
+use crate::reflink::FilesystemCapabilities;
+use crate::signature::{self, ManifestReader};
+use crate::transform::{is_unsafe_member_path, normalize_member_path, PathRewrite};
+use anyhow::{Context, Result};
+use ed25519_dalek::VerifyingKey;
+use std::cell::OnceCell;
 use std::collections::HashMap;
+use std::fmt;
 use std::fs::{self, File, OpenOptions};
-use std::io::{BufReader, Read, Seek, SeekFrom, Write};
-use std::os::unix::fs::PermissionsExt;
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, PermissionsExt};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 /// Extent data cache for resolving references
 #[derive(Debug, Clone)]
@@ -16,184 +27,1215 @@ struct CachedExtent {
     file_location: Option<(PathBuf, u64)>,
 }
 
+/// How to handle members whose output path already exists
+///
+/// Type changes follow the same policy: an existing file or symlink in the
+/// way of a directory member is removed, except under `KeepOldFiles` and
+/// `SkipOldFiles`. An existing directory in the way of a non-directory member
+/// is removed only if it is empty, or recursively under `RecursiveUnlink`.
+/// Existing directories are always merged with directory members.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverwritePolicy {
+    /// Replace existing files once the new copy is complete
+    #[default]
+    Overwrite,
+    /// Refuse to replace existing files and report them as errors
+    KeepOldFiles,
+    /// Leave existing files alone and skip the member
+    SkipOldFiles,
+    /// Skip members whose on-disk file is newer than the archived copy
+    KeepNewerFiles,
+    /// Remove existing files before extracting
+    UnlinkFirst,
+    /// Remove existing files and directory hierarchies before extracting
+    RecursiveUnlink,
+}
+
+/// What happened to a member whose output path already existed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictAction {
+    /// The existing entry was kept and the member was not extracted
+    Skipped,
+    /// The existing entry was removed or overwritten
+    Replaced,
+    /// The existing entry was kept and the conflict counts as an error
+    Refused,
+}
+
+/// A member that collided with an existing path during extraction
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub path: PathBuf,
+    pub action: ConflictAction,
+    pub reason: String,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.action {
+            ConflictAction::Skipped => write!(f, "Skipping {}: {}", self.path.display(), self.reason),
+            ConflictAction::Replaced => write!(f, "Replacing {}: {}", self.path.display(), self.reason),
+            ConflictAction::Refused => write!(f, "Not replacing {}: {}", self.path.display(), self.reason),
+        }
+    }
+}
+
+/// A problem found by `ArchiveExtractor::verify`
+#[derive(Debug, Clone)]
+pub struct VerifyIssue {
+    /// Archive offset of the header or data at fault
+    pub offset: u64,
+    pub message: String,
+}
+
+/// Outcome of `ArchiveExtractor::verify`
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// Members whose headers were read
+    pub members: usize,
+    /// Data and Compressed extents whose data was checked
+    pub extents: usize,
+    pub issues: Vec<VerifyIssue>,
+}
+
+impl VerifyReport {
+    /// Whether no problems were found
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn push(&mut self, offset: u64, message: String) {
+        self.issues.push(VerifyIssue { offset, message });
+    }
+}
+
+/// Archive bytes skipped by salvage to find the next member
+#[derive(Debug, Clone)]
+pub struct SkippedRange {
+    pub start: u64,
+    pub end: u64,
+    /// The problem that made the member at `start` unreadable
+    pub reason: String,
+}
+
+/// A member that salvage could not extract
+#[derive(Debug, Clone)]
+pub struct LostMember {
+    /// Archive offset of its file header
+    pub offset: u64,
+    /// Its path, if its header or the footer index could be read
+    pub path: Option<String>,
+}
+
+/// Bytes of an extracted file that could not be restored and were left
+/// as zeros
+#[derive(Debug, Clone)]
+pub struct UnrecoverableRange {
+    pub path: PathBuf,
+    /// File offsets of the bytes
+    pub start: u64,
+    pub end: u64,
+    pub reason: String,
+}
+
+/// What extraction with `with_salvage` had to give up on
+#[derive(Debug, Clone, Default)]
+pub struct SalvageReport {
+    pub skipped: Vec<SkippedRange>,
+    pub lost: Vec<LostMember>,
+    pub unrecoverable: Vec<UnrecoverableRange>,
+}
+
+impl SalvageReport {
+    /// Whether everything was extracted
+    pub fn is_complete(&self) -> bool {
+        self.skipped.is_empty() && self.lost.is_empty() && self.unrecoverable.is_empty()
+    }
+}
+
+/// Where a piece of a file's contents comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkSource {
+    /// Inline data in the file header
+    Inline,
+    /// A Data or Compressed extent stored with this file
+    Data { extent_id: u64 },
+    /// A Reference to an extent stored earlier, with another file or this one
+    Reference { extent_id: u64 },
+    /// A hole
+    Sparse,
+    /// Extents of a base archive
+    Base,
+}
+
+/// A piece of a regular file's contents, passed by
+/// `ArchiveExtractor::read_member_contents` in file order
+#[derive(Debug, Clone, Copy)]
+pub struct FileChunk<'a> {
+    /// Offset of the piece in the file
+    pub offset: u64,
+    pub length: u64,
+    pub source: ChunkSource,
+    /// The bytes, or None for a hole of `length` zeros
+    pub data: Option<&'a [u8]>,
+}
+
+/// A member's header and the (offset, length) pieces of its contents that
+/// hold data, as returned by `ArchiveExtractor::peek_member_layout`
+pub type MemberLayout = (FileHeader, Vec<(u64, u64)>);
+
+/// Whether an I/O error of `kind` caused `error`
+fn has_io_error_kind(error: &anyhow::Error, kind: io::ErrorKind) -> bool {
+    error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(|cause| cause.kind() == kind)
+}
+
+/// A stored extent seen by `verify`, for checking references to it
+struct VerifiedExtent {
+    /// Checksum references to the extent carry, if known
+    checksum: Option<u32>,
+    covered: u64,
+}
+
+/// Progress of the signature check requested with `with_signature_key`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SignatureCheck {
+    Pending,
+    Passed,
+    Failed,
+}
+
+/// What has been extracted from a stream whose signature is still to be
+/// checked at its footer: it stays under temporary names until then
+#[derive(Default)]
+struct Staging {
+    /// Temporary and final paths of the members, with their headers
+    members: Vec<(PathBuf, PathBuf, FileHeader)>,
+    /// Directories created for them, parents first
+    directories: Vec<PathBuf>,
+    /// Output paths of the members extracted, to report once in place
+    extracted: Vec<PathBuf>,
+}
+
+/// A regular file member scheduled for parallel extraction
+struct ParallelMember {
+    header: FileHeader,
+    /// Archive offset of the member's first extent header
+    extents_offset: u64,
+    output_path: PathBuf,
+    temp_path: PathBuf,
+    /// Open temporary file, or None if the overwrite policy skipped the member
+    file: Option<File>,
+}
+
+/// Where a Data extent is stored in the archive and written in the output
+#[derive(Debug, Clone, Copy)]
+struct DataExtentLocation {
+    member: usize,
+    file_offset: u64,
+    archive_offset: u64,
+    /// Bytes stored in the archive, in whole blocks
+    stored_size: u64,
+    length: u64,
+    compression: Option<ExtentCompression>,
+    seal: Option<DataSeal>,
+}
+
+/// The archive as read by parallel extraction workers
+struct SharedArchive<'a> {
+    file: &'a File,
+    block_size: u32,
+    cipher: &'a ArchiveCipher,
+}
+
+/// A Reference extent waiting for its source to be written
+#[derive(Debug, Clone, Copy)]
+struct PendingReference {
+    member: usize,
+    file_offset: u64,
+    extent_id: u64,
+    length: u64,
+}
+
+/// Reader over a shared file using positioned reads, so several threads can
+/// read different parts of the archive at once
+struct PositionedReader<'a> {
+    file: &'a File,
+    position: u64,
+}
+
+impl Read for PositionedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.file.read_at(buf, self.position)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+/// Directory metadata applied once the directory's contents are in place
+#[derive(Debug, Clone)]
+struct DeferredDirectory {
+    path: PathBuf,
+    mode: u32,
+    access_time: u64,
+    modify_time: u64,
+    time_nanos: TimeNanos,
+}
+
 /// Archive extractor
 pub struct ArchiveExtractor<R: Read + Seek> {
-    reader: BufReader<R>,
+    reader: BufReader<ManifestReader<R>>,
     block_size: u32,
+    footer_expected: bool, // Version 2+ archives end with a footer
     extent_cache: HashMap<u64, CachedExtent>, // Maps extent_id to cached data
     output_dir: PathBuf,
     current_file_path: Option<PathBuf>, // Track current file being extracted
+    capabilities: OnceCell<Option<FilesystemCapabilities>>, // Probed on the first reflink attempt
+    overwrite_policy: OverwritePolicy,
+    conflicts: Vec<Conflict>,
+    sync: bool,                                // fsync files before renaming them into place
+    deferred_directories: Vec<DeferredDirectory>,
+    temp_counter: u64,
+    path_rewrite: PathRewrite,
+    bases: BaseArchives, // Base archives for BaseReference extents
+    incremental: bool,   // Remove entries missing from directory listings
+    progress: Arc<dyn ProgressObserver>,
+    cipher: ArchiveCipher, // Opens the records of encrypted archives
+    signature_key: Option<VerifyingKey>, // Key the archive must be signed with
+    signature_check: SignatureCheck,
+    staging: Option<Staging>, // Held back until a stream's signature is checked
+    salvage: Option<SalvageReport>, // Damage skipped so far, when salvaging
 }
 
 impl<R: Read + Seek> ArchiveExtractor<R> {
     /// Create a new archive extractor
     pub fn new(reader: R, output_dir: PathBuf) -> Result<Self> {
-        let mut reader = BufReader::new(reader);
+        // Hash what is read in case the signature is checked along the way
+        let mut reader = BufReader::new(ManifestReader::new(reader));
 
         // Read archive header
         let header = ArchiveHeader::read(&mut reader)?;
+        let cipher = ArchiveCipher::new(&header)?;
 
         Ok(Self {
             reader,
             block_size: header.block_size,
+            footer_expected: header.version >= 2,
             extent_cache: HashMap::new(),
             output_dir,
             current_file_path: None,
+            capabilities: OnceCell::new(),
+            overwrite_policy: OverwritePolicy::default(),
+            conflicts: Vec::new(),
+            sync: false,
+            deferred_directories: Vec::new(),
+            temp_counter: 0,
+            path_rewrite: PathRewrite::new(),
+            bases: BaseArchives::default(),
+            incremental: false,
+            progress: Arc::new(NoProgress),
+            cipher,
+            signature_key: None,
+            signature_check: SignatureCheck::Pending,
+            staging: None,
+            salvage: None,
         })
     }
 
+    /// Refuse archives that are not signed with `key`
+    ///
+    /// The signature is checked in full before the first member is
+    /// extracted or read. An archive that can only be read front to back
+    /// has its footer after the members, so what is extracted from it
+    /// stays under temporary names until the signature is checked there,
+    /// and is removed if it doesn't match.
+    pub fn with_signature_key(mut self, key: VerifyingKey) -> Self {
+        self.signature_key = Some(key);
+        self
+    }
+
+    /// Check the signature of a seekable archive against the key given
+    /// with `with_signature_key`, reading the whole archive. Returns the
+    /// archive footer.
+    pub fn verify_signature(&mut self) -> Result<ArchiveFooter> {
+        let key = self
+            .signature_key
+            .ok_or_else(|| anyhow::anyhow!("No signature key given"))?;
+        self.reader.get_mut().stop_hashing();
+        let start = self.reader.stream_position()?;
+        let result = signature::verify_archive(&mut self.reader, &self.cipher, &key);
+        self.signature_check = match result {
+            Ok(_) => SignatureCheck::Passed,
+            Err(_) => SignatureCheck::Failed,
+        };
+        self.reader.seek(SeekFrom::Start(start))?;
+        result
+    }
+
+    /// Whether the signature was found good, either by `verify_signature`
+    /// or when the footer of an archive read front to back was reached
+    pub fn signature_verified(&self) -> bool {
+        self.signature_check == SignatureCheck::Passed
+    }
+
+    /// Refuse to go on extracting once the signature check has failed
+    fn check_signature_not_failed(&self) -> Result<()> {
+        if self.signature_key.is_some() && self.signature_check == SignatureCheck::Failed {
+            anyhow::bail!("Archive signature check failed");
+        }
+        Ok(())
+    }
+
+    /// Check the signature, if one is required, before anything is taken
+    /// out of the archive; a stream's is checked at its footer instead,
+    /// with what is extracted held back until then
+    fn check_signature_first(&mut self) -> Result<()> {
+        if self.signature_key.is_some() && self.signature_check == SignatureCheck::Pending && self.staging.is_none() {
+            match self.random_access() {
+                true => self.verify_signature().map(|_| ())?,
+                false => self.staging = Some(Staging::default()),
+            }
+        }
+        self.check_signature_not_failed()
+    }
+
+    /// Whether the archive can be read out of order, rather than only
+    /// front to back
+    fn random_access(&mut self) -> bool {
+        // The reader underneath is put back where it was
+        let inner = self.reader.get_mut().get_mut();
+        let Ok(position) = inner.stream_position() else {
+            return false;
+        };
+        inner.seek(SeekFrom::End(0)).is_ok() && inner.seek(SeekFrom::Start(position)).is_ok()
+    }
+
+    /// Create a directory and its missing parents, noting those created
+    /// while extraction is staged
+    fn create_dirs(&mut self, path: &Path) -> Result<()> {
+        if let Some(staging) = &mut self.staging {
+            let missing: Vec<PathBuf> = path
+                .ancestors()
+                .take_while(|dir| fs::symlink_metadata(dir).is_err())
+                .map(Path::to_path_buf)
+                .collect();
+            staging.directories.extend(missing.into_iter().rev());
+        }
+        fs::create_dir_all(path)?;
+        Ok(())
+    }
+
+    /// Move a member written under `temp_path` into place, or hold it there
+    /// while extraction is staged
+    fn place(&mut self, temp_path: &Path, output_path: &Path, header: &FileHeader) -> Result<()> {
+        let Some(staging) = &mut self.staging else {
+            return rename_into_place(temp_path, output_path, self.sync);
+        };
+        let header = FileHeader {
+            inline_data: Vec::new(),
+            directory_listing: None,
+            ..header.clone()
+        };
+        staging.members.push((temp_path.to_path_buf(), output_path.to_path_buf(), header));
+        Ok(())
+    }
+
+    /// Where the member extracted to `path` is for now: its temporary name
+    /// while extraction is staged
+    fn staged_path(&self, path: &Path) -> PathBuf {
+        let staged = self.staging.iter().flat_map(|staging| staging.members.iter().rev());
+        match staged.into_iter().find(|(_, output_path, _)| output_path == path) {
+            Some((temp_path, _, _)) => temp_path.clone(),
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// Once a stream's signature has been checked, move what was extracted
+    /// from it into place, under the overwrite policy
+    fn commit_staged(&mut self) -> Result<()> {
+        let Some(staging) = self.staging.take() else {
+            return Ok(());
+        };
+        let mut skipped = Vec::new();
+        let mut members = staging.members.into_iter();
+        while let Some((temp_path, output_path, header)) = members.next() {
+            let placed = self.prepare_output_path(&output_path, &header).and_then(|extract| match extract {
+                true => rename_into_place(&temp_path, &output_path, self.sync),
+                false => {
+                    skipped.push(output_path);
+                    Ok(fs::remove_file(&temp_path)?)
+                }
+            });
+            if let Err(e) = placed {
+                let _ = fs::remove_file(&temp_path);
+                for (temp_path, _, _) in members {
+                    let _ = fs::remove_file(&temp_path);
+                }
+                return Err(e);
+            }
+        }
+        for path in staging.extracted.iter().filter(|path| !skipped.contains(path)) {
+            println!("Extracted: {}", path.display());
+        }
+        Ok(())
+    }
+
+    /// Remove what was extracted from a stream that failed its signature
+    /// check, or ended before it
+    fn discard_staged(&mut self) {
+        let Some(staging) = self.staging.take() else {
+            return;
+        };
+        for (temp_path, _, _) in &staging.members {
+            let _ = fs::remove_file(temp_path);
+        }
+        self.deferred_directories
+            .retain(|dir| !staging.directories.contains(&dir.path));
+        for dir in staging.directories.iter().rev() {
+            let _ = fs::remove_dir(dir);
+        }
+    }
+
+    /// Whether the signature still has to be checked as the archive is read
+    fn checking_stream_signature(&mut self) -> bool {
+        if self.signature_key.is_none() || self.signature_check != SignatureCheck::Pending {
+            self.reader.get_mut().stop_hashing();
+            return false;
+        }
+        true
+    }
+
+    /// Unlock an encrypted archive with a passphrase or key file
+    pub fn with_keys(mut self, keys: &[KeySource]) -> Result<Self> {
+        self.cipher.unlock(keys)?;
+        Ok(self)
+    }
+
+    /// Whether the archive is encrypted and still needs a key to extract
+    pub fn is_locked(&self) -> bool {
+        self.cipher.is_locked()
+    }
+
+    /// Whether the members can be listed without (further) keys
+    pub fn can_list(&self) -> bool {
+        self.cipher.can_read_metadata()
+    }
+
+    /// Total size of the regular files in the archive, from its footer
+    /// index, or None if it has none. Needs a seekable archive.
+    pub fn regular_file_bytes(&mut self) -> Result<Option<u64>> {
+        self.reader.get_mut().stop_hashing();
+        let start = self.reader.stream_position()?;
+        let mut total = None;
+        if let Some(footer) = self.cipher.locate_footer(&mut self.reader)? {
+            let mut sum = 0;
+            for member in footer.members.iter().filter(|m| m.file_type == FileType::Regular) {
+                self.reader.seek(SeekFrom::Start(member.header_offset))?;
+                let header = self
+                    .cipher
+                    .read_file_header(&mut self.reader, member.header_offset, self.block_size)?;
+                sum += header.file_size as u64;
+            }
+            total = Some(sum);
+        }
+        self.reader.seek(SeekFrom::Start(start))?;
+        Ok(total)
+    }
+
+    /// Make a base archive available for restoring data that a
+    /// differential archive references
+    pub fn with_base(mut self, path: &Path) -> Result<Self> {
+        self.bases.add(BaseArchive::open(path)?);
+        Ok(self)
+    }
+
+    /// Whether reflinks can work in the output directory
+    ///
+    /// The directory is probed the first time a file is about to be cloned
+    /// into, not when the extractor is made, so that commands which only
+    /// read the archive never write probe files.
+    fn reflink_supported(&self) -> bool {
+        self.capabilities
+            .get_or_init(|| FilesystemCapabilities::probe(&self.output_dir).ok())
+            .as_ref()
+            .map(|caps| caps.reflink)
+            .unwrap_or(true)
+    }
+
+    /// Flush each file (and its directory entry) to stable storage before
+    /// it is renamed into place
+    pub fn with_sync(mut self, sync: bool) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Rewrite member paths with `--transform`/`--strip-components` rules
+    pub fn with_path_rewrite(mut self, rewrite: PathRewrite) -> Self {
+        self.path_rewrite = rewrite;
+        self
+    }
+
+    /// Treat the archive as part of an incremental chain: entries of an
+    /// extracted directory that its listing does not name were deleted or
+    /// renamed since the previous archive, and are removed
+    pub fn with_incremental(mut self, incremental: bool) -> Self {
+        self.incremental = incremental;
+        self
+    }
+
+    /// Report progress to an observer
+    pub fn with_progress(mut self, observer: Arc<dyn ProgressObserver>) -> Self {
+        self.progress = observer;
+        self
+    }
+
+    /// Set how existing files in the output directory are handled
+    pub fn with_overwrite_policy(mut self, policy: OverwritePolicy) -> Self {
+        self.overwrite_policy = policy;
+        self
+    }
+
+    /// Where a member is written, or `None` if it is skipped
+    ///
+    /// Leading `/` is removed, and members whose (rewritten) path contains
+    /// `..` are refused so nothing is written outside the output directory.
+    fn output_path_for(&self, header: &FileHeader) -> Option<PathBuf> {
+        self.output_path_of(&header.member_path())
+    }
+
+    /// Where the member at `path` is written, as for `output_path_for`;
+    /// also where a hard link's target is found
+    fn output_path_of(&self, path: &str) -> Option<PathBuf> {
+        Some(self.output_dir.join(self.rewritten_path(path)?))
+    }
+
+    /// The member at `path` after the path rewrite, relative to the output
+    /// directory. None for members that are skipped: those rewritten to
+    /// nothing, and those whose path contains `..`.
+    pub fn rewritten_path(&self, path: &str) -> Option<PathBuf> {
+        let member_path = normalize_member_path(&self.path_rewrite.apply(Path::new(path))?);
+        if member_path.as_os_str().is_empty() {
+            return None;
+        }
+        if is_unsafe_member_path(&member_path) {
+            eprintln!("Skipping {}: member path contains '..'", member_path.display());
+            return None;
+        }
+        Some(member_path)
+    }
+
+    /// Keep going past damage: `extract_all` skips to the next member
+    /// header it can read after one it can't, and file ranges whose data
+    /// is damaged or lost are left as zeros. Both are recorded in
+    /// `salvage_report`. Salvage extracts sequentially, even with
+    /// `extract_all_parallel`.
+    pub fn with_salvage(mut self, salvage: bool) -> Self {
+        self.salvage = salvage.then(SalvageReport::default);
+        self
+    }
+
+    /// What salvage skipped, if salvaging
+    pub fn salvage_report(&self) -> Option<&SalvageReport> {
+        self.salvage.as_ref()
+    }
+
+    /// Members that collided with existing paths so far
+    pub fn conflicts(&self) -> &[Conflict] {
+        &self.conflicts
+    }
+
     /// Extract all files from the archive
     pub fn extract_all(&mut self) -> Result<()> {
+        if self.salvage.is_some() {
+            let result = self.salvage_all();
+            if result.is_err() {
+                self.discard_staged();
+            }
+            return result;
+        }
         loop {
             match self.extract_next_file() {
                 Ok(true) => continue,
                 Ok(false) => break, // End of archive
                 Err(e) => {
-                    // Check if we hit EOF
-                    if e.to_string().contains("failed to fill whole buffer") {
-                        break;
-                    }
+                    // Leave what was extracted with its final metadata,
+                    // unless it was waiting on the signature
+                    self.discard_staged();
+                    let _ = self.finish();
                     return Err(e);
                 }
             }
         }
 
+        self.finish()?;
+        self.check_refused()
+    }
+
+    /// Fail if any existing file was refused under `KeepOldFiles`
+    fn check_refused(&self) -> Result<()> {
+        let refused = self
+            .conflicts
+            .iter()
+            .filter(|c| c.action == ConflictAction::Refused)
+            .count();
+        if refused > 0 {
+            anyhow::bail!("{} existing file(s) were not replaced", refused);
+        }
+
         Ok(())
     }
 
     /// Extract the next file from the archive
     /// Returns Ok(true) if a file was extracted, Ok(false) if EOF reached
     pub fn extract_next_file(&mut self) -> Result<bool> {
+        self.cipher.require_key()?;
+        self.check_signature_first()?;
+
         // Try to read file header
-        let file_header = match FileHeader::read(&mut self.reader, self.block_size) {
-            Ok(header) => header,
-            Err(e) => {
-                // Check if this is EOF
-                if e.to_string().contains("failed to fill whole buffer") {
-                    return Ok(false);
+        let file_header = match self.read_next_member(None)? {
+            Some(header) => header,
+            None => {
+                // A stream's signature was checked at its footer
+                self.commit_staged()?;
+                return Ok(false);
+            }
+        };
+        self.extract_file(&file_header).map_err(|e| match has_io_error_kind(&e, io::ErrorKind::UnexpectedEof) {
+            true => e.context(format!("Archive ends in the middle of {}", file_header.member_path())),
+            false => e,
+        })?;
+
+        Ok(true)
+    }
+
+    /// Extract the member whose header was just read
+    fn extract_file(&mut self, file_header: &FileHeader) -> Result<()> {
+        // Build output path
+        let output_path = self.output_path_for(file_header);
+        if let Some(parent) = output_path.as_ref().and_then(|path| path.parent()) {
+            // Create parent directories
+            self.create_dirs(parent)?;
+        }
+
+        // Resolve collisions with whatever is already on disk; staged
+        // members do so when they are moved into place
+        let staged = self.staging.is_some() && file_header.file_type != FileType::Directory;
+        let extract = match &output_path {
+            Some(_) if staged => true,
+            Some(path) => self.prepare_output_path(path, file_header)?,
+            None => false,
+        };
+        let Some(output_path) = output_path.filter(|_| extract) else {
+            if file_header.file_type == FileType::Regular
+                && file_header.inline_data.is_empty()
+                && file_header.file_size > 0
+            {
+                // Still read the extents: later members may reference them
+                self.extract_file_with_extents(None, file_header.file_size)?;
+            }
+            return Ok(());
+        };
+
+        self.extract_member(&output_path, file_header)
+    }
+
+    /// `extract_all` with salvage: damage ends the member it is in, and
+    /// extraction resumes at the next member header found after it
+    fn salvage_all(&mut self) -> Result<()> {
+        self.cipher.require_key()?;
+        self.check_signature_first()?;
+        let index = self.member_index();
+
+        let mut next = None;
+        loop {
+            self.check_signature_not_failed()?;
+            let offset = self.reader.stream_position()?;
+            let header = match next.take() {
+                Some(header) => Ok(Some(header)),
+                None => self.read_next_member(None),
+            };
+            let (path, result) = match header {
+                Ok(Some(header)) => (Some(header.member_path()), self.extract_file(&header)),
+                Ok(None) => break,
+                // Cut off between two members: nothing more to find
+                Err(e) if self.reader.stream_position()? == offset => {
+                    let report = self.salvage.as_mut().expect("salvaging");
+                    report.skipped.push(SkippedRange {
+                        start: offset,
+                        end: offset,
+                        reason: format!("{:#}", e),
+                    });
+                    break;
                 }
+                Err(e) => (None, Err(e)),
+            };
+            let Err(e) = result else {
+                continue;
+            };
+
+            // A damaged archive can't match its signature
+            if self.signature_key.is_some() {
+                let _ = self.finish();
                 return Err(e);
             }
+            let (end, header) = self.find_next_member(offset + self.block_size as u64)?;
+            let report = self.salvage.as_mut().expect("salvaging");
+            let reason = match has_io_error_kind(&e, io::ErrorKind::UnexpectedEof) {
+                true => "Archive ends in the middle of this member".to_string(),
+                false => format!("{:#}", e),
+            };
+            report.skipped.push(SkippedRange {
+                start: offset,
+                end,
+                reason,
+            });
+            // The index names the members that were skipped over
+            let mut lost: Vec<LostMember> = index
+                .iter()
+                .filter(|member| member.header_offset >= offset && member.header_offset < end)
+                .map(|member| LostMember {
+                    offset: member.header_offset,
+                    path: Some(member.path.clone()),
+                })
+                .collect();
+            match lost.first() {
+                _ if path.is_some() => {
+                    lost.retain(|member| member.offset != offset);
+                    lost.insert(0, LostMember { offset, path });
+                }
+                Some(first) if first.offset == offset => {}
+                _ => lost.insert(0, LostMember { offset, path: None }),
+            }
+            report.lost.extend(lost);
+            match header {
+                Some(header) => next = Some(header),
+                None => break,
+            }
+        }
+
+        self.commit_staged()?;
+        self.finish()?;
+        self.check_refused()
+    }
+
+    /// Members listed in the footer index of a seekable archive, to name
+    /// those salvage skips; empty if the index can't be read
+    fn member_index(&mut self) -> Vec<IndexEntry> {
+        if self.checking_stream_signature() {
+            return Vec::new();
+        }
+        let Ok(start) = self.reader.stream_position() else {
+            return Vec::new();
         };
+        let footer = self.cipher.locate_footer(&mut self.reader);
+        // Streams can't seek to their end, nor back
+        let _ = self.reader.seek(SeekFrom::Start(start));
+        match footer {
+            Ok(Some(footer)) => footer.members,
+            _ => Vec::new(),
+        }
+    }
 
-        // Build output path
-        let output_path = self.output_dir.join(&file_header.file_path).join(&file_header.file_name);
+    /// Scan block boundaries from `from` for the next member header that
+    /// reads correctly. Returns the offset where scanning stopped and the
+    /// header found there, or None at the footer or the end of the archive;
+    /// the reader is left after the header.
+    fn find_next_member(&mut self, from: u64) -> Result<(u64, Option<FileHeader>)> {
+        let block_size = self.block_size as u64;
+        let mut position = from.next_multiple_of(block_size);
+        let mut block = vec![0u8; self.block_size as usize];
+        loop {
+            // Seek back where the archive allows, else go on from where
+            // reading stopped
+            let current = self.reader.stream_position()?;
+            if current > position && self.reader.seek(SeekFrom::Start(position)).is_err() {
+                position = current.next_multiple_of(block_size);
+            }
+            let current = self.reader.stream_position()?;
+            io::copy(&mut (&mut self.reader).take(position - current), &mut io::sink())?;
 
-        // Create parent directories
-        if let Some(parent) = output_path.parent() {
-            fs::create_dir_all(parent)?;
+            let mut filled = 0;
+            while filled < block.len() {
+                match self.reader.read(&mut block[filled..])? {
+                    0 => return Ok((position + filled as u64, None)),
+                    n => filled += n,
+                }
+            }
+            if self.cipher.may_start_member(&block) {
+                let mut source = block.as_slice().chain(&mut self.reader);
+                if let Ok(header) = self.cipher.read_member_header(&mut source, position, self.block_size) {
+                    return Ok((position, header));
+                }
+            }
+            position += block_size;
         }
+    }
+
+    /// Create a member whose output path has been cleared
+    /// Regular files with extents read them from the archive stream
+    fn extract_member(&mut self, output_path: &Path, file_header: &FileHeader) -> Result<()> {
+        self.progress.file_started(output_path, file_header.file_size as u64);
 
         // Extract based on file type
         match file_header.file_type {
             FileType::Directory => {
-                fs::create_dir_all(&output_path)?;
+                self.create_dirs(output_path)?;
+                // Keep the directory writable until its contents are extracted
+                fs::set_permissions(output_path, fs::Permissions::from_mode(0o700))?;
+                if let Some(listing) = file_header.directory_listing.as_ref().filter(|_| self.incremental) {
+                    remove_unlisted_entries(output_path, listing)?;
+                }
+                self.deferred_directories.push(DeferredDirectory {
+                    path: output_path.to_path_buf(),
+                    mode: file_header.mode.unwrap_or(0o755),
+                    access_time: file_header.access_time,
+                    modify_time: file_header.modify_time,
+                    time_nanos: file_header.time_nanos,
+                });
             }
             FileType::SymbolicLink => {
+                let temp_path = self.temp_path_for(output_path);
                 #[cfg(unix)]
-                std::os::unix::fs::symlink(&file_header.link_name, &output_path)?;
+                std::os::unix::fs::symlink(&file_header.link_name, &temp_path)?;
+                if let Err(e) = set_times(&temp_path, file_header)
+                    .and_then(|_| self.place(&temp_path, output_path, file_header))
+                {
+                    let _ = fs::remove_file(&temp_path);
+                    return Err(e);
+                }
+            }
+            FileType::HardLink => {
+                let Some(target) = self.output_path_of(&file_header.link_name) else {
+                    eprintln!("Skipping {}: hard link target is not extracted", output_path.display());
+                    return Ok(());
+                };
+                let target = self.staged_path(&target);
+                let temp_path = self.temp_path_for(output_path);
+                fs::hard_link(&target, &temp_path)
+                    .with_context(|| format!("Failed to link {} to {}", output_path.display(), target.display()))?;
+                if let Err(e) = self.place(&temp_path, output_path, file_header) {
+                    let _ = fs::remove_file(&temp_path);
+                    return Err(e);
+                }
             }
             FileType::Regular => {
-                if !file_header.inline_data.is_empty() {
-                    // Small file with inline data
-                    let mut file = File::create(&output_path)?;
-                    file.write_all(&file_header.inline_data)?;
-                } else if file_header.file_size > 0 {
-                    // Large file with extents
-                    self.current_file_path = Some(output_path.clone());
-                    self.extract_file_with_extents(&output_path, file_header.file_size)?;
-                    self.current_file_path = None;
-                } else {
-                    // Empty file
-                    File::create(&output_path)?;
+                let temp_path = self.temp_path_for(output_path);
+                let result = self.extract_regular_file(&temp_path, output_path, file_header);
+                self.current_file_path = None;
+                if let Err(e) = result {
+                    // Never leave a partial file behind under either name
+                    let _ = fs::remove_file(&temp_path);
+                    return Err(e);
                 }
             }
             _ => {
                 // Skip other file types for now
                 eprintln!("Skipping unsupported file type: {:?}", file_header.file_type);
+                return Ok(());
             }
         }
 
-        // Set file metadata
-        self.set_file_metadata(&output_path, &file_header)?;
+        match &mut self.staging {
+            Some(staging) => staging.extracted.push(output_path.to_path_buf()),
+            None => println!("Extracted: {}", output_path.display()),
+        }
+        self.progress.file_finished(output_path);
+
+        Ok(())
+    }
 
-        println!("Extracted: {}", output_path.display());
+    /// Apply deferred directory metadata
+    ///
+    /// Directories are kept writable while their contents are extracted, so
+    /// their modes and timestamps are applied here, deepest first.
+    /// `extract_all` calls this automatically.
+    pub fn finish(&mut self) -> Result<()> {
+        let mut directories = std::mem::take(&mut self.deferred_directories);
+        directories.sort_by_key(|dir| std::cmp::Reverse(dir.path.components().count()));
 
-        Ok(true)
+        for dir in directories {
+            fs::set_permissions(&dir.path, fs::Permissions::from_mode(dir.mode))?;
+            set_path_times(&dir.path, dir.access_time, dir.modify_time, dir.time_nanos)?;
+            if self.sync {
+                File::open(&dir.path)?.sync_all()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write a regular file under a temporary name and rename it into place
+    /// once its contents are complete and verified
+    fn extract_regular_file(
+        &mut self,
+        temp_path: &Path,
+        output_path: &Path,
+        header: &FileHeader,
+    ) -> Result<()> {
+        let mut file = create_temp_file(temp_path)?;
+
+        if !header.inline_data.is_empty() {
+            // Small file with inline data
+            file.write_all(&header.inline_data)?;
+            self.progress.bytes_written(header.inline_data.len() as u64);
+        } else if header.file_size > 0 {
+            // Large file with extents
+            file.set_len(header.file_size as u64)?;
+            self.current_file_path = Some(output_path.to_path_buf());
+            self.extract_file_with_extents(Some(&mut file), header.file_size)?;
+        }
+
+        finalize_regular_file(file, temp_path, header, self.sync)?;
+        self.place(temp_path, output_path, header)
     }
 
-    /// Extract a file that has extents
-    fn extract_file_with_extents(&mut self, output_path: &Path, file_size: u128) -> Result<()> {
-        let mut output_file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(output_path)?;
+    /// Pick an unused temporary name next to `path`
+    fn temp_path_for(&mut self, path: &Path) -> PathBuf {
+        let name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        loop {
+            self.temp_counter += 1;
+            let candidate = path.with_file_name(format!(
+                ".{}.reftar-{}-{}",
+                name,
+                std::process::id(),
+                self.temp_counter
+            ));
+            if fs::symlink_metadata(&candidate).is_err() {
+                return candidate;
+            }
+        }
+    }
+
+    /// Decide whether a member may be written to `path`, clearing the way if
+    /// the overwrite policy allows it
+    /// Returns Ok(false) if the member should be skipped
+    fn prepare_output_path(&mut self, path: &Path, header: &FileHeader) -> Result<bool> {
+        let existing = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(true),
+        };
+        let member_is_dir = header.file_type == FileType::Directory;
+
+        // Directories merge into existing directories under every policy
+        if member_is_dir && existing.is_dir() {
+            return Ok(true);
+        }
+
+        let policy = self.overwrite_policy;
+        match policy {
+            OverwritePolicy::KeepOldFiles => {
+                self.record_conflict(path, ConflictAction::Refused, "file exists");
+                return Ok(false);
+            }
+            OverwritePolicy::SkipOldFiles => {
+                self.record_conflict(path, ConflictAction::Skipped, "file exists");
+                return Ok(false);
+            }
+            OverwritePolicy::KeepNewerFiles => {
+                use std::os::unix::fs::MetadataExt;
+                if existing.mtime() > header.modify_time as i64 {
+                    self.record_conflict(path, ConflictAction::Skipped, "existing file is newer");
+                    return Ok(false);
+                }
+            }
+            _ => {}
+        }
+
+        if existing.is_dir() {
+            if policy == OverwritePolicy::RecursiveUnlink {
+                fs::remove_dir_all(path)?;
+            } else if fs::remove_dir(path).is_err() {
+                self.record_conflict(
+                    path,
+                    ConflictAction::Refused,
+                    "cannot replace non-empty directory",
+                );
+                return Ok(false);
+            }
+            self.record_conflict(path, ConflictAction::Replaced, "directory replaced");
+            return Ok(true);
+        }
+
+        // Non-directory members are renamed over the existing entry, so the
+        // old file survives until the new one is complete; directories and
+        // the unlink policies need the path cleared first
+        let replaced_by_rename = !member_is_dir
+            && matches!(
+                policy,
+                OverwritePolicy::Overwrite | OverwritePolicy::KeepNewerFiles
+            );
+        if !replaced_by_rename {
+            fs::remove_file(path)?;
+        }
+        self.record_conflict(path, ConflictAction::Replaced, "existing file replaced");
 
-        // Set file size
-        output_file.set_len(file_size as u64)?;
+        Ok(true)
+    }
+
+    fn record_conflict(&mut self, path: &Path, action: ConflictAction, reason: &str) {
+        self.conflicts.push(Conflict {
+            path: path.to_path_buf(),
+            action,
+            reason: reason.to_string(),
+        });
+    }
 
+    /// Extract a file that has extents into an already sized output file
+    /// With no output file the extents are read and cached but not written
+    fn extract_file_with_extents(
+        &mut self,
+        mut output_file: Option<&mut File>,
+        file_size: u128,
+    ) -> Result<()> {
         let mut current_offset = 0u64;
 
         // Read extents until we've reconstructed the entire file
         while current_offset < file_size as u64 {
-            let extent_header = ExtentHeader::read(&mut self.reader, self.block_size)?;
+            let offset = self.reader.stream_position()?;
+            let extent_header = self.cipher.read_extent_header(&mut self.reader, offset, self.block_size)?;
+            // Bytes of the file the extent restores, leaving out block padding
+            let restored = extent_header
+                .covered_length(self.block_size)
+                .min(file_size as u64 - current_offset);
 
             match extent_header.extent_type {
-                ExtentType::Data => {
+                ExtentType::Data | ExtentType::Compressed => {
                     // Read and write data blocks
                     let data_size = extent_header.length_in_blocks as u64 * self.block_size as u64;
                     let mut data = vec![0u8; data_size as usize];
                     self.reader.read_exact(&mut data)?;
+                    self.progress.bytes_read(data_size);
 
                     // Verify checksum
+                    let covered = extent_header.covered_length(self.block_size);
                     let calculated_checksum = crc32fast::hash(&data);
                     if calculated_checksum != extent_header.checksum {
-                        anyhow::bail!(
-                            "Checksum mismatch for extent {}: expected {}, got {}",
-                            extent_header.extent_id,
-                            extent_header.checksum,
-                            calculated_checksum
-                        );
+                        self.unrecoverable(
+                            current_offset,
+                            restored,
+                            format!(
+                                "Checksum mismatch for extent {}: expected {}, got {}",
+                                extent_header.extent_id, extent_header.checksum, calculated_checksum
+                            ),
+                        )?;
+                        current_offset += covered;
+                        continue;
                     }
+                    let opened = self
+                        .cipher
+                        .open_extent_data(extent_header.seal.as_ref(), &mut data, extent_header.extent_id)
+                        .and_then(|_| match &extent_header.compression {
+                            Some(compression) => decompress(compression, &data, covered),
+                            // Chunks that do not fill their last block carry padding
+                            None => {
+                                data.truncate(covered as usize);
+                                Ok(std::mem::take(&mut data))
+                            }
+                        });
+                    let data = match opened {
+                        Ok(data) => data,
+                        Err(e) => {
+                            self.unrecoverable(current_offset, restored, format!("{:#}", e))?;
+                            current_offset += covered;
+                            continue;
+                        }
+                    };
 
                     // Write to output file
-                    output_file.seek(SeekFrom::Start(current_offset))?;
-                    output_file.write_all(&data)?;
+                    if let Some(output_file) = output_file.as_mut() {
+                        output_file.seek(SeekFrom::Start(current_offset))?;
+                        output_file.write_all(&data)?;
+                        self.progress.bytes_written(restored);
+                    }
 
                     // Cache this extent for potential references (with file location for reflinks)
-                    let file_location = self.current_file_path.clone().map(|p| (p, current_offset));
+                    let file_location = match output_file {
+                        Some(_) => self.current_file_path.clone().map(|p| (p, current_offset)),
+                        None => None,
+                    };
                     self.extent_cache.insert(
                         extent_header.extent_id,
                         CachedExtent {
-                            data: data.clone(),
+                            data,
                             file_location,
                         },
                     );
 
-                    current_offset += data_size;
+                    current_offset += covered;
                 }
                 ExtentType::Sparse => {
                     // Sparse extent - just skip (file is already sized correctly with zeros)
-                    let skip_size = extent_header.length_in_blocks as u64 * self.block_size as u64;
-                    current_offset += skip_size;
+                    let length = extent_header.covered_length(self.block_size);
+                    if output_file.is_some() {
+                        self.progress.bytes_written(restored);
+                    }
+                    current_offset += length;
+                }
+                ExtentType::BaseReference => {
+                    let length = extent_header.covered_length(self.block_size);
+                    if let Some(output_file) = output_file.as_mut() {
+                        output_file.flush()?;
+                        let reflinked = self.bases.restore(
+                            &extent_header,
+                            self.block_size,
+                            output_file,
+                            current_offset,
+                            self.reflink_supported(),
+                        )?;
+                        self.progress.dedup_hit(restored);
+                        self.progress.reflinked(reflinked.min(restored));
+                        self.progress.bytes_written(restored);
+                    }
+                    current_offset += length;
                 }
                 ExtentType::Reference => {
                     // Reference to earlier extent
-                    if let Some(cached) = self.extent_cache.get(&extent_header.extent_id) {
-                        let data_size = cached.data.len() as u64;
+                    let data_size = extent_header.covered_length(self.block_size);
+                    let cached = self
+                        .extent_cache
+                        .get(&extent_header.extent_id)
+                        .filter(|cached| data_size <= cached.data.len() as u64);
+                    if let Some(cached) = cached {
+                        let Some(output_file) = output_file.as_mut() else {
+                            current_offset += data_size;
+                            continue;
+                        };
                         let mut reflink_used = false;
+                        let reflink_supported = self.reflink_supported();
 
                         // Try to use reflink if we have file location information
-                        if let Some((ref source_path, source_offset)) = cached.file_location {
+                        // and the ranges are block-aligned, as cloning requires
+                        let block_size = self.block_size as u64;
+                        let aligned = current_offset.is_multiple_of(block_size) && data_size.is_multiple_of(block_size);
+                        let reflink_source = cached
+                            .file_location
+                            .as_ref()
+                            .filter(|(_, source_offset)| {
+                                reflink_supported && aligned && source_offset.is_multiple_of(block_size)
+                            });
+                        if let Some((source_path, source_offset)) = reflink_source {
+                            // The current file is still under its temporary name,
+                            // so clone within it through our own handle
+                            let source_file =
+                                if self.current_file_path.as_deref() == Some(source_path.as_path()) {
+                                    output_file.try_clone()
+                                } else {
+                                    File::open(source_path)
+                                };
+
                             // Try to open the source file and use FICLONERANGE
-                            if let Ok(source_file) = File::open(source_path) {
+                            if let Ok(source_file) = source_file {
                                 output_file.flush()?; // Ensure file is on disk
 
                                 match crate::reflink::try_reflink_range(
                                     &source_file,
-                                    source_offset,
-                                    &output_file,
+                                    *source_offset,
+                                    output_file,
                                     current_offset,
                                     data_size,
                                 ) {
@@ -213,17 +1255,24 @@ impl<R: Read + Seek> ArchiveExtractor<R> {
                         }
 
                         // Fall back to regular copy if reflink didn't work
-                        if !reflink_used {
+                        if reflink_used {
+                            self.progress.reflinked(restored);
+                        } else {
                             output_file.seek(SeekFrom::Start(current_offset))?;
-                            output_file.write_all(&cached.data)?;
+                            output_file.write_all(&cached.data[..data_size as usize])?;
                         }
+                        self.progress.dedup_hit(restored);
+                        self.progress.bytes_written(restored);
 
                         current_offset += data_size;
                     } else {
-                        anyhow::bail!(
-                            "Reference to unknown extent ID: {}",
-                            extent_header.extent_id
-                        );
+                        let message = match (self.extent_cache.contains_key(&extent_header.extent_id), &self.salvage) {
+                            (true, _) => format!("Reference to extent {} is longer than the extent", extent_header.extent_id),
+                            (false, Some(_)) => "Data it references was lost".to_string(),
+                            (false, None) => format!("Reference to unknown extent ID: {}", extent_header.extent_id),
+                        };
+                        self.unrecoverable(current_offset, restored, message)?;
+                        current_offset += data_size;
                     }
                 }
             }
@@ -232,39 +1281,90 @@ impl<R: Read + Seek> ArchiveExtractor<R> {
         Ok(())
     }
 
-    /// Set file metadata (permissions, timestamps, ownership)
-    fn set_file_metadata(&self, path: &Path, header: &FileHeader) -> Result<()> {
-        // Set permissions
-        #[cfg(unix)]
-        {
-            let metadata = fs::metadata(path)?;
-            let mut permissions = metadata.permissions();
-            // Default to 0644 for files, 0755 for directories
-            let mode = if header.file_type == FileType::Directory {
-                0o755
-            } else {
-                0o644
-            };
-            permissions.set_mode(mode);
-            fs::set_permissions(path, permissions)?;
+    /// Under salvage, record that `length` bytes of the file being
+    /// extracted from `start` can't be restored, and carry on; otherwise
+    /// fail with `message`
+    fn unrecoverable(&mut self, start: u64, length: u64, message: String) -> Result<()> {
+        let Some(report) = self.salvage.as_mut() else {
+            anyhow::bail!(message);
+        };
+        let Some(path) = &self.current_file_path else {
+            return Ok(());
+        };
+        match report.unrecoverable.last_mut() {
+            // Runs of lost blocks make one range
+            Some(last) if last.path == *path && last.end == start && last.reason == message => last.end += length,
+            _ => report.unrecoverable.push(UnrecoverableRange {
+                path: path.clone(),
+                start,
+                end: start + length,
+                reason: message,
+            }),
         }
+        Ok(())
+    }
 
-        // Set timestamps
-        #[cfg(unix)]
-        {
-            use std::time::{Duration, SystemTime};
-
-            let _atime = SystemTime::UNIX_EPOCH + Duration::from_secs(header.access_time);
-            let _mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(header.modify_time);
-
-            // Use filetime crate for setting times
-            // For now, we'll skip this as it requires an additional dependency
-            // TODO: Add filetime crate and implement timestamp setting
+    /// Read the next member header, or None at the end of the archive
+    ///
+    /// When the signature is checked as the archive is read, this marks
+    /// where members start, and checks the signature at the footer.
+    /// With `record`, the header bytes read, padding included, are copied
+    /// into it.
+    fn read_next_member(&mut self, record: Option<&mut Vec<u8>>) -> Result<Option<FileHeader>> {
+        let offset = self.reader.stream_position()?;
+        let checking = self.checking_stream_signature();
+        if checking {
+            self.reader.get_mut().start_record(offset)?;
         }
 
-        // Set ownership (requires root privileges)
-        // TODO: Implement ownership setting with appropriate privilege checks
+        let result = match record {
+            Some(bytes) => {
+                let mut reader = RecordingReader {
+                    inner: &mut self.reader,
+                    bytes,
+                };
+                self.cipher.read_member_header(&mut reader, offset, self.block_size)
+            }
+            None => self.cipher.read_member_header(&mut self.reader, offset, self.block_size),
+        };
+        let header = match result {
+            Ok(header) => header,
+            Err(e) if has_io_error_kind(&e, io::ErrorKind::UnexpectedEof) => {
+                return Err(e.context(format!("Archive is cut off in the header at offset {}", offset)));
+            }
+            Err(e) => return Err(e),
+        };
+        // None is the footer, or input ending where a header would start
+        if header.is_none() && self.footer_expected && self.reader.stream_position()? == offset {
+            return Err(anyhow::Error::new(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Archive ends at offset {} without its footer, so it is incomplete", offset),
+            )));
+        }
+        if checking {
+            match header {
+                Some(_) => self.reader.get_mut().confirm_member(),
+                None => self.check_stream_signature()?,
+            }
+        }
+        Ok(header)
+    }
 
+    /// Check the signature of an archive read front to back, once the
+    /// footer is reached
+    fn check_stream_signature(&mut self) -> Result<()> {
+        let Some(key) = self.signature_key else {
+            return Ok(());
+        };
+        self.signature_check = SignatureCheck::Failed;
+        let (manifest, footer_offset, rest) = self.reader.get_mut().finish()?;
+        if rest.is_empty() {
+            anyhow::bail!("Archive has no footer, so it is not signed");
+        }
+        let footer = self.cipher.read_footer(&mut rest.as_slice(), footer_offset)?;
+        let signed = signature::check_signature(&footer, &key)?;
+        signature::compare_manifests(&footer, signed, &manifest)?;
+        self.signature_check = SignatureCheck::Passed;
         Ok(())
     }
 
@@ -272,30 +1372,157 @@ impl<R: Read + Seek> ArchiveExtractor<R> {
     pub fn list_files(&mut self) -> Result<Vec<String>> {
         let mut files = Vec::new();
 
-        loop {
-            match FileHeader::read(&mut self.reader, self.block_size) {
-                Ok(header) => {
-                    let path = format!("{}/{}", header.file_path, header.file_name);
-                    files.push(path);
-
-                    // Skip extent data if present
-                    if header.file_type == FileType::Regular
-                        && header.inline_data.is_empty()
-                        && header.file_size > 0
-                    {
-                        self.skip_extents(header.file_size)?;
+        while let Some(header) = self.read_next_member(None)? {
+            files.push(header.member_path());
+
+            // Skip extent data if present
+            if header.file_type == FileType::Regular && header.inline_data.is_empty() && header.file_size > 0 {
+                self.skip_extents(header.file_size)?;
+            }
+        }
+
+        Ok(files)
+    }
+
+    /// Read the next member, and rebuild a regular file's contents in
+    /// memory, passing them to `visit` with the header piece by piece in
+    /// file order; nothing is written. Returns the header, or None at the
+    /// end of the archive. Damaged data is an error, as when extracting.
+    pub fn read_member_contents<F>(&mut self, mut visit: F) -> Result<Option<FileHeader>>
+    where
+        F: FnMut(&FileHeader, FileChunk<'_>) -> Result<()>,
+    {
+        self.cipher.require_key()?;
+        self.check_signature_first()?;
+        let Some(header) = self.read_next_member(None)? else {
+            return Ok(None);
+        };
+        if header.file_type != FileType::Regular || header.file_size == 0 {
+            return Ok(Some(header));
+        }
+        if !header.inline_data.is_empty() {
+            let chunk = FileChunk {
+                offset: 0,
+                length: header.inline_data.len() as u64,
+                source: ChunkSource::Inline,
+                data: Some(&header.inline_data),
+            };
+            visit(&header, chunk)?;
+            return Ok(Some(header));
+        }
+
+        let file_size = header.file_size as u64;
+        let mut current_offset = 0u64;
+        while current_offset < file_size {
+            let offset = self.reader.stream_position()?;
+            let extent_header = self.cipher.read_extent_header(&mut self.reader, offset, self.block_size)?;
+            let covered = extent_header.covered_length(self.block_size);
+            // Bytes of the file the extent restores, leaving out block padding
+            let length = covered.min(file_size - current_offset);
+            let extent_id = extent_header.extent_id;
+            let chunk = |source, data| FileChunk {
+                offset: current_offset,
+                length,
+                source,
+                data,
+            };
+
+            match extent_header.extent_type {
+                ExtentType::Data | ExtentType::Compressed => {
+                    let mut data = vec![0u8; extent_header.length_in_blocks as usize * self.block_size as usize];
+                    self.reader.read_exact(&mut data)?;
+                    let calculated_checksum = crc32fast::hash(&data);
+                    if calculated_checksum != extent_header.checksum {
+                        anyhow::bail!(
+                            "Checksum mismatch for extent {}: expected {}, got {}",
+                            extent_id,
+                            extent_header.checksum,
+                            calculated_checksum
+                        );
                     }
+                    self.cipher.open_extent_data(extent_header.seal.as_ref(), &mut data, extent_id)?;
+                    match &extent_header.compression {
+                        Some(compression) => data = decompress(compression, &data, covered)?,
+                        None => data.truncate(covered as usize),
+                    }
+                    visit(&header, chunk(ChunkSource::Data { extent_id }, Some(&data[..length as usize])))?;
+                    let file_location = None;
+                    self.extent_cache.insert(extent_id, CachedExtent { data, file_location });
                 }
-                Err(e) => {
-                    if e.to_string().contains("failed to fill whole buffer") {
-                        break;
+                ExtentType::Sparse => visit(&header, chunk(ChunkSource::Sparse, None))?,
+                ExtentType::BaseReference => {
+                    let data = self.bases.read(&extent_header, self.block_size)?;
+                    visit(&header, chunk(ChunkSource::Base, Some(&data[..length as usize])))?;
+                }
+                ExtentType::Reference => {
+                    let cached = self
+                        .extent_cache
+                        .get(&extent_id)
+                        .ok_or_else(|| anyhow::anyhow!("Reference to unknown extent ID: {}", extent_id))?;
+                    if covered > cached.data.len() as u64 {
+                        anyhow::bail!("Reference to extent {} is longer than the extent", extent_id);
                     }
-                    return Err(e);
+                    visit(&header, chunk(ChunkSource::Reference { extent_id }, Some(&cached.data[..length as usize])))?;
                 }
             }
+            current_offset += covered;
         }
 
-        Ok(files)
+        Ok(Some(header))
+    }
+
+    /// Read the next member's header and where its contents hold data,
+    /// without reading the data or moving past the member: the offsets and
+    /// lengths of the pieces of a regular file that aren't holes, in file
+    /// order, contiguous pieces merged. None at the end of the archive.
+    /// The archive must be able to seek back.
+    pub fn peek_member_layout(&mut self) -> Result<Option<MemberLayout>> {
+        self.cipher.require_key()?;
+        self.check_signature_first()?;
+        let start = self.reader.stream_position()?;
+        let layout = self.read_member_layout();
+        self.reader.seek(SeekFrom::Start(start))?;
+        layout
+    }
+
+    fn read_member_layout(&mut self) -> Result<Option<MemberLayout>> {
+        let Some(header) = self.read_next_member(None)? else {
+            return Ok(None);
+        };
+        let file_size = header.file_size as u64;
+        let mut regions: Vec<(u64, u64)> = Vec::new();
+        if header.file_type != FileType::Regular || file_size == 0 {
+            return Ok(Some((header, regions)));
+        }
+        if !header.inline_data.is_empty() {
+            regions.push((0, header.inline_data.len() as u64));
+            return Ok(Some((header, regions)));
+        }
+
+        let mut current_offset = 0u64;
+        while current_offset < file_size {
+            let offset = self.reader.stream_position()?;
+            let extent_header = self.cipher.read_extent_header(&mut self.reader, offset, self.block_size)?;
+            let covered = extent_header.covered_length(self.block_size);
+            let length = covered.min(file_size - current_offset);
+            match extent_header.extent_type {
+                ExtentType::Data | ExtentType::Compressed => {
+                    let data_size = extent_header.length_in_blocks as u64 * self.block_size as u64;
+                    self.reader.seek_relative(data_size as i64)?;
+                }
+                ExtentType::Sparse => {
+                    current_offset += covered;
+                    continue;
+                }
+                ExtentType::Reference | ExtentType::BaseReference => {}
+            }
+            match regions.last_mut() {
+                Some((start, region_length)) if *start + *region_length == current_offset => *region_length += length,
+                _ => regions.push((current_offset, length)),
+            }
+            current_offset += covered;
+        }
+        Ok(Some((header, regions)))
     }
 
     /// Skip over extent data without reading it
@@ -303,35 +1530,693 @@ impl<R: Read + Seek> ArchiveExtractor<R> {
         let mut current_offset = 0u64;
 
         while current_offset < file_size as u64 {
-            let extent_header = ExtentHeader::read(&mut self.reader, self.block_size)?;
+            let offset = self.reader.stream_position()?;
+            let extent_header = self.cipher.read_extent_header(&mut self.reader, offset, self.block_size)?;
 
             match extent_header.extent_type {
-                ExtentType::Data => {
+                ExtentType::Data | ExtentType::Compressed => {
                     // Skip data blocks
                     let data_size = extent_header.length_in_blocks as u64 * self.block_size as u64;
-                    self.reader.seek(SeekFrom::Current(data_size as i64))?;
-                    current_offset += data_size;
+                    self.reader.seek_relative(data_size as i64)?;
                 }
-                ExtentType::Sparse => {
-                    let skip_size = extent_header.length_in_blocks as u64 * self.block_size as u64;
-                    current_offset += skip_size;
+                ExtentType::Sparse | ExtentType::Reference | ExtentType::BaseReference => {
+                    // No data follows the header
                 }
-                ExtentType::Reference => {
-                    // References don't have data, just the header
-                    let ref_size = extent_header.length_in_blocks as u64 * self.block_size as u64;
-                    current_offset += ref_size;
+            }
+            current_offset += extent_header.covered_length(self.block_size);
+        }
+
+        Ok(())
+    }
+
+    /// Check the whole archive without extracting it
+    ///
+    /// Every header is parsed and every stored extent read: Data extents
+    /// must match their checksums, References must name an earlier stored
+    /// extent with the same checksum, a file's extents must add up to its
+    /// size, and header and data padding must be zero. Problems are
+    /// collected rather than returned as errors; only one that leaves the
+    /// rest of the archive unreadable, such as a corrupt header, ends the
+    /// walk early. Data of an encrypted archive is only checked beyond its
+    /// checksum when the archive is unlocked.
+    pub fn verify(&mut self) -> Result<VerifyReport> {
+        let mut report = VerifyReport::default();
+        let mut stored = HashMap::new();
+
+        loop {
+            let offset = self.reader.stream_position()?;
+            let mut record = Vec::new();
+            let header = match self.read_next_member(Some(&mut record)) {
+                Ok(Some(header)) => header,
+                Ok(None) => break,
+                Err(e) => {
+                    report.push(offset, format!("{:#}", e));
+                    break;
+                }
+            };
+            report.members += 1;
+            let length = sealed_record_length(&record).or_else(|| FileHeader::record_length(&record));
+            if !padding_is_zero(&record, length) {
+                report.push(offset, format!("Nonzero padding after the header of {}", header.member_path()));
+            }
+
+            if header.file_type == FileType::Regular && header.inline_data.is_empty() && header.file_size > 0 {
+                let complete = self.verify_extents(&header, offset, &mut stored, &mut report)?;
+                if !complete {
+                    break;
                 }
             }
         }
 
+        Ok(report)
+    }
+
+    /// Check the extents of the regular file whose header is at `offset`
+    /// Returns false if the archive can't be read past a problem
+    fn verify_extents(
+        &mut self,
+        header: &FileHeader,
+        offset: u64,
+        stored: &mut HashMap<u64, VerifiedExtent>,
+        report: &mut VerifyReport,
+    ) -> Result<bool> {
+        let file_size = header.file_size as u64;
+        let mut covered_total = 0u64;
+
+        while covered_total < file_size {
+            let extent_offset = self.reader.stream_position()?;
+            let mut record = Vec::new();
+            let mut recording = RecordingReader {
+                inner: &mut self.reader,
+                bytes: &mut record,
+            };
+            let extent = match self.cipher.read_extent_header(&mut recording, extent_offset, self.block_size) {
+                Ok(extent) => extent,
+                Err(e) => {
+                    report.push(extent_offset, format!("{:#}", e));
+                    return Ok(false);
+                }
+            };
+            let length = sealed_record_length(&record).or_else(|| ExtentHeader::record_length(&record));
+            if !padding_is_zero(&record, length) {
+                report.push(extent_offset, format!("Nonzero padding after extent header {}", extent.extent_id));
+            }
+
+            let covered = extent.covered_length(self.block_size);
+            if covered == 0 {
+                report.push(extent_offset, format!("Extent {} covers no data", extent.extent_id));
+                return Ok(false);
+            }
+            covered_total = covered_total.saturating_add(covered);
+
+            match extent.extent_type {
+                ExtentType::Data | ExtentType::Compressed => {
+                    report.extents += 1;
+                    let data_offset = self.reader.stream_position()?;
+                    let mut data = vec![0u8; extent.length_in_blocks as usize * self.block_size as usize];
+                    if let Err(e) = self.reader.read_exact(&mut data) {
+                        report.push(data_offset, format!("Data of extent {}: {}", extent.extent_id, e));
+                        return Ok(false);
+                    }
+
+                    let checksum = crc32fast::hash(&data);
+                    // References to compressed data carry the checksum of
+                    // the uncompressed data, unless it is sealed
+                    let mut reference_checksum = match (&extent.compression, &extent.seal) {
+                        (Some(_), None) => None,
+                        _ => Some(extent.checksum),
+                    };
+                    if checksum != extent.checksum {
+                        report.push(
+                            data_offset,
+                            format!(
+                                "Checksum mismatch for extent {}: expected {:08x}, got {:08x}",
+                                extent.extent_id, extent.checksum, checksum
+                            ),
+                        );
+                    } else if !self.cipher.is_locked() {
+                        if let Err(e) = self.cipher.open_extent_data(extent.seal.as_ref(), &mut data, extent.extent_id) {
+                            report.push(data_offset, format!("{:#}", e));
+                        } else if let Err(e) = self.verify_extent_data(&extent, &data, &mut reference_checksum) {
+                            report.push(data_offset, format!("Extent {}: {:#}", extent.extent_id, e));
+                        }
+                    }
+                    let verified = VerifiedExtent {
+                        checksum: reference_checksum,
+                        covered,
+                    };
+                    if stored.insert(extent.extent_id, verified).is_some() {
+                        report.push(extent_offset, format!("Extent {} is stored more than once", extent.extent_id));
+                    }
+                }
+                ExtentType::Reference => match stored.get(&extent.extent_id) {
+                    None => report.push(
+                        extent_offset,
+                        format!("Reference to extent {}, which is not stored before it", extent.extent_id),
+                    ),
+                    Some(target) => {
+                        if target.checksum.is_some_and(|checksum| checksum != extent.checksum) {
+                            report.push(
+                                extent_offset,
+                                format!(
+                                    "Reference to extent {} has checksum {:08x}, the extent has {:08x}",
+                                    extent.extent_id,
+                                    extent.checksum,
+                                    target.checksum.unwrap_or_default()
+                                ),
+                            );
+                        }
+                        if covered > target.covered {
+                            report.push(
+                                extent_offset,
+                                format!(
+                                    "Reference to extent {} covers {} bytes, the extent {}",
+                                    extent.extent_id, covered, target.covered
+                                ),
+                            );
+                        }
+                    }
+                },
+                // Base references are checked against their base when
+                // extracted
+                ExtentType::Sparse | ExtentType::BaseReference => {}
+            }
+        }
+
+        // Only the block padding of the last extent may run past the end
+        if covered_total - file_size >= self.block_size as u64 {
+            report.push(
+                offset,
+                format!(
+                    "Extents of {} cover {} bytes, more than its size of {}",
+                    header.member_path(),
+                    covered_total,
+                    file_size
+                ),
+            );
+        }
+        Ok(true)
+    }
+
+    /// Check the opened data of a stored extent: that it decompresses to
+    /// the length it covers and that its padding is zero. Sets the checksum
+    /// references to a compressed extent carry.
+    fn verify_extent_data(&self, extent: &ExtentHeader, data: &[u8], reference_checksum: &mut Option<u32>) -> Result<()> {
+        let covered = extent.covered_length(self.block_size);
+        let used = match &extent.compression {
+            Some(compression) => compression.stored_length,
+            None => covered,
+        };
+        if used > data.len() as u64 {
+            anyhow::bail!("covers {} bytes but stores {}", used, data.len());
+        }
+        if data[used as usize..].iter().any(|&byte| byte != 0) {
+            anyhow::bail!("nonzero padding after the data");
+        }
+        if let Some(compression) = &extent.compression {
+            let mut plain = decompress(compression, data, covered)?;
+            plain.resize(plain.len().next_multiple_of(self.block_size as usize), 0);
+            if extent.seal.is_none() {
+                *reference_checksum = Some(crc32fast::hash(&plain));
+            }
+        }
         Ok(())
     }
 }
 
+/// Reader that keeps a copy of the bytes read through it
+struct RecordingReader<'a, R> {
+    inner: &'a mut R,
+    bytes: &'a mut Vec<u8>,
+}
+
+impl<R: Read> Read for RecordingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Whether the block padding of a header record, after its first `length`
+/// bytes, is all zeros
+fn padding_is_zero(record: &[u8], length: Option<usize>) -> bool {
+    match length.and_then(|length| record.get(length..)) {
+        Some(padding) => padding.iter().all(|&byte| byte == 0),
+        None => true,
+    }
+}
+
+/// Remove the entries of `dir` that are not named in an incremental
+/// archive's listing of it
+fn remove_unlisted_entries(dir: &Path, listing: &[String]) -> Result<()> {
+    let listed: std::collections::HashSet<&std::ffi::OsStr> =
+        listing.iter().map(|name| std::ffi::OsStr::new(name.as_str())).collect();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if listed.contains(entry.file_name().as_os_str()) {
+            continue;
+        }
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(&path)?;
+        } else {
+            fs::remove_file(&path)?;
+        }
+        println!("Removed: {}", path.display());
+    }
+    Ok(())
+}
+
+fn create_temp_file(temp_path: &Path) -> Result<File> {
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(temp_path)?)
+}
+
+/// Trim, apply metadata to and (optionally) sync a fully written temporary
+/// file
+fn finalize_regular_file(file: File, temp_path: &Path, header: &FileHeader, sync: bool) -> Result<()> {
+    // The last data block is stored padded to the block size
+    file.set_len(header.file_size as u64)?;
+
+    let mode = header.mode.unwrap_or(0o644);
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+    set_times(temp_path, header)?;
+
+    if sync {
+        file.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Rename a member written under a temporary name into place
+fn rename_into_place(temp_path: &Path, output_path: &Path, sync: bool) -> Result<()> {
+    fs::rename(temp_path, output_path)?;
+
+    if sync {
+        if let Some(parent) = output_path.parent() {
+            File::open(parent)?.sync_all()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Set a member's access and modification times without following symlinks
+fn set_times(path: &Path, header: &FileHeader) -> Result<()> {
+    set_path_times(path, header.access_time, header.modify_time, header.time_nanos)
+}
+
+fn set_path_times(path: &Path, access_time: u64, modify_time: u64, nanos: TimeNanos) -> Result<()> {
+    use nix::sys::stat::{utimensat, UtimensatFlags};
+    use nix::sys::time::TimeSpec;
+
+    // An access time of zero means none was recorded
+    let atime = match access_time {
+        0 => TimeSpec::new(modify_time as i64, nanos.modify as i64),
+        _ => TimeSpec::new(access_time as i64, nanos.access as i64),
+    };
+    let mtime = TimeSpec::new(modify_time as i64, nanos.modify as i64);
+    utimensat(None, path, &atime, &mtime, UtimensatFlags::NoFollowSymlink)?;
+
+    // Ownership is not restored yet; it needs privilege checks
+    Ok(())
+}
+
+impl ArchiveExtractor<File> {
+    /// Extract all files using up to `jobs` worker threads
+    ///
+    /// This needs the archive footer index; archives without one are
+    /// extracted sequentially. Directories, symlinks and small files are
+    /// created first, then the Data extents of all regular files are written
+    /// in parallel with positioned I/O, then Reference extents are resolved
+    /// once every source extent is in place, and finally the files are
+    /// renamed into place.
+    pub fn extract_all_parallel(&mut self, jobs: usize) -> Result<()> {
+        if self.salvage.is_some() {
+            return self.extract_all();
+        }
+        self.cipher.require_key()?;
+        self.check_signature_first()?;
+        if self.staging.is_some() {
+            // A stream is read front to back, and checked as it goes
+            return self.extract_all();
+        }
+        self.reader.get_mut().stop_hashing();
+        let start = self.reader.stream_position()?;
+        let footer = self.cipher.locate_footer(&mut self.reader)?;
+        self.reader.seek(SeekFrom::Start(start))?;
+
+        let footer = match footer {
+            Some(footer) if jobs > 1 => footer,
+            _ => return self.extract_all(),
+        };
+
+        let mut members = Vec::new();
+        let result = self.extract_indexed(&footer, jobs, &mut members);
+        if let Err(e) = result {
+            for member in &members {
+                let _ = fs::remove_file(&member.temp_path);
+            }
+            let _ = self.finish();
+            return Err(e);
+        }
+
+        self.finish()?;
+        self.check_refused()
+    }
+
+    fn extract_indexed(
+        &mut self,
+        footer: &ArchiveFooter,
+        jobs: usize,
+        members: &mut Vec<ParallelMember>,
+    ) -> Result<()> {
+        let archive = self.reader.get_ref().get_ref().try_clone()?;
+        let block_size = self.block_size;
+
+        // Create everything that has no extents and open the rest; hard
+        // links wait for their targets to be renamed into place
+        let mut hard_links = Vec::new();
+        for entry in &footer.members {
+            let mut reader = PositionedReader {
+                file: &archive,
+                position: entry.header_offset,
+            };
+            let header = self.cipher.read_file_header(&mut reader, entry.header_offset, block_size)?;
+            let (output_path, extract) = match self.output_path_for(&header) {
+                Some(output_path) => {
+                    if let Some(parent) = output_path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    let extract = self.prepare_output_path(&output_path, &header)?;
+                    (output_path, extract)
+                }
+                None => (self.output_dir.join(header.member_path()), false),
+            };
+
+            let has_extents = header.file_type == FileType::Regular
+                && header.inline_data.is_empty()
+                && header.file_size > 0;
+            if !has_extents {
+                match header.file_type {
+                    FileType::HardLink if extract => hard_links.push((output_path, header)),
+                    _ if extract => self.extract_member(&output_path, &header)?,
+                    _ => {}
+                }
+                continue;
+            }
+
+            let temp_path = self.temp_path_for(&output_path);
+            let file = if extract {
+                let file = create_temp_file(&temp_path)?;
+                file.set_len(header.file_size as u64)?;
+                Some(file)
+            } else {
+                None
+            };
+            members.push(ParallelMember {
+                header,
+                extents_offset: reader.position,
+                output_path,
+                temp_path,
+                file,
+            });
+        }
+
+        // Phase 1: Data extents, and data from base archives
+        let members = &*members;
+        let reflink_supported = members.iter().any(|member| member.file.is_some()) && self.reflink_supported();
+        let shared = SharedArchive {
+            file: &archive,
+            block_size,
+            cipher: &self.cipher,
+        };
+        let bases = &self.bases;
+        let progress = &*self.progress;
+        let results = run_parallel(jobs, members.len(), |index| {
+            write_data_extents(&shared, members, index, bases, reflink_supported, progress)
+        })?;
+        let mut locations = HashMap::new();
+        let mut references = Vec::new();
+        for (data, refs) in results {
+            locations.extend(data);
+            references.extend(refs);
+        }
+
+        // Phase 2: Reference extents, now that all their sources are written
+        run_parallel(jobs, references.len(), |index| {
+            resolve_reference(&shared, members, &locations, references[index], reflink_supported, progress)
+        })?;
+
+        // Phase 3: trim, apply metadata and rename into place
+        let sync = self.sync;
+        run_parallel(jobs, members.len(), |index| {
+            let member = &members[index];
+            let Some(file) = &member.file else {
+                return Ok(());
+            };
+            finalize_regular_file(file.try_clone()?, &member.temp_path, &member.header, sync)?;
+            rename_into_place(&member.temp_path, &member.output_path, sync)?;
+            println!("Extracted: {}", member.output_path.display());
+            progress.file_finished(&member.output_path);
+            Ok(())
+        })?;
+
+        for (output_path, header) in hard_links {
+            self.extract_member(&output_path, &header)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Run `task` for every index in `0..count` on up to `jobs` threads
+/// Results are returned in index order; the first error wins
+fn run_parallel<T, F>(jobs: usize, count: usize, task: F) -> Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(usize) -> Result<T> + Sync,
+{
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results: Mutex<Vec<Option<Result<T>>>> = Mutex::new((0..count).map(|_| None).collect());
+
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.min(count) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                if index >= count || failed.load(Ordering::Relaxed) {
+                    break;
+                }
+                let result = task(index);
+                if result.is_err() {
+                    failed.store(true, Ordering::Relaxed);
+                }
+                results.lock().unwrap()[index] = Some(result);
+            });
+        }
+    });
+
+    let mut ordered = Vec::with_capacity(count);
+    for result in results.into_inner().unwrap() {
+        match result {
+            Some(result) => ordered.push(result?),
+            None => anyhow::bail!("Parallel extraction stopped early"),
+        }
+    }
+    Ok(ordered)
+}
+
+/// Write the Data and BaseReference extents of one member and collect its
+/// references
+#[allow(clippy::type_complexity)]
+fn write_data_extents(
+    archive: &SharedArchive,
+    members: &[ParallelMember],
+    index: usize,
+    bases: &BaseArchives,
+    reflink_supported: bool,
+    progress: &dyn ProgressObserver,
+) -> Result<(Vec<(u64, DataExtentLocation)>, Vec<PendingReference>)> {
+    let member = &members[index];
+    if member.file.is_some() {
+        progress.file_started(&member.output_path, member.header.file_size as u64);
+    }
+    let block_size = archive.block_size;
+    let mut reader = PositionedReader {
+        file: archive.file,
+        position: member.extents_offset,
+    };
+    let file_size = member.header.file_size as u64;
+    let mut locations = Vec::new();
+    let mut references = Vec::new();
+    let mut current_offset = 0u64;
+
+    while current_offset < file_size {
+        let header_offset = reader.position;
+        let extent_header = archive
+            .cipher
+            .read_extent_header(&mut reader, header_offset, block_size)?;
+        let length = extent_header.covered_length(block_size);
+        // Bytes of the file the extent restores, leaving out block padding
+        let restored = length.min(file_size - current_offset);
+
+        match extent_header.extent_type {
+            ExtentType::Data | ExtentType::Compressed => {
+                let archive_offset = reader.position;
+                let stored = extent_header.length_in_blocks as u64 * block_size as u64;
+                let mut data = vec![0u8; stored as usize];
+                reader.read_exact(&mut data)?;
+                progress.bytes_read(stored);
+
+                let calculated_checksum = crc32fast::hash(&data);
+                if calculated_checksum != extent_header.checksum {
+                    anyhow::bail!(
+                        "Checksum mismatch for extent {}: expected {}, got {}",
+                        extent_header.extent_id,
+                        extent_header.checksum,
+                        calculated_checksum
+                    );
+                }
+                archive
+                    .cipher
+                    .open_extent_data(extent_header.seal.as_ref(), &mut data, extent_header.extent_id)?;
+
+                if let Some(compression) = &extent_header.compression {
+                    data = decompress(compression, &data, length)?;
+                }
+                if let Some(file) = &member.file {
+                    file.write_all_at(&data[..length as usize], current_offset)?;
+                    progress.bytes_written(restored);
+                }
+                locations.push((
+                    extent_header.extent_id,
+                    DataExtentLocation {
+                        member: index,
+                        file_offset: current_offset,
+                        archive_offset,
+                        stored_size: stored,
+                        length,
+                        compression: extent_header.compression,
+                        seal: extent_header.seal,
+                    },
+                ));
+            }
+            ExtentType::Sparse => {
+                if member.file.is_some() {
+                    progress.bytes_written(restored);
+                }
+            }
+            ExtentType::BaseReference => {
+                if let Some(file) = &member.file {
+                    let reflinked =
+                        bases.restore(&extent_header, block_size, file, current_offset, reflink_supported)?;
+                    progress.dedup_hit(restored);
+                    progress.reflinked(reflinked.min(restored));
+                    progress.bytes_written(restored);
+                }
+            }
+            ExtentType::Reference => {
+                if member.file.is_some() {
+                    references.push(PendingReference {
+                        member: index,
+                        file_offset: current_offset,
+                        extent_id: extent_header.extent_id,
+                        length,
+                    });
+                }
+            }
+        }
+
+        current_offset += length;
+    }
+
+    Ok((locations, references))
+}
+
+/// Fill in one Reference extent by reflinking from where its source was
+/// written, or by copying the source data from the archive
+fn resolve_reference(
+    archive: &SharedArchive,
+    members: &[ParallelMember],
+    locations: &HashMap<u64, DataExtentLocation>,
+    reference: PendingReference,
+    reflink_supported: bool,
+    progress: &dyn ProgressObserver,
+) -> Result<()> {
+    let location = locations.get(&reference.extent_id).ok_or_else(|| {
+        anyhow::anyhow!("Reference to unknown extent ID: {}", reference.extent_id)
+    })?;
+    let Some(dest) = &members[reference.member].file else {
+        return Ok(());
+    };
+    if reference.length > location.length {
+        anyhow::bail!("Reference to extent {} is longer than the extent", reference.extent_id);
+    }
+    let file_size = members[reference.member].header.file_size as u64;
+    let restored = reference.length.min(file_size - reference.file_offset);
+    progress.dedup_hit(restored);
+    progress.bytes_written(restored);
+
+    // Cloning needs block-aligned ranges, which content-defined chunks often are not
+    let block_size = archive.block_size as u64;
+    let aligned = [location.file_offset, reference.file_offset, reference.length]
+        .iter()
+        .all(|value| value.is_multiple_of(block_size));
+    if reflink_supported && aligned {
+        if let Some(source) = &members[location.member].file {
+            let cloned = crate::reflink::try_reflink_range(
+                source,
+                location.file_offset,
+                dest,
+                reference.file_offset,
+                reference.length,
+            )
+            .unwrap_or(false);
+            if cloned {
+                progress.reflinked(restored);
+                return Ok(());
+            }
+        }
+    }
+
+    let data = if location.compression.is_none() && location.seal.is_none() {
+        let mut data = vec![0u8; reference.length as usize];
+        archive.file.read_exact_at(&mut data, location.archive_offset)?;
+        progress.bytes_read(reference.length);
+        data
+    } else {
+        // Sealed data is opened whole, compressed data decompressed whole
+        let stored_len = match (&location.seal, &location.compression) {
+            (None, Some(compression)) => compression.stored_length,
+            _ => location.stored_size,
+        };
+        let mut stored = vec![0u8; stored_len as usize];
+        archive.file.read_exact_at(&mut stored, location.archive_offset)?;
+        progress.bytes_read(stored_len);
+        archive
+            .cipher
+            .open_extent_data(location.seal.as_ref(), &mut stored, reference.extent_id)?;
+        match &location.compression {
+            Some(compression) => decompress(compression, &stored, location.length)?,
+            None => stored,
+        }
+    };
+    dest.write_all_at(&data[..reference.length as usize], reference.file_offset)?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::create::ArchiveCreator;
+    use crate::create::{ArchiveCreator, Chunking};
     use std::io::Cursor;
     use tempfile::TempDir;
 
@@ -350,4 +2235,698 @@ mod tests {
         let mut extractor = ArchiveExtractor::new(cursor, temp_dir.path().to_path_buf()).unwrap();
         extractor.extract_all().unwrap();
     }
+
+    fn archive_with_file(name: &str, contents: &[u8]) -> Vec<u8> {
+        let source_dir = TempDir::new().unwrap();
+        let source = source_dir.path().join(name);
+        fs::write(&source, contents).unwrap();
+
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None).unwrap();
+        creator.add_file(&source, Path::new(name)).unwrap();
+        creator.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_reading_an_archive_writes_nothing() {
+        let archive = archive_with_file("a.txt", &[7u8; 10000]);
+        let out = TempDir::new().unwrap();
+        let modified = fs::metadata(out.path()).unwrap().modified().unwrap();
+
+        let mut extractor = ArchiveExtractor::new(Cursor::new(archive.clone()), out.path().to_path_buf()).unwrap();
+        assert_eq!(extractor.list_files().unwrap(), ["a.txt"]);
+        let mut extractor = ArchiveExtractor::new(Cursor::new(archive.clone()), out.path().to_path_buf()).unwrap();
+        assert!(extractor.verify().unwrap().is_ok());
+        let mut extractor = ArchiveExtractor::new(Cursor::new(archive), out.path().to_path_buf()).unwrap();
+        while extractor.read_member_contents(|_, _| Ok(())).unwrap().is_some() {}
+
+        // The output directory is only probed once a file is written there
+        assert_eq!(fs::read_dir(out.path()).unwrap().count(), 0);
+        assert_eq!(fs::metadata(out.path()).unwrap().modified().unwrap(), modified);
+    }
+
+    #[test]
+    fn test_verify_reports_every_problem() {
+        let source_dir = TempDir::new().unwrap();
+        let contents: Vec<u8> = (0..8192u32).map(|i| (i % 251) as u8 + 1).collect();
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None).unwrap();
+        for name in ["a", "b"] {
+            fs::write(source_dir.path().join(name), &contents).unwrap();
+            creator.add_file(&source_dir.path().join(name), Path::new(name)).unwrap();
+        }
+        let archive = creator.finish().unwrap().into_inner();
+
+        let verify = |archive: &[u8]| {
+            let out = TempDir::new().unwrap();
+            let mut extractor = ArchiveExtractor::new(Cursor::new(archive.to_vec()), out.path().to_path_buf()).unwrap();
+            extractor.verify().unwrap()
+        };
+        let report = verify(&archive);
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!((report.members, report.extents), (2, 2));
+
+        // Header of a at 4096, its two extents at 8192 and 16384, then b's
+        // header at 24576 and its two references
+        let mut damaged = archive.clone();
+        damaged[4096 + 4000] = 1; // header padding
+        damaged[12288 + 5] ^= 1; // data of the first extent
+
+        // A reference whose checksum doesn't match, in an intact header
+        let mut reference = ExtentHeader::read(&mut &damaged[28672..], 4096, true).unwrap();
+        reference.checksum ^= 1;
+        let mut rewritten = Vec::new();
+        reference.write(&mut rewritten, 4096).unwrap();
+        damaged[28672..32768].copy_from_slice(&rewritten);
+        let report = verify(&damaged);
+        let offsets: Vec<u64> = report.issues.iter().map(|issue| issue.offset).collect();
+        assert_eq!(offsets, [4096, 12288, 28672], "{:?}", report.issues);
+        assert_eq!(report.members, 2);
+    }
+
+    #[test]
+    fn test_header_checksums_cannot_be_switched_off() {
+        let archive = archive_with_file("a.txt", &[7u8; 10000]);
+
+        // Alter a's uid, and flip a bit in the tag of its checksum record
+        let record = 4096 + archive[4096..].windows(6).position(|w| w == [4, 0, 40, 0, 0, 0]).unwrap();
+        let mut tampered = archive.clone();
+        tampered[4096 + 21] ^= 1;
+        tampered[record] ^= 1;
+
+        let out = TempDir::new().unwrap();
+        let mut extractor = ArchiveExtractor::new(Cursor::new(tampered.clone()), out.path().to_path_buf()).unwrap();
+        let error = extractor.extract_all().unwrap_err();
+        assert_eq!(error.to_string(), "File header is corrupted: its checksum record is missing");
+        assert_eq!(fs::read_dir(out.path()).unwrap().count(), 0);
+        let mut extractor = ArchiveExtractor::new(Cursor::new(tampered), out.path().to_path_buf()).unwrap();
+        let report = extractor.verify().unwrap();
+        let offsets: Vec<u64> = report.issues.iter().map(|issue| issue.offset).collect();
+        assert_eq!(offsets, [4096], "{:?}", report.issues);
+    }
+
+    #[test]
+    fn test_verify_unknown_reference_and_truncation() {
+        let source_dir = TempDir::new().unwrap();
+        let contents: Vec<u8> = (0..8192u32).map(|i| (i % 251) as u8 + 1).collect();
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None).unwrap();
+        for name in ["a", "b"] {
+            fs::write(source_dir.path().join(name), &contents).unwrap();
+            creator.add_file(&source_dir.path().join(name), Path::new(name)).unwrap();
+        }
+        let archive = creator.finish().unwrap().into_inner();
+        let verify = |archive: &[u8]| {
+            let out = TempDir::new().unwrap();
+            let mut extractor = ArchiveExtractor::new(Cursor::new(archive.to_vec()), out.path().to_path_buf()).unwrap();
+            extractor.verify().unwrap()
+        };
+
+        // b's first reference, at 28672, names an extent never stored
+        let mut damaged = archive.clone();
+        let mut reference = ExtentHeader::read(&mut &damaged[28672..], 4096, true).unwrap();
+        reference.extent_id = 99;
+        let mut rewritten = Vec::new();
+        reference.write(&mut rewritten, 4096).unwrap();
+        damaged[28672..32768].copy_from_slice(&rewritten);
+        let report = verify(&damaged);
+        assert_eq!(report.issues.len(), 1, "{:?}", report.issues);
+        assert_eq!(report.issues[0].offset, 28672);
+        assert_eq!(report.issues[0].message, "Reference to extent 99, which is not stored before it");
+        assert_eq!(report.members, 2);
+
+        // An archive cut off in the data of a's first extent ends the walk
+        // with the problem reported, not an error
+        let report = verify(&archive[..12288 + 100]);
+        assert_eq!(report.issues.len(), 1, "{:?}", report.issues);
+        assert_eq!(report.issues[0].offset, 12288);
+        assert!(report.issues[0].message.starts_with("Data of extent"), "{:?}", report.issues);
+        assert_eq!(report.members, 1);
+    }
+
+    #[test]
+    fn test_salvage_skips_damage() {
+        let source_dir = TempDir::new().unwrap();
+        let contents: Vec<u8> = (0..8192u32).map(|i| (i % 251) as u8 + 1).collect();
+        let other: Vec<u8> = contents.iter().map(|b| b ^ 0x80).collect();
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None).unwrap();
+        for (name, data) in [("a", &contents), ("b", &other), ("c", &contents)] {
+            fs::write(source_dir.path().join(name), data).unwrap();
+            creator.add_file(&source_dir.path().join(name), Path::new(name)).unwrap();
+        }
+        let mut archive = creator.finish().unwrap().into_inner();
+
+        // a at 4096 with Data extents, b at 24576, c at 45056 referencing a
+        archive[12288 + 5] ^= 1; // data of a's first extent
+        archive[24576 + 60] ^= 1; // b's header
+        let out = TempDir::new().unwrap();
+        let mut extractor = ArchiveExtractor::new(Cursor::new(archive.clone()), out.path().to_path_buf())
+            .unwrap()
+            .with_salvage(true);
+        extractor.extract_all().unwrap();
+
+        let report = extractor.salvage_report().unwrap();
+        let skipped: Vec<(u64, u64)> = report.skipped.iter().map(|range| (range.start, range.end)).collect();
+        assert_eq!(skipped, [(24576, 45056)]);
+        let lost: Vec<Option<&str>> = report.lost.iter().map(|member| member.path.as_deref()).collect();
+        assert_eq!(lost, [Some("b")]);
+        let unrecoverable: Vec<(&Path, u64, u64)> = report
+            .unrecoverable
+            .iter()
+            .map(|range| (range.path.strip_prefix(out.path()).unwrap(), range.start, range.end))
+            .collect();
+        assert_eq!(unrecoverable, [(Path::new("a"), 0, 4096), (Path::new("c"), 0, 4096)]);
+
+        // Everything else is restored, the lost block as zeros
+        let mut expected = contents.clone();
+        expected[..4096].fill(0);
+        assert_eq!(fs::read(out.path().join("a")).unwrap(), expected);
+        assert_eq!(fs::read(out.path().join("c")).unwrap(), expected);
+        assert!(!out.path().join("b").exists());
+
+        // Without salvage the damage stops extraction
+        let out = TempDir::new().unwrap();
+        let mut extractor = ArchiveExtractor::new(Cursor::new(archive), out.path().to_path_buf()).unwrap();
+        assert!(extractor.extract_all().is_err());
+    }
+
+    #[test]
+    fn test_salvage_marks_references_to_lost_data() {
+        let source_dir = TempDir::new().unwrap();
+        let contents: Vec<u8> = (0..8192u32).map(|i| (i % 251) as u8 + 1).collect();
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None).unwrap();
+        for name in ["a", "b"] {
+            fs::write(source_dir.path().join(name), &contents).unwrap();
+            creator.add_file(&source_dir.path().join(name), Path::new(name)).unwrap();
+        }
+        let intact = creator.finish().unwrap().into_inner();
+
+        // a's header at 4096 is lost with its Data extents, which b, at
+        // 24576, references
+        let mut archive = intact.clone();
+        archive[4096 + 60] ^= 1;
+        let out = TempDir::new().unwrap();
+        let mut extractor = ArchiveExtractor::new(Cursor::new(archive), out.path().to_path_buf())
+            .unwrap()
+            .with_salvage(true);
+        extractor.extract_all().unwrap();
+
+        let report = extractor.salvage_report().unwrap();
+        let skipped: Vec<(u64, u64)> = report.skipped.iter().map(|range| (range.start, range.end)).collect();
+        assert_eq!(skipped, [(4096, 24576)]);
+        let lost: Vec<Option<&str>> = report.lost.iter().map(|member| member.path.as_deref()).collect();
+        assert_eq!(lost, [Some("a")]);
+        let unrecoverable: Vec<(&Path, u64, u64)> = report
+            .unrecoverable
+            .iter()
+            .map(|range| (range.path.strip_prefix(out.path()).unwrap(), range.start, range.end))
+            .collect();
+        assert_eq!(unrecoverable, [(Path::new("b"), 0, 8192)]);
+        assert_eq!(fs::read(out.path().join("b")).unwrap(), vec![0u8; 8192]);
+
+        // An archive that ends in the extents of b loses b only
+        let out = TempDir::new().unwrap();
+        let mut extractor = ArchiveExtractor::new(Cursor::new(intact[..28672 + 100].to_vec()), out.path().to_path_buf())
+            .unwrap()
+            .with_salvage(true);
+        extractor.extract_all().unwrap();
+        let report = extractor.salvage_report().unwrap();
+        let reasons: Vec<&str> = report.skipped.iter().map(|range| range.reason.as_str()).collect();
+        assert_eq!(reasons, ["Archive ends in the middle of this member"]);
+        assert_eq!(fs::read(out.path().join("a")).unwrap(), contents);
+        assert!(!out.path().join("b").exists());
+
+        // Cut inside b's header, or before it, is reported, not taken for
+        // the end of the archive
+        let mut extractor = ArchiveExtractor::new(Cursor::new(intact[..24576 + 100].to_vec()), PathBuf::new()).unwrap();
+        let error = extractor.list_files().unwrap_err();
+        assert_eq!(format!("{}", error), "Archive is cut off in the header at offset 24576");
+        let mut extractor = ArchiveExtractor::new(Cursor::new(intact[..24576].to_vec()), PathBuf::new()).unwrap();
+        let error = extractor.list_files().unwrap_err();
+        assert_eq!(
+            format!("{}", error),
+            "Archive ends at offset 24576 without its footer, so it is incomplete"
+        );
+        let out = TempDir::new().unwrap();
+        let mut extractor = ArchiveExtractor::new(Cursor::new(intact[..24576].to_vec()), out.path().to_path_buf())
+            .unwrap()
+            .with_salvage(true);
+        extractor.extract_all().unwrap();
+        let report = extractor.salvage_report().unwrap();
+        let skipped: Vec<(u64, u64)> = report.skipped.iter().map(|range| (range.start, range.end)).collect();
+        assert_eq!(skipped, [(24576, 24576)]);
+        assert!(report.lost.is_empty());
+        assert_eq!(fs::read(out.path().join("a")).unwrap(), contents);
+    }
+
+    #[test]
+    fn test_skip_old_files_keeps_existing() {
+        let archive = archive_with_file("a.txt", &[7u8; 10000]);
+        let out = TempDir::new().unwrap();
+        fs::write(out.path().join("a.txt"), b"mine").unwrap();
+
+        let mut extractor = ArchiveExtractor::new(Cursor::new(archive), out.path().to_path_buf())
+            .unwrap()
+            .with_overwrite_policy(OverwritePolicy::SkipOldFiles);
+        extractor.extract_all().unwrap();
+
+        assert_eq!(fs::read(out.path().join("a.txt")).unwrap(), b"mine");
+        let conflict = &extractor.conflicts()[0];
+        assert_eq!(conflict.action, ConflictAction::Skipped);
+        assert_eq!(conflict.path, out.path().join("a.txt"));
+        assert_eq!(conflict.to_string(), format!("Skipping {}: file exists", out.path().join("a.txt").display()));
+    }
+
+    #[test]
+    fn test_overwrite_records_replaced_files() {
+        let archive = archive_with_file("a.txt", b"archived");
+        let out = TempDir::new().unwrap();
+        fs::write(out.path().join("a.txt"), b"mine").unwrap();
+
+        let mut extractor = ArchiveExtractor::new(Cursor::new(archive), out.path().to_path_buf()).unwrap();
+        extractor.extract_all().unwrap();
+
+        assert_eq!(fs::read(out.path().join("a.txt")).unwrap(), b"archived");
+        let conflicts = extractor.conflicts();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].action, ConflictAction::Replaced);
+        assert_eq!(conflicts[0].reason, "existing file replaced");
+    }
+
+    #[test]
+    fn test_keep_old_files_refuses() {
+        let archive = archive_with_file("a.txt", b"archived");
+        let out = TempDir::new().unwrap();
+        fs::write(out.path().join("a.txt"), b"mine").unwrap();
+
+        let mut extractor = ArchiveExtractor::new(Cursor::new(archive), out.path().to_path_buf())
+            .unwrap()
+            .with_overwrite_policy(OverwritePolicy::KeepOldFiles);
+        assert!(extractor.extract_all().is_err());
+        assert_eq!(fs::read(out.path().join("a.txt")).unwrap(), b"mine");
+    }
+
+    #[test]
+    fn test_directory_replaced_by_file() {
+        let archive = archive_with_file("a.txt", b"archived");
+        let out = TempDir::new().unwrap();
+        fs::create_dir_all(out.path().join("a.txt/inner")).unwrap();
+
+        // A non-empty directory is only removed by --recursive-unlink
+        let mut extractor =
+            ArchiveExtractor::new(Cursor::new(archive.clone()), out.path().to_path_buf()).unwrap();
+        assert!(extractor.extract_all().is_err());
+        assert!(out.path().join("a.txt").is_dir());
+
+        let mut extractor = ArchiveExtractor::new(Cursor::new(archive), out.path().to_path_buf())
+            .unwrap()
+            .with_overwrite_policy(OverwritePolicy::RecursiveUnlink);
+        extractor.extract_all().unwrap();
+        assert_eq!(fs::read(out.path().join("a.txt")).unwrap(), b"archived");
+    }
+
+    #[test]
+    fn test_failed_extraction_leaves_no_partial_file() {
+        let contents: Vec<u8> = (0..3 * 4096).map(|i| (i % 251) as u8).collect();
+        let mut archive = archive_with_file("big.bin", &contents);
+        // Corrupt the last data block (just before the footer) so its checksum fails
+        let trailer = &archive[archive.len() - 16..archive.len() - 8];
+        let footer_offset = u64::from_le_bytes(trailer.try_into().unwrap()) as usize;
+        archive[footer_offset - 1] ^= 0xFF;
+
+        let out = TempDir::new().unwrap();
+        fs::write(out.path().join("big.bin"), b"previous").unwrap();
+        let mut extractor =
+            ArchiveExtractor::new(Cursor::new(archive), out.path().to_path_buf()).unwrap();
+        assert!(extractor.extract_all().is_err());
+
+        // The old file is untouched and no temporary file is left over
+        assert_eq!(fs::read(out.path().join("big.bin")).unwrap(), b"previous");
+        assert_eq!(fs::read_dir(out.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_truncated_archive_fails_extraction() {
+        let contents: Vec<u8> = (0..3 * 4096).map(|i| (i % 251) as u8).collect();
+        let archive = archive_with_file("big.bin", &contents);
+        let trailer = &archive[archive.len() - 16..archive.len() - 8];
+        let footer_offset = u64::from_le_bytes(trailer.try_into().unwrap()) as usize;
+
+        // Cut in the data, in the middle, in the footer and before it
+        for cut in [12298, archive.len() / 2, footer_offset + 10, footer_offset] {
+            let out = TempDir::new().unwrap();
+            let mut extractor =
+                ArchiveExtractor::new(Cursor::new(archive[..cut].to_vec()), out.path().to_path_buf()).unwrap();
+            let error = extractor.extract_all().unwrap_err();
+            match cut < footer_offset {
+                true => assert_eq!(error.to_string(), "Archive ends in the middle of big.bin"),
+                false => assert!(format!("{:#}", error).contains("footer"), "{:#}", error),
+            }
+            // Only a member read whole is left, and no temporary file
+            let names: Vec<_> = fs::read_dir(out.path()).unwrap().map(|e| e.unwrap().file_name()).collect();
+            match cut < footer_offset {
+                true => assert!(names.is_empty(), "cut at {}: {:?}", cut, names),
+                false => assert_eq!(fs::read(out.path().join("big.bin")).unwrap(), contents),
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_only_directory_mode_deferred() {
+        let source_dir = TempDir::new().unwrap();
+        let tree = source_dir.path().join("ro");
+        fs::create_dir(&tree).unwrap();
+        fs::write(tree.join("inside.txt"), b"data").unwrap();
+        fs::set_permissions(&tree, fs::Permissions::from_mode(0o555)).unwrap();
+
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None).unwrap();
+        creator.add_directory(&tree, Path::new("")).unwrap();
+        let archive = creator.finish().unwrap().into_inner();
+        fs::set_permissions(&tree, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let out = TempDir::new().unwrap();
+        let mut extractor =
+            ArchiveExtractor::new(Cursor::new(archive), out.path().to_path_buf()).unwrap();
+        extractor.extract_all().unwrap();
+
+        let extracted = out.path().join("ro");
+        assert_eq!(fs::read(extracted.join("inside.txt")).unwrap(), b"data");
+        let mode = fs::metadata(&extracted).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(mode, 0o555);
+        fs::set_permissions(&extracted, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn test_parallel_extraction_matches_sources() {
+        let source_dir = TempDir::new().unwrap();
+        let tree = source_dir.path().join("tree");
+        fs::create_dir(&tree).unwrap();
+        let unique: Vec<u8> = (0..5 * 4096 + 123).map(|i| (i * 7 % 253) as u8).collect();
+        fs::write(tree.join("a.bin"), &unique).unwrap();
+        // b.bin repeats a.bin's blocks, so it is stored as references
+        fs::write(tree.join("b.bin"), &unique).unwrap();
+        fs::write(tree.join("small.txt"), b"tiny").unwrap();
+
+        let archive_path = source_dir.path().join("tree.reftar");
+        let mut creator = ArchiveCreator::new(File::create(&archive_path).unwrap(), None).unwrap();
+        creator.add_directory(&tree, Path::new("")).unwrap();
+        creator.finish().unwrap();
+
+        let out = TempDir::new().unwrap();
+        let mut extractor =
+            ArchiveExtractor::new(File::open(&archive_path).unwrap(), out.path().to_path_buf())
+                .unwrap();
+        extractor.extract_all_parallel(4).unwrap();
+
+        let extracted = out.path().join("tree");
+        assert_eq!(fs::read(extracted.join("a.bin")).unwrap(), unique);
+        assert_eq!(fs::read(extracted.join("b.bin")).unwrap(), unique);
+        assert_eq!(fs::read(extracted.join("small.txt")).unwrap(), b"tiny");
+        assert_eq!(fs::read_dir(&extracted).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_content_defined_chunks_roundtrip() {
+        let source_dir = TempDir::new().unwrap();
+        let tree = source_dir.path().join("dumps");
+        fs::create_dir(&tree).unwrap();
+        let mut state = 1u64;
+        let mut original: Vec<u8> = (0..512 * 1024)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect();
+        // A zero run becomes a hole that does not end on a block boundary
+        original[100_000..200_003].fill(0);
+        let mut edited = original.clone();
+        edited.splice(3000..3000, b"INSERT INTO t VALUES (1);".iter().copied());
+        fs::write(tree.join("monday.sql"), &original).unwrap();
+        fs::write(tree.join("tuesday.sql"), &edited).unwrap();
+
+        let archive_path = source_dir.path().join("dumps.reftar");
+        let create = |names: &[&str]| {
+            let mut creator = ArchiveCreator::new(File::create(&archive_path).unwrap(), None)
+                .unwrap()
+                .with_chunking(Chunking::ContentDefined { average_size: 16384 })
+                .unwrap();
+            for name in names {
+                creator.add_file(&tree.join(name), &Path::new("dumps").join(name)).unwrap();
+            }
+            creator.finish().unwrap();
+            fs::metadata(&archive_path).unwrap().len()
+        };
+
+        // The shifted copy is stored almost entirely as references
+        let single = create(&["monday.sql"]);
+        let both = create(&["monday.sql", "tuesday.sql"]);
+        assert!(both - single < original.len() as u64 / 4, "copy added {} bytes", both - single);
+
+        for jobs in [1, 4] {
+            let out = TempDir::new().unwrap();
+            let mut extractor =
+                ArchiveExtractor::new(File::open(&archive_path).unwrap(), out.path().to_path_buf())
+                    .unwrap();
+            if jobs == 1 {
+                extractor.extract_all().unwrap();
+            } else {
+                extractor.extract_all_parallel(jobs).unwrap();
+            }
+            assert_eq!(fs::read(out.path().join("dumps/monday.sql")).unwrap(), original);
+            assert_eq!(fs::read(out.path().join("dumps/tuesday.sql")).unwrap(), edited);
+        }
+    }
+
+    #[test]
+    fn test_progress_counts_dedup_and_restored_bytes() {
+        use crate::progress::ProgressCounters;
+
+        let work = TempDir::new().unwrap();
+        let data: Vec<u8> = (0..10 * 4096 + 100).map(|i| (i * 7 % 253) as u8).collect();
+        fs::write(work.path().join("a.bin"), &data).unwrap();
+        fs::write(work.path().join("b.bin"), &data).unwrap();
+
+        let counters = Arc::new(ProgressCounters::new());
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None)
+            .unwrap()
+            .with_progress(counters.clone());
+        creator.add_file(&work.path().join("a.bin"), Path::new("a.bin")).unwrap();
+        creator.add_file(&work.path().join("b.bin"), Path::new("b.bin")).unwrap();
+        let archive = creator.finish().unwrap().into_inner();
+        let archive_path = work.path().join("archive.reftar");
+        fs::write(&archive_path, &archive).unwrap();
+
+        let created = counters.totals();
+        assert_eq!(created.files, 2);
+        assert_eq!(created.bytes_read, 2 * data.len() as u64);
+        assert_eq!(created.dedup_bytes, data.len() as u64);
+        assert_eq!(created.bytes_written, archive.len() as u64);
+        assert_eq!(created.current_path.as_deref(), Some(Path::new("b.bin")));
+
+        for jobs in [1, 4] {
+            let out = TempDir::new().unwrap();
+            let counters = Arc::new(ProgressCounters::new());
+            ArchiveExtractor::new(File::open(&archive_path).unwrap(), out.path().to_path_buf())
+                .unwrap()
+                .with_progress(counters.clone())
+                .extract_all_parallel(jobs)
+                .unwrap();
+            let extracted = counters.totals();
+            assert_eq!(extracted.files, 2);
+            assert_eq!(extracted.bytes_written, 2 * data.len() as u64);
+            assert_eq!(extracted.dedup_bytes, data.len() as u64);
+            assert!(extracted.reflinked_bytes <= extracted.dedup_bytes);
+        }
+    }
+
+    #[test]
+    fn test_incremental_chain_restores_latest_tree() {
+        let work = TempDir::new().unwrap();
+        let tree = work.path().join("tree");
+        fs::create_dir_all(tree.join("sub/old")).unwrap();
+        fs::write(tree.join("keep.txt"), b"keep").unwrap();
+        fs::write(tree.join("edit.txt"), b"before").unwrap();
+        fs::write(tree.join("gone.txt"), b"gone").unwrap();
+        fs::write(tree.join("sub/move.txt"), b"move").unwrap();
+        fs::write(tree.join("sub/old/file.txt"), b"old").unwrap();
+
+        let mut snapshot = crate::incremental::Snapshot::new();
+        let mut create = |name: &str| {
+            let path = work.path().join(name);
+            let mut creator = ArchiveCreator::new(File::create(&path).unwrap(), None)
+                .unwrap()
+                .with_listed_incremental(std::mem::take(&mut snapshot));
+            creator.add_directory(&tree, Path::new("")).unwrap();
+            snapshot = creator.take_snapshot();
+            creator.finish().unwrap();
+            path
+        };
+        let level0 = create("level0.reftar");
+
+        fs::write(tree.join("edit.txt"), b"after the edit").unwrap();
+        fs::remove_file(tree.join("gone.txt")).unwrap();
+        fs::rename(tree.join("sub/move.txt"), tree.join("moved.txt")).unwrap();
+        fs::remove_dir_all(tree.join("sub/old")).unwrap();
+        let level1 = create("level1.reftar");
+
+        // Unchanged files are left out of the second archive
+        let mut members = ArchiveExtractor::new(File::open(&level1).unwrap(), work.path().to_path_buf())
+            .unwrap()
+            .list_files()
+            .unwrap();
+        members.sort();
+        assert_eq!(members, ["tree", "tree/edit.txt", "tree/moved.txt", "tree/sub"]);
+
+        for jobs in [1, 4] {
+            let out = TempDir::new().unwrap();
+            for archive in [&level0, &level1] {
+                ArchiveExtractor::new(File::open(archive).unwrap(), out.path().to_path_buf())
+                    .unwrap()
+                    .with_incremental(true)
+                    .extract_all_parallel(jobs)
+                    .unwrap();
+            }
+            let restored = out.path().join("tree");
+            assert_eq!(fs::read(restored.join("keep.txt")).unwrap(), b"keep");
+            assert_eq!(fs::read(restored.join("edit.txt")).unwrap(), b"after the edit");
+            assert_eq!(fs::read(restored.join("moved.txt")).unwrap(), b"move");
+            assert!(!restored.join("gone.txt").exists());
+            assert!(!restored.join("sub/move.txt").exists());
+            assert!(!restored.join("sub/old").exists());
+        }
+    }
+
+    #[test]
+    fn test_compressed_extents_roundtrip() {
+        use crate::compression::CompressionPolicy;
+
+        let work = TempDir::new().unwrap();
+        let tree = work.path().join("tree");
+        fs::create_dir(&tree).unwrap();
+        let text: Vec<u8> = (0..60_000).flat_map(|i: u32| format!("line {}\n", i).into_bytes()).collect();
+        fs::write(tree.join("text.txt"), &text).unwrap();
+        fs::write(tree.join("copy.txt"), &text).unwrap();
+
+        let create = |name: &str, base: Option<&Path>| {
+            let path = work.path().join(name);
+            let mut creator = ArchiveCreator::new(File::create(&path).unwrap(), None)
+                .unwrap()
+                .with_chunking(Chunking::ContentDefined { average_size: 16384 })
+                .unwrap()
+                .with_compression(CompressionPolicy::Auto);
+            if let Some(base) = base {
+                creator = creator.with_base(base).unwrap();
+            }
+            creator.add_directory(&tree, Path::new("")).unwrap();
+            creator.finish().unwrap();
+            path
+        };
+        let archive = create("full.reftar", None);
+
+        // Each distinct chunk is stored once, compressed
+        let footer = ArchiveFooter::locate(&mut File::open(&archive).unwrap()).unwrap().unwrap();
+        assert!(footer.extents.iter().all(|entry| entry.compression.is_some()));
+        assert!(fs::metadata(&archive).unwrap().len() < text.len() as u64);
+
+        // A differential archive restores compressed data from its base
+        fs::write(tree.join("new.txt"), b"new").unwrap();
+        let nightly = create("nightly.reftar", Some(&archive));
+
+        for jobs in [1, 4] {
+            for (path, base) in [(&archive, None), (&nightly, Some(&archive))] {
+                let out = TempDir::new().unwrap();
+                let mut extractor =
+                    ArchiveExtractor::new(File::open(path).unwrap(), out.path().to_path_buf()).unwrap();
+                if let Some(base) = base {
+                    extractor = extractor.with_base(base).unwrap();
+                }
+                extractor.extract_all_parallel(jobs).unwrap();
+                assert_eq!(fs::read(out.path().join("tree/text.txt")).unwrap(), text);
+                assert_eq!(fs::read(out.path().join("tree/copy.txt")).unwrap(), text);
+            }
+        }
+    }
+
+    #[test]
+    fn test_differential_archive_against_base() {
+        let work = TempDir::new().unwrap();
+        let tree = work.path().join("tree");
+        fs::create_dir(&tree).unwrap();
+        let big: Vec<u8> = (0..40 * 4096).map(|i| (i * 13 % 251) as u8).collect();
+        fs::write(tree.join("big.bin"), &big).unwrap();
+        fs::write(tree.join("notes.txt"), vec![b'n'; 6000]).unwrap();
+
+        let create = |name: &str, base: Option<&Path>| {
+            let path = work.path().join(name);
+            let mut creator = ArchiveCreator::new(File::create(&path).unwrap(), None).unwrap();
+            if let Some(base) = base {
+                creator = creator.with_base(base).unwrap();
+            }
+            creator.add_directory(&tree, Path::new("")).unwrap();
+            creator.finish().unwrap();
+            path
+        };
+        let full = create("full.reftar", None);
+
+        // Change one block of big.bin and add a file
+        let mut changed = big.clone();
+        changed[5 * 4096..6 * 4096].fill(0xAA);
+        fs::write(tree.join("big.bin"), &changed).unwrap();
+        fs::write(tree.join("new.txt"), b"new").unwrap();
+        let nightly = create("nightly.reftar", Some(&full));
+
+        // Only the changed block is stored again, and unchanged runs of
+        // blocks take one header each
+        let footer = ArchiveFooter::locate(&mut File::open(&nightly).unwrap()).unwrap().unwrap();
+        assert_eq!(footer.extents.len(), 1);
+        assert_eq!(footer.bases.len(), 1);
+        assert!(fs::metadata(&nightly).unwrap().len() <= 12 * 4096);
+
+        for jobs in [1, 4] {
+            let out = TempDir::new().unwrap();
+            let mut extractor =
+                ArchiveExtractor::new(File::open(&nightly).unwrap(), out.path().to_path_buf())
+                    .unwrap()
+                    .with_base(&full)
+                    .unwrap();
+            extractor.extract_all_parallel(jobs).unwrap();
+            assert_eq!(fs::read(out.path().join("tree/big.bin")).unwrap(), changed);
+            assert_eq!(fs::read(out.path().join("tree/notes.txt")).unwrap(), vec![b'n'; 6000]);
+            assert_eq!(fs::read(out.path().join("tree/new.txt")).unwrap(), b"new");
+        }
+
+        // Without the base the data cannot be restored
+        let out = TempDir::new().unwrap();
+        let mut extractor =
+            ArchiveExtractor::new(File::open(&nightly).unwrap(), out.path().to_path_buf()).unwrap();
+        let error = extractor.extract_all().unwrap_err();
+        assert!(error.to_string().contains("needs base archive"), "{}", error);
+    }
+
+    #[test]
+    fn test_strip_components_and_unsafe_paths() {
+        let source_dir = TempDir::new().unwrap();
+        fs::write(source_dir.path().join("big.bin"), [3u8; 3 * 4096]).unwrap();
+        fs::write(source_dir.path().join("small.txt"), b"tiny").unwrap();
+
+        let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None).unwrap();
+        creator
+            .add_file(&source_dir.path().join("big.bin"), Path::new("top/big.bin"))
+            .unwrap();
+        creator
+            .add_file(&source_dir.path().join("small.txt"), Path::new("../escape.txt"))
+            .unwrap();
+        let archive = creator.finish().unwrap().into_inner();
+
+        let out = TempDir::new().unwrap();
+        let target = out.path().join("target");
+        let rewrite = PathRewrite::new().strip_components(1);
+        let mut extractor = ArchiveExtractor::new(Cursor::new(archive), target.clone())
+            .unwrap()
+            .with_path_rewrite(rewrite);
+        extractor.extract_all().unwrap();
+
+        assert_eq!(fs::read(target.join("big.bin")).unwrap(), [3u8; 3 * 4096]);
+        assert!(!target.join("top").exists());
+        // "../escape.txt" loses its first component rather than escaping
+        assert_eq!(fs::read(target.join("escape.txt")).unwrap(), b"tiny");
+        assert!(!out.path().join("escape.txt").exists());
+    }
 }
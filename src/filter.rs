@@ -0,0 +1,344 @@
+//! Include/exclude filtering for archive creation
+
+use anyhow::{Context, Result};
+use glob::{MatchOptions, Pattern};
+use std::fs;
+use std::path::Path;
+
+/// Per-directory ignore file read while walking inputs
+pub const IGNORE_FILE_NAME: &str = ".reftarignore";
+
+/// Tag file marking a cache directory (https://bford.info/cachedir/)
+pub const CACHEDIR_TAG_NAME: &str = "CACHEDIR.TAG";
+
+/// Header every valid CACHEDIR.TAG starts with
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Version control directories and files skipped by `--exclude-vcs`
+pub const VCS_NAMES: &[&str] = &[
+    ".git",
+    ".gitignore",
+    ".gitattributes",
+    ".gitmodules",
+    ".svn",
+    ".hg",
+    ".hgignore",
+    ".hgtags",
+    ".bzr",
+    ".bzrignore",
+    ".bzrtags",
+    "CVS",
+    ".cvsignore",
+    "RCS",
+    "SCCS",
+    "_darcs",
+    ".arch-ids",
+    "{arch}",
+];
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: true,
+    require_literal_leading_dot: false,
+};
+
+/// A single glob pattern
+#[derive(Debug, Clone)]
+struct Rule {
+    pattern: Pattern,
+    negated: bool,  // `!pattern` in an ignore file re-includes matches
+    dir_only: bool, // `pattern/` only matches directories
+    anchored: bool, // Match the whole path rather than any trailing components
+}
+
+impl Rule {
+    /// Parse a command line pattern; a leading `/` anchors it to the archive root
+    fn command_line(pattern: &str) -> Result<Self> {
+        let anchored = pattern.starts_with('/');
+        let trimmed = pattern.trim_start_matches('/');
+        let dir_only = trimmed.ends_with('/');
+        Ok(Self {
+            pattern: Pattern::new(trimmed.trim_end_matches('/'))
+                .with_context(|| format!("Invalid pattern {:?}", pattern))?,
+            negated: false,
+            dir_only,
+            anchored,
+        })
+    }
+
+    /// Parse a line of an ignore file, using gitignore conventions
+    fn ignore_line(line: &str) -> Result<Option<Self>> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+
+        let (negated, line) = match line.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, line),
+        };
+        let dir_only = line.ends_with('/');
+        let line = line.trim_end_matches('/');
+        // A slash anywhere but the end anchors the pattern to the ignore file's directory
+        let anchored = line.contains('/');
+        let line = line.trim_start_matches('/');
+
+        Ok(Some(Self {
+            pattern: Pattern::new(line).with_context(|| format!("Invalid pattern {:?}", line))?,
+            negated,
+            dir_only,
+            anchored,
+        }))
+    }
+
+    fn matches(&self, path: &str, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.pattern.matches_with(path, MATCH_OPTIONS) {
+            return true;
+        }
+        if self.anchored {
+            return false;
+        }
+
+        // Unanchored patterns may match any trailing run of components
+        path.match_indices('/')
+            .any(|(i, _)| self.pattern.matches_with(&path[i + 1..], MATCH_OPTIONS))
+    }
+}
+
+/// Rules read from an ignore file, relative to the directory holding it
+#[derive(Debug, Clone)]
+pub struct IgnoreRules {
+    base: String,
+    rules: Vec<Rule>,
+}
+
+impl IgnoreRules {
+    /// Read `dir`'s ignore file, if it has one; `archive_dir` is its path in the archive
+    pub fn load(dir: &Path, archive_dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(IGNORE_FILE_NAME);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", path)),
+        };
+
+        let mut rules = Vec::new();
+        for line in contents.lines() {
+            if let Some(rule) = Rule::ignore_line(line)? {
+                rules.push(rule);
+            }
+        }
+
+        Ok(Some(Self {
+            base: archive_dir.to_string_lossy().into_owned(),
+            rules,
+        }))
+    }
+
+    /// Whether the last matching rule excludes (`Some(true)`) or re-includes
+    /// (`Some(false)`) an entry, or `None` if no rule matches
+    pub fn decision(&self, archive_path: &Path, is_dir: bool) -> Option<bool> {
+        let path = archive_path.to_string_lossy();
+        let relative = if self.base.is_empty() {
+            &path[..]
+        } else {
+            path.strip_prefix(self.base.as_str())?.strip_prefix('/')?
+        };
+
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(relative, is_dir))
+            .map(|rule| !rule.negated)
+    }
+}
+
+/// Filters applied to entries found while walking input directories
+#[derive(Debug, Clone)]
+pub struct PathFilter {
+    excludes: Vec<Rule>,
+    includes: Vec<Rule>,
+    exclude_caches: bool,
+    exclude_vcs: bool,
+    one_file_system: bool,
+    ignore_files: bool,
+}
+
+impl Default for PathFilter {
+    fn default() -> Self {
+        Self {
+            excludes: Vec::new(),
+            includes: Vec::new(),
+            exclude_caches: false,
+            exclude_vcs: false,
+            one_file_system: false,
+            ignore_files: true,
+        }
+    }
+}
+
+impl PathFilter {
+    /// Create a filter that only honours ignore files
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Skip entries matching a glob pattern
+    ///
+    /// Patterns match any trailing run of path components unless they start
+    /// with `/`, which anchors them to the archive root.
+    pub fn exclude(mut self, pattern: &str) -> Result<Self> {
+        self.excludes.push(Rule::command_line(pattern)?);
+        Ok(self)
+    }
+
+    /// Only archive files matching one of the include patterns
+    ///
+    /// Directories are still walked; an include also overrides an exclude.
+    pub fn include(mut self, pattern: &str) -> Result<Self> {
+        self.includes.push(Rule::command_line(pattern)?);
+        Ok(self)
+    }
+
+    /// Read exclude patterns from a file, one per line (`#` starts a comment)
+    pub fn exclude_from(mut self, path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read exclude file {:?}", path))?;
+        for line in contents.lines() {
+            let line = line.trim_end();
+            if !line.is_empty() && !line.starts_with('#') {
+                self = self.exclude(line)?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// Skip the contents of directories holding a valid CACHEDIR.TAG
+    pub fn exclude_caches(mut self, enabled: bool) -> Self {
+        self.exclude_caches = enabled;
+        self
+    }
+
+    /// Skip version control directories and files
+    pub fn exclude_vcs(mut self, enabled: bool) -> Self {
+        self.exclude_vcs = enabled;
+        self
+    }
+
+    /// Do not descend into directories on other filesystems
+    pub fn one_file_system(mut self, enabled: bool) -> Self {
+        self.one_file_system = enabled;
+        self
+    }
+
+    /// Read `.reftarignore` files in walked directories (on by default)
+    pub fn ignore_files(mut self, enabled: bool) -> Self {
+        self.ignore_files = enabled;
+        self
+    }
+
+    pub fn stays_on_file_system(&self) -> bool {
+        self.one_file_system
+    }
+
+    pub fn reads_ignore_files(&self) -> bool {
+        self.ignore_files
+    }
+
+    /// Whether a directory's contents are skipped as a cache
+    pub fn is_cache_directory(&self, dir: &Path) -> bool {
+        self.exclude_caches && has_cachedir_tag(dir)
+    }
+
+    /// Whether the patterns and VCS rule exclude an entry
+    pub fn is_excluded(&self, archive_path: &Path, is_dir: bool) -> bool {
+        if self.exclude_vcs {
+            let name = archive_path.file_name().and_then(|name| name.to_str());
+            if name.is_some_and(|name| VCS_NAMES.contains(&name)) {
+                return true;
+            }
+        }
+
+        let path = archive_path.to_string_lossy();
+        let included = self.includes.iter().any(|rule| rule.matches(&path, is_dir));
+        if self.excludes.iter().any(|rule| rule.matches(&path, is_dir)) && !included {
+            return true;
+        }
+
+        !self.includes.is_empty() && !is_dir && !included
+    }
+}
+
+/// Check for a CACHEDIR.TAG file with the standard signature
+fn has_cachedir_tag(dir: &Path) -> bool {
+    use std::io::Read;
+
+    let mut signature = [0u8; CACHEDIR_TAG_SIGNATURE.len()];
+    fs::File::open(dir.join(CACHEDIR_TAG_NAME))
+        .and_then(|mut file| file.read_exact(&mut signature))
+        .is_ok_and(|_| signature == CACHEDIR_TAG_SIGNATURE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exclude_patterns() {
+        let filter = PathFilter::new()
+            .exclude("*.log")
+            .unwrap()
+            .exclude("/top/build/")
+            .unwrap();
+
+        assert!(filter.is_excluded(Path::new("top/a.log"), false));
+        assert!(filter.is_excluded(Path::new("top/deep/b.log"), false));
+        assert!(!filter.is_excluded(Path::new("top/a.txt"), false));
+        assert!(filter.is_excluded(Path::new("top/build"), true));
+        assert!(!filter.is_excluded(Path::new("top/build"), false));
+        assert!(!filter.is_excluded(Path::new("top/sub/build"), true));
+    }
+
+    #[test]
+    fn test_include_patterns() {
+        let filter = PathFilter::new()
+            .include("*.rs")
+            .unwrap()
+            .exclude("target")
+            .unwrap()
+            .exclude("*.rs.bak")
+            .unwrap()
+            .exclude_vcs(true);
+
+        assert!(!filter.is_excluded(Path::new("src/main.rs"), false));
+        assert!(filter.is_excluded(Path::new("src/README.md"), false));
+        assert!(!filter.is_excluded(Path::new("src"), true));
+        assert!(filter.is_excluded(Path::new("target"), true));
+        assert!(filter.is_excluded(Path::new("src/main.rs.bak"), false));
+        assert!(filter.is_excluded(Path::new("src/.git"), true));
+    }
+
+    #[test]
+    fn test_ignore_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join(IGNORE_FILE_NAME),
+            "# comment\n*.tmp\n!keep.tmp\n/out/\ndocs/*.pdf\n",
+        )
+        .unwrap();
+
+        let rules = IgnoreRules::load(dir.path(), Path::new("proj"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(rules.decision(Path::new("proj/a/x.tmp"), false), Some(true));
+        assert_eq!(rules.decision(Path::new("proj/keep.tmp"), false), Some(false));
+        assert_eq!(rules.decision(Path::new("proj/out"), true), Some(true));
+        assert_eq!(rules.decision(Path::new("proj/a/out"), true), None);
+        assert_eq!(rules.decision(Path::new("proj/docs/a.pdf"), false), Some(true));
+        assert_eq!(rules.decision(Path::new("proj/a/docs/a.pdf"), false), None);
+        assert_eq!(rules.decision(Path::new("other/x.tmp"), false), None);
+    }
+}
@@ -5,14 +5,15 @@
 //! - File headers
 //! - Extent headers and data blocks
 
-use std::io::{self, Read, Write};
-use anyhow::Result;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use anyhow::{Context, Result};
 
 /// Magic bytes at the start of every reftar archive
 pub const REFTAR_MAGIC: &[u8; 6] = b"reftar";
 
 /// Current archive format version
-pub const REFTAR_VERSION: u16 = 1;
+pub const REFTAR_VERSION: u16 = 2;
 
 /// Default block size (4KB)
 pub const DEFAULT_BLOCK_SIZE: u32 = 4096;
@@ -20,11 +21,184 @@ pub const DEFAULT_BLOCK_SIZE: u32 = 4096;
 /// File header magic bytes
 pub const FILE_HEADER_MAGIC: &[u8; 4] = b"FILE";
 
+/// Archive footer magic bytes
+pub const FOOTER_MAGIC: &[u8; 4] = b"RTIX";
+
+/// Magic bytes closing the footer trailer at the very end of the archive
+pub const FOOTER_TRAILER_MAGIC: &[u8; 8] = b"reftarFT";
+
+/// Magic bytes closing the trailer of parity appended to an archive, or of
+/// a parity file
+pub const PARITY_TRAILER_MAGIC: &[u8; 8] = b"reftarPR";
+
+/// Parity trailer: protected length, parity index offset, magic
+pub const PARITY_TRAILER_SIZE: usize = 8 + 8 + 8;
+
+/// magic + version + block_size + flags + chunk_size
+const ARCHIVE_HEADER_FIXED_SIZE: usize = 6 + 2 + 4 + 4 + 4;
+
+/// Archive flag: file data was split into content-defined chunks
+pub const ARCHIVE_FLAG_CONTENT_CHUNKS: u32 = 1 << 0;
+
+/// Archive flag: some Data extents are stored compressed
+pub const ARCHIVE_FLAG_COMPRESSED_EXTENTS: u32 = 1 << 1;
+
+/// Archive flag: records and file data are sealed, see `EncryptionHeader`
+pub const ARCHIVE_FLAG_ENCRYPTED: u32 = 1 << 2;
+
+/// Archive flag: every file and extent header carries a checksum record
+pub const ARCHIVE_FLAG_HEADER_CHECKSUMS: u32 = 1 << 3;
+
+/// Archive flags this version understands
+const KNOWN_ARCHIVE_FLAGS: u32 = ARCHIVE_FLAG_CONTENT_CHUNKS
+    | ARCHIVE_FLAG_COMPRESSED_EXTENTS
+    | ARCHIVE_FLAG_ENCRYPTED
+    | ARCHIVE_FLAG_HEADER_CHECKSUMS;
+
+/// Magic bytes opening a sealed record in an encrypted archive
+pub const SEALED_RECORD_MAGIC: &[u8; 4] = b"SEAL";
+
+/// Cipher of encrypted archives: XChaCha20-Poly1305
+const CIPHER_XCHACHA20_POLY1305: u8 = 1;
+
+/// Encryption option: file headers and the footer are authenticated but
+/// left readable, so the archive can be listed without a key
+const ENCRYPTION_OPTION_CLEAR_METADATA: u8 = 1 << 0;
+
+/// Key slot kinds
+const KEY_SLOT_PASSPHRASE: u8 = 1;
+const KEY_SLOT_KEY_FILE: u8 = 2;
+
 /// Archive header structure
 #[derive(Debug, Clone)]
 pub struct ArchiveHeader {
     pub version: u16,
     pub block_size: u32,
+    pub flags: u32,      // Feature flags (version 2+, zero before)
+    pub chunk_size: u32, // Average content-defined chunk size, 0 for fixed blocks
+    pub encryption: Option<EncryptionHeader>, // Set with ARCHIVE_FLAG_ENCRYPTED
+}
+
+/// Encryption parameters of an encrypted archive
+///
+/// The archive's master key is random; each key slot holds it wrapped by a
+/// key derived from one passphrase or key file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptionHeader {
+    /// File headers and the footer are authenticated only, not encrypted
+    pub clear_metadata: bool,
+    pub key_slots: Vec<KeySlot>,
+}
+
+/// One wrapped copy of an archive's master key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeySlot {
+    pub kind: KeySlotKind,
+    pub nonce: [u8; 24],
+    /// The master key encrypted with the slot's key, followed by the tag
+    pub wrapped_key: [u8; 48],
+}
+
+/// How the key of a key slot is derived
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySlotKind {
+    /// Argon2id over a passphrase
+    Passphrase {
+        salt: [u8; 16],
+        memory_kib: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+    /// BLAKE3 key derivation over the contents of a key file
+    KeyFile,
+}
+
+impl KeySlot {
+    /// The slot's kind and derivation parameters, as serialized. The wrapped
+    /// key is authenticated together with these.
+    pub fn parameters(&self) -> Vec<u8> {
+        match self.kind {
+            KeySlotKind::Passphrase {
+                salt,
+                memory_kib,
+                iterations,
+                parallelism,
+            } => {
+                let mut parameters = vec![KEY_SLOT_PASSPHRASE];
+                parameters.extend_from_slice(&salt);
+                parameters.extend_from_slice(&memory_kib.to_le_bytes());
+                parameters.extend_from_slice(&iterations.to_le_bytes());
+                parameters.extend_from_slice(&parallelism.to_le_bytes());
+                parameters
+            }
+            KeySlotKind::KeyFile => vec![KEY_SLOT_KEY_FILE],
+        }
+    }
+
+    fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut kind = [0u8; 1];
+        reader.read_exact(&mut kind)?;
+        let kind = match kind[0] {
+            KEY_SLOT_PASSPHRASE => {
+                let mut salt = [0u8; 16];
+                reader.read_exact(&mut salt)?;
+                KeySlotKind::Passphrase {
+                    salt,
+                    memory_kib: read_u32(reader)?,
+                    iterations: read_u32(reader)?,
+                    parallelism: read_u32(reader)?,
+                }
+            }
+            KEY_SLOT_KEY_FILE => KeySlotKind::KeyFile,
+            other => anyhow::bail!("Unsupported key slot kind {}", other),
+        };
+        let mut slot = Self {
+            kind,
+            nonce: [0; 24],
+            wrapped_key: [0; 48],
+        };
+        reader.read_exact(&mut slot.nonce)?;
+        reader.read_exact(&mut slot.wrapped_key)?;
+        Ok(slot)
+    }
+}
+
+impl EncryptionHeader {
+    fn encode(&self) -> Result<Vec<u8>> {
+        if self.key_slots.is_empty() || self.key_slots.len() > u8::MAX as usize {
+            anyhow::bail!("An encrypted archive needs 1 to {} key slots", u8::MAX);
+        }
+        let options = if self.clear_metadata { ENCRYPTION_OPTION_CLEAR_METADATA } else { 0 };
+        let mut encoded = vec![CIPHER_XCHACHA20_POLY1305, options, self.key_slots.len() as u8];
+        for slot in &self.key_slots {
+            encoded.extend_from_slice(&slot.parameters());
+            encoded.extend_from_slice(&slot.nonce);
+            encoded.extend_from_slice(&slot.wrapped_key);
+        }
+        Ok(encoded)
+    }
+
+    fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut fields = [0u8; 3];
+        reader.read_exact(&mut fields)?;
+        let [cipher, options, slot_count] = fields;
+        if cipher != CIPHER_XCHACHA20_POLY1305 {
+            anyhow::bail!("Unsupported archive cipher {}", cipher);
+        }
+        if options & !ENCRYPTION_OPTION_CLEAR_METADATA != 0 {
+            anyhow::bail!("Unsupported encryption options 0x{:X}", options);
+        }
+        let key_slots = (0..slot_count)
+            .map(|_| KeySlot::read(reader))
+            .collect::<Result<Vec<_>>>()?;
+        if key_slots.is_empty() {
+            anyhow::bail!("Encrypted archive has no key slots");
+        }
+        Ok(Self {
+            clear_metadata: options & ENCRYPTION_OPTION_CLEAR_METADATA != 0,
+            key_slots,
+        })
+    }
 }
 
 impl ArchiveHeader {
@@ -32,27 +206,36 @@ impl ArchiveHeader {
         Self {
             version: REFTAR_VERSION,
             block_size,
+            flags: 0,
+            chunk_size: 0,
+            encryption: None,
         }
     }
 
     /// Write the archive header to a writer
     pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
-        // Write magic bytes
-        writer.write_all(REFTAR_MAGIC)?;
-
-        // Write version (2 bytes, little endian)
-        writer.write_all(&self.version.to_le_bytes())?;
-
-        // Write block size (4 bytes, little endian)
-        writer.write_all(&self.block_size.to_le_bytes())?;
-
-        // Calculate padding needed to reach block boundary
-        let header_size = 6 + 2 + 4; // magic + version + block_size
-        let padding = (self.block_size as usize - header_size) % self.block_size as usize;
+        let mut header = Vec::with_capacity(self.block_size as usize);
+        header.extend_from_slice(REFTAR_MAGIC);
+        header.extend_from_slice(&self.version.to_le_bytes());
+        header.extend_from_slice(&self.block_size.to_le_bytes());
+
+        // Flags and chunk size occupy what was padding in version 1
+        header.extend_from_slice(&self.flags.to_le_bytes());
+        header.extend_from_slice(&self.chunk_size.to_le_bytes());
+
+        // The encryption parameters follow when the archive is encrypted
+        match (&self.encryption, self.flags & ARCHIVE_FLAG_ENCRYPTED != 0) {
+            (Some(encryption), true) => header.extend_from_slice(&encryption.encode()?),
+            (None, false) => {}
+            _ => anyhow::bail!("Archive encryption flag and parameters disagree"),
+        }
+        if header.len() > self.block_size as usize {
+            anyhow::bail!("Archive header does not fit in a {} byte block", self.block_size);
+        }
 
-        // Write padding
-        let padding_buf = vec![0u8; padding];
-        writer.write_all(&padding_buf)?;
+        // Pad to block boundary
+        header.resize(self.block_size as usize, 0);
+        writer.write_all(&header)?;
 
         Ok(())
     }
@@ -76,20 +259,47 @@ impl ArchiveHeader {
         reader.read_exact(&mut block_size_buf)?;
         let block_size = u32::from_le_bytes(block_size_buf);
 
-        // Skip padding to block boundary
-        let header_size = 6 + 2 + 4;
+        if version == 0 || version > REFTAR_VERSION {
+            anyhow::bail!("Unsupported reftar format version: {}", version);
+        }
+
+        let flags = read_u32(reader)?;
+        let chunk_size = read_u32(reader)?;
+        if flags & !KNOWN_ARCHIVE_FLAGS != 0 {
+            anyhow::bail!("Unsupported archive features (flags 0x{:X})", flags & !KNOWN_ARCHIVE_FLAGS);
+        }
+
+        // The rest of the block is padding, after the encryption parameters
+        // of encrypted archives
+        let header_size = ARCHIVE_HEADER_FIXED_SIZE;
         let padding = (block_size as usize - header_size) % block_size as usize;
         let mut padding_buf = vec![0u8; padding];
         reader.read_exact(&mut padding_buf)?;
+        let encryption = if flags & ARCHIVE_FLAG_ENCRYPTED != 0 {
+            let mut rest = &padding_buf[..];
+            let encryption = EncryptionHeader::read(&mut rest)?;
+            // Seals cover the header as written, without this padding, so
+            // altered padding is only caught here
+            if rest.iter().any(|&byte| byte != 0) {
+                anyhow::bail!("Nonzero padding after the encryption parameters of the archive header");
+            }
+            Some(encryption)
+        } else {
+            None
+        };
 
         Ok(Self {
             version,
             block_size,
+            flags,
+            chunk_size,
+            encryption,
         })
     }
 }
 
 /// File type indicator (compatible with tar)
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum FileType {
@@ -137,10 +347,113 @@ pub struct FileHeader {
     pub extended_permissions: Vec<u8>,
     pub source_filesystem_type: String,
     pub source_filesystem_id: u64,
+    pub mode: Option<u32>, // Permission bits (format version 2+)
+    pub directory_listing: Option<Vec<String>>, // Entry names, in incremental archives
+    pub xattrs: Vec<(String, Vec<u8>)>, // Extended attributes, by name
+    pub time_nanos: TimeNanos, // Sub-second parts of the times
+    pub data_seal: Option<DataSeal>, // How the inline data is encrypted
     pub inline_data: Vec<u8>, // For files under block size
 }
 
+/// Nanoseconds past the whole second of a file header's access, modify and
+/// creation times
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimeNanos {
+    pub access: u32,
+    pub modify: u32,
+    pub creation: u32,
+}
+
+impl TimeNanos {
+    fn encode(&self) -> Vec<u8> {
+        [self.access, self.modify, self.creation].iter().flat_map(|nanos| nanos.to_le_bytes()).collect()
+    }
+
+    fn decode(value: &[u8]) -> Result<Self> {
+        let nanos: Vec<u32> = value
+            .chunks_exact(4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            .collect();
+        if value.len() != 12 || nanos.iter().any(|&nanos| nanos >= 1_000_000_000) {
+            anyhow::bail!("Invalid sub-second times extension");
+        }
+        Ok(Self {
+            access: nanos[0],
+            modify: nanos[1],
+            creation: nanos[2],
+        })
+    }
+}
+
+/// Nonce and authentication tag of file data encrypted in place
+///
+/// Encrypted data keeps its size, so that extents stay block-aligned; what
+/// is needed to open it is kept in the (sealed) header describing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataSeal {
+    pub nonce: [u8; 24],
+    pub tag: [u8; 16],
+}
+
+impl DataSeal {
+    fn encode(&self) -> Vec<u8> {
+        [&self.nonce[..], &self.tag[..]].concat()
+    }
+
+    fn decode(value: &[u8]) -> Result<Self> {
+        if value.len() != 24 + 16 {
+            anyhow::bail!("Invalid data seal length {}", value.len());
+        }
+        let mut seal = Self {
+            nonce: [0; 24],
+            tag: [0; 16],
+        };
+        seal.nonce.copy_from_slice(&value[..24]);
+        seal.tag.copy_from_slice(&value[24..]);
+        Ok(seal)
+    }
+}
+
+/// Tags for the optional records in the file header extension area
+const FILE_EXT_MODE: u16 = 1;
+const FILE_EXT_DIRECTORY_LISTING: u16 = 2;
+const FILE_EXT_DATA_SEAL: u16 = 3;
+const FILE_EXT_CHECKSUMS: u16 = 4;
+const FILE_EXT_XATTRS: u16 = 5;
+const FILE_EXT_TIME_NANOS: u16 = 6;
+
+/// Regions of a file header with their own checksum, so that a mismatch
+/// says what is corrupted
+const FILE_HEADER_REGIONS: [&str; 10] = [
+    "header size",
+    "file size and type",
+    "owner and device numbers",
+    "timestamps",
+    "user and group names",
+    "path and link target",
+    "extended permissions",
+    "source filesystem",
+    "extension records",
+    "inline data",
+];
+
 impl FileHeader {
+    /// Length of a serialized file header before its block padding, from
+    /// the header size it records
+    pub fn record_length(record: &[u8]) -> Option<usize> {
+        let size = record.get(4..8)?;
+        Some(u32::from_le_bytes(size.try_into().unwrap()) as usize)
+    }
+
+    /// Full path of the member within the archive
+    pub fn member_path(&self) -> String {
+        if self.file_path.is_empty() {
+            self.file_name.clone()
+        } else {
+            format!("{}/{}", self.file_path, self.file_name)
+        }
+    }
+
     /// Calculate the total size of the file header when serialized
     fn calculate_size(&self) -> u32 {
         let mut size = 0u32;
@@ -163,61 +476,159 @@ impl FileHeader {
         size += 4 + self.extended_permissions.len() as u32; // extended_permissions
         size += 128; // source_filesystem_type (fixed 128 bytes)
         size += 8; // source_filesystem_id
+        size += 6 + 4 * FILE_HEADER_REGIONS.len() as u32; // checksum record
+        size += self.extensions().iter().map(|(_, v)| 6 + v.len() as u32).sum::<u32>(); // extensions
         size += self.inline_data.len() as u32; // inline data
         size
     }
 
+    /// Serialize the optional fields as (tag, value) extension records
+    fn extensions(&self) -> Vec<(u16, Vec<u8>)> {
+        let mut extensions = Vec::new();
+        if let Some(mode) = self.mode {
+            extensions.push((FILE_EXT_MODE, mode.to_le_bytes().to_vec()));
+        }
+        if let Some(names) = &self.directory_listing {
+            // Each name is NUL-terminated
+            let mut listing = Vec::new();
+            for name in names {
+                listing.extend_from_slice(name.as_bytes());
+                listing.push(0);
+            }
+            extensions.push((FILE_EXT_DIRECTORY_LISTING, listing));
+        }
+        if let Some(seal) = &self.data_seal {
+            extensions.push((FILE_EXT_DATA_SEAL, seal.encode()));
+        }
+        if !self.xattrs.is_empty() {
+            // Each name is NUL-terminated, and its value length-prefixed
+            let mut xattrs = Vec::new();
+            for (name, value) in &self.xattrs {
+                xattrs.extend_from_slice(name.as_bytes());
+                xattrs.push(0);
+                xattrs.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                xattrs.extend_from_slice(value);
+            }
+            extensions.push((FILE_EXT_XATTRS, xattrs));
+        }
+        if self.time_nanos != TimeNanos::default() {
+            extensions.push((FILE_EXT_TIME_NANOS, self.time_nanos.encode()));
+        }
+        extensions
+    }
+
+    /// Apply extension records read from a header, skipping unknown tags
+    fn apply_extension(&mut self, tag: u16, value: &[u8]) -> Result<()> {
+        match tag {
+            FILE_EXT_MODE => {
+                let bytes: [u8; 4] = value
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("Invalid mode extension length"))?;
+                self.mode = Some(u32::from_le_bytes(bytes));
+            }
+            FILE_EXT_DIRECTORY_LISTING => {
+                let names = value
+                    .split_inclusive(|&b| b == 0)
+                    .map(|name| match name.strip_suffix(&[0]) {
+                        Some(name) => Ok(String::from_utf8(name.to_vec())?),
+                        None => anyhow::bail!("Unterminated name in directory listing"),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                self.directory_listing = Some(names);
+            }
+            FILE_EXT_DATA_SEAL => self.data_seal = Some(DataSeal::decode(value)?),
+            FILE_EXT_TIME_NANOS => self.time_nanos = TimeNanos::decode(value)?,
+            FILE_EXT_XATTRS => {
+                let mut rest = value;
+                while !rest.is_empty() {
+                    let Some(end) = rest.iter().position(|&b| b == 0) else {
+                        anyhow::bail!("Unterminated extended attribute name");
+                    };
+                    let name = String::from_utf8(rest[..end].to_vec())?;
+                    let length = rest
+                        .get(end + 1..end + 5)
+                        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+                        .ok_or_else(|| anyhow::anyhow!("Truncated extended attribute {:?}", name))?;
+                    let Some(value) = rest.get(end + 5..end + 5 + length) else {
+                        anyhow::bail!("Truncated extended attribute {:?}", name);
+                    };
+                    self.xattrs.push((name, value.to_vec()));
+                    rest = &rest[end + 5 + length..];
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
     /// Write the file header to a writer
     pub fn write<W: Write>(&self, writer: &mut W, block_size: u32) -> Result<()> {
+        // The header is assembled in memory to checksum it
+        let header_size = self.calculate_size();
+        let mut bytes = Vec::with_capacity(header_size as usize);
+
         // Write magic
-        writer.write_all(FILE_HEADER_MAGIC)?;
+        bytes.write_all(FILE_HEADER_MAGIC)?;
 
-        // Calculate and write header size
-        let header_size = self.calculate_size();
-        writer.write_all(&header_size.to_le_bytes())?;
+        // Write header size
+        bytes.write_all(&header_size.to_le_bytes())?;
 
         // Write file size (12 bytes for u128, but we use first 12 bytes)
         let file_size_bytes = self.file_size.to_le_bytes();
-        writer.write_all(&file_size_bytes[..12])?;
+        bytes.write_all(&file_size_bytes[..12])?;
 
         // Write file type
-        writer.write_all(&[self.file_type as u8])?;
+        bytes.write_all(&[self.file_type as u8])?;
 
         // Write metadata
-        writer.write_all(&self.uid.to_le_bytes())?;
-        writer.write_all(&self.gid.to_le_bytes())?;
-        writer.write_all(&self.device_major.to_le_bytes())?;
-        writer.write_all(&self.device_minor.to_le_bytes())?;
-        writer.write_all(&self.access_time.to_le_bytes())?;
-        writer.write_all(&self.modify_time.to_le_bytes())?;
-        writer.write_all(&self.creation_time.to_le_bytes())?;
+        bytes.write_all(&self.uid.to_le_bytes())?;
+        bytes.write_all(&self.gid.to_le_bytes())?;
+        bytes.write_all(&self.device_major.to_le_bytes())?;
+        bytes.write_all(&self.device_minor.to_le_bytes())?;
+        bytes.write_all(&self.access_time.to_le_bytes())?;
+        bytes.write_all(&self.modify_time.to_le_bytes())?;
+        bytes.write_all(&self.creation_time.to_le_bytes())?;
 
         // Write strings with length prefix
-        write_length_prefixed_string(writer, &self.username)?;
-        write_length_prefixed_string(writer, &self.groupname)?;
-        write_length_prefixed_string(writer, &self.file_path)?;
-        write_length_prefixed_string(writer, &self.file_name)?;
-        write_length_prefixed_string(writer, &self.link_name)?;
+        write_length_prefixed_string(&mut bytes, &self.username)?;
+        write_length_prefixed_string(&mut bytes, &self.groupname)?;
+        write_length_prefixed_string(&mut bytes, &self.file_path)?;
+        write_length_prefixed_string(&mut bytes, &self.file_name)?;
+        write_length_prefixed_string(&mut bytes, &self.link_name)?;
 
         // Write extended permissions with length prefix
-        writer.write_all(&(self.extended_permissions.len() as u32).to_le_bytes())?;
-        writer.write_all(&self.extended_permissions)?;
+        bytes.write_all(&(self.extended_permissions.len() as u32).to_le_bytes())?;
+        bytes.write_all(&self.extended_permissions)?;
 
         // Write source filesystem type (fixed 128 bytes)
         let mut fs_type_buf = [0u8; 128];
         let fs_type_bytes = self.source_filesystem_type.as_bytes();
         let copy_len = fs_type_bytes.len().min(128);
         fs_type_buf[..copy_len].copy_from_slice(&fs_type_bytes[..copy_len]);
-        writer.write_all(&fs_type_buf)?;
+        bytes.write_all(&fs_type_buf)?;
 
         // Write source filesystem ID
-        writer.write_all(&self.source_filesystem_id.to_le_bytes())?;
+        bytes.write_all(&self.source_filesystem_id.to_le_bytes())?;
+
+        // Write extension records (tag, length, value), starting with the
+        // checksums, filled in below
+        bytes.write_all(&FILE_EXT_CHECKSUMS.to_le_bytes())?;
+        bytes.write_all(&(4 * FILE_HEADER_REGIONS.len() as u32).to_le_bytes())?;
+        bytes.write_all(&[0u8; 4 * FILE_HEADER_REGIONS.len()])?;
+        for (tag, value) in self.extensions() {
+            bytes.write_all(&tag.to_le_bytes())?;
+            bytes.write_all(&(value.len() as u32).to_le_bytes())?;
+            bytes.write_all(&value)?;
+        }
 
         // Write inline data if present
         if !self.inline_data.is_empty() {
-            writer.write_all(&self.inline_data)?;
+            bytes.write_all(&self.inline_data)?;
         }
 
+        file_header_layout(&bytes, block_size)?.fill_checksums(&mut bytes);
+        writer.write_all(&bytes)?;
+
         // Pad to block boundary
         let padding = (block_size as usize - (header_size as usize % block_size as usize)) % block_size as usize;
         let padding_buf = vec![0u8; padding];
@@ -227,7 +638,11 @@ impl FileHeader {
     }
 
     /// Read the file header from a reader
-    pub fn read<R: Read>(reader: &mut R, block_size: u32) -> Result<Self> {
+    ///
+    /// With `require_checksums`, as in archives flagged
+    /// `ARCHIVE_FLAG_HEADER_CHECKSUMS`, a header without its checksum
+    /// record is corrupted; otherwise it is one from before checksums.
+    pub fn read<R: Read>(reader: &mut R, block_size: u32, require_checksums: bool) -> Result<Self> {
         // Read and verify magic
         let mut magic = [0u8; 4];
         reader.read_exact(&mut magic)?;
@@ -235,10 +650,30 @@ impl FileHeader {
             anyhow::bail!("Invalid file header magic");
         }
 
-        // Read header size
-        let mut header_size_buf = [0u8; 4];
-        reader.read_exact(&mut header_size_buf)?;
-        let header_size = u32::from_le_bytes(header_size_buf);
+        Self::read_after_magic(reader, block_size, require_checksums)
+    }
+
+    /// Read the rest of a file header whose magic has already been consumed
+    fn read_after_magic<R: Read>(source: &mut R, block_size: u32, require_checksums: bool) -> Result<Self> {
+        // Read header size, then the whole header, to check its checksums
+        // before any field is interpreted
+        let header_size = read_u32(source)?;
+        let mut bytes = FILE_HEADER_MAGIC.to_vec();
+        bytes.extend_from_slice(&header_size.to_le_bytes());
+        source
+            .by_ref()
+            .take((header_size as u64).saturating_sub(8))
+            .read_to_end(&mut bytes)?;
+        if bytes.len() < header_size as usize {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File header is cut short").into());
+        }
+        file_header_layout(&bytes, block_size)?.check_checksums(
+            "File header",
+            &FILE_HEADER_REGIONS,
+            &bytes,
+            require_checksums,
+        )?;
+        let reader = &mut &bytes[8..];
 
         // Read file size (12 bytes)
         let mut file_size_buf = [0u8; 16];
@@ -283,8 +718,25 @@ impl FileHeader {
         // Read source filesystem ID
         let source_filesystem_id = read_u64(reader)?;
 
+        // Whatever the header size covers beyond the fixed fields and inline
+        // data is extension records (absent in version 1 archives)
+        let has_inline = file_size < block_size as u128 && file_type == FileType::Regular;
+        let inline_len = if has_inline { file_size as u32 } else { 0 };
+        let fixed_len = 4 + 4 + 12 + 1 + 8 * 7 + 128 + 8;
+        let strings_len = [&username, &groupname, &file_path, &file_name, &link_name]
+            .iter()
+            .map(|s| 4 + s.len() as u32)
+            .sum::<u32>()
+            + 4
+            + ext_perm_len;
+        let extensions_len = header_size
+            .checked_sub(fixed_len + strings_len + inline_len)
+            .ok_or_else(|| anyhow::anyhow!("File header size {} is too small", header_size))?;
+        let mut extensions = vec![0u8; extensions_len as usize];
+        reader.read_exact(&mut extensions)?;
+
         // Determine if we have inline data
-        let inline_data = if file_size < block_size as u128 && file_type == FileType::Regular {
+        let inline_data = if has_inline {
             let mut data = vec![0u8; file_size as usize];
             reader.read_exact(&mut data)?;
             data
@@ -296,9 +748,9 @@ impl FileHeader {
         let total_read = header_size as usize;
         let padding = (block_size as usize - (total_read % block_size as usize)) % block_size as usize;
         let mut padding_buf = vec![0u8; padding];
-        reader.read_exact(&mut padding_buf)?;
+        source.read_exact(&mut padding_buf)?;
 
-        Ok(Self {
+        let mut header = Self {
             file_size,
             file_type,
             uid,
@@ -316,8 +768,29 @@ impl FileHeader {
             extended_permissions,
             source_filesystem_type,
             source_filesystem_id,
+            mode: None,
+            directory_listing: None,
+            xattrs: Vec::new(),
+            time_nanos: TimeNanos::default(),
+            data_seal: None,
             inline_data,
-        })
+        };
+
+        let mut records = &extensions[..];
+        while !records.is_empty() {
+            if records.len() < 6 {
+                anyhow::bail!("Truncated file header extension record");
+            }
+            let tag = u16::from_le_bytes([records[0], records[1]]);
+            let len = u32::from_le_bytes([records[2], records[3], records[4], records[5]]) as usize;
+            let value = records
+                .get(6..6 + len)
+                .ok_or_else(|| anyhow::anyhow!("Truncated file header extension record"))?;
+            header.apply_extension(tag, value)?;
+            records = &records[6 + len..];
+        }
+
+        Ok(header)
     }
 }
 
@@ -328,6 +801,8 @@ pub enum ExtentType {
     Data = b'D',       // Regular data block
     Sparse = b'S',     // Sparse/hole (no data)
     Reference = b'R',  // Reference to earlier extent
+    BaseReference = b'B', // Reference to an extent in a base archive
+    Compressed = b'Z', // Data block stored compressed
 }
 
 impl ExtentType {
@@ -336,6 +811,8 @@ impl ExtentType {
             b'D' => Ok(ExtentType::Data),
             b'S' => Ok(ExtentType::Sparse),
             b'R' => Ok(ExtentType::Reference),
+            b'B' => Ok(ExtentType::BaseReference),
+            b'Z' => Ok(ExtentType::Compressed),
             _ => anyhow::bail!("Invalid extent type: {}", b as char),
         }
     }
@@ -349,19 +826,186 @@ pub struct ExtentHeader {
     pub extent_type: ExtentType,
     pub source_extent_start: u64,
     pub checksum: u32,
+    pub byte_length: Option<u64>, // Exact length when not a whole number of blocks
+    pub base: Option<BaseLink>,   // Where a BaseReference extent's data lives
+    pub compression: Option<ExtentCompression>, // How a Compressed extent's data is stored
+    pub seal: Option<DataSeal>, // How the data is encrypted, in encrypted archives
 }
 
+/// Compression algorithm of a Compressed extent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CompressionAlgorithm {
+    Zstd = 1,
+}
+
+impl CompressionAlgorithm {
+    pub fn from_byte(b: u8) -> Result<Self> {
+        match b {
+            1 => Ok(CompressionAlgorithm::Zstd),
+            _ => anyhow::bail!("Unsupported compression algorithm {}", b),
+        }
+    }
+}
+
+/// How a Compressed extent's data is stored: `stored_length` bytes of
+/// compressed data, padded to whole blocks. The uncompressed length is the
+/// extent's byte length.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtentCompression {
+    pub algorithm: CompressionAlgorithm,
+    pub stored_length: u64,
+}
+
+/// The base archive extents a BaseReference extent stands for: a run of
+/// `extent_count` extents with consecutive ids, starting at the header's
+/// extent id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BaseLink {
+    /// Identity of the base archive (see `ArchiveFooter::archive_id`)
+    pub archive_id: [u8; 32],
+    /// BLAKE3 hash of the first extent's file data
+    pub content_hash: [u8; 32],
+    pub extent_count: u32,
+}
+
+/// Base reference records seen while reading an extent header
+#[derive(Default)]
+struct BaseRecords {
+    archive_id: Option<[u8; 32]>,
+    content_hash: Option<[u8; 32]>,
+    extent_count: Option<u32>,
+}
+
+/// Tags for the optional records in the extent header extension area
+const EXTENT_EXT_BYTE_LENGTH: u16 = 1;
+const EXTENT_EXT_BASE_ARCHIVE: u16 = 2;
+const EXTENT_EXT_CONTENT_HASH: u16 = 3;
+const EXTENT_EXT_BASE_COUNT: u16 = 4;
+const EXTENT_EXT_COMPRESSION: u16 = 5;
+const EXTENT_EXT_DATA_SEAL: u16 = 6;
+const EXTENT_EXT_CHECKSUMS: u16 = 7;
+
+/// Regions of an extent header with their own checksum
+const EXTENT_HEADER_REGIONS: [&str; 6] = [
+    "extent id",
+    "length and type",
+    "source offset",
+    "data checksum",
+    "extension length",
+    "extension records",
+];
+
+/// Fixed part of an extent header, before the extension area
+const EXTENT_HEADER_FIXED_SIZE: usize = 8 + 4 + 1 + 8 + 4; // = 25 bytes
+
 impl ExtentHeader {
+    /// Length of a serialized extent header before its block padding, from
+    /// the length of its extension area
+    pub fn record_length(record: &[u8]) -> Option<usize> {
+        let length = record.get(EXTENT_HEADER_FIXED_SIZE..EXTENT_HEADER_FIXED_SIZE + 2)?;
+        Some(EXTENT_HEADER_FIXED_SIZE + 2 + u16::from_le_bytes([length[0], length[1]]) as usize)
+    }
+
+    /// Bytes of file data this extent covers
+    pub fn covered_length(&self, block_size: u32) -> u64 {
+        self.byte_length
+            .unwrap_or(self.length_in_blocks as u64 * block_size as u64)
+    }
+
+    /// Encode the extension records
+    fn extensions(&self) -> Vec<u8> {
+        let mut records = Vec::new();
+        if let Some(byte_length) = self.byte_length {
+            records.extend_from_slice(&EXTENT_EXT_BYTE_LENGTH.to_le_bytes());
+            records.extend_from_slice(&8u32.to_le_bytes());
+            records.extend_from_slice(&byte_length.to_le_bytes());
+        }
+        if let Some(base) = &self.base {
+            for (tag, value) in [
+                (EXTENT_EXT_BASE_ARCHIVE, &base.archive_id),
+                (EXTENT_EXT_CONTENT_HASH, &base.content_hash),
+            ] {
+                records.extend_from_slice(&tag.to_le_bytes());
+                records.extend_from_slice(&32u32.to_le_bytes());
+                records.extend_from_slice(value);
+            }
+            if base.extent_count != 1 {
+                records.extend_from_slice(&EXTENT_EXT_BASE_COUNT.to_le_bytes());
+                records.extend_from_slice(&4u32.to_le_bytes());
+                records.extend_from_slice(&base.extent_count.to_le_bytes());
+            }
+        }
+        if let Some(compression) = &self.compression {
+            records.extend_from_slice(&EXTENT_EXT_COMPRESSION.to_le_bytes());
+            records.extend_from_slice(&9u32.to_le_bytes());
+            records.push(compression.algorithm as u8);
+            records.extend_from_slice(&compression.stored_length.to_le_bytes());
+        }
+        if let Some(seal) = &self.seal {
+            let value = seal.encode();
+            records.extend_from_slice(&EXTENT_EXT_DATA_SEAL.to_le_bytes());
+            records.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            records.extend_from_slice(&value);
+        }
+        records
+    }
+
+    /// Apply one extension record, ignoring unknown tags
+    fn apply_extension(&mut self, tag: u16, value: &[u8], base: &mut BaseRecords) -> Result<()> {
+        let invalid = || anyhow::anyhow!("Invalid extent header record {}", tag);
+        match tag {
+            EXTENT_EXT_BYTE_LENGTH => {
+                let bytes: [u8; 8] = value.try_into().map_err(|_| invalid())?;
+                self.byte_length = Some(u64::from_le_bytes(bytes));
+            }
+            EXTENT_EXT_BASE_ARCHIVE => base.archive_id = Some(value.try_into().map_err(|_| invalid())?),
+            EXTENT_EXT_CONTENT_HASH => base.content_hash = Some(value.try_into().map_err(|_| invalid())?),
+            EXTENT_EXT_BASE_COUNT => {
+                let bytes: [u8; 4] = value.try_into().map_err(|_| invalid())?;
+                base.extent_count = Some(u32::from_le_bytes(bytes));
+            }
+            EXTENT_EXT_COMPRESSION => {
+                let (&algorithm, stored_length) = value.split_first().ok_or_else(invalid)?;
+                let stored_length: [u8; 8] = stored_length.try_into().map_err(|_| invalid())?;
+                self.compression = Some(ExtentCompression {
+                    algorithm: CompressionAlgorithm::from_byte(algorithm)?,
+                    stored_length: u64::from_le_bytes(stored_length),
+                });
+            }
+            EXTENT_EXT_DATA_SEAL => self.seal = Some(DataSeal::decode(value)?),
+            _ => {}
+        }
+        Ok(())
+    }
+
     /// Write the extent header to a writer
     pub fn write<W: Write>(&self, writer: &mut W, block_size: u32) -> Result<()> {
-        writer.write_all(&self.extent_id.to_le_bytes())?;
-        writer.write_all(&self.length_in_blocks.to_le_bytes())?;
-        writer.write_all(&[self.extent_type as u8])?;
-        writer.write_all(&self.source_extent_start.to_le_bytes())?;
-        writer.write_all(&self.checksum.to_le_bytes())?;
+        let mut bytes = Vec::with_capacity(block_size as usize);
+        bytes.write_all(&self.extent_id.to_le_bytes())?;
+        bytes.write_all(&self.length_in_blocks.to_le_bytes())?;
+        bytes.write_all(&[self.extent_type as u8])?;
+        bytes.write_all(&self.source_extent_start.to_le_bytes())?;
+        bytes.write_all(&self.checksum.to_le_bytes())?;
+
+        // Extension area: a length, then records (version 2+), starting
+        // with the checksums, filled in below
+        let mut extensions = Vec::new();
+        extensions.extend_from_slice(&EXTENT_EXT_CHECKSUMS.to_le_bytes());
+        extensions.extend_from_slice(&(4 * EXTENT_HEADER_REGIONS.len() as u32).to_le_bytes());
+        extensions.extend_from_slice(&[0u8; 4 * EXTENT_HEADER_REGIONS.len()]);
+        extensions.extend_from_slice(&self.extensions());
+        let header_size = EXTENT_HEADER_FIXED_SIZE + 2 + extensions.len();
+        if header_size > block_size as usize {
+            anyhow::bail!("Extent header does not fit in a {} byte block", block_size);
+        }
+        bytes.write_all(&(extensions.len() as u16).to_le_bytes())?;
+        bytes.write_all(&extensions)?;
+
+        extent_header_layout(&bytes)?.fill_checksums(&mut bytes);
+        writer.write_all(&bytes)?;
 
         // Pad to block boundary
-        let header_size = 8 + 4 + 1 + 8 + 4; // = 25 bytes
         let padding = (block_size as usize - header_size) % block_size as usize;
         let padding_buf = vec![0u8; padding];
         writer.write_all(&padding_buf)?;
@@ -369,8 +1013,21 @@ impl ExtentHeader {
         Ok(())
     }
 
-    /// Read the extent header from a reader
-    pub fn read<R: Read>(reader: &mut R, block_size: u32) -> Result<Self> {
+    /// Read the extent header from a reader; `require_checksums` as for
+    /// `FileHeader::read`
+    pub fn read<R: Read>(source: &mut R, block_size: u32, require_checksums: bool) -> Result<Self> {
+        // Read the whole block, to check its checksums before any field is
+        // interpreted
+        let mut bytes = vec![0u8; block_size as usize];
+        source.read_exact(&mut bytes)?;
+        extent_header_layout(&bytes)?.check_checksums(
+            "Extent header",
+            &EXTENT_HEADER_REGIONS,
+            &bytes,
+            require_checksums,
+        )?;
+        let reader = &mut &bytes[..];
+
         let extent_id = read_u64(reader)?;
         let length_in_blocks = read_u32(reader)?;
 
@@ -381,19 +1038,659 @@ impl ExtentHeader {
         let source_extent_start = read_u64(reader)?;
         let checksum = read_u32(reader)?;
 
-        // Skip padding to block boundary
-        let header_size = 8 + 4 + 1 + 8 + 4;
-        let padding = (block_size as usize - header_size) % block_size as usize;
-        let mut padding_buf = vec![0u8; padding];
-        reader.read_exact(&mut padding_buf)?;
+        // Version 1 padding reads as an empty extension area
+        let rest = &bytes[EXTENT_HEADER_FIXED_SIZE..];
+        let extensions_len = u16::from_le_bytes([rest[0], rest[1]]) as usize;
+        let extensions = &rest[2..2 + extensions_len];
 
-        Ok(Self {
+        let mut header = Self {
             extent_id,
             length_in_blocks,
             extent_type,
             source_extent_start,
             checksum,
-        })
+            byte_length: None,
+            base: None,
+            compression: None,
+            seal: None,
+        };
+
+        let mut base = BaseRecords::default();
+        let mut records = extensions;
+        while !records.is_empty() {
+            if records.len() < 6 {
+                anyhow::bail!("Truncated extent header extension");
+            }
+            let tag = u16::from_le_bytes([records[0], records[1]]);
+            let len = u32::from_le_bytes([records[2], records[3], records[4], records[5]]) as usize;
+            let value = records
+                .get(6..6 + len)
+                .ok_or_else(|| anyhow::anyhow!("Truncated extent header extension"))?;
+            header.apply_extension(tag, value, &mut base)?;
+            records = &records[6 + len..];
+        }
+
+        if header.extent_type == ExtentType::BaseReference {
+            match (base.archive_id, base.content_hash, base.extent_count.unwrap_or(1)) {
+                (Some(archive_id), Some(content_hash), extent_count) if extent_count > 0 => {
+                    header.base = Some(BaseLink {
+                        archive_id,
+                        content_hash,
+                        extent_count,
+                    })
+                }
+                _ => anyhow::bail!("Base reference extent {} does not name its base", header.extent_id),
+            }
+        }
+        if header.extent_type == ExtentType::Compressed {
+            let stored_capacity = header.length_in_blocks as u64 * block_size as u64;
+            match (&header.compression, header.byte_length) {
+                (Some(compression), Some(_)) if compression.stored_length <= stored_capacity => {}
+                _ => anyhow::bail!("Compressed extent {} has invalid lengths", header.extent_id),
+            }
+        }
+
+        Ok(header)
+    }
+}
+
+/// Length of a seekable archive, leaving out any parity appended to it
+pub fn archive_length<R: Read + Seek>(reader: &mut R) -> Result<u64> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    let parity = read_parity_trailer(reader)?.filter(|&(protected_length, _)| protected_length <= file_len);
+    Ok(parity.map_or(file_len, |(protected_length, _)| protected_length))
+}
+
+/// Read the parity trailer at the end of a seekable file: the length of
+/// the data the parity protects and the offset of the parity index
+pub fn read_parity_trailer<R: Read + Seek>(reader: &mut R) -> Result<Option<(u64, u64)>> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    if file_len < PARITY_TRAILER_SIZE as u64 {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::End(-(PARITY_TRAILER_SIZE as i64)))?;
+    let protected_length = read_u64(reader)?;
+    let index_offset = read_u64(reader)?;
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != PARITY_TRAILER_MAGIC || index_offset >= file_len {
+        return Ok(None);
+    }
+    Ok(Some((protected_length, index_offset)))
+}
+
+/// Read the next member header, at archive offset `offset`
+/// Returns None at the archive footer, once the footer and its trailer have
+/// been read and checked, or at a clean end of input; the reader's position
+/// tells the two apart. Input that ends inside a header or the footer is an
+/// `UnexpectedEof` error.
+pub fn read_member_header<R: Read>(
+    reader: &mut R,
+    offset: u64,
+    block_size: u32,
+    require_checksums: bool,
+) -> Result<Option<FileHeader>> {
+    let mut magic = [0u8; 4];
+    let mut filled = 0;
+    while filled < magic.len() {
+        match reader.read(&mut magic[filled..])? {
+            0 if filled == 0 => return Ok(None),
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Truncated file header").into()),
+            n => filled += n,
+        }
+    }
+
+    if &magic == FOOTER_MAGIC {
+        // The footer is only read here to check it is whole
+        let mut counting = CountingReader {
+            inner: FOOTER_MAGIC.chain(&mut *reader),
+            count: 0,
+        };
+        ArchiveFooter::read(&mut counting).context("Archive footer is damaged")?;
+        let record_len = counting.count as usize;
+        let padded_len = (record_len + ArchiveFooter::TRAILER_SIZE).next_multiple_of(block_size as usize)
+            - ArchiveFooter::TRAILER_SIZE;
+        let mut padding = vec![0u8; padded_len - record_len];
+        reader.read_exact(&mut padding)?;
+        if padding.iter().any(|&byte| byte != 0) {
+            anyhow::bail!("Nonzero padding in the archive footer at offset {}", offset);
+        }
+        ArchiveFooter::read_trailer(reader, offset)?;
+        return Ok(None);
+    }
+    if &magic != FILE_HEADER_MAGIC {
+        anyhow::bail!("Invalid file header magic");
+    }
+
+    FileHeader::read_after_magic(reader, block_size, require_checksums).map(Some)
+}
+
+/// Reader that counts the bytes read through it
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+}
+
+/// Member entry in the archive footer index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    /// Archive offset of the member's file header
+    pub header_offset: u64,
+    pub file_type: FileType,
+    /// Member path (file_path/file_name)
+    pub path: String,
+}
+
+/// Data extent entry in the archive footer, used to reference the archive
+/// as a base for differential archives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExtentEntry {
+    /// BLAKE3 hash of the extent's file data
+    pub content_hash: [u8; 32],
+    pub extent_id: u64,
+    /// Archive offset of the extent's data (just after its header)
+    pub data_offset: u64,
+    pub length_in_blocks: u32,
+    /// Bytes of file data the extent holds
+    pub covered_length: u64,
+    /// Checksum of the stored blocks
+    pub checksum: u32,
+    /// Set when the data is stored compressed
+    pub compression: Option<ExtentCompression>,
+}
+
+/// A base archive that a differential archive references
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BaseEntry {
+    pub archive_id: [u8; 32],
+    /// File name of the base when the archive was created (a hint only)
+    pub name: String,
+}
+
+/// Hashes of everything an archive holds before its footer, signed along
+/// with the footer
+///
+/// The archive is cut at each member's file header: the first hash covers
+/// the archive header, and each member's hash covers its file header and
+/// extents, up to the next member or the footer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    /// BLAKE3 hash of the bytes before the first member
+    pub header_hash: [u8; 32],
+    pub members: Vec<ManifestEntry>,
+}
+
+/// A member's entry in the manifest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Archive offset of the member's file header
+    pub header_offset: u64,
+    /// BLAKE3 hash of the member's bytes
+    pub hash: [u8; 32],
+}
+
+/// Ed25519 signature over the footer, which holds the manifest
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveSignature {
+    pub public_key: [u8; 32],
+    pub signature: [u8; 64],
+}
+
+/// Footer section tags
+const FOOTER_SECTION_MEMBERS: &[u8; 4] = b"MEMB";
+const FOOTER_SECTION_EXTENTS: &[u8; 4] = b"EXTS";
+const FOOTER_SECTION_BASES: &[u8; 4] = b"BASE";
+const FOOTER_SECTION_MANIFEST: &[u8; 4] = b"MNFS";
+const FOOTER_SECTION_SIGNATURE: &[u8; 4] = b"SIGN";
+
+/// Prefix of the message an archive signature signs
+const SIGNATURE_CONTEXT: &[u8] = b"reftar archive signature\0";
+
+/// Archive footer, written after the last member
+///
+/// The footer is optional: archives cut short during creation have none and
+/// are still readable sequentially. It is made of tagged sections so that
+/// readers can skip sections they don't know.
+#[derive(Debug, Clone, Default)]
+pub struct ArchiveFooter {
+    pub members: Vec<IndexEntry>,
+    pub extents: Vec<ExtentEntry>,
+    pub bases: Vec<BaseEntry>,
+    /// Set in signed archives
+    pub manifest: Option<Manifest>,
+    pub signature: Option<ArchiveSignature>,
+}
+
+impl ArchiveFooter {
+    /// Size of the trailer closing the footer (footer offset + magic)
+    pub const TRAILER_SIZE: usize = 8 + 8;
+
+    /// Write the footer, starting at archive offset `footer_offset`
+    pub fn write<W: Write>(&self, writer: &mut W, block_size: u32, footer_offset: u64) -> Result<()> {
+        Self::write_with_trailer(writer, &self.encode()?, block_size, footer_offset)
+    }
+
+    /// Serialize the footer body: the magic and the sections
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut members = Vec::new();
+        members.extend_from_slice(&(self.members.len() as u64).to_le_bytes());
+        for entry in &self.members {
+            members.extend_from_slice(&entry.header_offset.to_le_bytes());
+            members.push(entry.file_type as u8);
+            write_length_prefixed_string(&mut members, &entry.path)?;
+        }
+
+        let extents = self.encode_extents();
+
+        let mut bases = Vec::new();
+        bases.extend_from_slice(&(self.bases.len() as u64).to_le_bytes());
+        for base in &self.bases {
+            bases.extend_from_slice(&base.archive_id);
+            write_length_prefixed_string(&mut bases, &base.name)?;
+        }
+
+        let mut manifest = Vec::new();
+        if let Some(contents) = &self.manifest {
+            manifest.extend_from_slice(&contents.header_hash);
+            manifest.extend_from_slice(&(contents.members.len() as u64).to_le_bytes());
+            for entry in &contents.members {
+                manifest.extend_from_slice(&entry.header_offset.to_le_bytes());
+                manifest.extend_from_slice(&entry.hash);
+            }
+        }
+
+        let mut signature = Vec::new();
+        if let Some(sig) = &self.signature {
+            signature.extend_from_slice(&sig.public_key);
+            signature.extend_from_slice(&sig.signature);
+        }
+
+        let mut sections: Vec<(&[u8; 4], &[u8])> =
+            vec![(FOOTER_SECTION_MEMBERS, &members), (FOOTER_SECTION_EXTENTS, &extents)];
+        if !self.bases.is_empty() {
+            sections.push((FOOTER_SECTION_BASES, &bases));
+        }
+        if self.manifest.is_some() {
+            sections.push((FOOTER_SECTION_MANIFEST, &manifest));
+        }
+        if self.signature.is_some() {
+            sections.push((FOOTER_SECTION_SIGNATURE, &signature));
+        }
+
+        let mut body = Vec::new();
+        body.extend_from_slice(FOOTER_MAGIC);
+        body.extend_from_slice(&(sections.len() as u32).to_le_bytes());
+        for (tag, payload) in sections {
+            body.extend_from_slice(tag);
+            body.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+            body.extend_from_slice(payload);
+        }
+        Ok(body)
+    }
+
+    /// Write a footer record (the encoded footer, or a sealed one), then
+    /// the trailer pointing at it
+    pub fn write_with_trailer<W: Write>(
+        writer: &mut W,
+        record: &[u8],
+        block_size: u32,
+        footer_offset: u64,
+    ) -> Result<()> {
+        // Pad so that the trailer ends exactly on a block boundary
+        let mut footer = record.to_vec();
+        let total = (footer.len() + Self::TRAILER_SIZE).next_multiple_of(block_size as usize);
+        footer.resize(total - Self::TRAILER_SIZE, 0);
+        footer.extend_from_slice(&footer_offset.to_le_bytes());
+        footer.extend_from_slice(FOOTER_TRAILER_MAGIC);
+
+        writer.write_all(&footer)?;
+        Ok(())
+    }
+
+    /// Identity of the archive: the BLAKE3 hash of its extent table
+    ///
+    /// Differential archives name their bases by this identity, so it only
+    /// depends on the data stored and where it lies in the archive.
+    pub fn archive_id(&self) -> [u8; 32] {
+        *blake3::hash(&self.encode_extents()).as_bytes()
+    }
+
+    /// The message an archive signature signs: the footer, manifest
+    /// included, encoded without its signature
+    pub fn signed_message(&self) -> Result<Vec<u8>> {
+        let unsigned = Self {
+            signature: None,
+            ..self.clone()
+        };
+        let mut message = SIGNATURE_CONTEXT.to_vec();
+        message.extend_from_slice(&unsigned.encode()?);
+        Ok(message)
+    }
+
+    fn encode_extents(&self) -> Vec<u8> {
+        let mut extents = Vec::with_capacity(8 + self.extents.len() * 64);
+        extents.extend_from_slice(&(self.extents.len() as u64).to_le_bytes());
+        for entry in &self.extents {
+            extents.extend_from_slice(&entry.content_hash);
+            extents.extend_from_slice(&entry.extent_id.to_le_bytes());
+            extents.extend_from_slice(&entry.data_offset.to_le_bytes());
+            extents.extend_from_slice(&entry.length_in_blocks.to_le_bytes());
+            extents.extend_from_slice(&entry.covered_length.to_le_bytes());
+            extents.extend_from_slice(&entry.checksum.to_le_bytes());
+            match &entry.compression {
+                Some(compression) => {
+                    extents.push(compression.algorithm as u8);
+                    extents.extend_from_slice(&compression.stored_length.to_le_bytes());
+                }
+                None => extents.extend_from_slice(&[0; 9]),
+            }
+        }
+        extents
+    }
+
+    /// Find and read the footer from the end of a seekable archive
+    /// Returns None if the archive has no footer. The reader position is
+    /// left undefined.
+    pub fn locate<R: Read + Seek>(reader: &mut R) -> Result<Option<Self>> {
+        match Self::locate_offset(reader)? {
+            Some(footer_offset) => {
+                reader.seek(SeekFrom::Start(footer_offset))?;
+                Self::read(reader).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Read the trailer that follows the footer at archive offset
+    /// `footer_offset`, and check that it points back at the footer
+    pub fn read_trailer<R: Read>(reader: &mut R, footer_offset: u64) -> Result<()> {
+        let offset = read_u64(reader)?;
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != FOOTER_TRAILER_MAGIC || offset != footer_offset {
+            anyhow::bail!("Archive footer at offset {} has no valid trailer", footer_offset);
+        }
+        Ok(())
+    }
+
+    /// Read the trailer at the end of a seekable archive and return the
+    /// footer offset, or None if the archive has no footer
+    pub fn locate_offset<R: Read + Seek>(reader: &mut R) -> Result<Option<u64>> {
+        let archive_len = archive_length(reader)?;
+        if archive_len < Self::TRAILER_SIZE as u64 {
+            return Ok(None);
+        }
+
+        reader.seek(SeekFrom::Start(archive_len - Self::TRAILER_SIZE as u64))?;
+        let footer_offset = read_u64(reader)?;
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != FOOTER_TRAILER_MAGIC || footer_offset >= archive_len {
+            return Ok(None);
+        }
+        Ok(Some(footer_offset))
+    }
+
+    /// Read an encoded footer body
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut footer_magic = [0u8; 4];
+        reader.read_exact(&mut footer_magic)?;
+        if &footer_magic != FOOTER_MAGIC {
+            anyhow::bail!("Archive trailer points at an invalid footer");
+        }
+
+        let mut footer = Self::default();
+        let section_count = read_u32(reader)?;
+        for _ in 0..section_count {
+            let mut tag = [0u8; 4];
+            reader.read_exact(&mut tag)?;
+            let len = read_u64(reader)?;
+            let mut payload = vec![0u8; len as usize];
+            reader.read_exact(&mut payload)?;
+
+            match &tag {
+                FOOTER_SECTION_MEMBERS => footer.members = read_member_entries(&mut &payload[..])?,
+                FOOTER_SECTION_EXTENTS => footer.extents = read_extent_entries(&mut &payload[..])?,
+                FOOTER_SECTION_BASES => footer.bases = read_base_entries(&mut &payload[..])?,
+                FOOTER_SECTION_MANIFEST => footer.manifest = Some(read_manifest(&mut &payload[..])?),
+                FOOTER_SECTION_SIGNATURE => footer.signature = Some(read_signature(&mut &payload[..])?),
+                _ => {}
+            }
+        }
+
+        Ok(footer)
+    }
+}
+
+fn read_member_entries<R: Read>(reader: &mut R) -> Result<Vec<IndexEntry>> {
+    let count = read_u64(reader)?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let header_offset = read_u64(reader)?;
+        let mut file_type = [0u8; 1];
+        reader.read_exact(&mut file_type)?;
+        entries.push(IndexEntry {
+            header_offset,
+            file_type: FileType::from_byte(file_type[0])?,
+            path: read_length_prefixed_string(reader)?,
+        });
+    }
+    Ok(entries)
+}
+
+fn read_extent_entries<R: Read>(reader: &mut R) -> Result<Vec<ExtentEntry>> {
+    let count = read_u64(reader)?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let mut content_hash = [0u8; 32];
+        reader.read_exact(&mut content_hash)?;
+        let mut entry = ExtentEntry {
+            content_hash,
+            extent_id: read_u64(reader)?,
+            data_offset: read_u64(reader)?,
+            length_in_blocks: read_u32(reader)?,
+            covered_length: read_u64(reader)?,
+            checksum: read_u32(reader)?,
+            compression: None,
+        };
+        let mut algorithm = [0u8; 1];
+        reader.read_exact(&mut algorithm)?;
+        let stored_length = read_u64(reader)?;
+        if algorithm[0] != 0 {
+            entry.compression = Some(ExtentCompression {
+                algorithm: CompressionAlgorithm::from_byte(algorithm[0])?,
+                stored_length,
+            });
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn read_base_entries<R: Read>(reader: &mut R) -> Result<Vec<BaseEntry>> {
+    let count = read_u64(reader)?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let mut archive_id = [0u8; 32];
+        reader.read_exact(&mut archive_id)?;
+        entries.push(BaseEntry {
+            archive_id,
+            name: read_length_prefixed_string(reader)?,
+        });
+    }
+    Ok(entries)
+}
+
+fn read_manifest<R: Read>(reader: &mut R) -> Result<Manifest> {
+    let mut manifest = Manifest::default();
+    reader.read_exact(&mut manifest.header_hash)?;
+    let count = read_u64(reader)?;
+    for _ in 0..count {
+        let header_offset = read_u64(reader)?;
+        let mut hash = [0u8; 32];
+        reader.read_exact(&mut hash)?;
+        manifest.members.push(ManifestEntry { header_offset, hash });
+    }
+    Ok(manifest)
+}
+
+fn read_signature<R: Read>(reader: &mut R) -> Result<ArchiveSignature> {
+    let mut signature = ArchiveSignature {
+        public_key: [0; 32],
+        signature: [0; 64],
+    };
+    reader.read_exact(&mut signature.public_key)?;
+    reader.read_exact(&mut signature.signature)?;
+    Ok(signature)
+}
+
+/// Where the checksummed parts of a serialized header are
+struct HeaderLayout {
+    /// Byte ranges checksummed separately, in the order of the region names
+    regions: Vec<Range<usize>>,
+    /// Value of the checksum record; headers from before checksums have none
+    checksums: Option<Range<usize>>,
+}
+
+/// Layout of a serialized file header, without its padding, with regions
+/// in the order of `FILE_HEADER_REGIONS`
+fn file_header_layout(bytes: &[u8], block_size: u32) -> Result<HeaderLayout> {
+    let mut regions = vec![4..8, 8..21, 21..53, 53..77];
+
+    // Length-prefixed fields, grouped by region
+    let mut position = 77;
+    for (name, count) in [("user and group names", 2), ("path and link target", 3), ("extended permissions", 1)] {
+        let start = position;
+        for _ in 0..count {
+            position = bytes
+                .get(position..position + 4)
+                .map(|length| u32::from_le_bytes(length.try_into().unwrap()) as usize)
+                .and_then(|length| position.checked_add(4 + length))
+                .filter(|&end| end <= bytes.len())
+                .ok_or_else(|| anyhow::anyhow!("File header is corrupted: the lengths of its {} overrun it", name))?;
+        }
+        regions.push(start..position);
+    }
+    let filesystem_end = position + 128 + 8;
+    if filesystem_end > bytes.len() {
+        anyhow::bail!("File header is corrupted: its header size is too small");
+    }
+    regions.push(position..filesystem_end);
+
+    // The checksum record comes first among the extension records
+    let checksums = record_value(bytes, filesystem_end, FILE_EXT_CHECKSUMS)
+        .ok_or_else(|| anyhow::anyhow!("File header is corrupted: its checksum record overruns it"))?;
+    let extensions_start = checksums.as_ref().map_or(filesystem_end, |value| value.end);
+
+    // Inline data ends the header; if the size or type it depends on is
+    // corrupted, the checksums say so
+    let mut file_size = [0u8; 16];
+    file_size[..12].copy_from_slice(&bytes[8..20]);
+    let file_size = u128::from_le_bytes(file_size);
+    let inline_len = match bytes[20] == FileType::Regular as u8 && file_size < block_size as u128 {
+        true => file_size as usize,
+        false => 0,
+    };
+    let inline_start = bytes.len().saturating_sub(inline_len).max(extensions_start);
+    regions.push(extensions_start..inline_start);
+    regions.push(inline_start..bytes.len());
+
+    Ok(HeaderLayout { regions, checksums })
+}
+
+/// Layout of a serialized extent header, with regions in the order of
+/// `EXTENT_HEADER_REGIONS`
+fn extent_header_layout(bytes: &[u8]) -> Result<HeaderLayout> {
+    let extensions_end = bytes
+        .get(EXTENT_HEADER_FIXED_SIZE..EXTENT_HEADER_FIXED_SIZE + 2)
+        .map(|length| EXTENT_HEADER_FIXED_SIZE + 2 + u16::from_le_bytes([length[0], length[1]]) as usize)
+        .filter(|&end| end <= bytes.len())
+        .ok_or_else(|| anyhow::anyhow!("Extent header extensions overrun the block"))?;
+
+    // The checksum record comes first in the extension area
+    let extensions = &bytes[..extensions_end];
+    let checksums = record_value(extensions, EXTENT_HEADER_FIXED_SIZE + 2, EXTENT_EXT_CHECKSUMS)
+        .ok_or_else(|| anyhow::anyhow!("Extent header is corrupted: its checksum record overruns it"))?;
+    let records_start = checksums.as_ref().map_or(EXTENT_HEADER_FIXED_SIZE + 2, |value| value.end);
+
+    let regions = vec![
+        0..8,
+        8..13,
+        13..21,
+        21..EXTENT_HEADER_FIXED_SIZE,
+        EXTENT_HEADER_FIXED_SIZE..EXTENT_HEADER_FIXED_SIZE + 2,
+        records_start..extensions_end,
+    ];
+    Ok(HeaderLayout { regions, checksums })
+}
+
+/// Where the value of the extension record at `position` is if it has tag
+/// `tag`; None inside if it has another tag, and None if it overruns
+/// `bytes`
+fn record_value(bytes: &[u8], position: usize, tag: u16) -> Option<Option<Range<usize>>> {
+    let Some(record) = bytes.get(position..position + 6) else {
+        return Some(None);
+    };
+    if u16::from_le_bytes([record[0], record[1]]) != tag {
+        return Some(None);
+    }
+    let length = u32::from_le_bytes(record[2..6].try_into().unwrap()) as usize;
+    let value = position + 6..(position + 6).checked_add(length)?;
+    (value.end <= bytes.len()).then_some(Some(value))
+}
+
+impl HeaderLayout {
+    /// CRC32C of each region of `bytes`
+    fn region_checksums(&self, bytes: &[u8]) -> Vec<u32> {
+        self.regions.iter().map(|region| crc32c::crc32c(&bytes[region.clone()])).collect()
+    }
+
+    /// Store the checksums of the regions in the checksum record
+    fn fill_checksums(&self, bytes: &mut [u8]) {
+        let Some(value) = self.checksums.clone() else {
+            return;
+        };
+        let computed: Vec<u8> = self
+            .region_checksums(bytes)
+            .iter()
+            .flat_map(|crc| crc.to_le_bytes())
+            .collect();
+        bytes[value].copy_from_slice(&computed);
+    }
+
+    /// Check the regions against the recorded checksums, naming those that
+    /// don't match. Headers from before checksums pass unless `required`.
+    fn check_checksums(&self, what: &str, names: &[&str], bytes: &[u8], required: bool) -> Result<()> {
+        let Some(value) = self.checksums.clone() else {
+            if required {
+                anyhow::bail!("{} is corrupted: its checksum record is missing", what);
+            }
+            return Ok(());
+        };
+        let recorded = &bytes[value];
+        if recorded.len() != 4 * names.len() {
+            anyhow::bail!("{} is corrupted: its checksum record has the wrong length", what);
+        }
+        let corrupted: Vec<&str> = self
+            .region_checksums(bytes)
+            .iter()
+            .zip(recorded.chunks_exact(4))
+            .zip(names)
+            .filter(|((computed, recorded), _)| computed.to_le_bytes() != **recorded)
+            .map(|(_, name)| *name)
+            .collect();
+        if !corrupted.is_empty() {
+            anyhow::bail!("{} is corrupted: checksum mismatch in its {}", what, corrupted.join(", "));
+        }
+        Ok(())
     }
 }
 
@@ -445,5 +1742,364 @@ mod tests {
 
         assert_eq!(header.version, read_header.version);
         assert_eq!(header.block_size, read_header.block_size);
+        assert_eq!(read_header.flags, 0);
+
+        let mut chunked = ArchiveHeader::new(4096);
+        chunked.flags = ARCHIVE_FLAG_CONTENT_CHUNKS;
+        chunked.chunk_size = 65536;
+        let mut buf = Vec::new();
+        chunked.write(&mut buf).unwrap();
+        let read_header = ArchiveHeader::read(&mut Cursor::new(&buf)).unwrap();
+        assert_eq!(read_header.flags, ARCHIVE_FLAG_CONTENT_CHUNKS);
+        assert_eq!(read_header.chunk_size, 65536);
+
+        // Flags from a newer writer are refused rather than misread
+        buf[12] |= 0x80;
+        assert!(ArchiveHeader::read(&mut Cursor::new(&buf)).is_err());
+    }
+
+    #[test]
+    fn test_extent_header_byte_length() {
+        let mut header = ExtentHeader {
+            extent_id: 7,
+            length_in_blocks: 2,
+            extent_type: ExtentType::Data,
+            source_extent_start: 4096,
+            checksum: 0xDEADBEEF,
+            byte_length: Some(5000),
+            base: None,
+            compression: None,
+            seal: None,
+        };
+        let mut buf = Vec::new();
+        header.write(&mut buf, 4096).unwrap();
+        assert_eq!(buf.len(), 4096);
+        let read_header = ExtentHeader::read(&mut Cursor::new(buf), 4096, true).unwrap();
+        assert_eq!(read_header.byte_length, Some(5000));
+        assert_eq!(read_header.covered_length(4096), 5000);
+
+        header.byte_length = None;
+        let mut buf = Vec::new();
+        header.write(&mut buf, 4096).unwrap();
+        let read_header = ExtentHeader::read(&mut Cursor::new(buf), 4096, true).unwrap();
+        assert_eq!(read_header.covered_length(4096), 8192);
+
+        // A base reference must say which base and which data it means
+        header.extent_type = ExtentType::BaseReference;
+        let mut buf = Vec::new();
+        header.write(&mut buf, 4096).unwrap();
+        assert!(ExtentHeader::read(&mut Cursor::new(&buf), 4096, true).is_err());
+
+        let link = BaseLink {
+            archive_id: [1; 32],
+            content_hash: [2; 32],
+            extent_count: 3,
+        };
+        header.base = Some(link);
+        let mut buf = Vec::new();
+        header.write(&mut buf, 4096).unwrap();
+        let read_header = ExtentHeader::read(&mut Cursor::new(buf), 4096, true).unwrap();
+        assert_eq!(read_header.base, Some(link));
+
+        // A compressed extent records its stored length, which must fit
+        header.extent_type = ExtentType::Compressed;
+        header.base = None;
+        header.byte_length = Some(20000);
+        header.compression = Some(ExtentCompression {
+            algorithm: CompressionAlgorithm::Zstd,
+            stored_length: 6000,
+        });
+        let mut buf = Vec::new();
+        header.write(&mut buf, 4096).unwrap();
+        let read_header = ExtentHeader::read(&mut Cursor::new(buf), 4096, true).unwrap();
+        assert_eq!(read_header.compression, header.compression);
+        assert_eq!(read_header.covered_length(4096), 20000);
+
+        header.length_in_blocks = 1;
+        let mut buf = Vec::new();
+        header.write(&mut buf, 4096).unwrap();
+        assert!(ExtentHeader::read(&mut Cursor::new(buf), 4096, true).is_err());
+    }
+
+    fn sample_file_header(mode: Option<u32>) -> FileHeader {
+        FileHeader {
+            file_size: 5,
+            file_type: FileType::Regular,
+            uid: 1000,
+            gid: 1000,
+            device_major: 0,
+            device_minor: 0,
+            access_time: 1,
+            modify_time: 2,
+            creation_time: 3,
+            username: "user".to_string(),
+            groupname: "group".to_string(),
+            file_path: "dir".to_string(),
+            file_name: "file.txt".to_string(),
+            link_name: String::new(),
+            extended_permissions: Vec::new(),
+            source_filesystem_type: "ext4".to_string(),
+            source_filesystem_id: 42,
+            mode,
+            directory_listing: None,
+            xattrs: Vec::new(),
+            time_nanos: TimeNanos::default(),
+            data_seal: None,
+            inline_data: b"hello".to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_file_header_roundtrip_with_mode() {
+        let header = sample_file_header(Some(0o4750));
+        let mut buf = Vec::new();
+        header.write(&mut buf, 4096).unwrap();
+        assert_eq!(buf.len(), 4096);
+
+        let read_header = FileHeader::read(&mut Cursor::new(buf), 4096, true).unwrap();
+        assert_eq!(read_header.mode, Some(0o4750));
+        assert_eq!(read_header.inline_data, b"hello");
+        assert_eq!(read_header.file_name, "file.txt");
+        assert_eq!(read_header.directory_listing, None);
+
+        let mut dir = sample_file_header(Some(0o755));
+        dir.file_type = FileType::Directory;
+        dir.inline_data.clear();
+        dir.directory_listing = Some(vec!["a b".to_string(), "ünï".to_string()]);
+        let mut buf = Vec::new();
+        dir.write(&mut buf, 4096).unwrap();
+        let read_header = FileHeader::read(&mut Cursor::new(buf), 4096, true).unwrap();
+        assert_eq!(read_header.directory_listing, dir.directory_listing);
+
+        let mut header = sample_file_header(Some(0o644));
+        header.xattrs = vec![
+            ("user.comment".to_string(), b"a\0b".to_vec()),
+            ("security.selinux".to_string(), Vec::new()),
+        ];
+        let mut buf = Vec::new();
+        header.write(&mut buf, 4096).unwrap();
+        let read_header = FileHeader::read(&mut Cursor::new(buf), 4096, true).unwrap();
+        assert_eq!(read_header.xattrs, header.xattrs);
+
+        let mut header = sample_file_header(Some(0o644));
+        header.time_nanos = TimeNanos {
+            access: 1,
+            modify: 999_999_999,
+            creation: 0,
+        };
+        let mut buf = Vec::new();
+        header.write(&mut buf, 4096).unwrap();
+        let read_header = FileHeader::read(&mut Cursor::new(buf), 4096, true).unwrap();
+        assert_eq!(read_header.time_nanos, header.time_nanos);
+        assert!(TimeNanos::decode(&[0, 0xca, 0x9a, 0x3b, 0, 0, 0, 0, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_file_header_without_extensions() {
+        // Without a mode, the header's only extension record is its
+        // checksums, which come before the inline data
+        let header = sample_file_header(None);
+        let mut buf = Vec::new();
+        header.write(&mut buf, 4096).unwrap();
+
+        let read_header = FileHeader::read(&mut Cursor::new(buf), 4096, true).unwrap();
+        assert_eq!(read_header.mode, None);
+        assert_eq!(read_header.inline_data, b"hello");
+    }
+
+    #[test]
+    fn test_header_checksums_name_corrupted_region() {
+        let mut buf = Vec::new();
+        sample_file_header(Some(0o644)).write(&mut buf, 4096).unwrap();
+        for (offset, region) in [(60, "timestamps"), (90, "user and group names"), (106, "path and link target")] {
+            let mut damaged = buf.clone();
+            damaged[offset] ^= 0x10;
+            let error = FileHeader::read(&mut Cursor::new(damaged), 4096, true).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("File header is corrupted: checksum mismatch in its {}", region)
+            );
+        }
+
+        // A corrupted length is reported even though the fields after it
+        // can't be found
+        let mut damaged = buf.clone();
+        damaged[80] = 0xff;
+        let error = FileHeader::read(&mut Cursor::new(damaged), 4096, true).unwrap_err();
+        assert!(error.to_string().contains("user and group names"), "{}", error);
+
+        let header = ExtentHeader {
+            extent_id: 7,
+            length_in_blocks: 2,
+            extent_type: ExtentType::Data,
+            source_extent_start: 4096,
+            checksum: 0xDEADBEEF,
+            byte_length: Some(5000),
+            base: None,
+            compression: None,
+            seal: None,
+        };
+        let mut buf = Vec::new();
+        header.write(&mut buf, 4096).unwrap();
+        for (offset, region) in [(0, "extent id"), (12, "length and type"), (60, "extension records")] {
+            let mut damaged = buf.clone();
+            damaged[offset] ^= 1;
+            let error = ExtentHeader::read(&mut Cursor::new(damaged), 4096, true).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("Extent header is corrupted: checksum mismatch in its {}", region)
+            );
+        }
+    }
+
+    #[test]
+    fn test_header_checksums_edge_cases() {
+        let header = sample_file_header(Some(0o644));
+        let mut buf = Vec::new();
+        header.write(&mut buf, 4096).unwrap();
+        let header_size = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as usize;
+
+        // Damage in several regions names each of them, and inline data
+        // ending the header is covered too
+        let mut damaged = buf.clone();
+        damaged[60] ^= 1;
+        damaged[header_size - 1] ^= 1;
+        let error = FileHeader::read(&mut Cursor::new(damaged), 4096, true).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "File header is corrupted: checksum mismatch in its timestamps, inline data"
+        );
+
+        // A damaged checksum is reported like damage in its region
+        let record = buf.windows(6).position(|w| w == [4, 0, 40, 0, 0, 0]).unwrap();
+        let mut damaged = buf.clone();
+        damaged[record + 6 + 4 * 3] ^= 1;
+        let error = FileHeader::read(&mut Cursor::new(damaged), 4096, true).unwrap_err();
+        assert!(error.to_string().ends_with("in its timestamps"), "{}", error);
+
+        // A checksum record of the wrong length is rejected
+        let mut damaged = buf.clone();
+        damaged[record + 2] = 36;
+        let error = FileHeader::read(&mut Cursor::new(damaged), 4096, true).unwrap_err();
+        assert_eq!(error.to_string(), "File header is corrupted: its checksum record has the wrong length");
+
+        // Headers written before checksums have no record, and are read as
+        // they are, unless the archive says its headers have checksums
+        let mut legacy = buf[..header_size].to_vec();
+        legacy.drain(record..record + 46);
+        legacy[4..8].copy_from_slice(&(header_size as u32 - 46).to_le_bytes());
+        legacy.resize(4096, 0);
+        let read = FileHeader::read(&mut Cursor::new(legacy.clone()), 4096, false).unwrap();
+        assert_eq!(read.file_path, "dir");
+        assert_eq!(read.inline_data, b"hello");
+        assert_eq!(read.mode, Some(0o644));
+        let error = FileHeader::read(&mut Cursor::new(legacy), 4096, true).unwrap_err();
+        assert_eq!(error.to_string(), "File header is corrupted: its checksum record is missing");
+
+        // A flipped bit in the record's tag can't turn the checks off
+        let mut damaged = buf.clone();
+        damaged[record] ^= 1;
+        let error = FileHeader::read(&mut Cursor::new(damaged), 4096, true).unwrap_err();
+        assert_eq!(error.to_string(), "File header is corrupted: its checksum record is missing");
+        let mut extent = Vec::new();
+        ExtentHeader {
+            extent_id: 7,
+            length_in_blocks: 2,
+            extent_type: ExtentType::Data,
+            source_extent_start: 4096,
+            checksum: 0xDEADBEEF,
+            byte_length: None,
+            base: None,
+            compression: None,
+            seal: None,
+        }
+        .write(&mut extent, 4096)
+        .unwrap();
+        extent[EXTENT_HEADER_FIXED_SIZE + 2] ^= 1;
+        let error = ExtentHeader::read(&mut Cursor::new(extent), 4096, true).unwrap_err();
+        assert_eq!(error.to_string(), "Extent header is corrupted: its checksum record is missing");
+    }
+
+    #[test]
+    fn test_footer_roundtrip() {
+        let footer = ArchiveFooter {
+            members: vec![
+                IndexEntry {
+                    header_offset: 4096,
+                    file_type: FileType::Directory,
+                    path: "dir".to_string(),
+                },
+                IndexEntry {
+                    header_offset: 8192,
+                    file_type: FileType::Regular,
+                    path: "dir/file.txt".to_string(),
+                },
+            ],
+            extents: vec![
+                ExtentEntry {
+                    content_hash: [7; 32],
+                    extent_id: 0,
+                    data_offset: 12288,
+                    length_in_blocks: 2,
+                    covered_length: 5000,
+                    checksum: 99,
+                    compression: None,
+                },
+                ExtentEntry {
+                    content_hash: [8; 32],
+                    extent_id: 1,
+                    data_offset: 24576,
+                    length_in_blocks: 1,
+                    covered_length: 65536,
+                    checksum: 98,
+                    compression: Some(ExtentCompression {
+                        algorithm: CompressionAlgorithm::Zstd,
+                        stored_length: 3000,
+                    }),
+                },
+            ],
+            bases: vec![BaseEntry {
+                archive_id: [9; 32],
+                name: "monday.reftar".to_string(),
+            }],
+            manifest: Some(Manifest {
+                header_hash: [1; 32],
+                members: vec![ManifestEntry {
+                    header_offset: 4096,
+                    hash: [2; 32],
+                }],
+            }),
+            signature: Some(ArchiveSignature {
+                public_key: [3; 32],
+                signature: [4; 64],
+            }),
+        };
+
+        // Pretend the archive has two blocks before the footer
+        let mut buf = vec![0u8; 8192];
+        footer.write(&mut buf, 4096, 8192).unwrap();
+        assert_eq!(buf.len() % 4096, 0);
+
+        let mut cursor = Cursor::new(buf);
+        let read_footer = ArchiveFooter::locate(&mut cursor).unwrap().unwrap();
+        assert_eq!(read_footer.members, footer.members);
+        assert_eq!(read_footer.extents, footer.extents);
+        assert_eq!(read_footer.bases, footer.bases);
+        assert_eq!(read_footer.manifest, footer.manifest);
+        assert_eq!(read_footer.signature, footer.signature);
+        assert_eq!(read_footer.signed_message().unwrap(), footer.signed_message().unwrap());
+        assert_eq!(read_footer.archive_id(), footer.archive_id());
+
+        // The footer ends a sequential read like end of input does
+        cursor.set_position(8192);
+        assert!(read_member_header(&mut cursor, 8192, 4096, true).unwrap().is_none());
+        assert_eq!(cursor.position(), cursor.get_ref().len() as u64);
+    }
+
+    #[test]
+    fn test_locate_without_footer() {
+        let mut buf = Vec::new();
+        ArchiveHeader::new(4096).write(&mut buf).unwrap();
+        assert!(ArchiveFooter::locate(&mut Cursor::new(buf)).unwrap().is_none());
     }
 }
@@ -0,0 +1,164 @@
+//! Snapshot files for incremental archives (`--listed-incremental`)
+//!
+//! A snapshot records the identity and change times of every member of an
+//! archive. The next incremental run compares the tree against it, archives
+//! only what changed, and writes a new snapshot for the run after that.
+
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// First line of a snapshot file
+const SNAPSHOT_SIGNATURE: &str = "reftar-snapshot 1\n";
+
+/// What a member looked like when it was last archived
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SnapshotEntry {
+    pub dev: u64,
+    pub ino: u64,
+    pub mtime_ns: i128,
+    pub ctime_ns: i128,
+    pub size: u64,
+}
+
+impl SnapshotEntry {
+    pub fn from_metadata(metadata: &fs::Metadata) -> Self {
+        let nanos = |secs: i64, nsecs: i64| secs as i128 * 1_000_000_000 + nsecs as i128;
+        Self {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            mtime_ns: nanos(metadata.mtime(), metadata.mtime_nsec()),
+            ctime_ns: nanos(metadata.ctime(), metadata.ctime_nsec()),
+            size: metadata.len(),
+        }
+    }
+}
+
+/// Members of an archive by member path
+///
+/// Stored as the signature line followed by one record per member: device,
+/// inode, mtime and ctime (nanoseconds) and size as decimal numbers
+/// separated by spaces, then a space, the path and a NUL byte.
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    entries: BTreeMap<PathBuf, SnapshotEntry>,
+}
+
+impl Snapshot {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read a snapshot file; a missing file is an empty snapshot, which
+    /// makes the next archive a full (level 0) one
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read snapshot {:?}", path)),
+        };
+        let Some(records) = contents.strip_prefix(SNAPSHOT_SIGNATURE.as_bytes()) else {
+            bail!("{:?} is not a reftar snapshot file", path);
+        };
+
+        let mut snapshot = Self::new();
+        for record in records.split(|&b| b == 0).filter(|record| !record.is_empty()) {
+            let mut fields = record.splitn(6, |&b| b == b' ');
+            let mut number = || -> Result<i128> {
+                let field = fields.next().unwrap_or_default();
+                std::str::from_utf8(field)?
+                    .parse()
+                    .with_context(|| format!("Corrupt record in snapshot {:?}", path))
+            };
+            let entry = SnapshotEntry {
+                dev: number()? as u64,
+                ino: number()? as u64,
+                mtime_ns: number()?,
+                ctime_ns: number()?,
+                size: number()? as u64,
+            };
+            let member_path = match fields.next() {
+                Some(member_path) if !member_path.is_empty() => member_path,
+                _ => bail!("Corrupt record in snapshot {:?}", path),
+            };
+            snapshot
+                .entries
+                .insert(PathBuf::from(OsStr::from_bytes(member_path)), entry);
+        }
+        Ok(snapshot)
+    }
+
+    /// Write the snapshot, replacing `path` only once it is complete
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut contents = SNAPSHOT_SIGNATURE.as_bytes().to_vec();
+        for (member_path, entry) in &self.entries {
+            contents.extend_from_slice(
+                format!(
+                    "{} {} {} {} {} ",
+                    entry.dev, entry.ino, entry.mtime_ns, entry.ctime_ns, entry.size
+                )
+                .as_bytes(),
+            );
+            contents.extend_from_slice(member_path.as_os_str().as_bytes());
+            contents.push(0);
+        }
+
+        let mut temp_name = path.as_os_str().to_owned();
+        temp_name.push(".tmp");
+        let temp_path = PathBuf::from(temp_name);
+        fs::write(&temp_path, contents).with_context(|| format!("Failed to write snapshot {:?}", temp_path))?;
+        fs::rename(&temp_path, path).with_context(|| format!("Failed to replace snapshot {:?}", path))?;
+        Ok(())
+    }
+
+    pub fn get(&self, member_path: &Path) -> Option<&SnapshotEntry> {
+        self.entries.get(member_path)
+    }
+
+    pub fn insert(&mut self, member_path: PathBuf, entry: SnapshotEntry) {
+        self.entries.insert(member_path, entry);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.snar");
+        assert!(Snapshot::load(&path).unwrap().is_empty());
+
+        let mut snapshot = Snapshot::new();
+        let entry = SnapshotEntry {
+            dev: 2049,
+            ino: 123,
+            mtime_ns: 1_700_000_000_123_456_789,
+            ctime_ns: -5,
+            size: 42,
+        };
+        snapshot.insert(PathBuf::from("dir/with space\nand newline"), entry);
+        snapshot.insert(PathBuf::from("dir"), SnapshotEntry { size: 0, ..entry });
+        snapshot.save(&path).unwrap();
+
+        let loaded = Snapshot::load(&path).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get(Path::new("dir/with space\nand newline")), Some(&entry));
+
+        fs::write(&path, "not a snapshot").unwrap();
+        assert!(Snapshot::load(&path).is_err());
+    }
+}
@@ -3,11 +3,29 @@
 //! This library provides functionality for creating and extracting archives
 //! with support for filesystem reflinks (copy-on-write) and data deduplication.
 
+pub mod base;
+pub mod chunking;
+pub mod compare;
+pub mod compression;
+pub mod convert;
 pub mod create;
+pub mod encryption;
 pub mod extract;
+pub mod filter;
 pub mod format;
+pub mod incremental;
+pub mod manifest;
+pub mod parity;
+pub mod progress;
 pub mod reflink;
+pub mod signature;
+pub mod stream;
+pub mod transform;
+
+#[cfg(test)]
+mod test_util;
 
 pub use create::ArchiveCreator;
 pub use extract::ArchiveExtractor;
+pub use filter::PathFilter;
 pub use format::{ArchiveHeader, FileHeader, ExtentHeader, FileType, ExtentType};
@@ -1,12 +1,30 @@
+mod base;
+mod chunking;
+mod compare;
+mod compression;
+mod convert;
 mod create;
+mod encryption;
 mod extract;
+mod filter;
 mod format;
+mod incremental;
+mod manifest;
+mod parity;
+mod progress;
 mod reflink;
+mod signature;
+mod stream;
+mod transform;
+
+#[cfg(test)]
+mod test_util;
 
 use anyhow::{Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
 use std::fs::{File, OpenOptions};
-use std::path::PathBuf;
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
 #[command(name = "reftar")]
@@ -18,10 +36,12 @@ struct Cli {
 }
 
 #[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)] // Parsed once
 enum Commands {
     /// Create a new archive
+    #[command(disable_help_flag = true)]
     Create {
-        /// Output archive file
+        /// Output archive file (- for standard output)
         #[arg(short = 'f', long)]
         file: PathBuf,
 
@@ -33,14 +53,194 @@ enum Commands {
         #[arg(required = true)]
         inputs: Vec<PathBuf>,
 
+        /// Read the inputs that follow from DIR (repeatable, relative to the
+        /// previous -C)
+        #[arg(short = 'C', long, value_name = "DIR")]
+        directory: Vec<PathBuf>,
+
+        /// Rewrite member paths with a sed-style s/regex/replacement/[gi]
+        /// expression (repeatable)
+        #[arg(long, value_name = "EXPR")]
+        transform: Vec<String>,
+
+        /// Remove this many leading components from member paths
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        strip_components: usize,
+
+        /// Number of threads reading and hashing file data
+        #[arg(short = 'j', long, default_value_t = 1)]
+        jobs: usize,
+
+        /// How file data is split for deduplication: fixed blocks keep
+        /// shared data reflinkable, content-defined chunks also find data
+        /// shifted by insertions or deletions [default: fixed, or cdc with
+        /// --compress]
+        #[arg(long, value_enum)]
+        chunking: Option<ChunkingArg>,
+
+        /// Average content-defined chunk size in bytes (K and M suffixes allowed)
+        #[arg(long, value_name = "BYTES", value_parser = parse_size,
+              default_value_t = chunking::DEFAULT_AVERAGE_CHUNK_SIZE)]
+        chunk_size: u32,
+
+        /// Compress extents: zstd, zstd:LEVEL, auto (skip incompressible
+        /// data) or none
+        #[arg(long, value_name = "POLICY", value_parser = parse_compression)]
+        compress: Option<compression::CompressionPolicy>,
+
+        /// Compress the whole archive with gzip
+        #[arg(short = 'z', long, group = "stream_compression")]
+        gzip: bool,
+
+        /// Compress the whole archive with xz
+        #[arg(short = 'J', long, group = "stream_compression")]
+        xz: bool,
+
+        /// Compress the whole archive with zstd, in the seekable format so
+        /// the footer index stays reachable
+        #[arg(long, group = "stream_compression")]
+        zstd: bool,
+
+        /// Encrypt the archive with a passphrase, asked for on the terminal
+        #[arg(long)]
+        encrypt: bool,
+
+        /// Encrypt the archive with the passphrase on the first line of FILE
+        #[arg(long, value_name = "FILE")]
+        passphrase_file: Option<PathBuf>,
+
+        /// Encrypt the archive with a key file of at least 32 random bytes
+        /// (repeatable; each passphrase and key file can open the archive)
+        #[arg(long, value_name = "FILE")]
+        key_file: Vec<PathBuf>,
+
+        /// Leave the headers and index of an encrypted archive readable
+        /// (still authenticated), so it can be listed without a key
+        #[arg(long)]
+        clear_metadata: bool,
+
+        /// Sign the archive with an Ed25519 private key (PEM file)
+        #[arg(long, value_name = "KEY")]
+        sign: Option<PathBuf>,
+
+        /// Add Reed-Solomon parity for repairing damage: PARITY blocks for
+        /// every DATA blocks (default 64:4), appended to the archive
+        #[arg(long, value_name = "DATA:PARITY", value_parser = parse_parity, num_args = 0..=1,
+              require_equals = true, default_missing_value = "64:4")]
+        parity: Option<parity::ParityScheme>,
+
+        /// Write the parity to FILE instead of appending it to the archive
+        #[arg(long, value_name = "FILE")]
+        parity_file: Option<PathBuf>,
+
+        /// Store data already in this archive as references into it,
+        /// creating a differential archive (repeatable)
+        #[arg(long, value_name = "ARCHIVE")]
+        base: Vec<PathBuf>,
+
+        /// Archive only what changed since the snapshot in FILE, then
+        /// update it (a missing FILE makes a full archive)
+        #[arg(short = 'g', long, value_name = "FILE")]
+        listed_incremental: Option<PathBuf>,
+
+        /// Skip files matching a glob pattern (repeatable)
+        #[arg(long, value_name = "PATTERN")]
+        exclude: Vec<String>,
+
+        /// Only archive files matching a glob pattern (repeatable)
+        #[arg(long, value_name = "PATTERN")]
+        include: Vec<String>,
+
+        /// Read exclude patterns from a file (repeatable)
+        #[arg(long, value_name = "FILE")]
+        exclude_from: Vec<PathBuf>,
+
+        /// Skip the contents of directories tagged with CACHEDIR.TAG
+        #[arg(long)]
+        exclude_caches: bool,
+
+        /// Skip version control directories and files
+        #[arg(long)]
+        exclude_vcs: bool,
+
+        /// Stay on the filesystem of each input directory
+        #[arg(long)]
+        one_file_system: bool,
+
+        /// Do not read .reftarignore files
+        #[arg(long)]
+        no_ignore_files: bool,
+
+        /// Archive the targets of symbolic links instead of the links
+        #[arg(short = 'h', long, conflicts_with = "dereference_args")]
+        dereference: bool,
+
+        /// Follow symbolic links named on the command line only
+        #[arg(long)]
+        dereference_args: bool,
+
+        /// Order of directory entries in the archive
+        #[arg(long, value_enum, default_value_t = SortArg::None)]
+        sort: SortArg,
+
+        /// Record this modification time (seconds since the epoch, optionally
+        /// prefixed with @) instead of each file's own; defaults to
+        /// SOURCE_DATE_EPOCH with --clamp-mtime when that is set
+        #[arg(long, value_name = "EPOCH", value_parser = parse_epoch)]
+        mtime: Option<u64>,
+
+        /// Only replace modification times later than --mtime
+        #[arg(long, requires = "mtime")]
+        clamp_mtime: bool,
+
+        /// Record access times as zero
+        #[arg(long)]
+        zero_atime: bool,
+
+        /// Record change times as zero
+        #[arg(long)]
+        zero_ctime: bool,
+
+        /// Leave out the source filesystem id
+        #[arg(long)]
+        zero_fs_id: bool,
+
+        /// Leave out the source filesystem type
+        #[arg(long)]
+        zero_fs_type: bool,
+
+        /// Record numeric ids only, without user and group names
+        #[arg(long)]
+        numeric_owner: bool,
+
+        /// Record this owner for every member: a user name, optionally
+        /// with its uid, or a numeric uid alone (NAME[:UID] or UID)
+        #[arg(long, value_name = "NAME[:UID]", value_parser = parse_owner)]
+        owner: Option<create::OwnerOverride>,
+
+        /// Record this group for every member: a group name, optionally
+        /// with its gid, or a numeric gid alone (NAME[:GID] or GID)
+        #[arg(long, value_name = "NAME[:GID]", value_parser = parse_group)]
+        group: Option<create::OwnerOverride>,
+
+        /// Show progress on stderr: a progress bar, or JSON records
+        #[arg(long, value_enum, value_name = "FORMAT", num_args = 0..=1,
+              require_equals = true, default_missing_value = "bar")]
+        progress: Option<ProgressArg>,
+
         /// Verbose output
         #[arg(short = 'v', long)]
         verbose: bool,
+
+        /// Print help (-h means --dereference, as in tar)
+        #[arg(long, action = clap::ArgAction::Help)]
+        help: Option<bool>,
     },
 
     /// Extract files from an archive
     Extract {
-        /// Input archive file
+        /// Input archive file (- for standard input); compression is
+        /// detected
         #[arg(short = 'f', long)]
         file: PathBuf,
 
@@ -48,6 +248,87 @@ enum Commands {
         #[arg(short = 'C', long, default_value = ".")]
         output_dir: PathBuf,
 
+        /// Write the members to FILE as a tar archive (- for standard
+        /// output) instead of extracting them
+        #[arg(long, value_name = "FILE",
+              conflicts_with_all = ["output_dir", "overwrite_policy", "sync", "incremental", "jobs", "salvage", "progress"])]
+        to_tar: Option<PathBuf>,
+
+        /// Rewrite member paths with a sed-style s/regex/replacement/[gi]
+        /// expression (repeatable)
+        #[arg(long, value_name = "EXPR")]
+        transform: Vec<String>,
+
+        /// Remove this many leading components from member paths
+        #[arg(long, value_name = "N", default_value_t = 0)]
+        strip_components: usize,
+
+        /// Don't replace existing files; report them as errors
+        #[arg(short = 'k', long, group = "overwrite_policy")]
+        keep_old_files: bool,
+
+        /// Don't replace existing files; skip them silently
+        #[arg(long, group = "overwrite_policy")]
+        skip_old_files: bool,
+
+        /// Overwrite existing files (default)
+        #[arg(long, group = "overwrite_policy")]
+        overwrite: bool,
+
+        /// Don't replace existing files that are newer than their archive copy
+        #[arg(long, group = "overwrite_policy")]
+        keep_newer_files: bool,
+
+        /// Remove each existing file before extracting over it
+        #[arg(short = 'U', long, group = "overwrite_policy")]
+        unlink_first: bool,
+
+        /// Remove existing directory hierarchies before extracting over them
+        #[arg(long, group = "overwrite_policy")]
+        recursive_unlink: bool,
+
+        /// Flush each file to disk before renaming it into place
+        #[arg(long)]
+        sync: bool,
+
+        /// Base archive holding data a differential archive references
+        /// (repeatable)
+        #[arg(long, value_name = "ARCHIVE")]
+        base: Vec<PathBuf>,
+
+        /// Extract an incremental archive, removing files that were
+        /// deleted or renamed since the previous archive of the chain
+        #[arg(short = 'G', long)]
+        incremental: bool,
+
+        /// Number of worker threads (needs an archive with a footer index)
+        #[arg(short = 'j', long, default_value_t = 1)]
+        jobs: usize,
+
+        /// Passphrase of an encrypted archive, on the first line of FILE
+        /// (asked for on the terminal otherwise)
+        #[arg(long, value_name = "FILE")]
+        passphrase_file: Option<PathBuf>,
+
+        /// Key file of an encrypted archive (repeatable)
+        #[arg(long, value_name = "FILE")]
+        key_file: Vec<PathBuf>,
+
+        /// Refuse archives not signed with this Ed25519 public key (PEM
+        /// file); archive files are checked before anything is extracted
+        #[arg(long, value_name = "KEY")]
+        require_signature: Option<PathBuf>,
+
+        /// Keep going past damage: skip to the next readable member, leave
+        /// lost file data as zeros, and report what was lost
+        #[arg(long, conflicts_with = "require_signature")]
+        salvage: bool,
+
+        /// Show progress on stderr: a progress bar, or JSON records
+        #[arg(long, value_enum, value_name = "FORMAT", num_args = 0..=1,
+              require_equals = true, default_missing_value = "bar")]
+        progress: Option<ProgressArg>,
+
         /// Verbose output
         #[arg(short = 'v', long)]
         verbose: bool,
@@ -55,10 +336,20 @@ enum Commands {
 
     /// List files in an archive
     List {
-        /// Input archive file
+        /// Input archive file (- for standard input); compression is
+        /// detected
         #[arg(short = 'f', long)]
         file: PathBuf,
 
+        /// Passphrase of an encrypted archive, on the first line of FILE
+        /// (asked for on the terminal otherwise)
+        #[arg(long, value_name = "FILE")]
+        passphrase_file: Option<PathBuf>,
+
+        /// Key file of an encrypted archive (repeatable)
+        #[arg(long, value_name = "FILE")]
+        key_file: Vec<PathBuf>,
+
         /// Verbose output
         #[arg(short = 'v', long)]
         verbose: bool,
@@ -70,97 +361,990 @@ enum Commands {
         #[arg(short = 'f', long)]
         file: PathBuf,
     },
+
+    /// Sign an archive file in place with an Ed25519 private key
+    Sign {
+        /// Archive file (uncompressed)
+        #[arg(short = 'f', long)]
+        file: PathBuf,
+
+        /// Ed25519 private key (PEM file)
+        #[arg(long, value_name = "KEY")]
+        key: PathBuf,
+
+        /// Passphrase of an encrypted archive, on the first line of FILE
+        /// (asked for on the terminal otherwise)
+        #[arg(long, value_name = "FILE")]
+        passphrase_file: Option<PathBuf>,
+
+        /// Key file of an encrypted archive (repeatable)
+        #[arg(long, value_name = "FILE")]
+        key_file: Vec<PathBuf>,
+    },
+
+    /// Check every header and extent of an archive, and optionally that it
+    /// is signed with a key
+    Verify {
+        /// Input archive file (- for standard input); compression is
+        /// detected
+        #[arg(short = 'f', long)]
+        file: PathBuf,
+
+        /// Also check the archive is signed with this Ed25519 public key
+        /// (PEM file)
+        #[arg(long, value_name = "KEY")]
+        key: Option<PathBuf>,
+
+        /// Parity file to check the archive against (default: parity
+        /// appended to the archive, or ARCHIVE.par)
+        #[arg(long, value_name = "FILE")]
+        parity_file: Option<PathBuf>,
+
+        /// Passphrase of an encrypted archive, on the first line of FILE
+        /// (asked for on the terminal otherwise)
+        #[arg(long, value_name = "FILE")]
+        passphrase_file: Option<PathBuf>,
+
+        /// Key file of an encrypted archive (repeatable)
+        #[arg(long, value_name = "FILE")]
+        key_file: Vec<PathBuf>,
+    },
+
+    /// Print a digest of every regular file, as `sha256sum -c` or `b3sum -c`
+    /// lines or as JSON, without extracting
+    Manifest {
+        /// Input archive file (- for standard input); compression is
+        /// detected
+        #[arg(short = 'f', long)]
+        file: PathBuf,
+
+        /// Digest algorithm
+        #[arg(long, value_enum, default_value = "sha256")]
+        algo: AlgoArg,
+
+        /// Print one JSON record per file, with its size, mode, mtime and
+        /// which of its data is shared
+        #[arg(long)]
+        json: bool,
+
+        /// Base archive holding data a differential archive references
+        /// (repeatable)
+        #[arg(long, value_name = "ARCHIVE")]
+        base: Vec<PathBuf>,
+
+        /// Passphrase of an encrypted archive, on the first line of FILE
+        /// (asked for on the terminal otherwise)
+        #[arg(long, value_name = "FILE")]
+        passphrase_file: Option<PathBuf>,
+
+        /// Key file of an encrypted archive (repeatable)
+        #[arg(long, value_name = "FILE")]
+        key_file: Vec<PathBuf>,
+    },
+
+    /// Compare members with the filesystem, like `tar -d`; exits 0 if
+    /// nothing differs, 1 if something does and 2 on errors
+    #[command(visible_alias = "compare")]
+    Diff {
+        /// Input archive file (- for standard input); compression is
+        /// detected
+        #[arg(short = 'f', long)]
+        file: PathBuf,
+
+        /// Directory to compare with (default: current directory)
+        #[arg(short = 'C', long, default_value = ".")]
+        directory: PathBuf,
+
+        /// Attributes not to compare (comma-separated or repeatable)
+        #[arg(long, value_enum, value_delimiter = ',', value_name = "ATTR")]
+        ignore: Vec<AttributeArg>,
+
+        /// Base archive holding data a differential archive references
+        /// (repeatable)
+        #[arg(long, value_name = "ARCHIVE")]
+        base: Vec<PathBuf>,
+
+        /// Passphrase of an encrypted archive, on the first line of FILE
+        /// (asked for on the terminal otherwise)
+        #[arg(long, value_name = "FILE")]
+        passphrase_file: Option<PathBuf>,
+
+        /// Key file of an encrypted archive (repeatable)
+        #[arg(long, value_name = "FILE")]
+        key_file: Vec<PathBuf>,
+    },
+
+    /// Convert a tar archive to reftar, deduplicating its data, or a
+    /// reftar archive to tar
+    Convert {
+        /// Format of the input archive, converted to reftar
+        #[arg(long, value_enum, value_name = "FORMAT", required_unless_present = "to", conflicts_with = "to")]
+        from: Option<FormatArg>,
+
+        /// Format to convert the reftar input archive to
+        #[arg(long, value_enum, value_name = "FORMAT")]
+        to: Option<FormatArg>,
+
+        /// Input archive (- for standard input); gzip, xz and zstd
+        /// compression is detected
+        input: PathBuf,
+
+        /// Output archive (- for standard output)
+        output: PathBuf,
+
+        /// Block size in bytes (default: 4096)
+        #[arg(short = 'b', long, conflicts_with = "to")]
+        block_size: Option<u32>,
+
+        /// How file data is split for deduplication [default: fixed, or
+        /// cdc with --compress]
+        #[arg(long, value_enum, conflicts_with = "to")]
+        chunking: Option<ChunkingArg>,
+
+        /// Average content-defined chunk size in bytes (K and M suffixes allowed)
+        #[arg(long, value_name = "BYTES", value_parser = parse_size,
+              default_value_t = chunking::DEFAULT_AVERAGE_CHUNK_SIZE, conflicts_with = "to")]
+        chunk_size: u32,
+
+        /// Compress extents: zstd, zstd:LEVEL, auto (skip incompressible
+        /// data) or none
+        #[arg(long, value_name = "POLICY", value_parser = parse_compression, conflicts_with = "to")]
+        compress: Option<compression::CompressionPolicy>,
+
+        /// Compress the whole archive with gzip
+        #[arg(short = 'z', long, group = "stream_compression")]
+        gzip: bool,
+
+        /// Compress the whole archive with xz
+        #[arg(short = 'J', long, group = "stream_compression")]
+        xz: bool,
+
+        /// Compress the whole archive with zstd, in the seekable format
+        #[arg(long, group = "stream_compression")]
+        zstd: bool,
+
+        /// Base archive holding data a differential input archive
+        /// references (repeatable)
+        #[arg(long, value_name = "ARCHIVE", requires = "to")]
+        base: Vec<PathBuf>,
+
+        /// Passphrase of an encrypted input archive, on the first line of
+        /// FILE (asked for on the terminal otherwise)
+        #[arg(long, value_name = "FILE", requires = "to")]
+        passphrase_file: Option<PathBuf>,
+
+        /// Key file of an encrypted input archive (repeatable)
+        #[arg(long, value_name = "FILE", requires = "to")]
+        key_file: Vec<PathBuf>,
+
+        /// List members as they are converted
+        #[arg(short = 'v', long)]
+        verbose: bool,
+    },
+
+    /// Add Reed-Solomon parity to an existing archive, replacing any it has
+    Parity {
+        /// Archive file
+        #[arg(short = 'f', long)]
+        file: PathBuf,
+
+        /// PARITY blocks for every DATA blocks
+        #[arg(long, value_name = "DATA:PARITY", value_parser = parse_parity, default_value = "64:4")]
+        parity: parity::ParityScheme,
+
+        /// Write the parity to FILE instead of appending it to the archive
+        #[arg(long, value_name = "FILE")]
+        parity_file: Option<PathBuf>,
+
+        /// Block size in bytes (default: the archive's)
+        #[arg(short = 'b', long)]
+        block_size: Option<u32>,
+    },
+
+    /// Rebuild damaged blocks of an archive from its parity
+    Repair {
+        /// Archive file
+        #[arg(short = 'f', long)]
+        file: PathBuf,
+
+        /// Parity file (default: parity appended to the archive, or
+        /// ARCHIVE.par)
+        #[arg(long, value_name = "FILE")]
+        parity_file: Option<PathBuf>,
+    },
+
+    /// Report what the filesystem holding a directory supports
+    Doctor {
+        /// Directory to probe (default: current directory)
+        #[arg(default_value = ".")]
+        dir: PathBuf,
+    },
 }
 
 fn main() -> Result<()> {
-    let cli = Cli::parse();
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
     match cli.command {
         Commands::Create {
             file,
             block_size,
-            inputs,
+            inputs: _,
+            directory: _,
+            transform,
+            strip_components,
+            jobs,
+            chunking,
+            chunk_size,
+            compress,
+            gzip,
+            xz,
+            zstd,
+            encrypt,
+            passphrase_file,
+            key_file,
+            clear_metadata,
+            sign,
+            parity,
+            parity_file,
+            base,
+            listed_incremental,
+            exclude,
+            include,
+            exclude_from,
+            exclude_caches,
+            exclude_vcs,
+            one_file_system,
+            no_ignore_files,
+            sort,
+            mtime,
+            clamp_mtime,
+            zero_atime,
+            zero_ctime,
+            zero_fs_id,
+            zero_fs_type,
+            numeric_owner,
+            owner,
+            group,
+            dereference,
+            dereference_args,
+            progress,
             verbose,
-        } => create_archive(file, block_size, inputs, verbose)?,
+            help: _,
+        } => {
+            let mut filter = filter::PathFilter::new()
+                .exclude_caches(exclude_caches)
+                .exclude_vcs(exclude_vcs)
+                .one_file_system(one_file_system)
+                .ignore_files(!no_ignore_files);
+            for pattern in &exclude {
+                filter = filter.exclude(pattern)?;
+            }
+            for pattern in &include {
+                filter = filter.include(pattern)?;
+            }
+            for path in &exclude_from {
+                filter = filter.exclude_from(path)?;
+            }
+
+            let mtime = match (mtime, clamp_mtime) {
+                (Some(time), false) => create::MtimePolicy::Set(time),
+                (Some(time), true) => create::MtimePolicy::Clamp(time),
+                (None, _) => match std::env::var("SOURCE_DATE_EPOCH") {
+                    Ok(value) => create::MtimePolicy::Clamp(
+                        parse_epoch(&value).map_err(|e| anyhow::anyhow!("SOURCE_DATE_EPOCH: {}", e))?,
+                    ),
+                    Err(_) => create::MtimePolicy::Keep,
+                },
+            };
+            let metadata = create::MetadataOptions {
+                mtime,
+                zero_atime,
+                zero_ctime,
+                zero_filesystem_id: zero_fs_id,
+                zero_filesystem_type: zero_fs_type,
+                numeric_owner,
+                owner,
+                group,
+            };
+
+            let compression = compress.unwrap_or_default();
+
+            let mut keys = read_keys(passphrase_file.as_deref(), &key_file)?;
+            if encrypt && passphrase_file.is_none() {
+                keys.insert(0, prompt_passphrase(true)?);
+            }
+            if clear_metadata && keys.is_empty() {
+                anyhow::bail!("--clear-metadata needs --encrypt, --passphrase-file or --key-file");
+            }
+
+            let stream = stream_compression(gzip, xz, zstd);
+            let parity = match (parity, &parity_file) {
+                (None, None) => None,
+                _ if file == Path::new("-") => anyhow::bail!("--parity needs the archive written to a file"),
+                (_, None) if stream != stream::StreamCompression::None => {
+                    anyhow::bail!("Parity can't be appended to a compressed archive; use --parity-file")
+                }
+                (scheme, path) => Some((scheme.unwrap_or_default(), path.clone())),
+            };
+
+            let creator_options = CreateOptions {
+                jobs,
+                chunking: extent_chunking(chunking, chunk_size, &compression)?,
+                compression,
+                stream,
+                keys,
+                clear_metadata,
+                signing_key: sign.as_deref().map(signature::load_signing_key).transpose()?,
+                parity,
+                bases: base,
+                snapshot: listed_incremental,
+                progress: progress.map(Into::into),
+                filter,
+                sort: sort.into(),
+                metadata,
+                rewrite: path_rewrite(&transform, strip_components)?,
+                dereference: if dereference {
+                    create::Dereference::Always
+                } else if dereference_args {
+                    create::Dereference::Arguments
+                } else {
+                    create::Dereference::Never
+                },
+            };
+            let create_matches = matches.subcommand_matches("create").expect("create subcommand");
+            let inputs = inputs_with_directories(create_matches);
+            create_archive(file, block_size, inputs, creator_options, verbose)?
+        }
 
         Commands::Extract {
             file,
             output_dir,
+            to_tar,
+            transform,
+            strip_components,
+            keep_old_files,
+            skip_old_files,
+            overwrite: _,
+            keep_newer_files,
+            unlink_first,
+            recursive_unlink,
+            sync,
+            base,
+            incremental,
+            jobs,
+            passphrase_file,
+            key_file,
+            require_signature,
+            salvage,
+            progress,
             verbose,
-        } => extract_archive(file, output_dir, verbose)?,
+        } => {
+            let policy = if keep_old_files {
+                extract::OverwritePolicy::KeepOldFiles
+            } else if skip_old_files {
+                extract::OverwritePolicy::SkipOldFiles
+            } else if keep_newer_files {
+                extract::OverwritePolicy::KeepNewerFiles
+            } else if unlink_first {
+                extract::OverwritePolicy::UnlinkFirst
+            } else if recursive_unlink {
+                extract::OverwritePolicy::RecursiveUnlink
+            } else {
+                extract::OverwritePolicy::Overwrite
+            };
+            let options = ExtractOptions {
+                policy,
+                sync,
+                jobs,
+                rewrite: path_rewrite(&transform, strip_components)?,
+                bases: base,
+                incremental,
+                keys: read_keys(passphrase_file.as_deref(), &key_file)?,
+                signature_key: require_signature
+                    .as_deref()
+                    .map(signature::load_verifying_key)
+                    .transpose()?,
+                salvage,
+                progress: progress.map(Into::into),
+            };
+            match to_tar {
+                Some(tar_path) => extract_to_tar(file, tar_path, options, verbose)?,
+                None => extract_archive(file, output_dir, options, verbose)?,
+            }
+        }
 
-        Commands::List { file, verbose } => list_archive(file, verbose)?,
+        Commands::List {
+            file,
+            passphrase_file,
+            key_file,
+            verbose,
+        } => list_archive(file, read_keys(passphrase_file.as_deref(), &key_file)?, verbose)?,
 
         Commands::Info { file } => show_archive_info(file)?,
+
+        Commands::Sign {
+            file,
+            key,
+            passphrase_file,
+            key_file,
+        } => sign_archive(
+            file,
+            &signature::load_signing_key(&key)?,
+            read_keys(passphrase_file.as_deref(), &key_file)?,
+        )?,
+
+        Commands::Verify {
+            file,
+            key,
+            parity_file,
+            passphrase_file,
+            key_file,
+        } => verify_archive(
+            file,
+            key.as_deref().map(signature::load_verifying_key).transpose()?,
+            parity_file,
+            read_keys(passphrase_file.as_deref(), &key_file)?,
+        )?,
+
+        Commands::Manifest {
+            file,
+            algo,
+            json,
+            base,
+            passphrase_file,
+            key_file,
+        } => print_manifest(file, algo.into(), json, base, read_keys(passphrase_file.as_deref(), &key_file)?)?,
+
+        Commands::Diff {
+            file,
+            directory,
+            ignore,
+            base,
+            passphrase_file,
+            key_file,
+        } => {
+            let ignore: Vec<compare::Attribute> = ignore.into_iter().map(Into::into).collect();
+            let differs = read_keys(passphrase_file.as_deref(), &key_file)
+                .and_then(|keys| diff_archive(file, directory, &ignore, base, keys));
+            let status = diff_status(differs);
+            if status != 0 {
+                std::process::exit(status);
+            }
+        }
+
+        Commands::Convert {
+            from,
+            to: _,
+            input,
+            output,
+            block_size,
+            chunking,
+            chunk_size,
+            compress,
+            gzip,
+            xz,
+            zstd,
+            base,
+            passphrase_file,
+            key_file,
+            verbose,
+        } => {
+            let stream = stream_compression(gzip, xz, zstd);
+            // Exactly one of --from and --to is given
+            match from {
+                Some(FormatArg::Tar) => {
+                    let compression = compress.unwrap_or_default();
+                    let chunking = extent_chunking(chunking, chunk_size, &compression)?;
+                    convert_from_tar(input, output, block_size, chunking, compression, stream, verbose)?
+                }
+                None => {
+                    let keys = read_keys(passphrase_file.as_deref(), &key_file)?;
+                    convert_to_tar(input, output, stream, base, keys, verbose)?
+                }
+            }
+        }
+
+        Commands::Parity {
+            file,
+            parity,
+            parity_file,
+            block_size,
+        } => {
+            let index = add_parity(file.clone(), parity, parity_file.clone(), block_size)?;
+            println!(
+                "Added {} parity blocks of {} bytes to {}",
+                index.parity_checksums.len(),
+                index.block_size,
+                parity_file.unwrap_or(file).display()
+            );
+        }
+
+        Commands::Repair { file, parity_file } => repair_archive(file, parity_file)?,
+
+        Commands::Doctor { dir } => run_doctor(dir)?,
     }
 
     Ok(())
 }
 
+/// Member order choices for `create --sort`
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SortArg {
+    None,
+    Name,
+}
+
+impl From<SortArg> for create::SortOrder {
+    fn from(sort: SortArg) -> Self {
+        match sort {
+            SortArg::None => create::SortOrder::None,
+            SortArg::Name => create::SortOrder::Name,
+        }
+    }
+}
+
+/// Extent splitting choices for `create --chunking`
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ChunkingArg {
+    /// Whole blocks (block-aligned, reflinkable)
+    Fixed,
+    /// Content-defined chunks (FastCDC)
+    Cdc,
+}
+
+/// Display choices for `--progress`
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ProgressArg {
+    /// Progress bar with throughput, ETA and dedup ratio
+    Bar,
+    /// One JSON record per second, and a final one
+    Json,
+}
+
+impl From<ProgressArg> for progress::ProgressFormat {
+    fn from(format: ProgressArg) -> Self {
+        match format {
+            ProgressArg::Bar => progress::ProgressFormat::Bar,
+            ProgressArg::Json => progress::ProgressFormat::Json,
+        }
+    }
+}
+
+/// Digest algorithms for `manifest`
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum AlgoArg {
+    Sha256,
+    Sha512,
+    /// BLAKE3, as printed by b3sum
+    Blake3,
+}
+
+impl From<AlgoArg> for manifest::DigestAlgorithm {
+    fn from(algo: AlgoArg) -> Self {
+        match algo {
+            AlgoArg::Sha256 => manifest::DigestAlgorithm::Sha256,
+            AlgoArg::Sha512 => manifest::DigestAlgorithm::Sha512,
+            AlgoArg::Blake3 => manifest::DigestAlgorithm::Blake3,
+        }
+    }
+}
+
+/// Archive formats for `convert`
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum FormatArg {
+    /// ustar, pax or GNU tar
+    Tar,
+}
+
+/// Attributes for `diff --ignore`
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum AttributeArg {
+    /// File type
+    Type,
+    Size,
+    /// Permission bits
+    Mode,
+    /// Numeric uid and gid
+    Owner,
+    Mtime,
+    /// Symlink target
+    Link,
+    Contents,
+}
+
+impl From<AttributeArg> for compare::Attribute {
+    fn from(attribute: AttributeArg) -> Self {
+        match attribute {
+            AttributeArg::Type => compare::Attribute::Type,
+            AttributeArg::Size => compare::Attribute::Size,
+            AttributeArg::Mode => compare::Attribute::Mode,
+            AttributeArg::Owner => compare::Attribute::Owner,
+            AttributeArg::Mtime => compare::Attribute::Mtime,
+            AttributeArg::Link => compare::Attribute::LinkTarget,
+            AttributeArg::Contents => compare::Attribute::Contents,
+        }
+    }
+}
+
+/// Archive creator settings gathered from the command line
+struct CreateOptions {
+    jobs: usize,
+    chunking: create::Chunking,
+    compression: compression::CompressionPolicy,
+    stream: stream::StreamCompression,
+    keys: Vec<encryption::KeySource>, // Key slots, when encrypting
+    clear_metadata: bool,
+    signing_key: Option<ed25519_dalek::SigningKey>,
+    parity: Option<(parity::ParityScheme, Option<PathBuf>)>, // Parity file, unless appended
+    bases: Vec<PathBuf>,
+    snapshot: Option<PathBuf>,
+    progress: Option<progress::ProgressFormat>,
+    filter: filter::PathFilter,
+    sort: create::SortOrder,
+    metadata: create::MetadataOptions,
+    dereference: create::Dereference,
+    rewrite: transform::PathRewrite,
+}
+
+/// Archive extractor settings gathered from the command line
+struct ExtractOptions {
+    policy: extract::OverwritePolicy,
+    sync: bool,
+    jobs: usize,
+    rewrite: transform::PathRewrite,
+    bases: Vec<PathBuf>,
+    incremental: bool,
+    keys: Vec<encryption::KeySource>,
+    signature_key: Option<ed25519_dalek::VerifyingKey>,
+    salvage: bool,
+    progress: Option<progress::ProgressFormat>,
+}
+
+/// Chunking for --chunking and --chunk-size; compression needs extents
+/// longer than a block, so it defaults to content-defined chunks
+fn extent_chunking(
+    chunking: Option<ChunkingArg>,
+    chunk_size: u32,
+    compression: &compression::CompressionPolicy,
+) -> Result<create::Chunking> {
+    let compressing = *compression != compression::CompressionPolicy::None;
+    let default_chunking = if compressing { ChunkingArg::Cdc } else { ChunkingArg::Fixed };
+    Ok(match chunking.unwrap_or(default_chunking) {
+        ChunkingArg::Fixed if compressing => {
+            anyhow::bail!("--compress needs --chunking cdc: fixed-block extents are one block and cannot shrink")
+        }
+        ChunkingArg::Fixed => create::Chunking::FixedBlocks,
+        ChunkingArg::Cdc => create::Chunking::ContentDefined { average_size: chunk_size },
+    })
+}
+
+/// Whole-archive compression for -z, -J and --zstd
+fn stream_compression(gzip: bool, xz: bool, zstd: bool) -> stream::StreamCompression {
+    if gzip {
+        stream::StreamCompression::Gzip
+    } else if xz {
+        stream::StreamCompression::Xz
+    } else if zstd {
+        stream::StreamCompression::Zstd
+    } else {
+        stream::StreamCompression::None
+    }
+}
+
+/// Build the member path rewrite for --transform and --strip-components
+fn path_rewrite(transforms: &[String], strip_components: usize) -> Result<transform::PathRewrite> {
+    let mut rewrite = transform::PathRewrite::new().strip_components(strip_components);
+    for expression in transforms {
+        rewrite = rewrite.transform(expression)?;
+    }
+    Ok(rewrite)
+}
+
+/// Pair each input with the directory it is read from, applying -C options
+/// in the order they appear among the inputs
+fn inputs_with_directories(matches: &ArgMatches) -> Vec<(PathBuf, PathBuf)> {
+    let indexed = |id: &str| -> Vec<(usize, PathBuf)> {
+        match (matches.indices_of(id), matches.get_many::<PathBuf>(id)) {
+            (Some(indices), Some(values)) => indices.zip(values.cloned()).collect(),
+            _ => Vec::new(),
+        }
+    };
+
+    let mut events: Vec<(usize, bool, PathBuf)> = indexed("directory")
+        .into_iter()
+        .map(|(index, dir)| (index, true, dir))
+        .chain(indexed("inputs").into_iter().map(|(index, input)| (index, false, input)))
+        .collect();
+    events.sort_by_key(|(index, _, _)| *index);
+
+    let mut base_dir = PathBuf::from(".");
+    let mut inputs = Vec::new();
+    for (_, is_directory, path) in events {
+        if is_directory {
+            base_dir = base_dir.join(path);
+        } else {
+            inputs.push((base_dir.clone(), path));
+        }
+    }
+    inputs
+}
+
+/// Parse seconds since the epoch, as in SOURCE_DATE_EPOCH or `--mtime=@N`
+fn parse_epoch(value: &str) -> Result<u64, String> {
+    let digits = value.trim().trim_start_matches('@');
+    digits
+        .parse()
+        .map_err(|_| format!("invalid timestamp {:?}, expected seconds since the epoch", value))
+}
+
+/// Parse `--owner`: NAME:UID, a numeric UID alone, or a user name looked up
+/// on this machine
+fn parse_owner(value: &str) -> Result<create::OwnerOverride, String> {
+    parse_owner_override(value, "user", |name| {
+        nix::unistd::User::from_name(name).ok().flatten().map(|user| user.uid.as_raw() as u64)
+    })
+}
+
+/// Parse `--group`, as `--owner`
+fn parse_group(value: &str) -> Result<create::OwnerOverride, String> {
+    parse_owner_override(value, "group", |name| {
+        nix::unistd::Group::from_name(name).ok().flatten().map(|group| group.gid.as_raw() as u64)
+    })
+}
+
+fn parse_owner_override(
+    value: &str,
+    kind: &str,
+    lookup: impl Fn(&str) -> Option<u64>,
+) -> Result<create::OwnerOverride, String> {
+    let parse_id = |id: &str| id.parse().map_err(|_| format!("invalid {} id {:?}", kind, id));
+    if let Some((name, id)) = value.split_once(':') {
+        return Ok(create::OwnerOverride {
+            name: name.to_string(),
+            id: parse_id(id)?,
+        });
+    }
+    // As in tar, a leading + marks a number that is not a name
+    let digits = value.strip_prefix('+').unwrap_or(value);
+    if !digits.is_empty() && digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Ok(create::OwnerOverride {
+            name: String::new(),
+            id: parse_id(digits)?,
+        });
+    }
+    match lookup(value) {
+        Some(id) => Ok(create::OwnerOverride {
+            name: value.to_string(),
+            id,
+        }),
+        None => Err(format!("unknown {} {:?}; give its id as {}:ID", kind, value, value)),
+    }
+}
+
+/// Parse a byte count with an optional K or M suffix
+fn parse_size(value: &str) -> Result<u32, String> {
+    let value = value.trim();
+    let (digits, multiplier) = match value.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&value[..i], 1024),
+        Some((i, 'M' | 'm')) => (&value[..i], 1024 * 1024),
+        _ => (value, 1),
+    };
+    digits
+        .parse::<u32>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size {:?}, expected bytes with an optional K or M suffix", value))
+}
+
+/// Size of the regular files under the inputs, as the total for the
+/// progress display; filters are not applied, so this can overestimate
+fn input_file_bytes(inputs: &[(PathBuf, PathBuf)], follow_inputs: bool) -> u64 {
+    fn walk(path: &Path, metadata: std::fs::Metadata) -> u64 {
+        if metadata.is_file() {
+            return metadata.len();
+        }
+        if !metadata.is_dir() {
+            return 0;
+        }
+        let Ok(entries) = std::fs::read_dir(path) else {
+            return 0;
+        };
+        entries
+            .flatten()
+            .filter_map(|entry| Some((entry.path(), entry.metadata().ok()?)))
+            .map(|(path, metadata)| walk(&path, metadata))
+            .sum()
+    }
+
+    inputs
+        .iter()
+        .map(|(base_dir, input)| {
+            let path = base_dir.join(input);
+            let metadata = match follow_inputs {
+                true => std::fs::metadata(&path),
+                false => std::fs::symlink_metadata(&path),
+            };
+            metadata.map(|metadata| walk(&path, metadata)).unwrap_or(0)
+        })
+        .sum()
+}
+
+/// Keys for an encrypted archive given with --passphrase-file and --key-file
+fn read_keys(passphrase_file: Option<&Path>, key_files: &[PathBuf]) -> Result<Vec<encryption::KeySource>> {
+    let mut keys = Vec::new();
+    if let Some(path) = passphrase_file {
+        keys.push(encryption::KeySource::passphrase_file(path)?);
+    }
+    for path in key_files {
+        keys.push(encryption::KeySource::key_file(path)?);
+    }
+    Ok(keys)
+}
+
+/// Ask for a passphrase on the terminal, twice when it is a new one
+fn prompt_passphrase(confirm: bool) -> Result<encryption::KeySource> {
+    let passphrase = rpassword::prompt_password("Passphrase: ")
+        .context("Failed to read a passphrase; see --passphrase-file and --key-file")?;
+    if confirm {
+        let repeated = zeroize::Zeroizing::new(rpassword::prompt_password("Repeat passphrase: ")?);
+        if *repeated != passphrase {
+            anyhow::bail!("The passphrases do not match");
+        }
+    }
+    encryption::KeySource::passphrase(passphrase)
+}
+
+/// Parse a `--compress` policy
+fn parse_compression(value: &str) -> Result<compression::CompressionPolicy, String> {
+    compression::CompressionPolicy::parse(value).map_err(|e| e.to_string())
+}
+
+fn parse_parity(value: &str) -> Result<parity::ParityScheme, String> {
+    parity::ParityScheme::parse(value).map_err(|e| e.to_string())
+}
+
 fn create_archive(
     output_path: PathBuf,
     block_size: Option<u32>,
-    inputs: Vec<PathBuf>,
+    inputs: Vec<(PathBuf, PathBuf)>,
+    options: CreateOptions,
     verbose: bool,
 ) -> Result<()> {
+    // Messages go to stderr when the archive is written to stdout
+    let to_stdout = output_path == Path::new("-");
+    let log = |message: String| match to_stdout {
+        true => eprintln!("{}", message),
+        false => println!("{}", message),
+    };
+
     if verbose {
-        println!("Creating archive: {}", output_path.display());
+        log(format!("Creating archive: {}", output_path.display()));
         if let Some(bs) = block_size {
-            println!("Block size: {} bytes", bs);
+            log(format!("Block size: {} bytes", bs));
         }
     }
 
-    let output_file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(&output_path)
-        .with_context(|| format!("Failed to create archive file: {:?}", output_path))?;
-
-    let mut creator = create::ArchiveCreator::new(output_file, block_size)?;
+    let output = create_output(&output_path)?;
+    let output = stream::StreamWriter::new(output, options.stream);
 
-    for input in inputs {
+    let mut creator = create::ArchiveCreator::new(output, block_size)?
+        .with_jobs(options.jobs)
+        .with_chunking(options.chunking)?
+        .with_compression(options.compression)
+        .with_path_filter(options.filter)
+        .with_sort_order(options.sort)
+        .with_metadata_options(options.metadata)
+        .with_dereference(options.dereference)
+        .with_path_rewrite(options.rewrite);
+    if !options.keys.is_empty() {
+        creator = creator.with_encryption(&options.keys, options.clear_metadata)?;
+    }
+    if let Some(key) = options.signing_key {
+        creator = creator.with_signing_key(key);
+    }
+    for base in &options.bases {
+        creator = creator.with_base(base)?;
+    }
+    if let Some(path) = &options.snapshot {
+        let previous = incremental::Snapshot::load(path)?;
         if verbose {
-            println!("Adding: {}", input.display());
+            match previous.is_empty() {
+                true => log("No snapshot yet, creating a full archive".to_string()),
+                false => log(format!("Snapshot lists {} members", previous.len())),
+            }
         }
+        creator = creator.with_listed_incremental(previous);
+    }
+    let reporter = options.progress.map(|format| {
+        let total = input_file_bytes(&inputs, options.dereference != create::Dereference::Never);
+        progress::ProgressReporter::start(format, progress::Operation::Create, Some(total))
+    });
+    if let Some(reporter) = &reporter {
+        creator = creator.with_progress(reporter.observer());
+    }
 
-        let metadata = std::fs::metadata(&input)
-            .with_context(|| format!("Failed to read metadata for {:?}", input))?;
-
-        if metadata.is_dir() {
-            creator.add_directory(&input, std::path::Path::new(""))?;
-        } else {
-            let file_name = input
-                .file_name()
-                .ok_or_else(|| anyhow::anyhow!("Invalid file name"))?;
-            creator.add_file(&input, std::path::Path::new(file_name))?;
+    for (base_dir, input) in inputs {
+        if verbose {
+            log(format!("Adding: {}", input.display()));
         }
+
+        creator
+            .add_input(&base_dir, &input)
+            .with_context(|| format!("Failed to add {:?}", base_dir.join(&input)))?;
     }
 
-    creator.finish()?;
+    let snapshot = creator.take_snapshot();
+    creator.finish()?.finish()?;
+    if let Some((scheme, parity_file)) = options.parity {
+        add_parity(output_path.clone(), scheme, parity_file, block_size)?;
+    }
+    // Only a complete archive may advance the snapshot
+    if let Some(path) = &options.snapshot {
+        snapshot.save(path)?;
+    }
+    if let Some(reporter) = reporter {
+        reporter.finish();
+    }
 
     if verbose {
-        println!("Archive created successfully");
+        log("Archive created successfully".to_string());
     }
 
     Ok(())
 }
 
-fn extract_archive(input_path: PathBuf, output_dir: PathBuf, verbose: bool) -> Result<()> {
+fn extract_archive(
+    input_path: PathBuf,
+    output_dir: PathBuf,
+    options: ExtractOptions,
+    verbose: bool,
+) -> Result<()> {
     if verbose {
         println!("Extracting archive: {}", input_path.display());
         println!("Output directory: {}", output_dir.display());
     }
 
-    let input_file = File::open(&input_path)
-        .with_context(|| format!("Failed to open archive file: {:?}", input_path))?;
+    let source = stream::ArchiveSource::open(&input_path)?;
+    // A compressed stream's footer index is only reached at the end
+    let random_access = source.random_access();
 
     // Create output directory if it doesn't exist
     std::fs::create_dir_all(&output_dir)?;
 
-    let mut extractor = extract::ArchiveExtractor::new(input_file, output_dir)?;
-
-    extractor.extract_all()?;
+    // Parallel extraction reads the archive file directly; decompressed
+    // archives are extracted sequentially
+    let jobs = options.jobs;
+    match source {
+        stream::ArchiveSource::File(file) => {
+            run_extractor(file, output_dir, options, random_access, verbose, |extractor| {
+                extractor.extract_all_parallel(jobs)
+            })?
+        }
+        source => run_extractor(source, output_dir, options, random_access, verbose, |extractor| {
+            extractor.extract_all()
+        })?,
+    }
 
     if verbose {
         println!("Extraction completed successfully");
@@ -169,16 +1353,129 @@ fn extract_archive(input_path: PathBuf, output_dir: PathBuf, verbose: bool) -> R
     Ok(())
 }
 
-fn list_archive(input_path: PathBuf, verbose: bool) -> Result<()> {
+/// Set up an extractor with the command line options and run it
+fn run_extractor<R: Read + Seek>(
+    reader: R,
+    output_dir: PathBuf,
+    options: ExtractOptions,
+    random_access: bool,
+    verbose: bool,
+    run: impl FnOnce(&mut extract::ArchiveExtractor<R>) -> Result<()>,
+) -> Result<()> {
+    let mut extractor = extract::ArchiveExtractor::new(reader, output_dir)?
+        .with_overwrite_policy(options.policy)
+        .with_sync(options.sync)
+        .with_path_rewrite(options.rewrite)
+        .with_incremental(options.incremental)
+        .with_salvage(options.salvage);
+    let mut keys = options.keys;
+    if extractor.is_locked() && keys.is_empty() {
+        keys.push(prompt_passphrase(false)?);
+    }
+    extractor = extractor.with_keys(&keys)?;
+    for base in &options.bases {
+        extractor = extractor.with_base(base)?;
+    }
+    if let Some(key) = options.signature_key {
+        // Checked in full before anything is extracted
+        extractor = extractor.with_signature_key(key);
+    }
+    let reporter = match options.progress {
+        Some(format) => {
+            // Salvaged archives may have headers the index can't reach
+            let total = match random_access && !options.salvage {
+                true => extractor.regular_file_bytes()?,
+                false => None,
+            };
+            Some(progress::ProgressReporter::start(format, progress::Operation::Extract, total))
+        }
+        None => None,
+    };
+    if let Some(reporter) = &reporter {
+        extractor = extractor.with_progress(reporter.observer());
+    }
+
+    let mut result = run(&mut extractor);
+    if let Some(reporter) = reporter {
+        reporter.finish();
+    }
+    if let Some(report) = extractor.salvage_report() {
+        for range in &report.skipped {
+            eprintln!("Skipped archive bytes {}-{}: {}", range.start, range.end, range.reason);
+        }
+        for member in &report.lost {
+            match &member.path {
+                Some(path) => eprintln!("Lost member: {} (header at {})", path, member.offset),
+                None => eprintln!("Lost member: unknown (header at {})", member.offset),
+            }
+        }
+        for range in &report.unrecoverable {
+            eprintln!(
+                "Unrecoverable: {} bytes {}-{} left as zeros: {}",
+                range.path.display(),
+                range.start,
+                range.end,
+                range.reason
+            );
+        }
+        if result.is_ok() && !report.is_complete() {
+            let damaged: std::collections::HashSet<&Path> =
+                report.unrecoverable.iter().map(|range| range.path.as_path()).collect();
+            result = Err(anyhow::anyhow!(
+                "Archive is damaged: {} members lost, {} files extracted incomplete",
+                report.lost.len(),
+                damaged.len()
+            ));
+        }
+    }
+
+    // Replacing is what extraction does by default, so it is only worth
+    // reporting when asked for
+    let policy = options.policy;
+    for conflict in extractor.conflicts() {
+        match conflict.action {
+            extract::ConflictAction::Refused => eprintln!("Error: {}", conflict),
+            extract::ConflictAction::Skipped => eprintln!("{}", conflict),
+            extract::ConflictAction::Replaced if verbose || policy != extract::OverwritePolicy::Overwrite => {
+                eprintln!("{}", conflict)
+            }
+            extract::ConflictAction::Replaced => {}
+        }
+    }
+    if verbose {
+        let count = |action| {
+            extractor
+                .conflicts()
+                .iter()
+                .filter(|c| c.action == action)
+                .count()
+        };
+        let skipped = count(extract::ConflictAction::Skipped);
+        let replaced = count(extract::ConflictAction::Replaced);
+        if skipped + replaced > 0 {
+            println!("Existing files skipped: {}, replaced: {}", skipped, replaced);
+        }
+    }
+
+    result
+}
+
+fn list_archive(input_path: PathBuf, mut keys: Vec<encryption::KeySource>, verbose: bool) -> Result<()> {
     if verbose {
         println!("Listing archive: {}", input_path.display());
         println!();
     }
 
-    let input_file = File::open(&input_path)
-        .with_context(|| format!("Failed to open archive file: {:?}", input_path))?;
+    let source = stream::ArchiveSource::open(&input_path)?;
 
-    let mut extractor = extract::ArchiveExtractor::new(input_file, PathBuf::from("/tmp"))?;
+    let extractor = extract::ArchiveExtractor::new(source, PathBuf::from("/tmp"))?;
+    if !extractor.can_list() && keys.is_empty() {
+        keys.push(prompt_passphrase(false)?);
+    }
+    let mut extractor = match keys.is_empty() {
+        true => extractor,
+        false => extractor.with_keys(&keys)?,
+    };
 
     let files = extractor.list_files()?;
 
@@ -195,18 +1492,599 @@ fn list_archive(input_path: PathBuf, verbose: bool) -> Result<()> {
 }
 
 fn show_archive_info(input_path: PathBuf) -> Result<()> {
-    let mut input_file = File::open(&input_path)
-        .with_context(|| format!("Failed to open archive file: {:?}", input_path))?;
+    let mut source = stream::ArchiveSource::open(&input_path)?;
 
-    let header = format::ArchiveHeader::read(&mut input_file)?;
+    let header = format::ArchiveHeader::read(&mut source)?;
 
     println!("Archive Information:");
     println!("  Format version: {}", header.version);
     println!("  Block size: {} bytes", header.block_size);
+    if header.flags & format::ARCHIVE_FLAG_CONTENT_CHUNKS != 0 {
+        println!("  Chunking: content-defined, {} byte average", header.chunk_size);
+        println!("    Chunk boundaries follow the data, so content shifted by insertions or");
+        println!("    deletions still deduplicates, but chunks do not start on block boundaries:");
+        println!("    shared chunks are copied on extraction rather than reflinked.");
+    } else {
+        println!("  Chunking: fixed {} byte blocks", header.block_size);
+        println!("    Shared blocks can be reflinked on extraction; data shifted by an");
+        println!("    insertion or deletion does not deduplicate.");
+    }
+    if header.flags & format::ARCHIVE_FLAG_COMPRESSED_EXTENTS != 0 {
+        println!("  Compression: zstd, per extent");
+    }
+    if header.flags & format::ARCHIVE_FLAG_HEADER_CHECKSUMS != 0 {
+        println!("  Header checksums: required on every header");
+    }
+    if let Some(encryption) = &header.encryption {
+        let slots: Vec<&str> = encryption
+            .key_slots
+            .iter()
+            .map(|slot| match slot.kind {
+                format::KeySlotKind::Passphrase { .. } => "passphrase",
+                format::KeySlotKind::KeyFile => "key file",
+            })
+            .collect();
+        println!("  Encryption: XChaCha20-Poly1305, key slots: {}", slots.join(", "));
+        match encryption.clear_metadata {
+            true => println!("    Headers and index are in clear (authenticated); file data is encrypted."),
+            false => println!("    Headers, index and file data are encrypted."),
+        }
+    }
+    match (source.compression(), source.random_access()) {
+        (stream::StreamCompression::None, _) => {}
+        (compression, true) => println!("  Stream compression: {} (seekable)", compression.name()),
+        (compression, false) => println!("  Stream compression: {}", compression.name()),
+    }
+
+    let cipher = encryption::ArchiveCipher::new(&header)?;
+    if !source.random_access() {
+        println!("  Footer index: not reachable without reading the whole stream");
+    } else if !cipher.can_read_metadata() {
+        println!("  Footer index: encrypted");
+    } else if let Some(footer) = cipher.locate_footer(&mut source)? {
+        println!("  Archive ID: {}", base::archive_id_hex(&footer.archive_id()));
+        if let Some(sig) = &footer.signature {
+            println!("  Signed by: {} (not checked, see `reftar verify`)", signature::key_hex(&sig.public_key));
+        }
+        let compressed = footer.extents.iter().filter(|e| e.compression.is_some()).count();
+        match compressed {
+            0 => println!("  Stored extents: {}", footer.extents.len()),
+            _ => println!("  Stored extents: {} ({} compressed)", footer.extents.len(), compressed),
+        }
+        if !footer.bases.is_empty() {
+            println!("  Differential, needs base archives:");
+            for base in &footer.bases {
+                println!("    {} ({})", base::archive_id_hex(&base.archive_id), base.name);
+            }
+        }
+    }
+
+    if input_path != Path::new("-") {
+        let mut file = File::open(&input_path)?;
+        let sidecar = default_parity_file(&input_path);
+        let parity = match parity::read_index(&mut file)? {
+            Some(index) => Some((index, "appended".to_string())),
+            None if sidecar.exists() => parity::read_index(&mut File::open(&sidecar)?)?
+                .map(|index| (index, format!("in {}", sidecar.display()))),
+            None => None,
+        };
+        if let Some((index, place)) = parity {
+            println!(
+                "  Parity: Reed-Solomon, {} blocks for every {} blocks of {} bytes, {}",
+                index.scheme.parity_blocks, index.scheme.data_blocks, index.block_size, place
+            );
+        }
+    }
 
     // Get file size
-    let metadata = std::fs::metadata(&input_path)?;
-    println!("  Archive size: {} bytes ({:.2} MB)", metadata.len(), metadata.len() as f64 / 1024.0 / 1024.0);
+    if input_path != Path::new("-") {
+        let metadata = std::fs::metadata(&input_path)?;
+        println!("  Archive size: {} bytes ({:.2} MB)", metadata.len(), metadata.len() as f64 / 1024.0 / 1024.0);
+    }
+
+    Ok(())
+}
+
+fn print_manifest(
+    input_path: PathBuf,
+    algorithm: manifest::DigestAlgorithm,
+    json: bool,
+    bases: Vec<PathBuf>,
+    mut keys: Vec<encryption::KeySource>,
+) -> Result<()> {
+    let source = stream::ArchiveSource::open(&input_path)?;
+    let mut extractor = extract::ArchiveExtractor::new(source, PathBuf::from("/tmp"))?;
+    if extractor.is_locked() && keys.is_empty() {
+        keys.push(prompt_passphrase(false)?);
+    }
+    if !keys.is_empty() {
+        extractor = extractor.with_keys(&keys)?;
+    }
+    for base in &bases {
+        extractor = extractor.with_base(base)?;
+    }
+
+    let files = manifest::build_manifest(&mut extractor, algorithm)?;
+    let mut stdout = std::io::stdout().lock();
+    for file in &files {
+        match json {
+            true => writeln!(stdout, "{}", file.json(algorithm))?,
+            false => writeln!(stdout, "{}", file.check_line())?,
+        }
+    }
+    Ok(())
+}
+
+/// Print how members differ from the files under `directory`, returning
+/// whether any do
+fn diff_archive(
+    input_path: PathBuf,
+    directory: PathBuf,
+    ignore: &[compare::Attribute],
+    bases: Vec<PathBuf>,
+    mut keys: Vec<encryption::KeySource>,
+) -> Result<bool> {
+    let source = stream::ArchiveSource::open(&input_path)?;
+    let mut extractor = extract::ArchiveExtractor::new(source, directory.clone())?;
+    if extractor.is_locked() && keys.is_empty() {
+        keys.push(prompt_passphrase(false)?);
+    }
+    if !keys.is_empty() {
+        extractor = extractor.with_keys(&keys)?;
+    }
+    for base in &bases {
+        extractor = extractor.with_base(base)?;
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    let summary = compare::compare_archive(&mut extractor, &directory, ignore, |path, difference| {
+        let _ = writeln!(stdout, "{}: {}", path, difference);
+    })?;
+    Ok(summary.differing > 0)
+}
+
+/// Exit status of `reftar diff`, as for `tar -d`: 0 if nothing differs, 1
+/// if something does, 2 if the archive could not be compared
+fn diff_status(differs: Result<bool>) -> i32 {
+    match differs {
+        Ok(false) => 0,
+        Ok(true) => 1,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            2
+        }
+    }
+}
+
+/// Open where an archive is written: a new file, or standard output for `-`
+fn create_output(output_path: &Path) -> Result<Box<dyn Write>> {
+    if output_path == Path::new("-") {
+        return Ok(Box::new(std::io::stdout()));
+    }
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output_path)
+        .with_context(|| format!("Failed to create archive file: {:?}", output_path))?;
+    Ok(Box::new(file))
+}
+
+/// Stream the members of a tar archive into a new reftar archive
+fn convert_from_tar(
+    input_path: PathBuf,
+    output_path: PathBuf,
+    block_size: Option<u32>,
+    chunking: create::Chunking,
+    compression: compression::CompressionPolicy,
+    stream: stream::StreamCompression,
+    verbose: bool,
+) -> Result<()> {
+    // Messages go to stderr when the archive is written to stdout
+    let to_stdout = output_path == Path::new("-");
+    let log = |message: String| match to_stdout {
+        true => eprintln!("{}", message),
+        false => println!("{}", message),
+    };
+
+    let input = stream::ArchiveSource::open(&input_path)?;
+    let output = create_output(&output_path)?;
+    let output = stream::StreamWriter::new(output, stream);
+    let mut creator = create::ArchiveCreator::new(output, block_size)?
+        .with_chunking(chunking)?
+        .with_compression(compression);
+
+    let import = convert::import_tar(input, &mut creator, |path| {
+        if verbose {
+            log(format!("Converted: {}", path));
+        }
+    })?;
+    creator.finish()?.finish()?;
+
+    for message in &import.skipped {
+        eprintln!("Skipped {}", message);
+    }
+    if verbose {
+        log(format!("Converted {} members", import.members));
+    }
+    Ok(())
+}
+
+/// Rebuild the members of a reftar archive into a new tar archive
+fn convert_to_tar(
+    input_path: PathBuf,
+    output_path: PathBuf,
+    stream: stream::StreamCompression,
+    bases: Vec<PathBuf>,
+    mut keys: Vec<encryption::KeySource>,
+    verbose: bool,
+) -> Result<()> {
+    let source = stream::ArchiveSource::open(&input_path)?;
+    let random_access = source.random_access();
+    let mut extractor = extract::ArchiveExtractor::new(source, PathBuf::from("."))?;
+    if extractor.is_locked() && keys.is_empty() {
+        keys.push(prompt_passphrase(false)?);
+    }
+    if !keys.is_empty() {
+        extractor = extractor.with_keys(&keys)?;
+    }
+    for base in &bases {
+        extractor = extractor.with_base(base)?;
+    }
+    write_tar(&mut extractor, random_access, &output_path, stream, verbose)
+}
+
+/// Write the members of an archive as a tar archive, with the extract
+/// options that apply: path rewrites, keys, bases and the signature check
+fn extract_to_tar(input_path: PathBuf, tar_path: PathBuf, options: ExtractOptions, verbose: bool) -> Result<()> {
+    let source = stream::ArchiveSource::open(&input_path)?;
+    let random_access = source.random_access();
+
+    // A stream's signature is checked at its footer, once the tar is
+    // written, so the tar is kept under a temporary name until then
+    let staged = options.signature_key.is_some() && !random_access;
+    if staged && tar_path == Path::new("-") {
+        anyhow::bail!(
+            "--require-signature can't hold back a tar written to standard output while the archive is read as a \
+             stream; write the tar to a file"
+        );
+    }
+    let write_path = match staged {
+        true => tar_path.with_file_name(format!(
+            ".{}.reftar-{}",
+            tar_path.file_name().unwrap_or_default().to_string_lossy(),
+            std::process::id()
+        )),
+        false => tar_path.clone(),
+    };
+    let result = run_extractor(source, PathBuf::from("."), options, random_access, false, |extractor| {
+        write_tar(extractor, random_access, &write_path, stream::StreamCompression::None, verbose)
+    });
+    if staged {
+        match &result {
+            Ok(()) => std::fs::rename(&write_path, &tar_path)?,
+            Err(_) => {
+                let _ = std::fs::remove_file(&write_path);
+            }
+        }
+    }
+    result
+}
+
+/// Write every member the extractor reads to `output_path` as tar, reading
+/// ahead in the archive if it is `random_access`
+fn write_tar<R: Read + Seek>(
+    extractor: &mut extract::ArchiveExtractor<R>,
+    random_access: bool,
+    output_path: &Path,
+    stream: stream::StreamCompression,
+    verbose: bool,
+) -> Result<()> {
+    // Messages go to stderr when the archive is written to stdout
+    let to_stdout = output_path == Path::new("-");
+    let log = |message: String| match to_stdout {
+        true => eprintln!("{}", message),
+        false => println!("{}", message),
+    };
+
+    let output = stream::StreamWriter::new(create_output(output_path)?, stream);
+    let mut members = 0;
+    let output = convert::export_tar(extractor, output, random_access, |path| {
+        members += 1;
+        if verbose {
+            log(format!("Converted: {}", path));
+        }
+    })?;
+    output.finish()?.flush()?;
+
+    if verbose {
+        log(format!("Converted {} members", members));
+    }
+    Ok(())
+}
+
+fn sign_archive(input_path: PathBuf, key: &ed25519_dalek::SigningKey, mut keys: Vec<encryption::KeySource>) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&input_path)
+        .with_context(|| format!("Failed to open archive file: {:?}", input_path))?;
+    let header = format::ArchiveHeader::read(&mut file).map_err(|e| {
+        match stream::ArchiveSource::open(&input_path).map(|source| source.compression()) {
+            Ok(stream::StreamCompression::None) | Err(_) => e,
+            Ok(_) => anyhow::anyhow!("Compressed archives can't be signed in place; sign them with `create --sign`"),
+        }
+    })?;
+    if header.encryption.is_some() && keys.is_empty() {
+        keys.push(prompt_passphrase(false)?);
+    }
 
+    // Signing rewrites the footer, so appended parity is made again after
+    let parity = parity::strip_parity(&mut file)?;
+    signature::sign_archive(&mut file, &keys, key)?;
+    if let Some(index) = parity {
+        parity::append_parity(&mut file, index.block_size, index.scheme)?;
+    }
+    println!("Signed {} with key {}", input_path.display(), signature::key_hex(&key.verifying_key().to_bytes()));
+    let sidecar = default_parity_file(&input_path);
+    if sidecar.exists() {
+        println!(
+            "Parity file {} no longer matches; update it with `reftar parity --parity-file`",
+            sidecar.display()
+        );
+    }
     Ok(())
 }
+
+fn verify_archive(
+    input_path: PathBuf,
+    key: Option<ed25519_dalek::VerifyingKey>,
+    parity_file: Option<PathBuf>,
+    mut keys: Vec<encryption::KeySource>,
+) -> Result<()> {
+    let source = stream::ArchiveSource::open(&input_path)?;
+    let random_access = source.random_access();
+
+    let extractor = extract::ArchiveExtractor::new(source, PathBuf::from("/tmp"))?;
+    if !extractor.can_list() && keys.is_empty() {
+        keys.push(prompt_passphrase(false)?);
+    }
+    let mut extractor = match keys.is_empty() {
+        true => extractor,
+        false => extractor.with_keys(&keys)?,
+    };
+
+    // A seekable archive has its signature checked up front; a stream is
+    // hashed as its members are walked, and a bad signature is reported
+    // with the other problems
+    let mut failed = false;
+    if let Some(key) = key {
+        extractor = extractor.with_signature_key(key);
+        if random_access {
+            if let Err(e) = extractor.verify_signature() {
+                println!("Signature: {:#}", e);
+                failed = true;
+            }
+        }
+    }
+
+    let report = extractor.verify()?;
+    for issue in &report.issues {
+        println!("offset {}: {}", issue.offset, issue.message);
+    }
+    if let Some(key) = key.filter(|_| extractor.signature_verified()) {
+        println!("Good signature, key {}", signature::key_hex(&key.to_bytes()));
+    }
+    if extractor.is_locked() {
+        println!("Archive is locked: extent data was checked against its checksums only");
+    }
+    println!(
+        "Checked {} members and {} stored extents: {}",
+        report.members,
+        report.extents,
+        match report.issues.len() {
+            0 => "no problems".to_string(),
+            1 => "1 problem".to_string(),
+            n => format!("{} problems", n),
+        }
+    );
+
+    if input_path != Path::new("-") {
+        failed |= !verify_parity(&input_path, parity_file)?;
+    } else if parity_file.is_some() {
+        anyhow::bail!("--parity-file needs the archive read from a file");
+    }
+
+    if failed || !report.is_ok() {
+        anyhow::bail!("Archive failed verification");
+    }
+    Ok(())
+}
+
+/// Check an archive against its parity, if it has any, and report what
+/// `reftar repair` can rebuild; false if blocks are damaged
+fn verify_parity(input_path: &Path, parity_file: Option<PathBuf>) -> Result<bool> {
+    let mut file = File::open(input_path)?;
+    let (parity, index) = match open_parity(&mut file, input_path, parity_file, false) {
+        Ok(Some(parity)) => parity,
+        Ok(None) => return Ok(true),
+        Err(e) => {
+            println!("Parity: {:#}", e);
+            return Ok(false);
+        }
+    };
+
+    let report = parity::check_parity(&file, &parity, &index, false)?;
+    let damaged_parity = match report.damaged_parity {
+        0 => String::new(),
+        n => format!(", {} damaged parity blocks", n),
+    };
+    if report.damaged.is_empty() {
+        println!("Parity: no damaged blocks{}", damaged_parity);
+    } else if report.recoverable() {
+        println!(
+            "Parity: {} damaged blocks{}, all recoverable with `reftar repair`",
+            report.damaged.len(),
+            damaged_parity
+        );
+    } else {
+        println!(
+            "Parity: {} damaged blocks{}, {} unrecoverable at offsets {}",
+            report.damaged.len(),
+            damaged_parity,
+            report.unrecoverable.len(),
+            report.unrecoverable.iter().map(u64::to_string).collect::<Vec<_>>().join(", ")
+        );
+    }
+    Ok(report.damaged.is_empty())
+}
+
+/// Where parity for an archive is looked for when it isn't appended to it
+fn default_parity_file(archive: &Path) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(".par");
+    PathBuf::from(path)
+}
+
+/// Add parity to an archive file, appended or in `parity_file`, replacing
+/// any it has
+fn add_parity(
+    input_path: PathBuf,
+    scheme: parity::ParityScheme,
+    parity_file: Option<PathBuf>,
+    block_size: Option<u32>,
+) -> Result<parity::ParityIndex> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&input_path)
+        .with_context(|| format!("Failed to open archive file: {:?}", input_path))?;
+    // Compressed archives have no readable header, and use the default
+    let block_size = block_size
+        .or_else(|| format::ArchiveHeader::read(&mut file).ok().map(|header| header.block_size))
+        .unwrap_or(format::DEFAULT_BLOCK_SIZE);
+    parity::strip_parity(&mut file)?;
+
+    let Some(parity_path) = parity_file else {
+        return parity::append_parity(&mut file, block_size, scheme);
+    };
+    let length = format::archive_length(&mut file)?;
+    let output = File::create(&parity_path).with_context(|| format!("Failed to create parity file: {:?}", parity_path))?;
+    let mut writer = std::io::BufWriter::new(output);
+    parity::write_parity(&file, length, block_size, scheme, &mut writer, 0)
+}
+
+/// Open the parity of an archive file: appended, in `parity_file`, or in
+/// the default parity file if there is one
+fn open_parity(file: &mut File, input_path: &Path, parity_file: Option<PathBuf>, write: bool) -> Result<Option<(File, parity::ParityIndex)>> {
+    let sidecar = parity_file.or_else(|| Some(default_parity_file(input_path)).filter(|path| path.exists()));
+    parity::open_parity(file, sidecar.as_deref(), write)
+}
+
+fn repair_archive(input_path: PathBuf, parity_file: Option<PathBuf>) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&input_path)
+        .with_context(|| format!("Failed to open archive file: {:?}", input_path))?;
+    let Some((parity, index)) = open_parity(&mut file, &input_path, parity_file, true)? else {
+        anyhow::bail!("Archive has no parity; add it with `reftar parity` before it is damaged");
+    };
+
+    let report = parity::check_parity(&file, &parity, &index, true)?;
+    for offset in &report.unrecoverable {
+        println!("offset {}: block can't be rebuilt, its group has too many damaged blocks", offset);
+    }
+    let repaired = report.damaged.len() - report.unrecoverable.len();
+    match (report.damaged.len(), report.damaged_parity) {
+        (0, 0) => println!("No damaged blocks"),
+        (_, 0) => println!("Repaired {} of {} damaged blocks", repaired, report.damaged.len()),
+        (_, parity) => println!(
+            "Repaired {} of {} damaged blocks ({} damaged parity blocks)",
+            repaired,
+            report.damaged.len(),
+            parity
+        ),
+    }
+    if !report.recoverable() {
+        anyhow::bail!("{} blocks could not be repaired", report.unrecoverable.len());
+    }
+    Ok(())
+}
+
+fn run_doctor(dir: PathBuf) -> Result<()> {
+    let caps = match reflink::FilesystemCapabilities::probe(&dir) {
+        Ok(caps) => caps,
+        Err(e) => {
+            eprintln!("Warning: active probe failed ({:#}); showing inferred capabilities", e);
+            let anchor = File::open(&dir)
+                .with_context(|| format!("Failed to open directory: {:?}", dir))?;
+            reflink::FilesystemCapabilities::inspect(&anchor)?
+        }
+    };
+
+    let yes_no = |b: bool| if b { "yes" } else { "no" };
+
+    println!("Filesystem report for {}:", dir.display());
+    println!(
+        "  Filesystem type: {} (magic 0x{:X})",
+        caps.filesystem_type, caps.filesystem_magic
+    );
+    println!("  Device ID: {}", caps.filesystem_id);
+    println!("  Block size: {} bytes", caps.block_size);
+    println!("  Reflink (FICLONERANGE): {}", yes_no(caps.reflink));
+    println!("  Dedupe (FIDEDUPERANGE): {}", yes_no(caps.dedupe_range));
+    println!("  Extended attributes: {}", yes_no(caps.xattr));
+    println!("  Sparse files: {}", yes_no(caps.sparse));
+    if !caps.probed {
+        println!("  (capabilities inferred from filesystem type, not tested)");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_diff_exit_status_and_ignore() {
+        let dir = TempDir::new().unwrap();
+        let tree = dir.path().join("tree");
+        fs::create_dir(&tree).unwrap();
+        fs::write(tree.join("a"), vec![5u8; 10000]).unwrap();
+        fs::set_permissions(tree.join("a"), fs::Permissions::from_mode(0o644)).unwrap();
+        let archive = dir.path().join("tree.reftar");
+        let mut creator = create::ArchiveCreator::new(File::create(&archive).unwrap(), None).unwrap();
+        creator.add_file(&tree.join("a"), Path::new("a")).unwrap();
+        creator.finish().unwrap();
+
+        let diff = |args: &[&str]| {
+            let mut argv = vec!["reftar", "diff", "-f", archive.to_str().unwrap(), "-C", tree.to_str().unwrap()];
+            argv.extend_from_slice(args);
+            let Commands::Diff { file, directory, ignore, .. } = Cli::try_parse_from(argv).unwrap().command else {
+                panic!("not a diff command");
+            };
+            let ignore: Vec<compare::Attribute> = ignore.into_iter().map(Into::into).collect();
+            diff_status(diff_archive(file, directory, &ignore, Vec::new(), Vec::new()))
+        };
+        assert_eq!(diff(&[]), 0);
+
+        fs::set_permissions(tree.join("a"), fs::Permissions::from_mode(0o600)).unwrap();
+        assert_eq!(diff(&[]), 1);
+        assert_eq!(diff(&["--ignore", "mode"]), 0);
+
+        // Contents differ at the same size
+        fs::write(tree.join("a"), vec![6u8; 10000]).unwrap();
+        assert_eq!(diff(&["--ignore", "mode,mtime"]), 1);
+        assert_eq!(diff(&["--ignore", "mode", "--ignore", "mtime", "--ignore", "contents"]), 0);
+        assert!(Cli::try_parse_from(["reftar", "diff", "-f", "x", "--ignore", "colour"]).is_err());
+
+        // An archive that can't be read is not a difference
+        fs::write(&archive, b"not an archive").unwrap();
+        assert_eq!(diff(&[]), 2);
+    }
+}
@@ -0,0 +1,353 @@
+//! Content manifests (`reftar manifest`)
+//!
+//! A digest of every regular file in an archive, computed from its contents
+//! as rebuilt from its extents in memory, without extracting anything; hard
+//! links are listed with the digest of the file they link to. The
+//! manifest is printed as `sha256sum`/`b3sum` check lines, or as JSON that
+//! adds each file's metadata and which of its data is shared.
+
+use crate::extract::{ArchiveExtractor, ChunkSource, FileChunk};
+use crate::format::FileType;
+use crate::progress::json_string;
+use anyhow::Result;
+use sha2::Digest;
+use std::collections::HashMap;
+use std::io::{Read, Seek};
+
+/// Hash function of a manifest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DigestAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl DigestAlgorithm {
+    /// Name as used by `sha256sum`, `sha512sum` and `b3sum`
+    pub fn name(self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha256 => "sha256",
+            DigestAlgorithm::Sha512 => "sha512",
+            DigestAlgorithm::Blake3 => "blake3",
+        }
+    }
+
+    fn hasher(self) -> Hasher {
+        match self {
+            DigestAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            DigestAlgorithm::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+            DigestAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+}
+
+enum Hasher {
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Sha512(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    /// Hash `length` zero bytes of a hole
+    fn update_zeros(&mut self, mut length: u64) {
+        let zeros = [0u8; 64 * 1024];
+        while length > 0 {
+            let n = length.min(zeros.len() as u64) as usize;
+            self.update(&zeros[..n]);
+            length -= n as u64;
+        }
+    }
+
+    fn finish_hex(self) -> String {
+        let digest: Vec<u8> = match self {
+            Hasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            Hasher::Sha512(hasher) => hasher.finalize().to_vec(),
+            Hasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        };
+        digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+}
+
+/// Consecutive file data from the same kind of source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtentRun {
+    /// Offset of the run in the file
+    pub offset: u64,
+    pub length: u64,
+    /// Source of the run; for stored and referenced data, its first extent
+    pub source: ChunkSource,
+    /// Last extent of the run, for stored and referenced data
+    pub last_extent: Option<u64>,
+    /// Member the referenced extents are stored with
+    pub shared_from: Option<String>,
+}
+
+/// Digest and metadata of one regular file
+#[derive(Debug, Clone)]
+pub struct FileDigest {
+    pub path: String,
+    pub size: u64,
+    pub mode: Option<u32>,
+    pub modify_time: u64,
+    /// Lowercase hex digest of the contents
+    pub digest: String,
+    pub runs: Vec<ExtentRun>,
+    /// For a hard link, the member it links to, whose data it shares
+    pub hard_link_to: Option<String>,
+}
+
+impl FileDigest {
+    /// A line `sha256sum -c` or `b3sum -c` accepts
+    ///
+    /// As those tools do, a path with a backslash or newline is escaped and
+    /// the line marked with a leading backslash.
+    pub fn check_line(&self) -> String {
+        if self.path.contains(['\\', '\n']) {
+            let escaped = self.path.replace('\\', "\\\\").replace('\n', "\\n");
+            format!("\\{}  {}", self.digest, escaped)
+        } else {
+            format!("{}  {}", self.digest, self.path)
+        }
+    }
+
+    /// The file as one line of JSON
+    pub fn json(&self, algorithm: DigestAlgorithm) -> String {
+        let bytes_from = |source: fn(&ChunkSource) -> bool| -> u64 {
+            self.runs.iter().filter(|run| source(&run.source)).map(|run| run.length).sum()
+        };
+        let runs: Vec<String> = self.runs.iter().map(run_json).collect();
+        let hard_link = match &self.hard_link_to {
+            Some(target) => format!("\"hard_link_to\":{},", json_string(target)),
+            None => String::new(),
+        };
+        format!(
+            concat!(
+                "{{\"path\":{},\"size\":{},\"mode\":{},\"mtime\":{},\"{}\":\"{}\",{}",
+                "\"stored_bytes\":{},\"shared_bytes\":{},\"sparse_bytes\":{},\"base_bytes\":{},\"extents\":[{}]}}"
+            ),
+            json_string(&self.path),
+            self.size,
+            self.mode.map_or("null".to_string(), |mode| format!("\"{:04o}\"", mode)),
+            self.modify_time,
+            algorithm.name(),
+            self.digest,
+            hard_link,
+            bytes_from(|source| matches!(source, ChunkSource::Inline | ChunkSource::Data { .. })),
+            bytes_from(|source| matches!(source, ChunkSource::Reference { .. })),
+            bytes_from(|source| matches!(source, ChunkSource::Sparse)),
+            bytes_from(|source| matches!(source, ChunkSource::Base)),
+            runs.join(","),
+        )
+    }
+}
+
+fn run_json(run: &ExtentRun) -> String {
+    let (source, first_extent) = match run.source {
+        ChunkSource::Inline => ("inline", None),
+        ChunkSource::Data { extent_id } => ("data", Some(extent_id)),
+        ChunkSource::Reference { extent_id } => ("reference", Some(extent_id)),
+        ChunkSource::Sparse => ("sparse", None),
+        ChunkSource::Base => ("base", None),
+    };
+    let mut json = format!("{{\"offset\":{},\"length\":{},\"source\":\"{}\"", run.offset, run.length, source);
+    if let (Some(first), Some(last)) = (first_extent, run.last_extent) {
+        json.push_str(&format!(",\"extents\":[{},{}]", first, last));
+    }
+    if let Some(path) = &run.shared_from {
+        json.push_str(&format!(",\"shared_from\":{}", json_string(path)));
+    }
+    json.push('}');
+    json
+}
+
+/// Compute the digest of every regular file in the archive, in archive
+/// order; a hard link gets the digest of the file it links to
+pub fn build_manifest<R: Read + Seek>(
+    extractor: &mut ArchiveExtractor<R>,
+    algorithm: DigestAlgorithm,
+) -> Result<Vec<FileDigest>> {
+    let mut files = Vec::new();
+    // Member each stored extent belongs to
+    let mut owners: HashMap<u64, String> = HashMap::new();
+
+    loop {
+        let mut hasher = algorithm.hasher();
+        let mut runs: Vec<ExtentRun> = Vec::new();
+        let header = extractor.read_member_contents(|header, chunk| {
+            match chunk.data {
+                Some(data) => hasher.update(data),
+                None => hasher.update_zeros(chunk.length),
+            }
+            if let ChunkSource::Data { extent_id } = chunk.source {
+                owners.insert(extent_id, header.member_path());
+            }
+            add_run(&mut runs, &chunk, &owners);
+            Ok(())
+        })?;
+        let Some(header) = header else {
+            break;
+        };
+        match header.file_type {
+            FileType::Regular => {
+                files.push(FileDigest {
+                    path: header.member_path(),
+                    size: header.file_size as u64,
+                    mode: header.mode,
+                    modify_time: header.modify_time,
+                    digest: hasher.finish_hex(),
+                    runs,
+                    hard_link_to: None,
+                });
+            }
+            // The link shares its target's data, which is not stored again
+            FileType::HardLink => {
+                let Some(target) = files.iter().rev().find(|file| file.path == header.link_name) else {
+                    continue;
+                };
+                files.push(FileDigest {
+                    path: header.member_path(),
+                    runs: Vec::new(),
+                    hard_link_to: Some(target.path.clone()),
+                    ..target.clone()
+                });
+            }
+            _ => {}
+        }
+    }
+
+    Ok(files)
+}
+
+/// Add a chunk to the runs of a file, extending the last run if the chunk
+/// continues it
+fn add_run(runs: &mut Vec<ExtentRun>, chunk: &FileChunk, owners: &HashMap<u64, String>) {
+    let (extent_id, shared_from) = match chunk.source {
+        ChunkSource::Data { extent_id } => (Some(extent_id), None),
+        ChunkSource::Reference { extent_id } => (Some(extent_id), owners.get(&extent_id).cloned()),
+        _ => (None, None),
+    };
+    if let Some(last) = runs.last_mut() {
+        let same_source = std::mem::discriminant(&last.source) == std::mem::discriminant(&chunk.source);
+        let next_extent = match (last.last_extent, extent_id) {
+            (Some(last_extent), Some(extent_id)) => extent_id == last_extent + 1,
+            (None, None) => true,
+            _ => false,
+        };
+        if same_source && next_extent && last.shared_from == shared_from && last.offset + last.length == chunk.offset {
+            last.length += chunk.length;
+            last.last_extent = extent_id;
+            return;
+        }
+    }
+    runs.push(ExtentRun {
+        offset: chunk.offset,
+        length: chunk.length,
+        source: chunk.source,
+        last_extent: extent_id,
+        shared_from,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{archive_from_tar, archive_of, extractor_for, tar_header, test_data};
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_manifest_digests_rebuilt_contents() {
+        let source_dir = TempDir::new().unwrap();
+        let contents = test_data(10000);
+        for name in ["a", "b"] {
+            fs::write(source_dir.path().join(name), &contents).unwrap();
+        }
+        fs::write(source_dir.path().join("small"), b"hello\n").unwrap();
+        let archive = archive_of(source_dir.path(), &["a", "b", "small"]);
+
+        let out = TempDir::new().unwrap();
+        let mut extractor = extractor_for(&archive, out.path());
+        let files = build_manifest(&mut extractor, DigestAlgorithm::Sha256).unwrap();
+        let expected = format!("{:x}", sha2::Sha256::digest(&contents));
+        assert_eq!(files[0].digest, expected);
+        // b is stored as references to a's extents, and hashes the same
+        assert_eq!(files[1].digest, expected);
+        assert_eq!(files[1].runs.len(), 1);
+        assert_eq!(files[1].runs[0].shared_from.as_deref(), Some("a"));
+        assert_eq!(
+            files[2].check_line(),
+            "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03  small"
+        );
+        assert!(files[1].json(DigestAlgorithm::Sha256).contains("\"shared_bytes\":10000"));
+    }
+
+    #[test]
+    fn test_manifest_holes_escapes_and_damage() {
+        let source_dir = TempDir::new().unwrap();
+        // Zeros, which are stored as a hole, around one block of data
+        let mut sparse = vec![0u8; 16384];
+        sparse[8192..12288].copy_from_slice(&test_data(4096));
+        for (name, data) in [("sparse", sparse.as_slice()), ("empty", b"".as_slice()), ("new\nline", b"x".as_slice())] {
+            fs::write(source_dir.path().join(name), data).unwrap();
+        }
+        std::os::unix::fs::symlink("sparse", source_dir.path().join("link")).unwrap();
+        let archive = archive_of(source_dir.path(), &["sparse", "empty", "new\nline", "link"]);
+
+        let manifest = |archive: &[u8], algorithm| {
+            let out = TempDir::new().unwrap();
+            build_manifest(&mut extractor_for(archive, out.path()), algorithm)
+        };
+
+        // Holes hash as zeros; symlinks are left out
+        let files = manifest(&archive, DigestAlgorithm::Blake3).unwrap();
+        let paths: Vec<&str> = files.iter().map(|file| file.path.as_str()).collect();
+        assert_eq!(paths, ["sparse", "empty", "new\nline"]);
+        assert_eq!(files[0].digest, blake3::hash(&sparse).to_hex().to_string());
+        assert!(files[0].json(DigestAlgorithm::Blake3).contains("\"sparse_bytes\":12288"));
+        assert_eq!(files[1].digest, blake3::hash(b"").to_hex().to_string());
+        assert!(files[1].runs.is_empty());
+        assert_eq!(files[2].check_line(), format!("\\{}  new\\nline", blake3::hash(b"x").to_hex()));
+
+        let files = manifest(&archive, DigestAlgorithm::Sha512).unwrap();
+        assert_eq!(files[0].digest, format!("{:x}", sha2::Sha512::digest(&sparse)));
+
+        // Data that fails its checksum is an error, not a wrong digest
+        let mut damaged = archive.clone();
+        let data = damaged.windows(64).position(|w| w == &sparse[8192..8256]).unwrap();
+        damaged[data + 100] ^= 1;
+        let error = manifest(&damaged, DigestAlgorithm::Sha256).unwrap_err();
+        assert!(error.to_string().starts_with("Checksum mismatch for extent 0"), "{}", error);
+    }
+
+    #[test]
+    fn test_manifest_lists_hard_links() {
+        let contents = test_data(10000);
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar_header(tar::Header::new_gnu(), tar::EntryType::Regular, 0o640, contents.len());
+        builder.append_data(&mut header, "dir/file", contents.as_slice()).unwrap();
+        let mut header = tar_header(tar::Header::new_gnu(), tar::EntryType::Link, 0o640, 0);
+        builder.append_link(&mut header, "dir/hard", "dir/file").unwrap();
+        let archive = archive_from_tar(&builder.into_inner().unwrap());
+
+        // The link is listed under its own path, with its target's digest
+        let out = TempDir::new().unwrap();
+        let mut extractor = extractor_for(&archive, out.path());
+        let files = build_manifest(&mut extractor, DigestAlgorithm::Sha256).unwrap();
+        let expected = format!("{:x}", sha2::Sha256::digest(&contents));
+        assert_eq!(files[1].check_line(), format!("{}  dir/hard", expected));
+        assert_eq!((files[1].size, files[1].mode), (10000, Some(0o640)));
+        let json = files[1].json(DigestAlgorithm::Sha256);
+        assert!(json.contains("\"hard_link_to\":\"dir/file\",\"stored_bytes\":0,"), "{}", json);
+    }
+}
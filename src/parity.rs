@@ -0,0 +1,687 @@
+//! Reed-Solomon parity for repairing damaged archives (`--parity`)
+//!
+//! The archive file is cut into blocks, and every group of N consecutive
+//! blocks gets M parity blocks, so any M damaged blocks of a group can be
+//! rebuilt. A parity index records a CRC32C of every block, which is how
+//! damaged blocks are found. Parity is appended to the archive after its
+//! footer, or kept in a separate parity file; either way it ends with a
+//! trailer pointing at the index. A copy of the index comes before the
+//! parity blocks, so parity whose end is cut off can still be used. Parity
+//! protects the file's bytes as stored, so a parity file also works for
+//! compressed archives.
+
+use crate::format::*;
+use anyhow::{bail, Context, Result};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+
+/// Magic bytes starting the parity index
+const PARITY_INDEX_MAGIC: &[u8; 4] = b"RTPI";
+
+/// Bytes of the parity index before its checksums
+const PARITY_INDEX_FIXED_SIZE: usize = 28;
+
+/// Parity block sizes are multiples of this, as archive block sizes are
+const MIN_PARITY_BLOCK_SIZE: u64 = 512;
+
+/// Parity blocks per group of data blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParityScheme {
+    pub data_blocks: usize,
+    pub parity_blocks: usize,
+}
+
+impl Default for ParityScheme {
+    /// Four parity blocks for every 64 data blocks, about 6% more space
+    fn default() -> Self {
+        Self {
+            data_blocks: 64,
+            parity_blocks: 4,
+        }
+    }
+}
+
+impl ParityScheme {
+    /// Parse `N:M`, M parity blocks for every N data blocks
+    pub fn parse(value: &str) -> Result<Self> {
+        let parsed = value
+            .split_once(':')
+            .and_then(|(data, parity)| Some((data.parse().ok()?, parity.parse().ok()?)));
+        let Some((data_blocks, parity_blocks)) = parsed else {
+            bail!("Invalid parity {:?}, expected DATA:PARITY block counts such as 64:4", value);
+        };
+        let scheme = Self {
+            data_blocks,
+            parity_blocks,
+        };
+        scheme.codec()?;
+        Ok(scheme)
+    }
+
+    fn codec(self) -> Result<ReedSolomon> {
+        if self.data_blocks == 0 || self.parity_blocks == 0 || self.data_blocks + self.parity_blocks > 256 {
+            bail!(
+                "Parity needs at least one data and one parity block per group, and at most 256 in all, not {}:{}",
+                self.data_blocks,
+                self.parity_blocks
+            );
+        }
+        ReedSolomon::new(self.data_blocks, self.parity_blocks).map_err(|e| anyhow::anyhow!("{:?}", e))
+    }
+}
+
+/// What the parity of a file covers, and the checksums that find damage
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParityIndex {
+    pub block_size: u32,
+    pub scheme: ParityScheme,
+    /// Bytes of the archive file the parity covers, from its start
+    pub protected_length: u64,
+    /// Where the parity blocks start in the file holding them, after the
+    /// copy of the index
+    pub parity_offset: u64,
+    /// CRC32C of each protected block, the last one zero-padded
+    pub block_checksums: Vec<u32>,
+    /// CRC32C of each parity block
+    pub parity_checksums: Vec<u32>,
+}
+
+impl ParityIndex {
+    /// Number of block groups
+    fn groups(&self) -> usize {
+        self.block_checksums.len().div_ceil(self.scheme.data_blocks)
+    }
+
+    /// Bytes of parity blocks
+    pub fn parity_length(&self) -> u64 {
+        self.parity_checksums.len() as u64 * self.block_size as u64
+    }
+
+    /// Where the copy of the index is, ahead of the parity blocks
+    fn copy_offset(&self) -> u64 {
+        self.parity_offset - copy_length(self.block_checksums.len() + self.parity_checksums.len(), self.block_size)
+    }
+
+    /// Whether the parity is in a parity file rather than appended to the
+    /// archive: the copy of its index starts the file
+    fn in_parity_file(&self) -> bool {
+        self.copy_offset() == 0
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut index = Vec::new();
+        index.extend_from_slice(PARITY_INDEX_MAGIC);
+        index.extend_from_slice(&self.block_size.to_le_bytes());
+        index.extend_from_slice(&(self.scheme.data_blocks as u16).to_le_bytes());
+        index.extend_from_slice(&(self.scheme.parity_blocks as u16).to_le_bytes());
+        index.extend_from_slice(&self.protected_length.to_le_bytes());
+        index.extend_from_slice(&self.parity_offset.to_le_bytes());
+        for checksum in self.block_checksums.iter().chain(&self.parity_checksums) {
+            index.extend_from_slice(&checksum.to_le_bytes());
+        }
+        let checksum = crc32c::crc32c(&index);
+        index.extend_from_slice(&checksum.to_le_bytes());
+        index
+    }
+
+    fn decode(index: &[u8]) -> Result<Self> {
+        let damaged = || anyhow::anyhow!("Parity index is damaged");
+        let (body, checksum) = index.split_last_chunk::<4>().ok_or_else(damaged)?;
+        if body.len() < 28 || &body[..4] != PARITY_INDEX_MAGIC || crc32c::crc32c(body) != u32::from_le_bytes(*checksum) {
+            return Err(damaged());
+        }
+        let u16_at = |at: usize| u16::from_le_bytes(body[at..at + 2].try_into().unwrap()) as usize;
+        let u64_at = |at: usize| u64::from_le_bytes(body[at..at + 8].try_into().unwrap());
+        let block_size = u32::from_le_bytes(body[4..8].try_into().unwrap());
+        let scheme = ParityScheme {
+            data_blocks: u16_at(8),
+            parity_blocks: u16_at(10),
+        };
+        scheme.codec()?;
+        let protected_length = u64_at(12);
+        let parity_offset = u64_at(20);
+
+        let checksums: Vec<u32> = body[PARITY_INDEX_FIXED_SIZE..]
+            .chunks_exact(4)
+            .map(|crc| u32::from_le_bytes(crc.try_into().unwrap()))
+            .collect();
+        let counts = block_counts(block_size, scheme, protected_length).ok_or_else(damaged)?;
+        let (blocks, parity) = counts;
+        if !(body.len() - PARITY_INDEX_FIXED_SIZE).is_multiple_of(4) || blocks.checked_add(parity) != Some(checksums.len()) {
+            return Err(damaged());
+        }
+        if parity_offset < copy_length(checksums.len(), block_size) {
+            return Err(damaged());
+        }
+        Ok(Self {
+            block_size,
+            scheme,
+            protected_length,
+            parity_offset,
+            block_checksums: checksums[..blocks].to_vec(),
+            parity_checksums: checksums[blocks..].to_vec(),
+        })
+    }
+}
+
+/// Protected blocks and parity blocks of parity with these parameters, or
+/// None if the block size isn't a multiple of 512
+fn block_counts(block_size: u32, scheme: ParityScheme, protected_length: u64) -> Option<(usize, usize)> {
+    if block_size == 0 || !(block_size as u64).is_multiple_of(MIN_PARITY_BLOCK_SIZE) {
+        return None;
+    }
+    let blocks = usize::try_from(protected_length.div_ceil(block_size as u64)).ok()?;
+    let parity = blocks.div_ceil(scheme.data_blocks).checked_mul(scheme.parity_blocks)?;
+    Some((blocks, parity))
+}
+
+/// Bytes taken by the copy of an index of `checksums` block checksums ahead
+/// of the parity blocks, padded to a whole block
+fn copy_length(checksums: usize, block_size: u32) -> u64 {
+    let index_len = (PARITY_INDEX_FIXED_SIZE + 4 * checksums + 4) as u64;
+    index_len.next_multiple_of(block_size as u64)
+}
+
+/// Read the parity index of a file holding parity: an archive with
+/// parity appended, or a parity file. None if it has no parity.
+///
+/// The index at the end of the file is used if it is intact, and else the
+/// copy ahead of the parity blocks, so that parity whose end was cut off
+/// or damaged can still repair the archive.
+pub fn read_index<R: Read + Seek>(reader: &mut R) -> Result<Option<ParityIndex>> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    let damaged = match read_parity_trailer(reader)? {
+        Some((_, index_offset)) => {
+            reader.seek(SeekFrom::Start(index_offset))?;
+            let mut index = Vec::new();
+            reader
+                .take((file_len - index_offset).saturating_sub(PARITY_TRAILER_SIZE as u64))
+                .read_to_end(&mut index)?;
+            match ParityIndex::decode(&index) {
+                Ok(index) => return Ok(Some(index)),
+                Err(e) => Some(e),
+            }
+        }
+        None => None,
+    };
+    match (find_index_copy(reader, file_len, damaged.is_some())?, damaged) {
+        (Some(index), _) => Ok(Some(index)),
+        (None, Some(e)) => Err(e),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Find the copy of the parity index ahead of the parity blocks: at the
+/// start of a parity file, or after the archive where parity is appended.
+/// Archives that end with their own footer trailer have no appended parity
+/// to look for, unless `trailer_found` says the file ends with parity.
+fn find_index_copy<R: Read + Seek>(reader: &mut R, file_len: u64, trailer_found: bool) -> Result<Option<ParityIndex>> {
+    let mut start = [0u8; 6];
+    reader.seek(SeekFrom::Start(0))?;
+    if read_up_to(reader, &mut start)? < start.len() {
+        return Ok(None);
+    }
+    if start.starts_with(PARITY_INDEX_MAGIC) {
+        return read_index_copy(reader, 0, file_len);
+    }
+    if &start != REFTAR_MAGIC || (!trailer_found && ArchiveFooter::locate_offset(reader)?.is_some()) {
+        return Ok(None);
+    }
+
+    // Appended parity starts on a block boundary after the archive: look
+    // for the copy from the end of what is left, a chunk at a time
+    const CHUNK: u64 = 1024 * 1024;
+    let mut buf = vec![0u8; CHUNK as usize];
+    let mut end = file_len;
+    while end > 0 {
+        let chunk_start = end.saturating_sub(CHUNK) / MIN_PARITY_BLOCK_SIZE * MIN_PARITY_BLOCK_SIZE;
+        let chunk = &mut buf[..(end - chunk_start) as usize];
+        reader.seek(SeekFrom::Start(chunk_start))?;
+        reader.read_exact(chunk)?;
+        for at in (0..chunk.len()).step_by(MIN_PARITY_BLOCK_SIZE as usize).rev() {
+            if chunk[at..].starts_with(PARITY_INDEX_MAGIC) {
+                let offset = chunk_start + at as u64;
+                if let Some(index) = read_index_copy(reader, offset, file_len)?.filter(|index| !index.in_parity_file()) {
+                    return Ok(Some(index));
+                }
+            }
+        }
+        end = chunk_start;
+    }
+    Ok(None)
+}
+
+/// Read the copy of a parity index that would be at `offset`; None if
+/// there is none there
+fn read_index_copy<R: Read + Seek>(reader: &mut R, offset: u64, file_len: u64) -> Result<Option<ParityIndex>> {
+    let mut fixed = [0u8; PARITY_INDEX_FIXED_SIZE];
+    reader.seek(SeekFrom::Start(offset))?;
+    if read_up_to(reader, &mut fixed)? < fixed.len() {
+        return Ok(None);
+    }
+    let block_size = u32::from_le_bytes(fixed[4..8].try_into().unwrap());
+    let scheme = ParityScheme {
+        data_blocks: u16::from_le_bytes(fixed[8..10].try_into().unwrap()) as usize,
+        parity_blocks: u16::from_le_bytes(fixed[10..12].try_into().unwrap()) as usize,
+    };
+    let protected_length = u64::from_le_bytes(fixed[12..20].try_into().unwrap());
+    let index_len = scheme
+        .codec()
+        .ok()
+        .and_then(|_| block_counts(block_size, scheme, protected_length))
+        .and_then(|(blocks, parity)| blocks.checked_add(parity)?.checked_mul(4))
+        .map(|checksums| (PARITY_INDEX_FIXED_SIZE + checksums + 4) as u64)
+        .filter(|&len| len <= file_len - offset);
+    let Some(index_len) = index_len else {
+        return Ok(None);
+    };
+
+    let mut index = fixed.to_vec();
+    index.resize(index_len as usize, 0);
+    reader.read_exact(&mut index[PARITY_INDEX_FIXED_SIZE..])?;
+    // Only the copy where the index itself says it is will do
+    Ok(ParityIndex::decode(&index).ok().filter(|index| index.copy_offset() == offset))
+}
+
+/// Read until `buf` is full or the input ends; the bytes read
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Read protected block `block` of `archive`, zero-padded; false if the
+/// file ends before it is complete
+fn read_block(archive: &File, index: &ParityIndex, block: usize, buf: &mut [u8]) -> Result<bool> {
+    buf.fill(0);
+    let offset = block as u64 * index.block_size as u64;
+    let wanted = (index.protected_length - offset).min(index.block_size as u64) as usize;
+    let mut filled = 0;
+    while filled < wanted {
+        match archive.read_at(&mut buf[filled..wanted], offset + filled as u64)? {
+            0 => return Ok(false),
+            n => filled += n,
+        }
+    }
+    Ok(true)
+}
+
+/// Compute the parity of the first `protected_length` bytes of `archive`
+/// and write it to `out`, from its offset `start`: a copy of the index,
+/// the parity blocks, the index and the trailer
+pub fn write_parity<W: Write + Seek>(
+    archive: &File,
+    protected_length: u64,
+    block_size: u32,
+    scheme: ParityScheme,
+    out: &mut W,
+    start: u64,
+) -> Result<ParityIndex> {
+    let codec = scheme.codec()?;
+    let Some((blocks, parity)) = block_counts(block_size, scheme, protected_length) else {
+        bail!("Parity block size {} is not a multiple of {}", block_size, MIN_PARITY_BLOCK_SIZE);
+    };
+    let mut index = ParityIndex {
+        block_size,
+        scheme,
+        protected_length,
+        parity_offset: start + copy_length(blocks + parity, block_size),
+        block_checksums: Vec::with_capacity(blocks),
+        parity_checksums: Vec::new(),
+    };
+
+    // The copy of the index is written once the checksums are known
+    out.write_all(&vec![0u8; (index.parity_offset - start) as usize])?;
+
+    let mut shards = vec![vec![0u8; block_size as usize]; scheme.data_blocks + scheme.parity_blocks];
+    for group in 0..blocks.div_ceil(scheme.data_blocks) {
+        for (position, shard) in shards.iter_mut().enumerate().take(scheme.data_blocks) {
+            let block = group * scheme.data_blocks + position;
+            if block < blocks {
+                if !read_block(archive, &index, block, shard)? {
+                    bail!("Archive is shorter than {} bytes", protected_length);
+                }
+                index.block_checksums.push(crc32c::crc32c(shard));
+            } else {
+                // Past the end, the last group is filled with zeros
+                shard.fill(0);
+            }
+        }
+        codec.encode(&mut shards).map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        for shard in &shards[scheme.data_blocks..] {
+            index.parity_checksums.push(crc32c::crc32c(shard));
+            out.write_all(shard)?;
+        }
+    }
+
+    let encoded = index.encode();
+    out.write_all(&encoded)?;
+    write_trailer(out, &index)?;
+    out.seek(SeekFrom::Start(start))?;
+    out.write_all(&encoded)?;
+    out.flush()?;
+    Ok(index)
+}
+
+/// Write the trailer after the index at the end of the parity
+fn write_trailer<W: Write>(out: &mut W, index: &ParityIndex) -> Result<()> {
+    let index_offset = index.parity_offset + index.parity_length();
+    out.write_all(&index.protected_length.to_le_bytes())?;
+    out.write_all(&index_offset.to_le_bytes())?;
+    out.write_all(PARITY_TRAILER_MAGIC)?;
+    Ok(())
+}
+
+/// Append parity to an archive file, replacing any it already has
+pub fn append_parity(file: &mut File, block_size: u32, scheme: ParityScheme) -> Result<ParityIndex> {
+    let protected_length = archive_length(file)?;
+    file.set_len(protected_length)?;
+
+    // Parity starts on a block boundary
+    let parity_offset = protected_length.next_multiple_of(block_size as u64);
+    let mut out = BufWriter::new(file.try_clone()?);
+    out.seek(SeekFrom::Start(protected_length))?;
+    out.write_all(&vec![0u8; (parity_offset - protected_length) as usize])?;
+    write_parity(file, protected_length, block_size, scheme, &mut out, parity_offset)
+}
+
+/// Remove parity appended to an archive file, returning how it was made
+pub fn strip_parity(file: &mut File) -> Result<Option<ParityIndex>> {
+    let Some(index) = read_index(file)? else {
+        return Ok(None);
+    };
+    if index.in_parity_file() {
+        bail!("This is a parity file, not an archive");
+    }
+    file.set_len(index.protected_length)?;
+    Ok(Some(index))
+}
+
+/// Damage found by `check_parity`
+#[derive(Debug, Clone, Default)]
+pub struct ParityReport {
+    /// Offsets of archive blocks that fail their checksums
+    pub damaged: Vec<u64>,
+    /// Offsets of damaged blocks that can't be rebuilt, because their group
+    /// has more damaged blocks than parity blocks
+    pub unrecoverable: Vec<u64>,
+    /// Parity blocks that fail their checksums
+    pub damaged_parity: usize,
+}
+
+impl ParityReport {
+    /// Whether every damaged block can be rebuilt
+    pub fn recoverable(&self) -> bool {
+        self.unrecoverable.is_empty()
+    }
+}
+
+/// Check every block of an archive against the parity index, and with
+/// `repair`, rebuild the damaged blocks of groups that have enough intact
+/// blocks and write them back. `parity` holds the parity blocks: the
+/// archive file itself, or the parity file.
+pub fn check_parity(archive: &File, parity: &File, index: &ParityIndex, repair: bool) -> Result<ParityReport> {
+    let scheme = index.scheme;
+    let codec = scheme.codec()?;
+    let block_size = index.block_size as usize;
+    let blocks = index.block_checksums.len();
+    let mut report = ParityReport::default();
+
+    let mut buf = vec![0u8; block_size];
+    for group in 0..index.groups() {
+        let mut shards: Vec<Option<Vec<u8>>> = Vec::with_capacity(scheme.data_blocks + scheme.parity_blocks);
+        let mut damaged = Vec::new();
+        for position in 0..scheme.data_blocks {
+            let block = group * scheme.data_blocks + position;
+            if block >= blocks {
+                shards.push(Some(vec![0u8; block_size]));
+            } else if read_block(archive, index, block, &mut buf)? && crc32c::crc32c(&buf) == index.block_checksums[block] {
+                shards.push(Some(buf.clone()));
+            } else {
+                damaged.push(position);
+                shards.push(None);
+            }
+        }
+        for position in 0..scheme.parity_blocks {
+            let block = group * scheme.parity_blocks + position;
+            let offset = index.parity_offset + (block * block_size) as u64;
+            let intact = parity.read_exact_at(&mut buf, offset).is_ok()
+                && crc32c::crc32c(&buf) == index.parity_checksums[block];
+            if intact {
+                shards.push(Some(buf.clone()));
+            } else {
+                report.damaged_parity += 1;
+                damaged.push(scheme.data_blocks + position);
+                shards.push(None);
+            }
+        }
+        if damaged.is_empty() {
+            continue;
+        }
+
+        let block_offset = |position: usize| ((group * scheme.data_blocks + position) * block_size) as u64;
+        let data_damaged = damaged.iter().filter(|&&position| position < scheme.data_blocks);
+        report.damaged.extend(data_damaged.clone().map(|&position| block_offset(position)));
+        if damaged.len() > scheme.parity_blocks {
+            report.unrecoverable.extend(data_damaged.map(|&position| block_offset(position)));
+            continue;
+        }
+        if !repair {
+            continue;
+        }
+
+        codec.reconstruct(&mut shards).map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        for position in damaged {
+            let shard = shards[position].as_ref().unwrap();
+            if position < scheme.data_blocks {
+                let offset = block_offset(position);
+                let length = (index.protected_length - offset).min(block_size as u64) as usize;
+                archive.write_all_at(&shard[..length], offset)?;
+            } else {
+                let block = group * scheme.parity_blocks + position - scheme.data_blocks;
+                parity.write_all_at(shard, index.parity_offset + (block * block_size) as u64)?;
+            }
+        }
+    }
+
+    if repair {
+        // Put back the index and trailer, in case the end was cut off
+        let mut end = index.encode();
+        write_trailer(&mut end, index)?;
+        parity.write_all_at(&end, index.parity_offset + index.parity_length())?;
+        archive.sync_all()?;
+        parity.sync_all()?;
+    }
+    Ok(report)
+}
+
+/// Open the parity of an archive: parity appended to it, or else the
+/// parity file `sidecar`. Returns the file holding the parity and its index.
+pub fn open_parity(archive: &mut File, sidecar: Option<&std::path::Path>, write: bool) -> Result<Option<(File, ParityIndex)>> {
+    if let Some(index) = read_index(archive)? {
+        return Ok(Some((archive.try_clone()?, index)));
+    }
+    let Some(path) = sidecar else {
+        return Ok(None);
+    };
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(write)
+        .open(path)
+        .with_context(|| format!("Failed to open parity file {:?}", path))?;
+    match read_index(&mut file)? {
+        Some(index) => Ok(Some((file, index))),
+        None => bail!("{:?} is not a parity file", path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::ArchiveCreator;
+    use std::fs;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    fn create_archive(dir: &TempDir) -> std::path::PathBuf {
+        let source = dir.path().join("data.bin");
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i * 7 % 253) as u8).collect();
+        fs::write(&source, data).unwrap();
+        let path = dir.path().join("archive.reftar");
+        let mut creator = ArchiveCreator::new(File::create(&path).unwrap(), None).unwrap();
+        creator.add_file(&source, Path::new("data.bin")).unwrap();
+        creator.finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn test_appended_parity_repairs_damage() {
+        let dir = TempDir::new().unwrap();
+        let path = create_archive(&dir);
+        let original = fs::read(&path).unwrap();
+        let scheme = ParityScheme::parse("8:2").unwrap();
+
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        append_parity(&mut file, 4096, scheme).unwrap();
+        // The footer is still found behind the parity
+        assert!(ArchiveFooter::locate(&mut file).unwrap().is_some());
+
+        // Two damaged blocks in one group are rebuilt
+        let mut damaged = fs::read(&path).unwrap();
+        damaged[5000] ^= 0xff;
+        damaged[9000] ^= 0xff;
+        fs::write(&path, &damaged).unwrap();
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let (parity, index) = open_parity(&mut file, None, true).unwrap().unwrap();
+        let report = check_parity(&file, &parity, &index, false).unwrap();
+        assert_eq!(report.damaged, [4096, 8192]);
+        assert!(report.recoverable());
+
+        check_parity(&file, &parity, &index, true).unwrap();
+        assert_eq!(&fs::read(&path).unwrap()[..original.len()], &original[..]);
+        assert!(check_parity(&file, &parity, &index, false).unwrap().damaged.is_empty());
+
+        // Three in one group are more than the parity covers
+        let mut damaged = fs::read(&path).unwrap();
+        for offset in [100, 5000, 9000] {
+            damaged[offset] ^= 1;
+        }
+        fs::write(&path, &damaged).unwrap();
+        let report = check_parity(&file, &parity, &index, true).unwrap();
+        assert_eq!(report.unrecoverable, [0, 4096, 8192]);
+    }
+
+    #[test]
+    fn test_parity_scheme_parse() {
+        assert_eq!(
+            ParityScheme::parse("10:3").unwrap(),
+            ParityScheme {
+                data_blocks: 10,
+                parity_blocks: 3
+            }
+        );
+        for value in ["10", "a:b", "0:2", "8:0", "200:100"] {
+            assert!(ParityScheme::parse(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_damaged_parity_and_index() {
+        let dir = TempDir::new().unwrap();
+        let path = create_archive(&dir);
+        let original = fs::read(&path).unwrap();
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let index = append_parity(&mut file, 4096, ParityScheme::parse("8:2").unwrap()).unwrap();
+
+        // A damaged parity block counts against its group, and is rebuilt
+        // along with the damaged data block
+        let with_parity = fs::read(&path).unwrap();
+        let mut damaged = with_parity.clone();
+        damaged[index.parity_offset as usize + 10] ^= 1;
+        damaged[5000] ^= 1;
+        fs::write(&path, &damaged).unwrap();
+        let report = check_parity(&file, &file, &index, true).unwrap();
+        assert_eq!((report.damaged.as_slice(), report.damaged_parity), ([4096].as_slice(), 1));
+        assert!(report.recoverable());
+        assert_eq!(fs::read(&path).unwrap(), with_parity);
+
+        // A damaged index is passed over for its copy, and refused rather
+        // than trusted once the copy is damaged too
+        let mut damaged = with_parity.clone();
+        let last_index_byte = damaged.len() - PARITY_TRAILER_SIZE - 1;
+        damaged[last_index_byte] ^= 1;
+        fs::write(&path, &damaged).unwrap();
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        assert_eq!(read_index(&mut file).unwrap(), Some(index.clone()));
+        damaged[index.copy_offset() as usize + 30] ^= 1;
+        fs::write(&path, &damaged).unwrap();
+        assert_eq!(read_index(&mut file).unwrap_err().to_string(), "Parity index is damaged");
+
+        // Stripping gives back the archive, which then has no parity
+        fs::write(&path, &with_parity).unwrap();
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        assert_eq!(strip_parity(&mut file).unwrap(), Some(index));
+        assert_eq!(fs::read(&path).unwrap(), original);
+        assert!(strip_parity(&mut file).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parity_repairs_archive_with_its_end_cut_off() {
+        let dir = TempDir::new().unwrap();
+        let path = create_archive(&dir);
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let index = append_parity(&mut file, 4096, ParityScheme::parse("8:2").unwrap()).unwrap();
+        let with_parity = fs::read(&path).unwrap();
+
+        // Cut off in the parity blocks, the trailer and index are gone,
+        // but the copy of the index still finds the damage and repairs it
+        let mut damaged = with_parity.clone();
+        damaged[5000] ^= 1;
+        damaged.truncate(index.parity_offset as usize + 4096 + 100);
+        fs::write(&path, &damaged).unwrap();
+        let mut file = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let (parity, found) = open_parity(&mut file, None, true).unwrap().unwrap();
+        assert_eq!(found, index);
+        let report = check_parity(&file, &parity, &found, true).unwrap();
+        assert_eq!(report.damaged, [4096]);
+        assert!(report.recoverable());
+        assert_eq!(fs::read(&path).unwrap(), with_parity);
+
+        // An archive without parity has no copy to find
+        let plain = dir.path().join("plain.rtar");
+        fs::write(&plain, &with_parity[..index.protected_length as usize]).unwrap();
+        assert!(read_index(&mut File::open(&plain).unwrap()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_parity_file_protects_archive() {
+        let dir = TempDir::new().unwrap();
+        let path = create_archive(&dir);
+        let original = fs::read(&path).unwrap();
+        let archive = File::open(&path).unwrap();
+        let sidecar = dir.path().join("archive.reftar.par");
+        let mut out = BufWriter::new(File::create(&sidecar).unwrap());
+        write_parity(&archive, original.len() as u64, 4096, ParityScheme::default(), &mut out, 0).unwrap();
+        drop(out);
+
+        // Truncation loses the last block, which the parity rebuilds
+        let mut archive = fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        archive.set_len(original.len() as u64 - 100).unwrap();
+        let (parity, index) = open_parity(&mut archive, Some(&sidecar), true).unwrap().unwrap();
+        let report = check_parity(&archive, &parity, &index, true).unwrap();
+        assert_eq!(report.damaged, [original.len() as u64 - 4096]);
+        assert_eq!(fs::read(&path).unwrap(), original);
+
+        // A parity file is not an archive to strip parity from
+        let mut parity = fs::OpenOptions::new().read(true).write(true).open(&sidecar).unwrap();
+        let error = strip_parity(&mut parity).unwrap_err();
+        assert_eq!(error.to_string(), "This is a parity file, not an archive");
+    }
+}
@@ -0,0 +1,434 @@
+//! Progress reporting for archive creation and extraction
+//!
+//! `ArchiveCreator` and `ArchiveExtractor` report what they do to a
+//! `ProgressObserver`. `ProgressCounters` keeps running totals of those
+//! events, and `ProgressReporter` shows them as a progress bar or a stream
+//! of JSON records.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Receives progress events
+///
+/// Extraction with several jobs calls the observer from several threads at
+/// once. Every method defaults to doing nothing.
+pub trait ProgressObserver: Send + Sync {
+    /// A member is about to be archived or extracted
+    fn file_started(&self, _path: &Path, _size: u64) {}
+
+    /// A member has been archived or extracted
+    fn file_finished(&self, _path: &Path) {}
+
+    /// Bytes read: file data on create, counting holes as read, and stored
+    /// data from the archive on extract
+    fn bytes_read(&self, _bytes: u64) {}
+
+    /// Bytes written: the archive on create, and file data restored on
+    /// extract, counting holes and cloned data as written
+    fn bytes_written(&self, _bytes: u64) {}
+
+    /// File data found already stored, in this archive or a base archive,
+    /// and not stored (create) or read (extract) again
+    fn dedup_hit(&self, _bytes: u64) {}
+
+    /// File data cloned with a reflink instead of being copied
+    fn reflinked(&self, _bytes: u64) {}
+}
+
+/// Observer that ignores every event
+pub struct NoProgress;
+
+impl ProgressObserver for NoProgress {}
+
+/// A point-in-time copy of `ProgressCounters`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProgressTotals {
+    pub files: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub dedup_bytes: u64,
+    pub reflinked_bytes: u64,
+    pub current_path: Option<PathBuf>,
+}
+
+/// Observer keeping running totals of every event
+#[derive(Debug, Default)]
+pub struct ProgressCounters {
+    files: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    dedup_bytes: AtomicU64,
+    reflinked_bytes: AtomicU64,
+    current_path: Mutex<Option<PathBuf>>,
+}
+
+impl ProgressCounters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn totals(&self) -> ProgressTotals {
+        ProgressTotals {
+            files: self.files.load(Ordering::Relaxed),
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            dedup_bytes: self.dedup_bytes.load(Ordering::Relaxed),
+            reflinked_bytes: self.reflinked_bytes.load(Ordering::Relaxed),
+            current_path: self.current_path.lock().unwrap().clone(),
+        }
+    }
+}
+
+impl ProgressObserver for ProgressCounters {
+    fn file_started(&self, path: &Path, _size: u64) {
+        *self.current_path.lock().unwrap() = Some(path.to_path_buf());
+    }
+
+    fn file_finished(&self, _path: &Path) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn bytes_read(&self, bytes: u64) {
+        self.bytes_read.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn bytes_written(&self, bytes: u64) {
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn dedup_hit(&self, bytes: u64) {
+        self.dedup_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn reflinked(&self, bytes: u64) {
+        self.reflinked_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Which side of the archive the progress is for; decides which byte count
+/// measures file data done
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Create,
+    Extract,
+}
+
+/// How `ProgressReporter` shows progress
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressFormat {
+    /// A progress bar on stderr, redrawn in place
+    Bar,
+    /// One JSON object per line on stderr
+    Json,
+}
+
+/// Derived figures for one report
+struct Rates {
+    done: u64,
+    bytes_per_second: f64,
+    eta_seconds: Option<f64>,
+    dedup_ratio: f64,
+}
+
+impl ProgressTotals {
+    fn rates(&self, operation: Operation, total: Option<u64>, elapsed: Duration) -> Rates {
+        let done = match operation {
+            Operation::Create => self.bytes_read,
+            Operation::Extract => self.bytes_written,
+        };
+        let seconds = elapsed.as_secs_f64();
+        let bytes_per_second = if seconds > 0.0 { done as f64 / seconds } else { 0.0 };
+        let eta_seconds = total
+            .filter(|_| bytes_per_second > 0.0)
+            .map(|total| total.saturating_sub(done) as f64 / bytes_per_second);
+        let dedup_ratio = if done > 0 { self.dedup_bytes as f64 / done as f64 } else { 0.0 };
+        Rates {
+            done,
+            bytes_per_second,
+            eta_seconds,
+            dedup_ratio,
+        }
+    }
+}
+
+/// Shows progress from a `ProgressCounters` on a background thread until
+/// `finish` is called
+pub struct ProgressReporter {
+    counters: Arc<ProgressCounters>,
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+    display: Arc<Display>,
+}
+
+/// What the reporter needs to render a record
+struct Display {
+    format: ProgressFormat,
+    operation: Operation,
+    total: Option<u64>,
+    started: Instant,
+}
+
+impl ProgressReporter {
+    /// Start reporting; `total` is the expected amount of file data, if
+    /// known, and enables the percentage and ETA
+    pub fn start(format: ProgressFormat, operation: Operation, total: Option<u64>) -> Self {
+        let counters = Arc::new(ProgressCounters::new());
+        let display = Arc::new(Display {
+            format,
+            operation,
+            total,
+            started: Instant::now(),
+        });
+        let interval = match format {
+            ProgressFormat::Bar => Duration::from_millis(200),
+            ProgressFormat::Json => Duration::from_secs(1),
+        };
+
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = {
+            let counters = counters.clone();
+            let display = display.clone();
+            std::thread::spawn(move || {
+                while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    display.render(&counters.totals(), false);
+                }
+            })
+        };
+
+        Self {
+            counters,
+            stop: Some(stop),
+            thread: Some(thread),
+            display,
+        }
+    }
+
+    /// The observer to pass to the creator or extractor
+    pub fn observer(&self) -> Arc<dyn ProgressObserver> {
+        self.counters.clone()
+    }
+
+    /// Stop the background thread and show the final totals
+    pub fn finish(mut self) {
+        self.stop_thread();
+        self.display.render(&self.counters.totals(), true);
+    }
+
+    fn stop_thread(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ProgressReporter {
+    fn drop(&mut self) {
+        // Leave the terminal on a fresh line if we stopped early
+        if self.thread.is_some() {
+            self.stop_thread();
+            if self.display.format == ProgressFormat::Bar {
+                eprintln!();
+            }
+        }
+    }
+}
+
+impl Display {
+    fn render(&self, totals: &ProgressTotals, done: bool) {
+        let elapsed = self.started.elapsed();
+        let line = match self.format {
+            ProgressFormat::Bar => {
+                let line = bar_line(totals, self.operation, self.total, elapsed, terminal_width());
+                format!("\r{}\x1b[K{}", line, if done { "\n" } else { "" })
+            }
+            ProgressFormat::Json => json_record(totals, self.operation, self.total, elapsed, done) + "\n",
+        };
+        let mut stderr = std::io::stderr().lock();
+        let _ = stderr.write_all(line.as_bytes());
+        let _ = stderr.flush();
+    }
+}
+
+/// One line of the progress bar, at most `width` characters wide
+fn bar_line(
+    totals: &ProgressTotals,
+    operation: Operation,
+    total: Option<u64>,
+    elapsed: Duration,
+    width: usize,
+) -> String {
+    let rates = totals.rates(operation, total, elapsed);
+    let mut stats = match total {
+        Some(total) => format!(
+            "{:3.0}% {}/{}",
+            fraction_done(rates.done, total) * 100.0,
+            human_bytes(rates.done),
+            human_bytes(total)
+        ),
+        None => human_bytes(rates.done),
+    };
+    stats.push_str(&format!("  {}/s", human_bytes(rates.bytes_per_second as u64)));
+    if let Some(eta) = rates.eta_seconds {
+        let eta = eta.round() as u64;
+        stats.push_str(&format!("  ETA {}:{:02}:{:02}", eta / 3600, eta / 60 % 60, eta % 60));
+    }
+    stats.push_str(&format!("  dedup {:.1}%", rates.dedup_ratio * 100.0));
+
+    // The bar gets up to 20 columns of the room left, the current path the rest
+    let mut line = String::new();
+    let mut room = width.saturating_sub(stats.chars().count());
+    if let Some(total) = total {
+        let bar_width = room.saturating_sub(3).min(20);
+        if bar_width >= 5 {
+            let filled = (fraction_done(rates.done, total) * bar_width as f64) as usize;
+            line.push_str(&format!("[{}{}] ", "#".repeat(filled), "-".repeat(bar_width - filled)));
+            room -= bar_width + 3;
+        }
+    }
+    line.push_str(&stats);
+
+    // Keep the end of the path, which names the file
+    if let Some(path) = &totals.current_path {
+        let path = path.to_string_lossy();
+        let room = room.saturating_sub(2);
+        let count = path.chars().count();
+        if count <= room {
+            line.push_str(&format!("  {}", path));
+        } else if room > 3 {
+            let tail: String = path.chars().skip(count - (room - 3)).collect();
+            line.push_str(&format!("  ...{}", tail));
+        }
+    }
+    line.chars().take(width).collect()
+}
+
+fn fraction_done(done: u64, total: u64) -> f64 {
+    if total > 0 {
+        (done as f64 / total as f64).min(1.0)
+    } else {
+        1.0
+    }
+}
+
+/// One progress record as a single line of JSON
+fn json_record(
+    totals: &ProgressTotals,
+    operation: Operation,
+    total: Option<u64>,
+    elapsed: Duration,
+    done: bool,
+) -> String {
+    let rates = totals.rates(operation, total, elapsed);
+    let optional = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+    format!(
+        concat!(
+            "{{\"type\":\"{}\",\"elapsed_seconds\":{:.3},\"files\":{},\"bytes_read\":{},",
+            "\"bytes_written\":{},\"dedup_bytes\":{},\"reflinked_bytes\":{},\"bytes_done\":{},",
+            "\"total_bytes\":{},\"bytes_per_second\":{:.0},\"eta_seconds\":{},\"dedup_ratio\":{:.4},",
+            "\"current_path\":{}}}"
+        ),
+        if done { "done" } else { "progress" },
+        elapsed.as_secs_f64(),
+        totals.files,
+        totals.bytes_read,
+        totals.bytes_written,
+        totals.dedup_bytes,
+        totals.reflinked_bytes,
+        rates.done,
+        optional(total.map(|total| total.to_string())),
+        rates.bytes_per_second,
+        optional(rates.eta_seconds.map(|eta| format!("{:.1}", eta))),
+        rates.dedup_ratio,
+        optional(totals.current_path.as_ref().map(|path| json_string(&path.to_string_lossy()))),
+    )
+}
+
+/// Quote a string for JSON
+pub(crate) fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Format a byte count with a binary unit
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} B", bytes),
+        _ => format!("{:.1} {}", value, UNITS[unit]),
+    }
+}
+
+/// Width of the terminal on stderr, or 80 if it is not a terminal
+fn terminal_width() -> usize {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let result = unsafe { libc::ioctl(libc::STDERR_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if result == 0 && size.ws_col > 0 {
+        size.ws_col as usize
+    } else {
+        80
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_record() {
+        let totals = ProgressTotals {
+            files: 3,
+            bytes_read: 1000,
+            bytes_written: 600,
+            dedup_bytes: 250,
+            reflinked_bytes: 0,
+            current_path: Some(PathBuf::from("dir/\"odd\"\nname")),
+        };
+        let record = json_record(&totals, Operation::Create, Some(4000), Duration::from_secs(2), false);
+        assert!(record.starts_with("{\"type\":\"progress\",\"elapsed_seconds\":2.000,\"files\":3,"));
+        assert!(record.contains("\"bytes_done\":1000,\"total_bytes\":4000,\"bytes_per_second\":500,"));
+        assert!(record.contains("\"eta_seconds\":6.0,\"dedup_ratio\":0.2500,"));
+        assert!(record.ends_with("\"current_path\":\"dir/\\\"odd\\\"\\nname\"}"));
+
+        let record = json_record(&ProgressTotals::default(), Operation::Extract, None, Duration::ZERO, true);
+        assert!(record.contains("\"type\":\"done\""));
+        assert!(record.contains("\"total_bytes\":null,"));
+        assert!(record.ends_with("\"current_path\":null}"));
+    }
+
+    #[test]
+    fn test_bar_line_fits_width() {
+        let totals = ProgressTotals {
+            bytes_read: 3 << 20,
+            current_path: Some(PathBuf::from("a/very/long/path/".repeat(10) + "file.bin")),
+            ..Default::default()
+        };
+        let line = bar_line(&totals, Operation::Create, Some(6 << 20), Duration::from_secs(3), 100);
+        assert!(line.starts_with("[##########----------]  50% 3.0 MiB/6.0 MiB  1.0 MiB/s  ETA 0:00:03"));
+        assert!(line.ends_with("path/file.bin"));
+        assert_eq!(line.chars().count(), 100);
+    }
+}
@@ -1,11 +1,15 @@
 //! Reflink support for copy-on-write file operations
 //!
 //! This module provides functionality to detect and use filesystem reflinks
-//! (also known as copy-on-write clones) to efficiently copy file data.
+//! (also known as copy-on-write clones) to efficiently copy file data, and a
+//! capability probe that reports what a filesystem can do for reftar.
 
-use anyhow::Result;
-use std::fs::File;
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::AsRawFd;
+use std::path::Path;
 
 /// Check if two files are on the same filesystem
 #[allow(dead_code)]
@@ -82,29 +86,327 @@ pub fn try_reflink_range(
     Ok(false)
 }
 
-/// Get the filesystem type of a file
+/// Attempt to deduplicate a range of identical bytes from src into dest
+/// Returns true if the kernel shared the range, false if dedupe is not
+/// supported or the ranges differ
 #[cfg(target_os = "linux")]
-pub fn get_filesystem_type(file: &File) -> Result<String> {
+pub fn try_dedupe_range(
+    src: &File,
+    src_offset: u64,
+    dest: &File,
+    dest_offset: u64,
+    length: u64,
+) -> Result<bool> {
+    use nix::libc::ioctl;
+
+    #[repr(C)]
+    struct FileDedupeRangeInfo {
+        dest_fd: i64,
+        dest_offset: u64,
+        bytes_deduped: u64,
+        status: i32,
+        reserved: u32,
+    }
+
+    #[repr(C)]
+    struct FileDedupeRange {
+        src_offset: u64,
+        src_length: u64,
+        dest_count: u16,
+        reserved1: u16,
+        reserved2: u32,
+        info: [FileDedupeRangeInfo; 1],
+    }
+
+    let mut range = FileDedupeRange {
+        src_offset,
+        src_length: length,
+        dest_count: 1,
+        reserved1: 0,
+        reserved2: 0,
+        info: [FileDedupeRangeInfo {
+            dest_fd: dest.as_raw_fd() as i64,
+            dest_offset,
+            bytes_deduped: 0,
+            status: 0,
+            reserved: 0,
+        }],
+    };
+
+    let result = unsafe {
+        ioctl(
+            src.as_raw_fd(),
+            0xC0189436, // FIDEDUPERANGE ioctl number
+            &mut range as *mut FileDedupeRange,
+        )
+    };
+
+    if result == 0 {
+        // status 0 is FILE_DEDUPE_RANGE_SAME; 1 means the data differed
+        let info = &range.info[0];
+        Ok(info.status == 0 && info.bytes_deduped == length)
+    } else {
+        let errno = nix::errno::Errno::last();
+        if errno == nix::errno::Errno::EOPNOTSUPP
+            || errno == nix::errno::Errno::ENOTTY
+            || errno == nix::errno::Errno::EINVAL
+        {
+            Ok(false)
+        } else {
+            Err(anyhow::anyhow!("FIDEDUPERANGE failed: {}", errno))
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn try_dedupe_range(
+    _src: &File,
+    _src_offset: u64,
+    _dest: &File,
+    _dest_offset: u64,
+    _length: u64,
+) -> Result<bool> {
+    Ok(false)
+}
+
+/// What a filesystem can do for reftar
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilesystemCapabilities {
+    /// Filesystem type name (e.g. "btrfs", "nfs4", "fuse.sshfs")
+    pub filesystem_type: String,
+    /// Raw magic number reported by statfs
+    pub filesystem_magic: u64,
+    /// Device ID of the filesystem
+    pub filesystem_id: u64,
+    /// Fundamental block size of the filesystem
+    pub block_size: u64,
+    /// FICLONERANGE works
+    pub reflink: bool,
+    /// FIDEDUPERANGE works
+    pub dedupe_range: bool,
+    /// User extended attributes can be set
+    pub xattr: bool,
+    /// Files can contain holes
+    pub sparse: bool,
+    /// Whether the capability flags were measured (probe) or inferred from
+    /// the filesystem type (inspect)
+    pub probed: bool,
+}
+
+impl FilesystemCapabilities {
+    /// Probe the filesystem holding `dir` by creating scratch files in it
+    ///
+    /// Every capability is tested for real: a clone of a temp file for
+    /// reflink, a dedupe of two identical files, setting a user xattr and
+    /// creating a file with a hole. The scratch files are removed again.
+    pub fn probe(dir: &Path) -> Result<Self> {
+        let anchor = File::open(dir)
+            .with_context(|| format!("Failed to open {:?} for probing", dir))?;
+        let mut caps = Self::inspect(&anchor)?;
+
+        let src_path = probe_path(dir, "src");
+        let dest_path = probe_path(dir, "dest");
+        let result = caps.run_probes(&src_path, &dest_path);
+        let _ = fs::remove_file(&src_path);
+        let _ = fs::remove_file(&dest_path);
+        result?;
+
+        caps.probed = true;
+        Ok(caps)
+    }
+
+    /// Describe the filesystem holding an open file without writing to it
+    ///
+    /// Capability flags are inferred from the filesystem type, so they are a
+    /// best guess; use [`FilesystemCapabilities::probe`] for a definitive
+    /// answer on a writable directory.
+    pub fn inspect(file: &File) -> Result<Self> {
+        let (filesystem_magic, block_size) = statfs_info(file)?;
+        let filesystem_id = get_filesystem_id(file)?;
+        let filesystem_type = filesystem_type_name(filesystem_magic, filesystem_id);
+        let (reflink, dedupe_range, xattr, sparse) = inferred_capabilities(&filesystem_type);
+
+        Ok(Self {
+            filesystem_type,
+            filesystem_magic,
+            filesystem_id,
+            block_size,
+            reflink,
+            dedupe_range,
+            xattr,
+            sparse,
+            probed: false,
+        })
+    }
+
+    fn run_probes(&mut self, src_path: &Path, dest_path: &Path) -> Result<()> {
+        let probe_len = self.block_size.clamp(512, 1024 * 1024);
+        let pattern: Vec<u8> = (0..probe_len).map(|i| (i % 251) as u8).collect();
+
+        let mut src = create_probe_file(src_path)?;
+        src.write_all(&pattern)?;
+        src.sync_all()?;
+
+        // Reflink: clone the source block into an empty file
+        let dest = create_probe_file(dest_path)?;
+        self.reflink = try_reflink_range(&src, 0, &dest, 0, probe_len).unwrap_or(false);
+
+        // Dedupe: write the same bytes by hand, then ask the kernel to share them
+        dest.set_len(0)?;
+        (&dest).write_all(&pattern)?;
+        dest.sync_all()?;
+        self.dedupe_range = try_dedupe_range(&src, 0, &dest, 0, probe_len).unwrap_or(false);
+
+        self.xattr = probe_xattr(&src);
+
+        // Sparse: extend a file far past its data and see if blocks were allocated
+        dest.set_len(0)?;
+        dest.set_len(probe_len * 64)?;
+        dest.sync_all()?;
+        let meta = dest.metadata()?;
+        self.sparse = meta.blocks() * 512 < meta.len();
+
+        Ok(())
+    }
+}
+
+fn probe_path(dir: &Path, tag: &str) -> std::path::PathBuf {
+    dir.join(format!(".reftar-probe-{}-{}", std::process::id(), tag))
+}
+
+fn create_probe_file(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .with_context(|| format!("Failed to create probe file {:?}", path))
+}
+
+#[cfg(target_os = "linux")]
+fn probe_xattr(file: &File) -> bool {
+    let name = b"user.reftar.probe\0";
+    let value = b"1";
+    let set = unsafe {
+        nix::libc::fsetxattr(
+            file.as_raw_fd(),
+            name.as_ptr() as *const nix::libc::c_char,
+            value.as_ptr() as *const nix::libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if set != 0 {
+        return false;
+    }
+    unsafe {
+        nix::libc::fremovexattr(file.as_raw_fd(), name.as_ptr() as *const nix::libc::c_char);
+    }
+    true
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe_xattr(_file: &File) -> bool {
+    false
+}
+
+#[cfg(target_os = "linux")]
+fn statfs_info(file: &File) -> Result<(u64, u64)> {
     use nix::sys::statfs::fstatfs;
 
     let stat = fstatfs(file)?;
+    Ok((stat.filesystem_type().0 as u32 as u64, stat.block_size() as u64))
+}
 
-    // Map filesystem type codes to names
-    let fs_type = match stat.filesystem_type().0 {
+#[cfg(not(target_os = "linux"))]
+fn statfs_info(file: &File) -> Result<(u64, u64)> {
+    Ok((0, file.metadata()?.blksize()))
+}
+
+/// Map a statfs magic number to a filesystem name
+///
+/// Magic numbers shared by several filesystems (ext2/3/4, nfs versions,
+/// fuse subtypes) are refined from the mount table when it is available.
+pub fn filesystem_type_name(magic: u64, filesystem_id: u64) -> String {
+    let name = match magic {
         0xEF53 => "ext4",
         0x58465342 => "xfs",
         0x9123683E => "btrfs",
         0x6969 => "nfs",
         0x01021994 => "tmpfs",
+        0x2FC12FC1 => "zfs",
+        0xCA451A4E => "bcachefs",
+        0xF2F52010 => "f2fs",
+        0x794C7630 => "overlayfs",
+        0x7461636F => "ocfs2",
+        0x65735546 => "fuse",
+        0x858458F6 => "ramfs",
+        0x4D44 => "vfat",
+        0x2011BAB0 => "exfat",
+        0x5346544E => "ntfs",
+        0x73717368 => "squashfs",
+        0xFF534D42 => "cifs",
+        0xFE534D42 => "smb2",
+        0x6165676C => "pstorefs",
+        0x9FA0 => "proc",
         _ => "unknown",
     };
 
-    Ok(fs_type.to_string())
+    let family = match name {
+        "ext4" => Some("ext"),
+        "nfs" => Some("nfs"),
+        "fuse" => Some("fuse"),
+        _ => None,
+    };
+    if let Some(family) = family {
+        if let Some(mounted) = mount_table_type(filesystem_id) {
+            if mounted.starts_with(family) {
+                return mounted;
+            }
+        }
+    }
+
+    name.to_string()
+}
+
+/// Look up the filesystem type of a device in /proc/self/mountinfo
+#[cfg(target_os = "linux")]
+fn mount_table_type(filesystem_id: u64) -> Option<String> {
+    use nix::sys::stat::{major, minor};
+
+    let wanted = format!("{}:{}", major(filesystem_id), minor(filesystem_id));
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+
+    mountinfo.lines().find_map(|line| {
+        let mut fields = line.split(' ');
+        if fields.nth(2)? != wanted {
+            return None;
+        }
+        // Optional fields end at the "-" separator; the fs type follows it
+        fields
+            .skip_while(|f| *f != "-")
+            .nth(1)
+            .map(|t| t.to_string())
+    })
 }
 
 #[cfg(not(target_os = "linux"))]
-pub fn get_filesystem_type(_file: &File) -> Result<String> {
-    Ok("unknown".to_string())
+fn mount_table_type(_filesystem_id: u64) -> Option<String> {
+    None
+}
+
+/// Best-guess (reflink, dedupe_range, xattr, sparse) for a filesystem type
+fn inferred_capabilities(filesystem_type: &str) -> (bool, bool, bool, bool) {
+    match filesystem_type {
+        "btrfs" | "bcachefs" => (true, true, true, true),
+        "xfs" => (true, true, true, true),
+        "ocfs2" => (true, false, true, true),
+        "zfs" => (false, false, true, true),
+        "ext2" | "ext3" | "ext4" | "f2fs" | "tmpfs" | "overlayfs" => (false, false, true, true),
+        "nfs4" => (false, false, true, true),
+        "vfat" | "exfat" | "squashfs" | "ramfs" => (false, false, false, false),
+        _ => (false, false, false, true),
+    }
 }
 
 /// Get filesystem device ID
@@ -118,7 +420,7 @@ pub fn get_filesystem_id(file: &File) -> Result<u64> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tempfile::NamedTempFile;
+    use tempfile::{NamedTempFile, TempDir};
 
     #[test]
     fn test_same_filesystem() {
@@ -130,10 +432,29 @@ mod tests {
     }
 
     #[test]
-    fn test_get_filesystem_type() {
+    fn test_inspect_filesystem() {
         let file = NamedTempFile::new().unwrap();
-        let fs_type = get_filesystem_type(file.as_file()).unwrap();
+        let caps = FilesystemCapabilities::inspect(file.as_file()).unwrap();
         // Should return some filesystem type
-        assert!(!fs_type.is_empty());
+        assert!(!caps.filesystem_type.is_empty());
+        assert!(!caps.probed);
+    }
+
+    #[test]
+    fn test_probe_cleans_up() {
+        let dir = TempDir::new().unwrap();
+        let caps = FilesystemCapabilities::probe(dir.path()).unwrap();
+
+        assert!(caps.probed);
+        assert!(caps.block_size > 0);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_filesystem_type_names() {
+        assert_eq!(filesystem_type_name(0x9123683E, 0), "btrfs");
+        assert_eq!(filesystem_type_name(0x2FC12FC1, 0), "zfs");
+        assert_eq!(filesystem_type_name(0xCA451A4E, 0), "bcachefs");
+        assert_eq!(filesystem_type_name(0x12345678, 0), "unknown");
     }
 }
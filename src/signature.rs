@@ -0,0 +1,513 @@
+//! Archive signatures
+//!
+//! A signed archive's footer holds a manifest: BLAKE3 hashes of every byte
+//! before the footer, cut at each member's file header, so each hash covers
+//! one member's header and extents. An Ed25519 signature over the footer,
+//! manifest included, then vouches for the whole archive. Keys are PEM
+//! files as written by `openssl genpkey -algorithm ed25519`.
+
+use crate::encryption::{ArchiveCipher, KeySource};
+use crate::format::*;
+use anyhow::{bail, Context, Result};
+use ed25519_dalek::pkcs8::{DecodePrivateKey, DecodePublicKey};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Bytes a `ManifestReader` keeps unhashed behind the read position, at
+/// least as many as any buffer between it and the archive parser holds
+const LOOKAHEAD: usize = 64 * 1024;
+
+/// Load an Ed25519 private key from a PKCS#8 PEM file
+pub fn load_signing_key(path: &Path) -> Result<SigningKey> {
+    let pem = fs::read_to_string(path).with_context(|| format!("Failed to read signing key {:?}", path))?;
+    SigningKey::from_pkcs8_pem(&pem)
+        .map_err(|e| anyhow::anyhow!("{:?} is not an Ed25519 private key in PEM format: {}", path, e))
+}
+
+/// Load an Ed25519 public key from a PEM file; the public half of a
+/// private key file works too
+pub fn load_verifying_key(path: &Path) -> Result<VerifyingKey> {
+    let pem = fs::read_to_string(path).with_context(|| format!("Failed to read public key {:?}", path))?;
+    if let Ok(key) = VerifyingKey::from_public_key_pem(&pem) {
+        return Ok(key);
+    }
+    match SigningKey::from_pkcs8_pem(&pem) {
+        Ok(key) => Ok(key.verifying_key()),
+        Err(_) => bail!("{:?} is not an Ed25519 public key in PEM format", path),
+    }
+}
+
+/// Lowercase hex of a public key, as shown to users
+pub fn key_hex(public_key: &[u8; 32]) -> String {
+    public_key.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Builds a manifest from the bytes of an archive, in order
+pub struct ManifestBuilder {
+    manifest: Manifest,
+    /// Header offset of the member being hashed, None before the first
+    member: Option<u64>,
+    hasher: blake3::Hasher,
+}
+
+impl ManifestBuilder {
+    pub fn new() -> Self {
+        Self {
+            manifest: Manifest::default(),
+            member: None,
+            hasher: blake3::Hasher::new(),
+        }
+    }
+
+    /// End the current hash; the bytes that follow belong to the member
+    /// whose file header starts at `offset`
+    pub fn start_member(&mut self, offset: u64) {
+        self.end_member();
+        self.member = Some(offset);
+    }
+
+    pub fn finish(mut self) -> Manifest {
+        self.end_member();
+        self.manifest
+    }
+
+    fn end_member(&mut self) {
+        let hash = *self.hasher.finalize().as_bytes();
+        self.hasher.reset();
+        match self.member {
+            Some(header_offset) => self.manifest.members.push(ManifestEntry { header_offset, hash }),
+            None => self.manifest.header_hash = hash,
+        }
+    }
+}
+
+impl Default for ManifestBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Write for ManifestBuilder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hash an archive up to `footer_offset`, cutting at `member_offsets`
+pub fn hash_archive<R: Read + Seek>(
+    reader: &mut R,
+    member_offsets: impl IntoIterator<Item = u64>,
+    footer_offset: u64,
+) -> Result<Manifest> {
+    reader.seek(SeekFrom::Start(0))?;
+    let mut builder = ManifestBuilder::new();
+    let mut position = 0;
+    for offset in member_offsets.into_iter().chain([footer_offset]) {
+        if offset < position || offset > footer_offset {
+            bail!("Member offset {} is out of order", offset);
+        }
+        let copied = io::copy(&mut reader.by_ref().take(offset - position), &mut builder)?;
+        if copied != offset - position {
+            bail!("Archive ends before offset {}", offset);
+        }
+        position = offset;
+        if offset < footer_offset {
+            builder.start_member(offset);
+        }
+    }
+    Ok(builder.finish())
+}
+
+/// Add a manifest to a footer and sign it
+pub fn sign_footer(footer: &mut ArchiveFooter, manifest: Manifest, key: &SigningKey) -> Result<()> {
+    footer.manifest = Some(manifest);
+    let signature = key.sign(&footer.signed_message()?);
+    footer.signature = Some(ArchiveSignature {
+        public_key: key.verifying_key().to_bytes(),
+        signature: signature.to_bytes(),
+    });
+    Ok(())
+}
+
+/// Check that a footer is signed by `key`, and return its manifest
+pub fn check_signature<'a>(footer: &'a ArchiveFooter, key: &VerifyingKey) -> Result<&'a Manifest> {
+    let signature = footer
+        .signature
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Archive is not signed"))?;
+    if signature.public_key != key.to_bytes() {
+        bail!("Archive is signed by a different key ({})", key_hex(&signature.public_key));
+    }
+    let manifest = footer
+        .manifest
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Archive signature has no manifest"))?;
+    key.verify_strict(&footer.signed_message()?, &Signature::from_bytes(&signature.signature))
+        .map_err(|_| anyhow::anyhow!("Archive signature is invalid"))?;
+    Ok(manifest)
+}
+
+/// Fail, naming each differing member, unless the archive's contents match
+/// its signed manifest
+pub fn compare_manifests(footer: &ArchiveFooter, signed: &Manifest, actual: &Manifest) -> Result<()> {
+    let mut problems = Vec::new();
+    if signed.header_hash != actual.header_hash {
+        problems.push("archive header".to_string());
+    }
+    for (expected, found) in signed.members.iter().zip(&actual.members) {
+        if expected.header_offset != found.header_offset {
+            problems.push(format!("members from offset {} on", expected.header_offset));
+            break;
+        }
+        if expected.hash != found.hash {
+            let path = footer
+                .members
+                .iter()
+                .find(|member| member.header_offset == expected.header_offset)
+                .map_or("?", |member| member.path.as_str());
+            problems.push(format!("{} (offset {})", path, expected.header_offset));
+        }
+    }
+    if signed.members.len() != actual.members.len() {
+        problems.push(format!(
+            "{} members signed, {} found",
+            signed.members.len(),
+            actual.members.len()
+        ));
+    }
+
+    if !problems.is_empty() {
+        bail!("Archive does not match its signature: {}", problems.join(", "));
+    }
+    Ok(())
+}
+
+/// Check the signature of a seekable archive before anything is extracted,
+/// returning its footer
+pub fn verify_archive<R: Read + Seek>(
+    reader: &mut R,
+    cipher: &ArchiveCipher,
+    key: &VerifyingKey,
+) -> Result<ArchiveFooter> {
+    let footer_offset = ArchiveFooter::locate_offset(reader)?
+        .ok_or_else(|| anyhow::anyhow!("Archive has no footer, so it is not signed"))?;
+    reader.seek(SeekFrom::Start(footer_offset))?;
+    let footer = cipher.read_footer(reader, footer_offset)?;
+    let signed = check_signature(&footer, key)?;
+
+    let offsets = signed.members.iter().map(|member| member.header_offset);
+    let actual = hash_archive(reader, offsets, footer_offset)?;
+    compare_manifests(&footer, signed, &actual)?;
+    Ok(footer)
+}
+
+/// Sign an uncompressed archive file in place, replacing its footer
+///
+/// Encrypted archives need `keys` to seal the new footer.
+pub fn sign_archive(file: &mut File, keys: &[KeySource], key: &SigningKey) -> Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    let header = ArchiveHeader::read(file)?;
+    let mut cipher = ArchiveCipher::new(&header)?;
+    if header.encryption.is_some() {
+        cipher.unlock(keys)?;
+        cipher.require_key()?;
+    }
+
+    let footer_offset = ArchiveFooter::locate_offset(file)?
+        .ok_or_else(|| anyhow::anyhow!("Archive has no footer index; only complete archives can be signed"))?;
+    file.seek(SeekFrom::Start(footer_offset))?;
+    let mut footer = cipher.read_footer(file, footer_offset)?;
+
+    let offsets: Vec<u64> = footer.members.iter().map(|member| member.header_offset).collect();
+    let manifest = hash_archive(file, offsets, footer_offset)?;
+    sign_footer(&mut footer, manifest, key)?;
+
+    file.seek(SeekFrom::Start(footer_offset))?;
+    let mut writer = BufWriter::new(&mut *file);
+    cipher.write_footer(&mut writer, &footer, footer_offset, header.block_size)?;
+    writer.flush()?;
+    drop(writer);
+    let end = file.stream_position()?;
+    file.set_len(end)?;
+    Ok(())
+}
+
+/// Reader that hashes an archive as it is read front to back, so that a
+/// stream's signature can be checked once its footer is reached
+///
+/// The owner marks where each record starts with `start_record`, and
+/// `confirm_member` once it turns out to be a file header; `finish` is
+/// called on reaching the footer. Hashing starts at offset 0 and stops for
+/// good at `stop_hashing`; until then only forward seeks work.
+pub struct ManifestReader<R> {
+    inner: R,
+    /// Archive offset of the next byte read from `inner`
+    position: u64,
+    check: Option<StreamCheck>,
+}
+
+struct StreamCheck {
+    builder: ManifestBuilder,
+    /// Bytes read but not hashed yet, starting at archive offset `pending_start`
+    pending: Vec<u8>,
+    pending_start: u64,
+    /// Start of the record being read, until it is known to be a member
+    record_start: Option<u64>,
+}
+
+impl StreamCheck {
+    /// Hash the pending bytes before archive offset `offset`
+    fn hash_to(&mut self, offset: u64) -> Result<()> {
+        let len = offset
+            .checked_sub(self.pending_start)
+            .filter(|len| *len <= self.pending.len() as u64)
+            .ok_or_else(|| anyhow::anyhow!("Lost track of the archive stream at offset {}", offset))?;
+        self.builder.hasher.update(&self.pending[..len as usize]);
+        self.pending.drain(..len as usize);
+        self.pending_start = offset;
+        Ok(())
+    }
+}
+
+impl<R> ManifestReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            position: 0,
+            check: Some(StreamCheck {
+                builder: ManifestBuilder::new(),
+                pending: Vec::new(),
+                pending_start: 0,
+                record_start: None,
+            }),
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// The reader underneath; reading from it directly bypasses the hash
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn stop_hashing(&mut self) {
+        self.check = None;
+    }
+
+    /// A member header or the footer starts at archive offset `offset`
+    pub fn start_record(&mut self, offset: u64) -> Result<()> {
+        if let Some(check) = &mut self.check {
+            check.hash_to(offset)?;
+            check.record_start = Some(offset);
+        }
+        Ok(())
+    }
+
+    /// The record last started is a member
+    pub fn confirm_member(&mut self) {
+        if let Some(check) = &mut self.check {
+            if let Some(offset) = check.record_start.take() {
+                check.builder.start_member(offset);
+            }
+        }
+    }
+
+    /// The record last started is the footer, or the end of the archive:
+    /// stop hashing, and return the manifest of what came before, the
+    /// footer offset and the rest of the archive
+    pub fn finish(&mut self) -> Result<(Manifest, u64, Vec<u8>)>
+    where
+        R: Read,
+    {
+        let check = self
+            .check
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("The archive signature can't be checked after seeking in the archive"))?;
+        let footer_offset = check
+            .record_start
+            .ok_or_else(|| anyhow::anyhow!("Archive stream ended inside a member"))?;
+        let mut rest = check.pending;
+        self.inner.read_to_end(&mut rest)?;
+        Ok((check.builder.finish(), footer_offset, rest))
+    }
+}
+
+impl<R: Read> Read for ManifestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.position += n as u64;
+        if let Some(check) = &mut self.check {
+            check.pending.extend_from_slice(&buf[..n]);
+            // Records are held whole until they turn out to be members
+            if check.record_start.is_none() && check.pending.len() > 2 * LOOKAHEAD {
+                let offset = self.position - LOOKAHEAD as u64;
+                check.hash_to(offset).map_err(io::Error::other)?;
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for ManifestReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        if self.check.is_none() {
+            self.position = self.inner.seek(pos)?;
+            return Ok(self.position);
+        }
+
+        // Skipped bytes must still be hashed
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(_) => None,
+        };
+        match target {
+            Some(target) if target >= self.position => {
+                let skip = target - self.position;
+                io::copy(&mut self.by_ref().take(skip), &mut io::sink())?;
+                Ok(self.position)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "an archive whose signature is being checked can only be read forwards",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::ArchiveCreator;
+    use crate::extract::ArchiveExtractor;
+    use std::fs;
+    use std::io::Cursor;
+    use tempfile::TempDir;
+
+    fn test_key(byte: u8) -> SigningKey {
+        SigningKey::from_bytes(&[byte; 32])
+    }
+
+    fn create_archive(temp_dir: &TempDir, signing_key: Option<SigningKey>) -> Vec<u8> {
+        let source = temp_dir.path().join("src");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("a.txt"), b"alpha").unwrap();
+        fs::write(source.join("b.bin"), vec![7u8; 20000]).unwrap();
+
+        let mut creator = ArchiveCreator::new(Vec::new(), None).unwrap();
+        if let Some(key) = signing_key {
+            creator = creator.with_signing_key(key);
+        }
+        creator.add_input(temp_dir.path(), Path::new("src")).unwrap();
+        creator.finish().unwrap()
+    }
+
+    #[test]
+    fn test_sign_in_place_and_verify() {
+        let temp_dir = TempDir::new().unwrap();
+        let archive_path = temp_dir.path().join("archive.reftar");
+        fs::write(&archive_path, create_archive(&temp_dir, None)).unwrap();
+        let cipher = ArchiveCipher::default();
+        let key = test_key(1);
+
+        let mut file = File::options().read(true).write(true).open(&archive_path).unwrap();
+        let error = verify_archive(&mut file, &cipher, &key.verifying_key()).unwrap_err();
+        assert_eq!(error.to_string(), "Archive is not signed");
+
+        sign_archive(&mut file, &[], &key).unwrap();
+        let footer = verify_archive(&mut file, &cipher, &key.verifying_key()).unwrap();
+        assert_eq!(footer.manifest.unwrap().members.len(), footer.members.len());
+        assert!(verify_archive(&mut file, &cipher, &test_key(2).verifying_key()).is_err());
+
+        // Signing again replaces the signature without growing the archive
+        let len = fs::metadata(&archive_path).unwrap().len();
+        sign_archive(&mut file, &[], &test_key(2)).unwrap();
+        assert_eq!(fs::metadata(&archive_path).unwrap().len(), len);
+        verify_archive(&mut file, &cipher, &test_key(2).verifying_key()).unwrap();
+
+        // Flip a byte of the second block, the first member's header
+        let mut archive = fs::read(&archive_path).unwrap();
+        archive[4096 + 40] ^= 1;
+        let error = verify_archive(&mut Cursor::new(archive), &cipher, &test_key(2).verifying_key()).unwrap_err();
+        assert!(error.to_string().contains("(offset 4096)"), "{}", error);
+    }
+
+    #[test]
+    fn test_extraction_requires_signature() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = test_key(3);
+        let archive = create_archive(&temp_dir, Some(test_key(3)));
+        let output = temp_dir.path().join("out");
+        let extract = |archive: Vec<u8>| {
+            ArchiveExtractor::new(Cursor::new(archive), output.clone())
+                .unwrap()
+                .with_signature_key(key.verifying_key())
+                .extract_all()
+        };
+        let extracted = || fs::read_dir(&output).map_or(0, |entries| entries.count());
+
+        // Nothing of an unsigned or altered archive is extracted
+        let unsigned = create_archive(&temp_dir, None);
+        assert_eq!(extract(unsigned).unwrap_err().to_string(), "Archive is not signed");
+        assert_eq!(extracted(), 0);
+
+        // Change file data the signature covers, rewriting its header so
+        // the header checksums still match, as a deliberate change would
+        let mut altered = archive.clone();
+        let offset = altered.windows(5).position(|w| w == b"alpha").unwrap();
+        let start = offset - offset % 4096;
+        let mut header = FileHeader::read(&mut &altered[start..], 4096, true).unwrap();
+        header.inline_data[0] = b'A';
+        let mut rewritten = Vec::new();
+        header.write(&mut rewritten, 4096).unwrap();
+        altered[start..start + 4096].copy_from_slice(&rewritten);
+        let error = extract(altered.clone()).unwrap_err().to_string();
+        assert!(error.starts_with("Archive does not match its signature: src/a.txt"), "{}", error);
+        assert_eq!(extracted(), 0);
+
+        // The footer of an archive that can only be read front to back
+        // comes after its members: they are extracted under temporary
+        // names, and removed if the signature at the footer doesn't match
+        let extract_stream = |archive: Vec<u8>| {
+            let stream = crate::stream::ForwardReader::new(Cursor::new(archive));
+            ArchiveExtractor::new(stream, output.clone())
+                .unwrap()
+                .with_signature_key(key.verifying_key())
+                .extract_all()
+        };
+        let error = extract_stream(altered).unwrap_err().to_string();
+        assert!(error.starts_with("Archive does not match its signature: src/a.txt"), "{}", error);
+        assert_eq!(extracted(), 0);
+        let unsigned = create_archive(&temp_dir, None);
+        assert_eq!(extract_stream(unsigned).unwrap_err().to_string(), "Archive is not signed");
+        assert_eq!(extracted(), 0);
+        let mut cut = archive.clone();
+        cut.truncate(cut.len() - 100);
+        assert!(extract_stream(cut).is_err());
+        assert_eq!(extracted(), 0);
+
+        // Files already there stay until a signed stream replaces them
+        fs::create_dir_all(output.join("src")).unwrap();
+        fs::write(output.join("src/a.txt"), b"old").unwrap();
+        let unsigned = create_archive(&temp_dir, None);
+        assert!(extract_stream(unsigned).is_err());
+        assert_eq!(fs::read(output.join("src/a.txt")).unwrap(), b"old");
+        assert_eq!(fs::read_dir(output.join("src")).unwrap().count(), 1);
+        extract_stream(archive.clone()).unwrap();
+        assert_eq!(fs::read(output.join("src/b.bin")).unwrap(), vec![7u8; 20000]);
+        assert_ne!(fs::read(output.join("src/a.txt")).unwrap(), b"old");
+        fs::remove_dir_all(&output).unwrap();
+
+        extract(archive).unwrap();
+        assert_eq!(fs::read(output.join("src/b.bin")).unwrap(), vec![7u8; 20000]);
+    }
+}
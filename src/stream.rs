@@ -0,0 +1,580 @@
+//! Whole-stream compression (`create -z/-J/--zstd`)
+//!
+//! A compressed archive is an ordinary archive passed through gzip, xz or
+//! zstd. Readers detect the compression from the first bytes, so `extract`
+//! and `list` need no flag. Gzip and xz streams can only be read front to
+//! back, which the sequential extraction path needs; zstd archives are
+//! written in the zstd seekable format, whose seek table gives random access
+//! and so keeps the footer index reachable.
+
+use crate::compression::DEFAULT_ZSTD_LEVEL;
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Uncompressed bytes per frame of a seekable zstd archive
+const ZSTD_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Largest compressed frame a seek table may declare: zstd's worst case for
+/// `ZSTD_FRAME_SIZE` bytes (`ZSTD_compressBound`), with room for headers
+const ZSTD_FRAME_BOUND: usize = ZSTD_FRAME_SIZE + ZSTD_FRAME_SIZE / 256 + 1024;
+
+/// Skippable frame magic number holding the seek table
+const ZSTD_SKIPPABLE_MAGIC: u32 = 0x184D2A5E;
+
+/// Magic number ending a seek table
+const ZSTD_SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+
+/// Number of frames, descriptor and seekable magic
+const SEEK_TABLE_FOOTER_SIZE: u64 = 9;
+
+/// Bytes sniffed to detect the compression of an archive
+const MAGIC_LEN: usize = 6;
+
+/// Compression wrapping a whole archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamCompression {
+    #[default]
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl StreamCompression {
+    /// Identify the compression from the first bytes of a stream
+    pub fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1F, 0x8B]) {
+            Self::Gzip
+        } else if magic.starts_with(&[0xFD, b'7', b'z', b'X', b'Z', 0x00]) {
+            Self::Xz
+        } else if magic.starts_with(&[0x28, 0xB5, 0x2F, 0xFD]) {
+            Self::Zstd
+        } else {
+            Self::None
+        }
+    }
+
+    /// Name shown to users
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Gzip => "gzip",
+            Self::Xz => "xz",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+/// Writer compressing an archive as it is written
+pub enum StreamWriter<W: Write> {
+    Plain(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Xz(xz2::write::XzEncoder<W>),
+    Zstd(SeekableZstdWriter<W>),
+}
+
+impl<W: Write> StreamWriter<W> {
+    pub fn new(writer: W, compression: StreamCompression) -> Self {
+        match compression {
+            StreamCompression::None => Self::Plain(writer),
+            StreamCompression::Gzip => Self::Gzip(flate2::write::GzEncoder::new(writer, flate2::Compression::default())),
+            StreamCompression::Xz => Self::Xz(xz2::write::XzEncoder::new(writer, 6)),
+            StreamCompression::Zstd => Self::Zstd(SeekableZstdWriter::new(writer, DEFAULT_ZSTD_LEVEL)),
+        }
+    }
+
+    /// Write the end of the compressed stream and return the writer
+    pub fn finish(self) -> Result<W> {
+        let mut writer = match self {
+            Self::Plain(writer) => writer,
+            Self::Gzip(encoder) => encoder.finish()?,
+            Self::Xz(encoder) => encoder.finish()?,
+            Self::Zstd(encoder) => encoder.finish()?,
+        };
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+impl<W: Write> Write for StreamWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(writer) => writer.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Xz(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(writer) => writer.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Xz(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Writer producing the zstd seekable format: independent frames of
+/// `ZSTD_FRAME_SIZE` bytes, then a skippable frame with their sizes
+///
+/// The result is a valid zstd stream, so `zstd -d` decompresses it.
+pub struct SeekableZstdWriter<W: Write> {
+    inner: W,
+    level: i32,
+    frame: Vec<u8>,
+    frames: Vec<(u32, u32)>, // Compressed and decompressed size of each frame
+}
+
+impl<W: Write> SeekableZstdWriter<W> {
+    pub fn new(inner: W, level: i32) -> Self {
+        Self {
+            inner,
+            level,
+            frame: Vec::with_capacity(ZSTD_FRAME_SIZE),
+            frames: Vec::new(),
+        }
+    }
+
+    fn write_frame(&mut self) -> io::Result<()> {
+        if self.frame.is_empty() {
+            return Ok(());
+        }
+        let compressed = zstd::bulk::compress(&self.frame, self.level)?;
+        self.inner.write_all(&compressed)?;
+        self.frames.push((compressed.len() as u32, self.frame.len() as u32));
+        self.frame.clear();
+        Ok(())
+    }
+
+    /// Write the last frame and the seek table, returning the writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_frame()?;
+
+        let mut table = Vec::with_capacity(self.frames.len() * 8 + 17);
+        table.extend_from_slice(&ZSTD_SKIPPABLE_MAGIC.to_le_bytes());
+        let frame_size = self.frames.len() as u64 * 8 + SEEK_TABLE_FOOTER_SIZE;
+        table.extend_from_slice(&(frame_size as u32).to_le_bytes());
+        for (compressed, decompressed) in &self.frames {
+            table.extend_from_slice(&compressed.to_le_bytes());
+            table.extend_from_slice(&decompressed.to_le_bytes());
+        }
+        table.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        table.push(0); // Descriptor: no checksums
+        table.extend_from_slice(&ZSTD_SEEKABLE_MAGIC.to_le_bytes());
+        self.inner.write_all(&table)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for SeekableZstdWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(ZSTD_FRAME_SIZE - self.frame.len());
+        self.frame.extend_from_slice(&buf[..n]);
+        if self.frame.len() == ZSTD_FRAME_SIZE {
+            self.write_frame()?;
+        }
+        Ok(n)
+    }
+
+    /// Flushes the frames written so far; the current frame stays open, so
+    /// flushing does not shorten frames
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A frame of a seekable zstd stream
+#[derive(Debug, Clone, Copy)]
+struct ZstdFrame {
+    compressed_offset: u64,
+    compressed_size: u32,
+    decompressed_offset: u64,
+    decompressed_size: u32,
+}
+
+/// Read the seek table at the end of a zstd stream, or `None` if it has none
+fn read_seek_table<R: Read + Seek>(reader: &mut R) -> Result<Option<Vec<ZstdFrame>>> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    if len < SEEK_TABLE_FOOTER_SIZE + 8 {
+        return Ok(None);
+    }
+
+    reader.seek(SeekFrom::End(-(SEEK_TABLE_FOOTER_SIZE as i64)))?;
+    let mut footer = [0u8; SEEK_TABLE_FOOTER_SIZE as usize];
+    reader.read_exact(&mut footer)?;
+    let frame_count = u32::from_le_bytes(footer[0..4].try_into().unwrap()) as u64;
+    let descriptor = footer[4];
+    if u32::from_le_bytes(footer[5..9].try_into().unwrap()) != ZSTD_SEEKABLE_MAGIC {
+        return Ok(None);
+    }
+    if descriptor & 0x7C != 0 {
+        bail!("zstd seek table uses reserved descriptor bits");
+    }
+    let entry_size = if descriptor & 0x80 != 0 { 12 } else { 8 };
+
+    let table_size = 8 + frame_count * entry_size + SEEK_TABLE_FOOTER_SIZE;
+    if table_size > len {
+        bail!("zstd seek table is larger than the archive");
+    }
+    reader.seek(SeekFrom::End(-(table_size as i64)))?;
+    let mut table = vec![0u8; (table_size - SEEK_TABLE_FOOTER_SIZE) as usize];
+    reader.read_exact(&mut table)?;
+    let word = |offset: usize| u32::from_le_bytes(table[offset..offset + 4].try_into().unwrap());
+    if word(0) != ZSTD_SKIPPABLE_MAGIC || word(4) as u64 != table_size - 8 {
+        bail!("zstd seek table is not a valid skippable frame");
+    }
+
+    // Frames are read into buffers of the sizes given here, so sizes no
+    // writer of this format produces are refused before anything is read
+    let mut frames = Vec::with_capacity(frame_count as usize);
+    let (mut compressed_offset, mut decompressed_offset) = (0u64, 0u64);
+    for i in 0..frame_count as usize {
+        let entry = 8 + i * entry_size as usize;
+        let frame = ZstdFrame {
+            compressed_offset,
+            compressed_size: word(entry),
+            decompressed_offset,
+            decompressed_size: word(entry + 4),
+        };
+        if frame.decompressed_size as usize > ZSTD_FRAME_SIZE || frame.compressed_size as usize > ZSTD_FRAME_BOUND {
+            bail!("zstd seek table gives frame {} a size over the {} byte frame limit", i, ZSTD_FRAME_SIZE);
+        }
+        compressed_offset += frame.compressed_size as u64;
+        if compressed_offset > len - table_size {
+            bail!("zstd seek table does not match the frames before it");
+        }
+        decompressed_offset += frame.decompressed_size as u64;
+        frames.push(frame);
+    }
+    if compressed_offset != len - table_size {
+        bail!("zstd seek table does not match the frames before it");
+    }
+
+    reader.seek(SeekFrom::Start(0))?;
+    Ok(Some(frames))
+}
+
+/// Random-access reader over a seekable zstd stream, decompressing one
+/// frame at a time
+pub struct SeekableZstdReader<R: Read + Seek> {
+    inner: R,
+    frames: Vec<ZstdFrame>,
+    length: u64,
+    position: u64,
+    current: Option<usize>, // Index of the frame held in `data`
+    data: Vec<u8>,
+}
+
+impl<R: Read + Seek> SeekableZstdReader<R> {
+    /// Reader over the frames of a seek table read by `read_seek_table`
+    fn with_frames(inner: R, frames: Vec<ZstdFrame>) -> Self {
+        let length = frames
+            .last()
+            .map(|frame| frame.decompressed_offset + frame.decompressed_size as u64)
+            .unwrap_or(0);
+        Self {
+            inner,
+            frames,
+            length,
+            position: 0,
+            current: None,
+            data: Vec::new(),
+        }
+    }
+
+    fn load_frame(&mut self, index: usize) -> io::Result<()> {
+        let frame = self.frames[index];
+        let mut compressed = vec![0u8; frame.compressed_size as usize];
+        self.inner.seek(SeekFrom::Start(frame.compressed_offset))?;
+        self.inner.read_exact(&mut compressed)?;
+        self.data = zstd::bulk::decompress(&compressed, frame.decompressed_size as usize)?;
+        if self.data.len() != frame.decompressed_size as usize {
+            self.current = None;
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "zstd frame size differs from its seek table entry"));
+        }
+        self.current = Some(index);
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for SeekableZstdReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.length || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self
+            .frames
+            .partition_point(|frame| frame.decompressed_offset + frame.decompressed_size as u64 <= self.position);
+        if self.current != Some(index) {
+            self.load_frame(index)?;
+        }
+
+        let start = (self.position - self.frames[index].decompressed_offset) as usize;
+        let n = buf.len().min(self.data.len() - start);
+        buf[..n].copy_from_slice(&self.data[start..start + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for SeekableZstdReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+        };
+        match target {
+            Some(target) => {
+                self.position = target;
+                Ok(target)
+            }
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the archive")),
+        }
+    }
+}
+
+/// Reader over a stream that can only be read front to back; seeking
+/// forwards skips data and seeking backwards fails
+pub struct ForwardReader<R: Read> {
+    inner: R,
+    position: u64,
+}
+
+impl<R: Read> ForwardReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self { inner, position: 0 }
+    }
+}
+
+impl<R: Read> Read for ForwardReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read> Seek for ForwardReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(_) => None,
+        };
+        match target {
+            Some(target) if target >= self.position => {
+                let skipped = io::copy(&mut (&mut self.inner).take(target - self.position), &mut io::sink())?;
+                self.position += skipped;
+                Ok(self.position)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, "archive stream can only be read forwards")),
+        }
+    }
+}
+
+/// An archive opened for reading, decompressed if it was written with
+/// `-z`, `-J` or `--zstd`
+pub enum ArchiveSource {
+    /// Uncompressed archive file
+    File(File),
+    /// Seekable zstd archive file
+    Seekable(SeekableZstdReader<File>),
+    /// Compressed archive or standard input, read front to back
+    Stream {
+        compression: StreamCompression,
+        reader: ForwardReader<Box<dyn Read>>,
+    },
+}
+
+impl ArchiveSource {
+    /// Open an archive file, or standard input for `-`, detecting its
+    /// compression
+    pub fn open(path: &Path) -> Result<Self> {
+        if path == Path::new("-") {
+            return Self::stream(Box::new(io::stdin()));
+        }
+
+        let mut file = File::open(path).with_context(|| format!("Failed to open archive file: {:?}", path))?;
+        let (magic, n) = read_magic(&mut file)?;
+        file.seek(SeekFrom::Start(0))?;
+        match StreamCompression::detect(&magic[..n]) {
+            StreamCompression::None => Ok(Self::File(file)),
+            StreamCompression::Zstd => match read_seek_table(&mut file)? {
+                Some(frames) => Ok(Self::Seekable(SeekableZstdReader::with_frames(file, frames))),
+                None => Self::stream(Box::new(file)),
+            },
+            _ => Self::stream(Box::new(file)),
+        }
+    }
+
+    /// Detect the compression of a stream and decompress it
+    fn stream(mut reader: Box<dyn Read>) -> Result<Self> {
+        let (magic, n) = read_magic(&mut reader)?;
+        let compression = StreamCompression::detect(&magic[..n]);
+        let reader: Box<dyn Read> = Box::new(Cursor::new(magic[..n].to_vec()).chain(reader));
+        let reader: Box<dyn Read> = match compression {
+            StreamCompression::None => reader,
+            StreamCompression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(io::BufReader::new(reader))),
+            StreamCompression::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(io::BufReader::new(reader))),
+            StreamCompression::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+        };
+        Ok(Self::Stream {
+            compression,
+            reader: ForwardReader::new(reader),
+        })
+    }
+
+    /// Compression the archive was written with
+    pub fn compression(&self) -> StreamCompression {
+        match self {
+            Self::File(_) => StreamCompression::None,
+            Self::Seekable(_) => StreamCompression::Zstd,
+            Self::Stream { compression, .. } => *compression,
+        }
+    }
+
+    /// Whether the archive can be read out of order, as reading the footer
+    /// index needs
+    pub fn random_access(&self) -> bool {
+        !matches!(self, Self::Stream { .. })
+    }
+}
+
+impl Read for ArchiveSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::File(file) => file.read(buf),
+            Self::Seekable(reader) => reader.read(buf),
+            Self::Stream { reader, .. } => reader.read(buf),
+        }
+    }
+}
+
+impl Seek for ArchiveSource {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Self::File(file) => file.seek(pos),
+            Self::Seekable(reader) => reader.seek(pos),
+            Self::Stream { reader, .. } => reader.seek(pos),
+        }
+    }
+}
+
+/// Read up to `MAGIC_LEN` bytes, fewer only at the end of the stream
+fn read_magic<R: Read + ?Sized>(reader: &mut R) -> Result<([u8; MAGIC_LEN], usize)> {
+    let mut magic = [0u8; MAGIC_LEN];
+    let mut n = 0;
+    while n < MAGIC_LEN {
+        match reader.read(&mut magic[n..])? {
+            0 => break,
+            read => n += read,
+        }
+    }
+    Ok((magic, n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create::ArchiveCreator;
+    use crate::extract::ArchiveExtractor;
+    use crate::format::ArchiveFooter;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_seekable_zstd_roundtrip() {
+        let data: Vec<u8> = (0..ZSTD_FRAME_SIZE * 5 / 2).map(|i| (i / 3 % 251) as u8).collect();
+        let mut writer = SeekableZstdWriter::new(Vec::new(), 3);
+        writer.write_all(&data).unwrap();
+        let compressed = writer.finish().unwrap();
+
+        // Plain zstd decoders skip the seek table
+        assert_eq!(zstd::decode_all(&compressed[..]).unwrap(), data);
+
+        let mut compressed = Cursor::new(compressed);
+        let frames = read_seek_table(&mut compressed).unwrap().unwrap();
+        let mut reader = SeekableZstdReader::with_frames(compressed, frames);
+        assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), data.len() as u64);
+        let offset = ZSTD_FRAME_SIZE as u64 * 2 - 10;
+        reader.seek(SeekFrom::Start(offset)).unwrap();
+        let mut across = [0u8; 20];
+        reader.read_exact(&mut across).unwrap();
+        assert_eq!(&across[..], &data[offset as usize..offset as usize + 20]);
+        reader.seek(SeekFrom::Start(5)).unwrap();
+        let mut start = [0u8; 5];
+        reader.read_exact(&mut start).unwrap();
+        assert_eq!(&start[..], &data[5..10]);
+    }
+
+    #[test]
+    fn test_seek_table_sizes_are_bounded() {
+        let mut writer = SeekableZstdWriter::new(Vec::new(), 3);
+        writer.write_all(&vec![1u8; ZSTD_FRAME_SIZE + 10]).unwrap();
+        let compressed = writer.finish().unwrap();
+        let table = compressed.len() - SEEK_TABLE_FOOTER_SIZE as usize - 2 * 8;
+
+        // Frame sizes over the limit are refused before a frame is read
+        for entry in [table, table + 4] {
+            let mut damaged = compressed.clone();
+            damaged[entry..entry + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+            let error = read_seek_table(&mut Cursor::new(damaged)).unwrap_err();
+            assert!(error.to_string().contains("frame limit"), "{}", error);
+        }
+
+        // So are compressed sizes beyond the end of the stream
+        let mut damaged = compressed.clone();
+        damaged[table..table + 4].copy_from_slice(&(ZSTD_FRAME_BOUND as u32).to_le_bytes());
+        let error = read_seek_table(&mut Cursor::new(damaged)).unwrap_err();
+        assert_eq!(error.to_string(), "zstd seek table does not match the frames before it");
+    }
+
+    #[test]
+    fn test_forward_reader_only_skips_forwards() {
+        let mut reader = ForwardReader::new(&b"0123456789"[..]);
+        assert_eq!(reader.seek(SeekFrom::Current(3)).unwrap(), 3);
+        let mut byte = [0u8; 1];
+        reader.read_exact(&mut byte).unwrap();
+        assert_eq!(&byte, b"3");
+        assert_eq!(reader.stream_position().unwrap(), 4);
+        assert!(reader.seek(SeekFrom::Start(0)).is_err());
+        assert!(reader.seek(SeekFrom::End(0)).is_err());
+    }
+
+    #[test]
+    fn test_compressed_archives_are_detected() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("source");
+        std::fs::create_dir(&source).unwrap();
+        std::fs::write(source.join("small.txt"), b"hello").unwrap();
+        std::fs::write(source.join("large.bin"), vec![7u8; 100_000]).unwrap();
+
+        for compression in [StreamCompression::Gzip, StreamCompression::Xz, StreamCompression::Zstd] {
+            let archive_path = temp_dir.path().join(format!("archive.{}", compression.name()));
+            let writer = StreamWriter::new(File::create(&archive_path).unwrap(), compression);
+            let mut creator = ArchiveCreator::new(writer, None).unwrap();
+            creator.add_input(temp_dir.path(), Path::new("source")).unwrap();
+            creator.finish().unwrap().finish().unwrap();
+
+            let mut archive = ArchiveSource::open(&archive_path).unwrap();
+            assert_eq!(archive.compression(), compression);
+            assert_eq!(archive.random_access(), compression == StreamCompression::Zstd);
+            if archive.random_access() {
+                crate::format::ArchiveHeader::read(&mut archive).unwrap();
+                let footer = ArchiveFooter::locate(&mut archive).unwrap().unwrap();
+                assert_eq!(footer.members.len(), 3);
+                archive.seek(SeekFrom::Start(0)).unwrap();
+            }
+
+            let output = temp_dir.path().join(format!("output.{}", compression.name()));
+            let mut extractor = ArchiveExtractor::new(archive, output.clone()).unwrap();
+            extractor.extract_all().unwrap();
+            assert_eq!(std::fs::read(output.join("source/small.txt")).unwrap(), b"hello");
+            assert_eq!(std::fs::read(output.join("source/large.bin")).unwrap(), vec![7u8; 100_000]);
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! Helpers shared by the unit tests
+
+use crate::create::ArchiveCreator;
+use crate::extract::ArchiveExtractor;
+use crate::format::{FileHeader, FileType, TimeNanos};
+use std::io::Cursor;
+use std::path::Path;
+
+/// `len` bytes of file contents with no zeros, which would be stored as
+/// holes, and no repeats within a block
+pub fn test_data(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i % 251) as u8 + 1).collect()
+}
+
+/// An archive in memory of the entries `names` under `dir`, in that order
+pub fn archive_of(dir: &Path, names: &[&str]) -> Vec<u8> {
+    let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None).unwrap();
+    for name in names {
+        creator.add_file(&dir.join(name), Path::new(name)).unwrap();
+    }
+    creator.finish().unwrap().into_inner()
+}
+
+/// An archive in memory converted from a tar archive
+pub fn archive_from_tar(tar: &[u8]) -> Vec<u8> {
+    let mut creator = ArchiveCreator::new(Cursor::new(Vec::new()), None).unwrap();
+    crate::convert::import_tar(tar, &mut creator, |_| {}).unwrap();
+    creator.finish().unwrap().into_inner()
+}
+
+/// A tar header for a member of `size` bytes, owned by uid and gid 1000
+pub fn tar_header(mut header: tar::Header, entry_type: tar::EntryType, mode: u32, size: usize) -> tar::Header {
+    header.set_entry_type(entry_type);
+    header.set_mode(mode);
+    header.set_size(size as u64);
+    header.set_uid(1000);
+    header.set_gid(1000);
+    header.set_mtime(0);
+    header
+}
+
+/// The header of a hard link member at `path` to the member `target`
+pub fn hard_link_header(path: &str, target: &str) -> FileHeader {
+    let (file_path, file_name) = path.rsplit_once('/').unwrap_or(("", path));
+    FileHeader {
+        file_size: 0,
+        file_type: FileType::HardLink,
+        uid: 0,
+        gid: 0,
+        device_major: 0,
+        device_minor: 0,
+        access_time: 0,
+        modify_time: 0,
+        creation_time: 0,
+        username: String::new(),
+        groupname: String::new(),
+        file_path: file_path.to_string(),
+        file_name: file_name.to_string(),
+        link_name: target.to_string(),
+        extended_permissions: Vec::new(),
+        source_filesystem_type: String::new(),
+        source_filesystem_id: 0,
+        mode: None,
+        directory_listing: None,
+        xattrs: Vec::new(),
+        time_nanos: TimeNanos::default(),
+        data_seal: None,
+        inline_data: Vec::new(),
+    }
+}
+
+/// An extractor of an archive in memory into `output_dir`
+pub fn extractor_for(archive: &[u8], output_dir: &Path) -> ArchiveExtractor<Cursor<Vec<u8>>> {
+    ArchiveExtractor::new(Cursor::new(archive.to_vec()), output_dir.to_path_buf()).unwrap()
+}
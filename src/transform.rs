@@ -0,0 +1,208 @@
+//! Member path normalization and rewriting (`--transform`, `--strip-components`)
+
+use anyhow::{bail, Context, Result};
+use regex::{Regex, RegexBuilder};
+use std::path::{Component, Path, PathBuf};
+
+/// Turn an input path into a member path the way tar does: relative as
+/// given, with the root, `.` components and any leading `..` removed
+pub fn normalize_member_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir if normalized.as_os_str().is_empty() => {}
+            Component::ParentDir => normalized.push(".."),
+            Component::RootDir | Component::Prefix(_) | Component::CurDir => {}
+        }
+    }
+    normalized
+}
+
+/// Whether a member path could escape the extraction directory
+pub fn is_unsafe_member_path(path: &Path) -> bool {
+    path.components().any(|c| c == Component::ParentDir)
+}
+
+/// A sed-style `s/regex/replacement/flags` expression
+#[derive(Debug, Clone)]
+struct Substitution {
+    regex: Regex,
+    replacement: String, // In `regex` crate syntax
+    global: bool,
+}
+
+impl Substitution {
+    fn parse(expression: &str) -> Result<Self> {
+        let mut chars = expression.chars();
+        if chars.next() != Some('s') {
+            bail!("Transform {:?} must start with 's'", expression);
+        }
+        let delimiter = chars
+            .next()
+            .with_context(|| format!("Transform {:?} has no delimiter", expression))?;
+
+        // Split on unescaped delimiters
+        let mut parts = vec![String::new()];
+        while let Some(c) = chars.next() {
+            if c == '\\' {
+                match chars.next() {
+                    Some(next) if next == delimiter => parts.last_mut().unwrap().push(next),
+                    Some(next) => {
+                        parts.last_mut().unwrap().push('\\');
+                        parts.last_mut().unwrap().push(next);
+                    }
+                    None => parts.last_mut().unwrap().push('\\'),
+                }
+            } else if c == delimiter {
+                parts.push(String::new());
+            } else {
+                parts.last_mut().unwrap().push(c);
+            }
+        }
+        if parts.len() != 3 {
+            bail!("Transform {:?} is not of the form s/regex/replacement/flags", expression);
+        }
+
+        let mut global = false;
+        let mut case_insensitive = false;
+        for flag in parts[2].chars() {
+            match flag {
+                'g' => global = true,
+                'i' => case_insensitive = true,
+                'x' => {} // Expressions are always extended
+                _ => bail!("Unsupported transform flag {:?} in {:?}", flag, expression),
+            }
+        }
+
+        let regex = RegexBuilder::new(&parts[0])
+            .case_insensitive(case_insensitive)
+            .build()
+            .with_context(|| format!("Invalid regex in transform {:?}", expression))?;
+
+        Ok(Self {
+            regex,
+            replacement: convert_replacement(&parts[1]),
+            global,
+        })
+    }
+
+    fn apply(&self, path: &str) -> String {
+        if self.global {
+            self.regex.replace_all(path, self.replacement.as_str()).into_owned()
+        } else {
+            self.regex.replace(path, self.replacement.as_str()).into_owned()
+        }
+    }
+}
+
+/// Convert sed replacement syntax (`&`, `\1`) to `regex` syntax
+fn convert_replacement(replacement: &str) -> String {
+    let mut converted = String::new();
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => converted.push_str("${0}"),
+            '$' => converted.push_str("$$"),
+            '\\' => match chars.next() {
+                Some(digit) if digit.is_ascii_digit() => {
+                    converted.push_str(&format!("${{{}}}", digit));
+                }
+                Some('$') => converted.push_str("$$"),
+                Some(other) => converted.push(other),
+                None => converted.push('\\'),
+            },
+            _ => converted.push(c),
+        }
+    }
+    converted
+}
+
+/// Rewrites member paths on creation or extraction
+///
+/// Transforms run first, in the order given, then leading components are
+/// stripped. A member left with no components is skipped.
+#[derive(Debug, Clone, Default)]
+pub struct PathRewrite {
+    substitutions: Vec<Substitution>,
+    strip_components: usize,
+}
+
+impl PathRewrite {
+    /// A rewrite that leaves paths unchanged
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a sed-style `s/regex/replacement/[gi]` expression
+    ///
+    /// The regex uses extended syntax; `&` and `\1`..`\9` in the
+    /// replacement refer to the match and its groups.
+    pub fn transform(mut self, expression: &str) -> Result<Self> {
+        self.substitutions.push(Substitution::parse(expression)?);
+        Ok(self)
+    }
+
+    /// Remove this many leading path components
+    pub fn strip_components(mut self, count: usize) -> Self {
+        self.strip_components = count;
+        self
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.substitutions.is_empty() && self.strip_components == 0
+    }
+
+    /// Rewrite a member path, or return `None` if the member should be skipped
+    pub fn apply(&self, path: &Path) -> Option<PathBuf> {
+        if self.is_identity() {
+            return Some(path.to_path_buf());
+        }
+
+        let mut rewritten = path.to_string_lossy().into_owned();
+        for substitution in &self.substitutions {
+            rewritten = substitution.apply(&rewritten);
+        }
+
+        let components: Vec<&str> = rewritten.split('/').filter(|c| !c.is_empty()).collect();
+        if components.len() <= self.strip_components {
+            return None;
+        }
+        Some(components[self.strip_components..].iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_member_path() {
+        assert_eq!(normalize_member_path(Path::new("a/b/c.txt")), Path::new("a/b/c.txt"));
+        assert_eq!(normalize_member_path(Path::new("/etc/hosts")), Path::new("etc/hosts"));
+        assert_eq!(normalize_member_path(Path::new("./x/./y")), Path::new("x/y"));
+        assert_eq!(normalize_member_path(Path::new("../../x")), Path::new("x"));
+        assert_eq!(normalize_member_path(Path::new("/")), Path::new(""));
+        assert!(is_unsafe_member_path(Path::new("a/../../b")));
+        assert!(!is_unsafe_member_path(Path::new("a/b")));
+    }
+
+    #[test]
+    fn test_transform_and_strip() {
+        assert!(PathRewrite::new().transform("s/a/b/q").is_err());
+        assert!(PathRewrite::new().transform("s/a/b").is_err());
+
+        let rewrite = PathRewrite::new()
+            .transform("s,^usr/local/,opt/,")
+            .unwrap()
+            .transform(r"s/(v)([0-9])/<&>\2/g")
+            .unwrap()
+            .strip_components(1);
+        assert_eq!(
+            rewrite.apply(Path::new("usr/local/lib/v1/v2")),
+            Some(PathBuf::from("lib/<v1>1/<v2>2"))
+        );
+        assert_eq!(rewrite.apply(Path::new("usr")), None);
+        assert_eq!(rewrite.apply(Path::new("etc/x")), Some(PathBuf::from("x")));
+    }
+}